@@ -0,0 +1,213 @@
+use std::io;
+
+use crate::bufio::*;
+
+/// A bitwise model driving a BitEncoder or BitDecoder. Any model can
+/// be used with either, including one chosen at runtime as a `Box<dyn Model>`,
+/// or a `&mut` borrow of one that outlives the coder.
+pub trait Model {
+    /// Probability that the next bit is a 1, scaled to 12 bits (0..4096).
+    fn p(&mut self) -> u16;
+    /// Update the model with the bit that was actually coded.
+    fn update(&mut self, bit: u8);
+}
+
+impl<M: Model + ?Sized> Model for &mut M {
+    fn p(&mut self) -> u16 {
+        (**self).p()
+    }
+
+    fn update(&mut self, bit: u8) {
+        (**self).update(bit)
+    }
+}
+
+impl<M: Model + ?Sized> Model for Box<M> {
+    fn p(&mut self) -> u16 {
+        (**self).p()
+    }
+
+    fn update(&mut self, bit: u8) {
+        (**self).update(bit)
+    }
+}
+
+/// Binary arithmetic encoder. The code is kept as a range [low, high]
+/// that narrows with each bit; once the top bytes of low and high
+/// agree they can't change anymore and are shifted out.
+pub struct BitEncoder<M: Model, W: BufferedWrite> {
+    model:     M,
+    high:      u32,
+    low:       u32,
+    file_out:  W,
+}
+
+impl<M: Model, W: BufferedWrite> BitEncoder<M, W> {
+    pub fn new(model: M, file_out: W) -> Self {
+        Self {
+            model,
+            high: 0xFFFFFFFF,
+            low:  0,
+            file_out,
+        }
+    }
+
+    pub fn encode(&mut self, bit: u8) -> io::Result<()> {
+        let p = self.model.p();
+        self.code(bit, p)?;
+        self.model.update(bit);
+        Ok(())
+    }
+
+    /// Encode a bit with probability 1/2, without consulting or updating
+    /// the model. For side information like flags.
+    pub fn encode_flat(&mut self, bit: u8) -> io::Result<()> {
+        self.code(bit, 2048)
+    }
+
+    fn code(&mut self, bit: u8, p: u16) -> io::Result<()> {
+        let mid = split(self.high, self.low, p);
+
+        if bit == 1 {
+            self.high = mid;
+        }
+        else {
+            self.low = mid + 1;
+        }
+
+        while ((self.high ^ self.low) & 0xFF000000) == 0 {
+            self.file_out.write_u8_forced(self.high >> 24)?;
+            self.high = (self.high << 8) + 255;
+            self.low <<= 8;
+        }
+        Ok(())
+    }
+
+    /// Encode a byte, most significant bit first.
+    pub fn encode_byte(&mut self, byte: u8) -> io::Result<()> {
+        for i in (0..8).rev() {
+            self.encode((byte >> i) & 1)?;
+        }
+        Ok(())
+    }
+
+    /// Write out the leading byte of high, which is enough to decode the
+    /// last bit, and flush the output.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while ((self.high ^ self.low) & 0xFF000000) == 0 {
+            self.file_out.write_u8_forced(self.high >> 24)?;
+            self.high = (self.high << 8) + 255;
+            self.low <<= 8;
+        }
+        self.file_out.write_u8_forced(self.high >> 24)?;
+        self.file_out.flush_buffer()
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.file_out
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+}
+
+/// Binary arithmetic decoder, mirroring BitEncoder. x holds the next 4
+/// bytes of the code, which always lie within [low, high].
+pub struct BitDecoder<M: Model, R: BufferedRead> {
+    model:     M,
+    high:      u32,
+    low:       u32,
+    x:         u32,
+    past_end:  u32, // Number of bytes read past the end of the input
+    file_in:   R,
+}
+
+impl<M: Model, R: BufferedRead> BitDecoder<M, R> {
+    /// Create a decoder, reading the first 4 bytes of the code.
+    pub fn new(model: M, file_in: R) -> io::Result<Self> {
+        let mut dec = Self {
+            model,
+            high:     0xFFFFFFFF,
+            low:      0,
+            x:        0,
+            past_end: 0,
+            file_in,
+        };
+        for _ in 0..4 {
+            dec.x = (dec.x << 8) + dec.next_byte()?;
+        }
+        Ok(dec)
+    }
+
+    pub fn decode(&mut self) -> io::Result<u8> {
+        let p = self.model.p();
+        let bit = self.code(p)?;
+        self.model.update(bit);
+        Ok(bit)
+    }
+
+    /// Decode a bit coded with encode_flat.
+    pub fn decode_flat(&mut self) -> io::Result<u8> {
+        self.code(2048)
+    }
+
+    fn code(&mut self, p: u16) -> io::Result<u8> {
+        let mid = split(self.high, self.low, p);
+
+        let mut bit = 0;
+        if self.x <= mid {
+            bit = 1;
+            self.high = mid;
+        }
+        else {
+            self.low = mid + 1;
+        }
+
+        while ((self.high ^ self.low) & 0xFF000000) == 0 {
+            self.high = (self.high << 8) + 255;
+            self.low <<= 8;
+            self.x = (self.x << 8) + self.next_byte()?;
+        }
+        Ok(bit)
+    }
+
+    /// Decode a byte, most significant bit first.
+    pub fn decode_byte(&mut self) -> io::Result<u8> {
+        let mut byte = 0;
+        for _ in 0..8 {
+            byte = (byte << 1) | self.decode()?;
+        }
+        Ok(byte)
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    /// True once the decoder has read more than 3 bytes past the end of
+    /// the input. Decoding a whole stream reads at most 3, so after that
+    /// the stream must have been truncated or corrupted.
+    pub fn overrun(&self) -> bool {
+        self.past_end > 3
+    }
+
+    // Next byte of the code, or 0 past the end of the input.
+    fn next_byte(&mut self) -> io::Result<u32> {
+        let byte = self.file_in.read_u8_checked()?.unwrap_or_else(|| {
+            self.past_end += 1;
+            0
+        });
+        Ok(byte as u32)
+    }
+}
+
+// Split the range in proportion to p, the probability of a 1. A 1 takes
+// [low, mid] and a 0 takes [mid + 1, high]. The range is split in two
+// parts so the multiplication doesn't overflow.
+fn split(high: u32, low: u32, p: u16) -> u32 {
+    assert!(p < 4096);
+    let p = p as u32;
+    let range = high - low;
+    low + (range >> 12) * p + (((range & 0x0FFF) * p) >> 12)
+}
@@ -1,8 +1,16 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::fs::File;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
 use crate::ari::log::squash;
 use crate::ari::log::stretch;
 use crate::ari::state::next_state;
@@ -11,6 +19,10 @@ use crate::ari::state::next_state;
 const PR_MSK: i32 = 0xFFFFFE00; // High 23 bit mask
 const LIMIT: usize = 127; // Controls rate of adaptation (higher = slower) (0..512)
 
+/// Approximate memory needed to compress or decompress with any SSE
+/// profile: the model and SSE tables, and I/O buffers.
+pub const MEMORY: u64 = 12 << 20;
+
 // StateMap --------------------------------------------------------
 struct StateMap {
     cxt:     usize,         
@@ -44,7 +56,7 @@ impl StateMap {
 
         let pr_err = (bit << 18) - pr; // Prediction error
         let rec_v = self.rec[count] as i32; // Reciprocal value
-        let update = (pr_err * rec_v & PR_MSK) as u32;
+        let update = ((pr_err * rec_v) & PR_MSK) as u32;
         self.cxt_map[self.cxt] = self.cxt_map[self.cxt].wrapping_add(update); 
     }
 }
@@ -71,7 +83,7 @@ impl Apm {
 
     fn p(&mut self, bit: i32, rate: i32, mut pr: i32, cxt: usize) -> i32 {
         assert!(bit == 0 || bit == 1);
-        assert!(pr >= 0 && pr < 4096);
+        assert!((0..4096).contains(&pr));
         assert!(cxt < self.cxts);
 
         self.update(bit, rate);
@@ -99,45 +111,225 @@ impl Apm {
     }
 }
 
+// SSE profiles ----------------------------------------------------
+//
+// The prediction is refined by a chain of SSE stages. Each stage maps
+// the prediction through an APM selected by a context, then mixes the 
+// result with its input:
+//
+//   pr <- (apm(pr) * weight + pr * (4 - weight) + 2) / 4
+//
+// A stage with two rates averages two APMs with the same context that 
+// adapt at different speeds. Higher rates adapt more slowly.
+//
+// A profile is written as stages separated by commas, each as
+// CONTEXT:RATE[+RATE]:WEIGHT, where CONTEXT is one of
+//
+//   o0   bits of the current byte
+//   o1   o0 and the previous byte
+//   o1s  o0 and the low 5 bits of the previous byte
+//   h3   o0 and a hash of the previous 3 bytes
+//
+// The profile is stored in the stream header, so any profile can be 
+// decompressed. Fewer stages run faster, at some cost in compression.
+const DEFAULT_PROFILE: &str = "o0:5+9:4,o1:7:4,o1s:7:3,h3:7:2";
+const FAST_PROFILE: &str = "o1:5:4,h3:6:2";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum SseContext {
+    Order0,
+    Order1,
+    Order1Short,
+    Hash3,
+}
+
+impl SseContext {
+    const ALL: [SseContext; 4] = [
+        SseContext::Order0, 
+        SseContext::Order1, 
+        SseContext::Order1Short, 
+        SseContext::Hash3,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            SseContext::Order0      => "o0",
+            SseContext::Order1      => "o1",
+            SseContext::Order1Short => "o1s",
+            SseContext::Hash3       => "h3",
+        }
+    }
+
+    // Number of contexts
+    fn size(&self) -> usize {
+        match self {
+            SseContext::Order0      => 256,
+            SseContext::Order1      => 65536,
+            SseContext::Order1Short => 8192,
+            SseContext::Hash3       => 16384,
+        }
+    }
+
+    fn cxt(&self, cxt: usize, cxt4: usize) -> usize {
+        match self {
+            SseContext::Order0      => cxt,
+            SseContext::Order1      => cxt | ((cxt4 << 8) & 0xFF00),
+            SseContext::Order1Short => cxt | (cxt4 & 0x1F00),
+            SseContext::Hash3       => {
+                let hash = (((cxt4 as u32) & 0xFFFFFF).wrapping_mul(123456791)) >> 18;
+                ((cxt as u32) ^ hash) as usize
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+struct SseStage {
+    cxt:    SseContext,
+    rate:   u8,
+    rate2:  u8, // Rate of the second APM, 0 if there isn't one
+    weight: u8, // Out of 4
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SseProfile {
+    stages: Vec<SseStage>,
+}
+
+impl Default for SseProfile {
+    fn default() -> SseProfile {
+        SseProfile::parse(DEFAULT_PROFILE).unwrap()
+    }
+}
+
+impl SseProfile {
+    pub fn fast() -> SseProfile {
+        SseProfile::parse(FAST_PROFILE).unwrap()
+    }
+
+    // Parse a profile as described above. "none" disables SSE.
+    pub fn parse(spec: &str) -> Result<SseProfile, String> {
+        if spec == "none" {
+            return Ok(SseProfile { stages: Vec::new() });
+        }
+        let stages = spec.split(',').map(|stage| {
+            let fields = stage.split(':').collect::<Vec<&str>>();
+            let [cxt, rates, weight] = fields[..] else {
+                return Err(format!("expected CONTEXT:RATE:WEIGHT, got '{}'", stage));
+            };
+            let cxt = SseContext::ALL.iter()
+                .find(|c| c.name() == cxt)
+                .ok_or_else(|| format!("unknown SSE context '{}'", cxt))?;
+            let (rate, rate2) = rates.split_once('+').unwrap_or((rates, "0"));
+            SseStage::new(
+                *cxt, 
+                rate.parse().map_err(|_| format!("invalid rate '{}'", rate))?, 
+                rate2.parse().map_err(|_| format!("invalid rate '{}'", rate2))?, 
+                weight.parse().map_err(|_| format!("invalid weight '{}'", weight))?,
+            )
+        })
+        .collect::<Result<Vec<SseStage>, String>>()?;
+        Ok(SseProfile { stages })
+    }
+
+    // Header: number of stages, then 4 bytes per stage.
+    fn write<W: Write>(&self, file_out: &mut BufWriter<W>) -> io::Result<()> {
+        file_out.write_u8(self.stages.len() as u8)?;
+        for stage in self.stages.iter() {
+            let cxt = SseContext::ALL.iter().position(|c| *c == stage.cxt).unwrap();
+            file_out.write_bytes(&[cxt as u8, stage.rate, stage.rate2, stage.weight])?;
+        }
+        Ok(())
+    }
+
+    fn read<R: Read>(file_in: &mut BufReader<R>) -> io::Result<SseProfile> {
+        let truncated = || io::Error::new(ErrorKind::InvalidData, "truncated fpaq header");
+        let len = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+        let stages = (0..len).map(|_| {
+            let [cxt, rate, rate2, weight] = file_in.read_checked::<4>()?.ok_or_else(truncated)?;
+            let cxt = SseContext::ALL.get(cxt as usize).ok_or_else(|| 
+                format!("unknown SSE context {}", cxt)
+            );
+            cxt.and_then(|cxt| SseStage::new(*cxt, rate, rate2, weight))
+                .map_err(|e| io::Error::new(ErrorKind::InvalidData, format!("invalid fpaq header: {}", e)))
+        })
+        .collect::<io::Result<Vec<SseStage>>>()?;
+        Ok(SseProfile { stages })
+    }
+}
+
+impl SseStage {
+    fn new(cxt: SseContext, rate: u8, rate2: u8, weight: u8) -> Result<SseStage, String> {
+        if !(1..32).contains(&rate) || !(rate2 == 0 || (1..32).contains(&rate2)) {
+            return Err("rates must be between 1 and 31".to_string());
+        }
+        if weight > 4 {
+            return Err("weights must be between 0 and 4".to_string());
+        }
+        Ok(SseStage { cxt, rate, rate2, weight })
+    }
+}
+
+struct Sse {
+    stage: SseStage,
+    apm:   Apm,
+    apm2:  Option<Apm>,
+}
+
+impl Sse {
+    fn new(stage: SseStage) -> Sse {
+        Sse {
+            stage,
+            apm:  Apm::new(stage.cxt.size()),
+            apm2: if stage.rate2 > 0 { Some(Apm::new(stage.cxt.size())) } else { None },
+        }
+    }
+
+    fn p(&mut self, bit: i32, pr: i32, cxt: usize, cxt4: usize) -> i32 {
+        let cxt = self.stage.cxt.cxt(cxt, cxt4);
+        let mut sse_pr = self.apm.p(bit, self.stage.rate as i32, pr, cxt);
+        if let Some(apm2) = &mut self.apm2 {
+            sse_pr = (sse_pr + apm2.p(bit, self.stage.rate2 as i32, pr, cxt) + 1) >> 1;
+        }
+        let weight = self.stage.weight as i32;
+        (sse_pr * weight + pr * (4 - weight) + 2) >> 2
+    }
+}
+
 struct Predictor {
     cxt:   usize,         
     cxt4:  usize,        
     pr:    i32,         
     state: [u8; 256],  
     sm:    StateMap, 
-    apm:   [Apm; 5],  
+    sse:   Vec<Sse>,  
 }
 
 impl Predictor {
-    fn new() -> Self {
-        let apm = [
-            Apm::new(256),    
-            Apm::new(256),   
-            Apm::new(65536), 
-            Apm::new(8192),
-            Apm::new(16384), 
-        ];
-
+    fn new(profile: &SseProfile) -> Self {
         Self {
             cxt:   0,                    
             cxt4:  0,                    
             pr:    2048,                 
             state: [0; 256],             
             sm:    StateMap::new(65536), 
-            apm,
+            sse:   profile.stages.iter().map(|stage| Sse::new(*stage)).collect(),
         }
     }
+}
 
-    fn p(&mut self) -> i32 { 
+impl Model for Predictor {
+    fn p(&mut self) -> u16 { 
         assert!(self.pr >= 0 && self.pr < 4096);
-        self.pr 
+        self.pr as u16
     } 
 
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: u8) {
         assert!(bit == 0 || bit == 1);
+        let bit = bit as i32;
         self.state[self.cxt] = next_state(self.state[self.cxt], bit);
 
-        self.cxt += self.cxt + bit as usize;
+        self.cxt = self.cxt * 2 + bit as usize;
         if self.cxt >= 256 {
             self.cxt4 = (self.cxt4 << 8) | (self.cxt - 256);
             self.cxt = 0;
@@ -146,136 +338,44 @@ impl Predictor {
         self.pr = self.sm.p(bit, self.state[self.cxt] as usize);
 
         // SSE
-        let cxt = self.cxt;
-        self.pr = self.apm[0].p(bit, 5, self.pr, cxt) + 
-                  self.apm[1].p(bit, 9, self.pr, cxt) + 1 >> 1;
-        
-        let cxt = self.cxt | (self.cxt4 << 8) & 0xFF00;
-        self.pr = self.apm[2].p(bit, 7, self.pr, cxt);
-        
-        let cxt = self.cxt | (self.cxt4 & 0x1F00);
-        self.pr = self.apm[3].p(bit, 7, self.pr, cxt) * 3 + self.pr + 2 >> 2;
-
-        let hash = (((self.cxt4 as u32) & 0xFFFFFF).wrapping_mul(123456791)) >> 18;
-        let cxt = ((self.cxt as u32) ^ hash) as usize;
-        self.pr = self.apm[4].p(bit, 7, self.pr, cxt) + self.pr + 1 >> 1;
-    }   
-}
-
-struct Encoder {
-    predictor: Predictor,
-    high:      u32,
-    low:       u32,
-    file_out:  BufWriter<File>,
-}
-
-impl Encoder {
-    fn new(file_out: BufWriter<File>) -> Self {
-        Self {
-            predictor: Predictor::new(), 
-            high: 0xFFFFFFFF, 
-            low: 0,  
-            file_out,
+        for sse in self.sse.iter_mut() {
+            self.pr = sse.p(bit, self.pr, self.cxt, self.cxt4);
         }
-    }
-
-    fn encode(&mut self, bit: i32) {
-        let p = self.predictor.p() as u32;
-        let range = self.high - self.low;
-        let mid = self.low + (range >> 12) * p + ((range & 0x0FFF) * p >> 12);
-
-        if bit == 1 { 
-            self.high = mid;    
-        } 
-        else {        
-            self.low = mid + 1; 
-        }
-        self.predictor.update(bit);
-
-        while ((self.high ^ self.low) & 0xFF000000) == 0 {
-            self.file_out.write_u8_forced(self.high >> 24);
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8;  
-        }
-    }
-
-    fn flush(&mut self) {
-        while ((self.high ^ self.low) & 0xFF000000) == 0 {
-            self.file_out.write_u8_forced(self.high >> 24);
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8; 
-        }
-        self.file_out.write_u8_forced(self.high >> 24);
-        self.file_out.flush_buffer();
-    }
+    }   
 }
 
-struct Decoder {
-    predictor: Predictor,
-    high:      u32,
-    low:       u32,
-    x:         u32,
-    file_in:   BufReader<File>,   
+pub fn fpaq_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, profile: &SseProfile) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, profile))
 }
 
-impl Decoder {
-    fn new(file_in: BufReader<File>) -> Self {
-        let mut dec = Self {
-            predictor: Predictor::new(), 
-            high: 0xFFFFFFFF, 
-            low: 0, 
-            x: 0, 
-            file_in, 
-        };
-        for _ in 0..4 {
-            dec.x = (dec.x << 8) + dec.file_in.read_u8() as u32;
-        }
-        dec
-    }
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, profile: &SseProfile) -> io::Result<()> {
+    profile.write(&mut file_out)?;
+    let mut enc = BitEncoder::new(Predictor::new(profile), file_out);
 
-    fn decode(&mut self) -> u8 {
-        let p = self.predictor.p() as u32;
-        let range = self.high - self.low;
-        let mid = self.low + (range >> 12) * p + ((range & 0x0FFF) * p >> 12);
-
-        let mut bit = 0;
-        if self.x <= mid {
-            bit = 1;
-            self.high = mid;
-        } 
-        else {
-            self.low = mid + 1;
-        }
-        self.predictor.update(bit);
-        
-        while ((self.high ^ self.low) & 0xFF000000) == 0 {
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8;
-            self.x = (self.x << 8) + self.file_in.read_u8() as u32; 
-        }
-        bit as u8
-    }
+    while let Some(byte) = file_in.read_u8_checked()? { 
+        enc.encode(1)?;
+        enc.encode_byte(byte)?;
+    }   
+    enc.encode(0)?;
+    enc.flush()
 }
 
-pub fn fpaq_compress(mut file_in: BufReader<File>, file_out: BufWriter<File>) {
-    let mut enc = Encoder::new(file_out);
-
-    while let Some(byte) = file_in.read_u8_checked() { 
-        enc.encode(1);
-        for i in (0..8).rev() {
-            enc.encode(((byte >> i) & 1).into());
-        } 
-    }   
-    enc.encode(0);
-    enc.flush(); 
+// Decompress an fpaq stream, returning an InvalidData error if the SSE
+// profile in the header isn't valid or the stream ends early.
+#[allow(clippy::redundant_closure)]
+pub fn fpaq_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
 }
 
-pub fn fpaq_decompress(file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let mut dec = Decoder::new(file_in);
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let profile = SseProfile::read(&mut file_in)?;
+    let mut dec = BitDecoder::new(Predictor::new(&profile), file_in)?;
             
-    while dec.decode() != 0 { 
-        let byte = (0..8).fold(1, |acc, _| (acc << 1) + dec.decode());
-        file_out.write_u8(byte);
+    while dec.decode()? != 0 { 
+        file_out.write_u8(dec.decode_byte()?)?;
+        if dec.overrun() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated fpaq stream"));
+        }
     }
-    file_out.flush_buffer();
+    file_out.flush()
 }
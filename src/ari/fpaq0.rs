@@ -0,0 +1,91 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
+
+// Order 0 arithmetic coding as in Mahoney's fpaq0, the simplest model
+// the coder can drive: for each bit of the byte so far, a count of the
+// 0s and 1s that followed it, and p(1) = (n1 + 1) / (n0 + n1 + 2). No
+// bit history states, no SSE. It's here as a baseline for what fpaq's
+// modeling buys, and as the fastest of the arithmetic coders.
+//
+// Each byte is preceded by a 0 flag, and the stream ends with a 1, both
+// coded in the same model as the bits.
+
+struct Predictor {
+    cxt:    usize,         // 1, then the flag and the bits so far
+    counts: [[u16; 2]; 512],
+}
+
+impl Predictor {
+    fn new() -> Predictor {
+        Predictor { cxt: 1, counts: [[0; 2]; 512] }
+    }
+}
+
+impl Model for Predictor {
+    fn p(&mut self) -> u16 {
+        let [n0, n1] = self.counts[self.cxt];
+        let p = 4096 * (n1 as u32 + 1) / (n0 as u32 + n1 as u32 + 2);
+        p.clamp(1, 4095) as u16
+    }
+
+    fn update(&mut self, bit: u8) {
+        let counts = &mut self.counts[self.cxt];
+        counts[bit as usize] += 1;
+        // Halve both counts before either overflows, which also lets
+        // the model follow changes in the input.
+        if counts[bit as usize] == u16::MAX {
+            counts[0] >>= 1;
+            counts[1] >>= 1;
+        }
+        self.cxt = self.cxt * 2 + bit as usize;
+        if self.cxt >= 512 {
+            self.cxt = 1;
+        }
+    }
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn fpaq0_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, file_out: BufWriter<W>) -> io::Result<()> {
+    let mut enc = BitEncoder::new(Predictor::new(), file_out);
+
+    while let Some(byte) = file_in.read_u8_checked()? {
+        enc.encode(0)?;
+        enc.encode_byte(byte)?;
+    }
+    enc.encode(1)?;
+    enc.flush()
+}
+
+// Decompress an fpaq0 stream, returning an InvalidData error if it ends
+// early.
+#[allow(clippy::redundant_closure)]
+pub fn fpaq0_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut dec = BitDecoder::new(Predictor::new(), file_in)?;
+
+    while dec.decode()? == 0 {
+        file_out.write_u8(dec.decode_byte()?)?;
+        if dec.overrun() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated fpaq0 stream"));
+        }
+    }
+    file_out.flush()
+}
@@ -0,0 +1,322 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::rans::normalize;
+
+// Order 0 tANS, as in Collet's Finite State Entropy: rANS with its
+// arithmetic precomputed into tables. Each of the TABLE_SIZE states
+// stands for a symbol, and coding one moves to another state, shifting
+// a few bits in or out, by table lookup alone, so it runs at about the
+// speed of huffman coding while charging fractional bits per symbol.
+//
+// Each block's counts are scaled to sum to TABLE_SIZE and stored in
+// front of it, and the symbols are spread over the states from them, so
+// the decoder builds the same tables. As with rANS, decoding runs in the
+// reverse order of encoding, so each block is encoded backwards and the
+// bits written out in the order they're decoded.
+//
+// Output format:
+//
+//   blocks, each:
+//     length       (u32, bytes of input in the block, at most BLOCK_SIZE)
+//     symbols      (32 bytes, a bit for each byte that occurs, low first)
+//     counts       (u16 for each of those bytes, summing to TABLE_SIZE)
+//     size         (u32, bytes of coded data)
+//     data         (the first state less TABLE_SIZE in TABLE_LOG bits,
+//                   then the bits read after decoding each byte, packed
+//                   low bit first)
+//   end            (u32 0)
+//
+// Once the last byte of a block is decoded, the state is back to the
+// encoder's initial TABLE_SIZE, which the decoder checks.
+
+const TABLE_LOG: u32 = 12;
+const TABLE_SIZE: usize = 1 << TABLE_LOG;
+const BLOCK_SIZE: usize = 1 << 17;
+
+// The symbol of each state. Stepping by a little over half the table,
+// an odd number, visits every state once, and scatters each symbol's
+// states over the table so they're reached from all the others.
+fn spread(counts: &[u32; 256]) -> Vec<u8> {
+    let step = (TABLE_SIZE >> 1) + (TABLE_SIZE >> 3) + 3;
+    let mut symbols = vec![0; TABLE_SIZE];
+    let mut pos = 0;
+    for (s, &count) in counts.iter().enumerate() {
+        for _ in 0..count {
+            symbols[pos] = s as u8;
+            pos = (pos + step) & (TABLE_SIZE - 1);
+        }
+    }
+    symbols
+}
+
+// The position of the highest set bit of x, which is nonzero.
+fn high_bit(x: u32) -> u32 {
+    31 - x.leading_zeros()
+}
+
+// For each symbol, how to find the number of bits to shift out of a
+// state before coding it, and where its next states start in next.
+struct EncodeTable {
+    next:        Vec<u16>,    // Next states, TABLE_SIZE and up, grouped by symbol
+    delta_bits:  [u32; 256],  // Added to a state, the top 16 bits are the bits to shift out
+    delta_state: [i32; 256],
+}
+
+impl EncodeTable {
+    fn new(counts: &[u32; 256]) -> EncodeTable {
+        let symbols = spread(counts);
+        let mut cum = [0; 256];
+        let mut total = 0;
+        for (cum, &count) in cum.iter_mut().zip(counts.iter()) {
+            *cum = total;
+            total += count;
+        }
+
+        let mut next = vec![0; TABLE_SIZE];
+        let mut pos = cum;
+        for (state, &s) in symbols.iter().enumerate() {
+            next[pos[s as usize] as usize] = (TABLE_SIZE + state) as u16;
+            pos[s as usize] += 1;
+        }
+
+        let mut delta_bits = [0; 256];
+        let mut delta_state = [0; 256];
+        for s in 0..256 {
+            let count = counts[s];
+            if count == 0 {
+                continue;
+            }
+            // A symbol with count n is coded from states n..2n, after
+            // shifting out max_bits or one less.
+            let max_bits = if count == 1 { TABLE_LOG } else { TABLE_LOG - high_bit(count - 1) };
+            delta_bits[s] = (max_bits << 16) - (count << max_bits);
+            delta_state[s] = cum[s] as i32 - count as i32;
+        }
+        EncodeTable { next, delta_bits, delta_state }
+    }
+}
+
+// For each state, its symbol, and how to find the next state from the
+// bits read after it.
+#[derive(Clone, Copy, Default)]
+struct Entry {
+    symbol: u8,
+    bits:   u8,
+    base:   u16,
+}
+
+fn decode_table(counts: &[u32; 256]) -> Vec<Entry> {
+    let symbols = spread(counts);
+    let mut next = *counts;
+    symbols.iter().map(|&s| {
+        let x = next[s as usize];
+        next[s as usize] += 1;
+        let bits = TABLE_LOG - high_bit(x);
+        Entry { symbol: s, bits: bits as u8, base: ((x << bits) as usize - TABLE_SIZE) as u16 }
+    })
+    .collect()
+}
+
+// Bits packed low bit first.
+struct BitWriter<'a> {
+    out:   &'a mut Vec<u8>,
+    bits:  u64,
+    count: u32,
+}
+
+impl BitWriter<'_> {
+    fn write(&mut self, value: u32, n: u32) {
+        self.bits |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+        }
+    }
+}
+
+struct BitReader<'a> {
+    data:  &'a [u8],
+    pos:   usize,
+    bits:  u64,
+    count: u32,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let &byte = self.data.get(self.pos).ok_or_else(|| corrupt("block overrun"))?;
+            self.bits |= (byte as u64) << self.count;
+            self.count += 8;
+            self.pos += 1;
+        }
+        let value = (self.bits & ((1 << n) - 1)) as u32;
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+}
+
+// Code block with a table of its own counts, appending the counts and
+// the coded data to out.
+pub(crate) fn encode_block(block: &[u8], out: &mut Vec<u8>) {
+    let mut counts = [0; 256];
+    for &byte in block.iter() {
+        counts[byte as usize] += 1;
+    }
+    let counts = normalize(&counts, TABLE_SIZE as u32);
+    let mut symbols = [0u8; 32];
+    for s in (0..256).filter(|&s| counts[s] > 0) {
+        symbols[s / 8] |= 1 << (s % 8);
+    }
+    out.extend_from_slice(&symbols);
+    for &count in counts.iter().filter(|&&count| count > 0) {
+        out.extend_from_slice(&(count as u16).to_le_bytes());
+    }
+
+    // The bits shifted out coding each byte, backwards.
+    let table = EncodeTable::new(&counts);
+    let mut shifted = Vec::with_capacity(block.len());
+    let mut state = TABLE_SIZE as u32;
+    for &byte in block.iter().rev() {
+        let s = byte as usize;
+        let n = (state + table.delta_bits[s]) >> 16;
+        shifted.push((state & ((1 << n) - 1), n));
+        state = table.next[((state >> n) as i32 + table.delta_state[s]) as usize] as u32;
+    }
+
+    let size_pos = out.len();
+    out.extend_from_slice(&[0; 4]);
+    let mut writer = BitWriter { out, bits: 0, count: 0 };
+    writer.write(state - TABLE_SIZE as u32, TABLE_LOG);
+    for &(value, n) in shifted.iter().rev() {
+        writer.write(value, n);
+    }
+    writer.flush();
+    let size = (out.len() - size_pos - 4) as u32;
+    out[size_pos..size_pos + 4].copy_from_slice(&size.to_le_bytes());
+}
+
+// Decode a block of len bytes from encode_block at the start of data,
+// appending it to out, and return the number of bytes of data it took.
+pub(crate) fn decode_block(data: &[u8], len: usize, out: &mut Vec<u8>) -> io::Result<usize> {
+    let symbols = data.get(..32).ok_or_else(truncated)?;
+    let mut pos = 32;
+    let mut counts = [0; 256];
+    for s in (0..256).filter(|&s| symbols[s / 8] & (1 << (s % 8)) != 0) {
+        let count = data.get(pos..pos + 2).ok_or_else(truncated)?;
+        counts[s] = u16::from_le_bytes([count[0], count[1]]) as u32;
+        pos += 2;
+    }
+    if counts.iter().sum::<u32>() != TABLE_SIZE as u32
+        || (0..256).any(|s| symbols[s / 8] & (1 << (s % 8)) != 0 && counts[s] == 0) {
+        return Err(corrupt("bad count table"));
+    }
+
+    let size = data.get(pos..pos + 4).ok_or_else(truncated)?;
+    let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+    pos += 4;
+    if size > max_size(len) {
+        return Err(corrupt("bad block size"));
+    }
+    let coded = data.get(pos..pos + size).ok_or_else(truncated)?;
+
+    let table = decode_table(&counts);
+    let mut reader = BitReader { data: coded, pos: 0, bits: 0, count: 0 };
+    let mut state = reader.read(TABLE_LOG)? as usize;
+    for _ in 0..len {
+        let entry = table[state];
+        out.push(entry.symbol);
+        state = entry.base as usize + reader.read(entry.bits as u32)? as usize;
+    }
+    if state != 0 || reader.pos != size {
+        return Err(corrupt("block doesn't decode to its end"));
+    }
+    Ok(pos + size)
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn fse_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut coded = Vec::with_capacity(2 * BLOCK_SIZE);
+    for block in Blocks::new(&mut file_in, BLOCK_SIZE) {
+        let block = block?;
+        file_out.write_u32(block.len() as u32)?;
+        coded.clear();
+        encode_block(&block, &mut coded);
+        file_out.write_bytes(&coded)?;
+    }
+    file_out.write_u32(0u32)?;
+    file_out.flush_buffer()
+}
+
+// Decompress an fse stream, returning an InvalidData error if it ends
+// early or doesn't decode to what was coded.
+#[allow(clippy::redundant_closure)]
+pub fn fse_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut data = Vec::new();
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    loop {
+        let len = file_in.read_u32_checked()?.ok_or_else(truncated)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len > BLOCK_SIZE {
+            return Err(corrupt("block too large"));
+        }
+        // The counts and size come first, then size bytes of data.
+        data.clear();
+        file_in.by_ref().take(32).read_to_end(&mut data)?;
+        let present = data.iter().map(|byte| byte.count_ones() as u64).sum::<u64>();
+        file_in.by_ref().take(present * 2 + 4).read_to_end(&mut data)?;
+        if data.len() as u64 != 32 + present * 2 + 4 {
+            return Err(truncated());
+        }
+        let size = u32::from_le_bytes(data[data.len() - 4..].try_into().unwrap()) as usize;
+        if size > max_size(len) {
+            return Err(corrupt("bad block size"));
+        }
+        file_in.by_ref().take(size as u64).read_to_end(&mut data)?;
+
+        block.clear();
+        decode_block(&data, len, &mut block)?;
+        file_out.write_bytes(&block)?;
+    }
+    file_out.flush()
+}
+
+// The most coded data a block of len bytes can take: TABLE_LOG bits for
+// the first state and at most TABLE_LOG for each byte.
+fn max_size(len: usize) -> usize {
+    ((len + 1) * TABLE_LOG as usize).div_ceil(8)
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "truncated fse stream")
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("corrupt fse stream: {}", msg))
+}
@@ -1,3 +1,24 @@
+// Fixed point logistic functions for mixing and SSE.
+//
+// stretch(p) = ln(p / (1 - p)) and squash is its inverse. There are two
+// precisions:
+//
+//   squash, stretch      p in 12 bits (0 to 4095), and the logistic
+//                        domain in 1/256ths, clamped to -2047 to 2047.
+//   squash16, stretch16  p in 16 bits (1 to 65535), and the logistic
+//                        domain in 1/2048ths, -22713 to 22713.
+//
+// The stream formats of fpaq and lpaq1 depend on the 12 bit versions
+// exactly, so they stay as they are. New models should use the 16 bit
+// versions, which lose much less near 0 and 1.
+
+/// Scale of the 16 bit logistic domain: squash16(d) is the
+/// probability of ln(p / (1 - p)) = d / STRETCH16_SCALE.
+pub const STRETCH16_SCALE: i32 = 2048;
+
+/// Largest value stretch16 returns, stretch16(65535).
+pub const STRETCH16_MAX: i32 = 22713;
+
 pub const fn squash(d: i32) -> i32 {
     const SQ_T: [i32; 33] = [
     1,2,3,6,10,16,27,45,73,120,194,310,488,747,1101,
@@ -32,4 +53,94 @@ const fn build_stretch_table() -> [i16; 4096] {
 
 pub fn stretch(p: i32) -> i32 {
     STRETCH[p as usize] as i32
-}
\ No newline at end of file
+}
+
+// squash16 at every 16th point of the domain from -32768 to 32768,
+// interpolated between.
+const SQUASH16: [u16; 4097] = build_squash16_table();
+
+const fn build_squash16_table() -> [u16; 4097] {
+    let mut table = [0u16; 4097];
+    let mut i = 0;
+    while i <= 4096 {
+        let d = ((i as i32 - 2048) * 16) as f64 / STRETCH16_SCALE as f64;
+        let p = 65536.0 / (1.0 + exp(-d)) + 0.5;
+        table[i] = clamp16(p as i64);
+        i += 1;
+    }
+    table
+}
+
+/// The 16 bit probability of d / STRETCH16_SCALE in the logistic
+/// domain, 1 to 65535.
+pub const fn squash16(d: i32) -> i32 {
+    if d >= 32768  { return 65535; }
+    if d <= -32768 { return 1;     }
+    let w = d & 15;
+    let i = ((d >> 4) + 2048) as usize;
+    (SQUASH16[i] as i32 * (16 - w) + SQUASH16[i + 1] as i32 * w + 8) >> 4
+}
+
+static STRETCH16: [i16; 65536] = build_stretch16_table();
+
+const fn build_stretch16_table() -> [i16; 65536] {
+    let mut table = [0i16; 65536];
+    let mut p = 1;
+    while p < 65536 {
+        let d = ln(p as f64 / (65536 - p) as f64) * STRETCH16_SCALE as f64;
+        table[p] = (if d < 0.0 { d - 0.5 } else { d + 0.5 }) as i16;
+        p += 1;
+    }
+    table[0] = table[1];
+    table
+}
+
+/// ln(p / (1 - p)) of a 16 bit probability, times STRETCH16_SCALE.
+/// p = 0 is taken as 1.
+pub fn stretch16(p: i32) -> i32 {
+    STRETCH16[p as usize] as i32
+}
+
+const fn clamp16(p: i64) -> u16 {
+    if p < 1 { 1 } else if p > 65535 { 65535 } else { p as u16 }
+}
+
+// e^x, as far as the tables need it: x / ln 2 is split into an integer
+// power of 2 and a remainder under ln 2 / 2, which the series handles.
+const fn exp(x: f64) -> f64 {
+    const LN2: f64 = std::f64::consts::LN_2;
+    let k = (if x < 0.0 { x / LN2 - 0.5 } else { x / LN2 + 0.5 }) as i32;
+    let r = x - k as f64 * LN2;
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut n = 1;
+    while n < 20 {
+        term = term * r / n as f64;
+        sum += term;
+        n += 1;
+    }
+    let mut k = k;
+    while k > 0 { sum *= 2.0; k -= 1; }
+    while k < 0 { sum /= 2.0; k += 1; }
+    sum
+}
+
+// ln x for x > 0: x = m * 2^e with m in [1, 2), and ln m = 2 atanh(z)
+// with z = (m - 1) / (m + 1), which is under 1/3.
+const fn ln(x: f64) -> f64 {
+    let mut m = x;
+    let mut e = 0;
+    while m >= 2.0 { m /= 2.0; e += 1; }
+    while m < 1.0  { m *= 2.0; e -= 1; }
+    let z = (m - 1.0) / (m + 1.0);
+    let z2 = z * z;
+    let mut sum = 0.0;
+    let mut term = z;
+    let mut n = 1;
+    while n < 40 {
+        sum += term / n as f64;
+        term *= z2;
+        n += 2;
+    }
+    2.0 * sum + e as f64 * std::f64::consts::LN_2
+}
@@ -1,17 +1,24 @@
+use std::io;
 use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Seek;
-use std::fs::File;
-use std::cell::RefCell;
-use std::rc::Rc;
+use std::io::SeekFrom;
 
 use crate::bufio::*;
+use crate::cpu;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
 use crate::ari::log::squash;
 use crate::ari::log::stretch;
-use crate::ari::state::next_state;
+use crate::ari::state::{StateTable, STATE_TABLE, RUN_STATE_TABLE};
     
-const MEM: usize = 1 << 23;
+const MAX_INPUTS: usize = 11;// Most predictions a Mixer can combine
 
 /// An APM takes an existing prediction and a context, and interpolates a 
 /// new, refined prediction. Also known as Secondary Symbol Estimation (SSE).
@@ -37,7 +44,7 @@ impl Apm {
 
     fn p(&mut self, bit: i32, rate: i32, mut pr: i32, cxt: usize) -> i32 {
         assert!(bit == 0 || bit == 1);
-        assert!(pr >= 0 && pr < 4096);
+        assert!((0..4096).contains(&pr));
         assert!(cxt < self.cxts);
 
         self.update(bit, rate);
@@ -65,12 +72,12 @@ impl Apm {
     }
 }
 
-/// A bit history (state) is mapped to a probability using an adaptive table
-/// (StateMap). Each table entry has a 22-bit probability (initially p = 0.5) 
-/// and 10-bit count (initially n = 0) packed into 32 bits.  After bit y is 
-/// predicted, n is incremented up to the limit (1023) and the probability is 
-/// adjusted by p := p + (y - p)/(n + 0.5).  This model is stationary: 
-/// p = (n1 + 0.5)/(n + 1), where n1 is the number of times y = 1 out of n.
+// A bit history (state) is mapped to a probability using an adaptive table
+// (StateMap). Each table entry has a 22-bit probability (initially p = 0.5) 
+// and 10-bit count (initially n = 0) packed into 32 bits.  After bit y is 
+// predicted, n is incremented up to the limit (1023) and the probability is 
+// adjusted by p := p + (y - p)/(n + 0.5).  This model is stationary: 
+// p = (n1 + 0.5)/(n + 1), where n1 is the number of times y = 1 out of n.
 
 #[allow(overflowing_literals)]
 const PR_MSK: i32 = 0xFFFFFC00; // High 22 bit mask
@@ -129,19 +136,22 @@ impl StateMap {
 /// backpropagation network modified to minimize coding cost rather than RMS 
 /// prediction error (thus dropping the factors p * (1 - p) from learning).
 struct Mixer {
-    max_in:  usize,    // Maximum number of inputs
-    inputs:  Vec<i32>, // Current inputs
-    weights: Vec<i32>, // Weights used for weighted averaging
-    wht_set: usize,    // Single set of weights chosen by a context
-    pr:      i32,      // Current prediction
+    max_in:  usize,             // Maximum number of inputs
+    inputs:  [i32; MAX_INPUTS], // Current inputs
+    len:     usize,             // Number of current inputs
+    weights: Vec<i32>,          // Weights used for weighted averaging
+    wht_set: usize,             // Single set of weights chosen by a context
+    pr:      i32,               // Current prediction
 }
 
 impl Mixer {
     /// Create a new Mixer with m sets of n weights.
     fn new(n: usize, m: usize) -> Self {
+        assert!(n <= MAX_INPUTS);
         Self {
             max_in:   n,                     
-            inputs:   Vec::with_capacity(n), 
+            inputs:   [0; MAX_INPUTS], 
+            len:      0,
             weights:  vec![0; n * m],        
             wht_set:  0,                     
             pr:       2048,                  
@@ -150,8 +160,8 @@ impl Mixer {
 
     /// Add an input prediction to the Mixer.
     fn add(&mut self, pr: i32) {
-        assert!(self.inputs.len() < self.inputs.capacity());
-        self.inputs.push(pr);
+        self.inputs[self.len] = pr;
+        self.len += 1;
     }
 
     /// Choose the set of weights to be used for averaging.
@@ -161,7 +171,7 @@ impl Mixer {
 
     /// Compute weighted average of input predictions.
     fn p(&mut self) -> i32 {
-        let d = dot_product(&self.inputs[..], &self.weights[self.wht_set..]);
+        let d = dot_product(&self.inputs[..self.len], &self.weights[self.wht_set..]);
         self.pr = squash(d);
         self.pr
     }
@@ -169,22 +179,19 @@ impl Mixer {
     /// Update weights based on prediction error.
     fn update(&mut self, bit: i32) {
         let error: i32 = ((bit << 12) - self.pr) * 7;
-        assert!(error >= -32768 && error < 32768);
-        train(&self.inputs[..], &mut self.weights[self.wht_set..], error);
-        self.inputs.clear();
+        assert!((-32768..32768).contains(&error));
+        train(&self.inputs[..self.len], &mut self.weights[self.wht_set..], error);
+        self.len = 0;
     }
 }
 
 /// Update weights based on prediction error.
 fn train(inputs: &[i32], weights: &mut [i32], error: i32) {
-    for (input, weight) in inputs.iter().zip(weights.iter_mut()) {
-        *weight += ((*input * error) + 0x8000) >> 16;
-    }
+    cpu::train(inputs, weights, error);
 }
 
 fn dot_product(inputs: &[i32], weights: &[i32]) -> i32 {
-    (inputs.iter().zip(weights.iter())
-    .map(|(i, w)| i * w).sum::<i32>()) >> 16
+    cpu::dot_product(inputs, weights) >> 16
 }
 
 
@@ -271,7 +278,7 @@ impl MatchModel {
                 cxt = (self.match_len >> 2) * 2 + pr_bit + 24; 
             }
             
-            let prev = self.buf[(self.buf_pos - 1) & self.buf_end];
+            let prev = self.buf[self.buf_pos.wrapping_sub(1) & self.buf_end];
             cxt = cxt * 256 + prev as usize;
         } 
         else {
@@ -328,14 +335,14 @@ impl MatchModel {
         self.match_ptr = self.ht[hash] as usize;
 
         if self.match_ptr != self.buf_pos {
-            let mut m1 = (self.match_ptr - self.match_len - 1) & self.buf_end;
-            let mut m2 = (self.buf_pos - self.match_len - 1) & self.buf_end;
+            let mut m1 = self.match_ptr.wrapping_sub(self.match_len + 1) & self.buf_end;
+            let mut m2 = self.buf_pos.wrapping_sub(self.match_len + 1) & self.buf_end;
 
             // Check subsequent previous bytes, stopping at a mismatch
             while self.match_len < MAX_LEN && m1 != self.buf_pos && self.buf[m2] == self.buf[m1] {
                 self.match_len += 1;
-                m1 = (m1 - 1) & self.buf_end; 
-                m2 = (m2 - 1) & self.buf_end;  
+                m1 = m1.wrapping_sub(1) & self.buf_end; 
+                m2 = m2.wrapping_sub(1) & self.buf_end;  
             }
         }
     }
@@ -354,6 +361,23 @@ impl MatchModel {
     fn len(&self) -> usize {
         self.match_len
     }
+
+    /// Return the next bit of the matched byte. Only meaningful while
+    /// len is non-zero.
+    fn expected_bit(&self) -> usize {
+        (self.buf[self.match_ptr] >> (7 - self.bits) & 1) as usize
+    }
+
+    /// The prediction of a match of L bytes taken directly: the expected
+    /// bit with probability 1 - 1/8L, stretched, or 0 without a match.
+    /// Call after p.
+    fn expected(&self) -> i32 {
+        if self.match_len == 0 {
+            return 0;
+        }
+        let pr = stretch(4095 - 4096 / (8 * self.match_len as i32));
+        if self.expected_bit() == 1 { pr } else { -pr }
+    }
 }
 
 
@@ -380,11 +404,8 @@ impl HashTable {
 
     /// Map context i to element 0 of state array. A state array is a set 
     /// of states corresponding to possible future contexts.
-    fn hash(&mut self, mut i: u32) -> *mut u8 {
-        i = i.wrapping_mul(123456791).rotate_right(16).wrapping_mul(234567891);
-        let chksum = (i >> 24) as u8;
-        let mut i = i as usize;
-        i = (i * B) & (self.size - B);
+    fn hash(&mut self, i: u32) -> *mut u8 {
+        let (mut i, chksum) = self.index(i);
 
         if self.t[i]       == chksum { return &mut self.t[i];       }
         if self.t[i^B]     == chksum { return &mut self.t[i^B];     }
@@ -403,10 +424,41 @@ impl HashTable {
         self.t[i] = chksum;
         &mut self.t[i]
     }
+
+    /// Index of the first state array context i could be stored in, and
+    /// its checksum.
+    fn index(&self, i: u32) -> (usize, u8) {
+        let i = i.wrapping_mul(123456791).rotate_right(16).wrapping_mul(234567891);
+        let chksum = (i >> 24) as u8;
+        ((i as usize * B) & (self.size - B), chksum)
+    }
+
+    /// Start loading the state arrays for context cxt + partial * 2 + bit 
+    /// into cache, for both values of the next bit. Called one bit before
+    /// a nibble boundary, so the lookup there doesn't wait on memory.
+    fn prefetch_nibble(&self, cxt: u32, partial: u32) {
+        if cfg!(feature = "prefetch") {
+            for bit in 0..2 {
+                // The 4 arrays hash() might use, which can span 2 cache lines
+                let (i, _) = self.index(cxt.wrapping_add(partial * 2 + bit));
+                let first = i & !(B * 4 - 1);
+                prefetch(&self.t[first]);
+                prefetch(&self.t[first + B * 4 - 1]);
+            }
+        }
+    }
 }
 
 
-type SharedHashTable = Rc<RefCell<HashTable>>;
+#[cfg(target_arch = "x86_64")]
+fn prefetch(p: *const u8) {
+    use std::arch::x86_64::{_mm_prefetch, _MM_HINT_T0};
+    unsafe { _mm_prefetch(p as *const i8, _MM_HINT_T0); }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn prefetch(_: *const u8) {}
+
 
 struct ContextModelO1 {
     bits:      usize,
@@ -415,10 +467,11 @@ struct ContextModelO1 {
     pub state: *mut u8,
     pub t0:    [u8; 65_536],
     sm:        StateMap,
+    states:    &'static StateTable,
 }
 
 impl ContextModelO1 {
-    fn new() -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             bits:  0,
             cxt:   1,
@@ -426,6 +479,7 @@ impl ContextModelO1 {
             state: &mut 0,
             t0:    [0; 65_536], 
             sm:    StateMap::new(256),
+            states,
         }
     }
 
@@ -438,7 +492,7 @@ impl ContextModelO1 {
 
     fn update(&mut self, bit: i32) {
         unsafe { 
-            *self.state = next_state(*self.state, bit); 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
         }
 
         self.cxt = (self.cxt << 1) + bit as u32;
@@ -467,11 +521,11 @@ struct ContextModelO2 {
     pub o2cxt: u32,
     pub state: *mut u8,
     sm:        StateMap,
-    ht:        SharedHashTable,
+    states:    &'static StateTable,
 }
 
 impl ContextModelO2 {
-    fn new(ht: SharedHashTable) -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             bits:   0,
             cxt:    1,
@@ -479,20 +533,20 @@ impl ContextModelO2 {
             o2cxt:  0,
             state:  &mut 0,
             sm:     StateMap::new(256),
-            ht,
+            states,
         }
     }
 
-    fn p(&mut self, bit: i32) -> i32 {
-        self.update(bit);
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
         unsafe { 
             self.sm.p(bit, *self.state as i32) 
         }
     }
 
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
         unsafe { 
-            *self.state = next_state(*self.state, bit); 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
         }
 
         self.cxt = (self.cxt << 1) + bit as u32;
@@ -503,14 +557,14 @@ impl ContextModelO2 {
             self.cxt4 = (self.cxt4 << 8) | self.cxt;
             self.o2cxt = (self.cxt4 & 0xFFFF) << 5 | 0x57000000;
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o2cxt).add(1); 
+                self.state = ht.hash(self.o2cxt).add(1); 
             }
             self.cxt = 1;
             self.bits = 0;
         }
         if self.bits == 4 {
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o2cxt + self.cxt).add(1); 
+                self.state = ht.hash(self.o2cxt.wrapping_add(self.cxt)).add(1); 
             }
         }
         else if self.bits > 0 {
@@ -519,6 +573,9 @@ impl ContextModelO2 {
                 self.state = self.state.add(j); 
             }
         }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.o2cxt, self.cxt);
+        }
     }
 }
 
@@ -529,11 +586,11 @@ struct ContextModelO3 {
     pub o3cxt: u32,
     pub state: *mut u8,
     sm:        StateMap,
-    ht:        SharedHashTable,
+    states:    &'static StateTable,
 }
 
 impl ContextModelO3 {
-    fn new(ht: SharedHashTable) -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             bits:  0,
             cxt:   1,
@@ -541,20 +598,20 @@ impl ContextModelO3 {
             o3cxt: 0,
             state: &mut 0,
             sm:    StateMap::new(256),
-            ht,
+            states,
         }
     }
 
-    fn p(&mut self, bit: i32) -> i32 {
-        self.update(bit);
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
         unsafe { 
             self.sm.p(bit, *self.state as i32) 
         }
     }
 
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
         unsafe { 
-            *self.state = next_state(*self.state, bit); 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
         }
 
         self.cxt = (self.cxt << 1) + bit as u32;
@@ -565,14 +622,14 @@ impl ContextModelO3 {
             self.cxt4 = (self.cxt4 << 8) | self.cxt;
             self.o3cxt = (self.cxt4 << 8).wrapping_mul(3);
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o3cxt).add(1); 
+                self.state = ht.hash(self.o3cxt).add(1); 
             }
             self.cxt = 1;
             self.bits = 0;
         }
         if self.bits == 4 {
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o3cxt + self.cxt).add(1); 
+                self.state = ht.hash(self.o3cxt.wrapping_add(self.cxt)).add(1); 
             }
         }
         else if self.bits > 0 {
@@ -581,6 +638,9 @@ impl ContextModelO3 {
                 self.state = self.state.add(j); 
             }
         }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.o3cxt, self.cxt);
+        }
     }
 }
 
@@ -591,11 +651,11 @@ struct ContextModelO4 {
     pub o4cxt: u32,
     pub state: *mut u8,
     sm:        StateMap,
-    ht:        SharedHashTable,
+    states:    &'static StateTable,
 }
 
 impl ContextModelO4 {
-    fn new(ht: SharedHashTable) -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             bits:  0,
             cxt:   1,
@@ -603,20 +663,20 @@ impl ContextModelO4 {
             o4cxt: 0,
             state: &mut 0,
             sm:    StateMap::new(256),
-            ht,
+            states,
         }
     }
 
-    fn p(&mut self, bit: i32) -> i32 {
-        self.update(bit);
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
         unsafe { 
             self.sm.p(bit, *self.state as i32) 
         }
     }
 
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
         unsafe { 
-            *self.state = next_state(*self.state, bit); 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
         }
 
         self.cxt = (self.cxt << 1) + bit as u32;
@@ -627,14 +687,14 @@ impl ContextModelO4 {
             self.cxt4 = (self.cxt4 << 8) | self.cxt;
             self.o4cxt = self.cxt4.wrapping_mul(5); 
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o4cxt).add(1); 
+                self.state = ht.hash(self.o4cxt).add(1); 
             }
             self.cxt = 1;
             self.bits = 0;
         }
         if self.bits == 4 {
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o4cxt + self.cxt).add(1); 
+                self.state = ht.hash(self.o4cxt.wrapping_add(self.cxt)).add(1); 
             }
         }
         else if self.bits > 0 {
@@ -643,6 +703,9 @@ impl ContextModelO4 {
                 self.state = self.state.add(j); 
             }
         }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.o4cxt, self.cxt);
+        }
     }
 }
 
@@ -653,11 +716,11 @@ struct ContextModelO6 {
     pub o6cxt: u32,
     pub state: *mut u8,
     sm:        StateMap,
-    ht:        SharedHashTable,
+    states:    &'static StateTable,
 }
 
 impl ContextModelO6 {
-    fn new(ht: SharedHashTable) -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             bits:  0,
             cxt:   1,
@@ -665,20 +728,89 @@ impl ContextModelO6 {
             o6cxt: 0,
             state: &mut 0,
             sm:    StateMap::new(256),
-            ht,
+            states,
         }
     }
 
-    fn p(&mut self, bit: i32) -> i32 {
-        self.update(bit);
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
         unsafe { 
             self.sm.p(bit, *self.state as i32) 
         }
     }
 
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
+        unsafe { 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
+        }
+
+        self.cxt = (self.cxt << 1) + bit as u32;
+        self.bits += 1;
+
+        if self.cxt >= 256 {
+            self.cxt -= 256;
+            self.cxt4 = (self.cxt4 << 8) | self.cxt;
+            self.o6cxt = self.o6cxt.wrapping_mul(11 << 5).wrapping_add(self.cxt * 13) & 0x3FFFFFFF;
+            unsafe { 
+                self.state = ht.hash(self.o6cxt).add(1); 
+            }
+            self.cxt = 1;
+            self.bits = 0;
+        }
+        if self.bits == 4 {
+            unsafe { 
+                self.state = ht.hash(self.o6cxt.wrapping_add(self.cxt)).add(1); 
+            }
+        }
+        else if self.bits > 0 {
+            let j = ((bit as usize) + 1) << ((self.bits & 3) - 1);
+            unsafe { 
+                self.state = self.state.add(j); 
+            }
+        }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.o6cxt, self.cxt);
+        }
+    }
+}
+
+/// Like an order 2 context model, but the context is the 2 bytes before
+/// the previous byte, skipping it. Helps on binary data with 2 byte
+/// fields, where the byte in the same position of the previous field
+/// predicts better than the adjacent byte.
+struct ContextModelSparse {
+    bits:           usize,
+    cxt:            u32,
+    cxt4:           u32,
+    pub sparse_cxt: u32,
+    pub state:      *mut u8,
+    sm:             StateMap,
+    states:         &'static StateTable,
+}
+
+impl ContextModelSparse {
+    fn new(states: &'static StateTable) -> Self {
+        Self {
+            bits:       0,
+            cxt:        1,
+            cxt4:       0,
+            sparse_cxt: 0,
+            state:      &mut 0,
+            sm:         StateMap::new(256),
+            states,
+        }
+    }
+
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
+        unsafe { 
+            self.sm.p(bit, *self.state as i32) 
+        }
+    }
+
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
         unsafe { 
-            *self.state = next_state(*self.state, bit); 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
         }
 
         self.cxt = (self.cxt << 1) + bit as u32;
@@ -687,16 +819,92 @@ impl ContextModelO6 {
         if self.cxt >= 256 {
             self.cxt -= 256;
             self.cxt4 = (self.cxt4 << 8) | self.cxt;
-            self.o6cxt = (self.o6cxt.wrapping_mul(11 << 5) + self.cxt * 13) & 0x3FFFFFFF;
+            self.sparse_cxt = (self.cxt4 & 0xFFFF00) << 5 | 0x2B000000;
+            unsafe { 
+                self.state = ht.hash(self.sparse_cxt).add(1); 
+            }
+            self.cxt = 1;
+            self.bits = 0;
+        }
+        if self.bits == 4 {
+            unsafe { 
+                self.state = ht.hash(self.sparse_cxt.wrapping_add(self.cxt)).add(1); 
+            }
+        }
+        else if self.bits > 0 {
+            let j = ((bit as usize) + 1) << ((self.bits & 3) - 1);
+            unsafe { 
+                self.state = self.state.add(j); 
+            }
+        }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.sparse_cxt, self.cxt);
+        }
+    }
+}
+
+/// Context models for data aligned to 4 or 8 byte records, like tables
+/// of pointers or 32 bit integers. The context is the position in the
+/// record (the byte count modulo period) and the byte one record back,
+/// so each field is predicted from the same field of the last record.
+struct ContextModelAligned {
+    bits:          usize,
+    cxt:           u32,
+    hist:          u64,
+    pos:           u32,
+    period:        u32,
+    pub align_cxt: u32,
+    pub state:     *mut u8,
+    sm:            StateMap,
+    states:        &'static StateTable,
+}
+
+impl ContextModelAligned {
+    fn new(period: u32, states: &'static StateTable) -> Self {
+        Self {
+            bits:      0,
+            cxt:       1,
+            hist:      0,
+            pos:       0,
+            period,
+            align_cxt: 0,
+            state:     &mut 0,
+            sm:        StateMap::new(256),
+            states,
+        }
+    }
+
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
+        unsafe { 
+            self.sm.p(bit, *self.state as i32) 
+        }
+    }
+
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
+        unsafe { 
+            *self.state = self.states[*self.state as usize][bit as usize]; 
+        }
+
+        self.cxt = (self.cxt << 1) + bit as u32;
+        self.bits += 1;
+
+        if self.cxt >= 256 {
+            self.cxt -= 256;
+            self.hist = (self.hist << 8) | self.cxt as u64;
+            self.pos = self.pos.wrapping_add(1);
+            let back = (self.hist >> (8 * (self.period - 1))) as u32 & 0xFF;
+            let field = self.pos & (self.period - 1);
+            self.align_cxt = (self.period << 20 | field << 16 | back << 8).wrapping_mul(29) | 0x31000000;
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o6cxt).add(1); 
+                self.state = ht.hash(self.align_cxt).add(1); 
             }
             self.cxt = 1;
             self.bits = 0;
         }
         if self.bits == 4 {
             unsafe { 
-                self.state = self.ht.borrow_mut().hash(self.o6cxt + self.cxt).add(1); 
+                self.state = ht.hash(self.align_cxt.wrapping_add(self.cxt)).add(1); 
             }
         }
         else if self.bits > 0 {
@@ -705,6 +913,9 @@ impl ContextModelO6 {
                 self.state = self.state.add(j); 
             }
         }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.align_cxt, self.cxt);
+        }
     }
 }
 
@@ -714,28 +925,28 @@ struct WordModel {
     pub word_cxt: u32,
     pub state:    *mut u8,
     sm:           StateMap,
-    ht:           Rc<RefCell<HashTable>>,
+    states:       &'static StateTable,
 }
 
 impl WordModel {
-    fn new(ht: Rc<RefCell<HashTable>>) -> Self {
+    fn new(states: &'static StateTable) -> Self {
         Self {
             cxt:      1,
             bits:     0,
             word_cxt: 0,
             state:    &mut 0,
             sm:       StateMap::new(256),
-            ht,
+            states,
         }
     }
 
-    fn p(&mut self, bit: i32) -> i32 {
-        self.update(bit);
+    fn p(&mut self, bit: i32, ht: &mut HashTable) -> i32 {
+        self.update(bit, ht);
         unsafe { self.sm.p(bit, *self.state as i32) }
     }
 
-    fn update(&mut self, bit: i32) {
-        unsafe { *self.state = next_state(*self.state, bit); }
+    fn update(&mut self, bit: i32, ht: &mut HashTable) {
+        unsafe { *self.state = self.states[*self.state as usize][bit as usize]; }
 
         self.cxt = (self.cxt << 1) + bit as u32;
         self.bits += 1;
@@ -745,24 +956,27 @@ impl WordModel {
             self.word_cxt = match self.cxt {
                 65..=90 => {
                     self.cxt += 32; // Fold to lowercase
-                    (self.word_cxt + self.cxt).wrapping_mul(7 << 3)
+                    self.word_cxt.wrapping_add(self.cxt).wrapping_mul(7 << 3)
                 },
                 97..=122 => {
-                    (self.word_cxt + self.cxt).wrapping_mul(7 << 3)
+                    self.word_cxt.wrapping_add(self.cxt).wrapping_mul(7 << 3)
                 },
                 _ => 0,
             };
-            unsafe { self.state = self.ht.borrow_mut().hash(self.word_cxt).add(1); }
+            unsafe { self.state = ht.hash(self.word_cxt).add(1); }
             self.cxt = 1;
             self.bits = 0;
         }
         if self.bits == 4 {
-            unsafe { self.state = self.ht.borrow_mut().hash(self.word_cxt + self.cxt).add(1); }
+            unsafe { self.state = ht.hash(self.word_cxt.wrapping_add(self.cxt)).add(1); }
         }
         else if self.bits > 0 {
             let j = ((bit as usize) + 1) << ((self.bits & 3) - 1);
             unsafe { self.state = self.state.add(j); }
         }
+        if self.bits == 3 {
+            ht.prefetch_nibble(self.word_cxt, self.cxt);
+        }
     }
 }
 
@@ -816,79 +1030,162 @@ impl WordModel {
 /// initialized to p = pr and n = 6 (to slow down initial adaptation)
 /// with a limit n <= 255. The two stages use a discrete order 0 context 
 /// (last 0..7 bits) and a hashed order-1 context (14 bits). Each output 
-/// is averaged with its input weighted by 1/4.
+/// is averaged with its input weighted by 1/4. Streams with MATCH_APM 
+/// have a third stage in the same way, whose context is the number of
+/// nonzero bit histories, the match length (quantized to 8 levels), the
+/// bit the match predicts and the last 0..7 bits.
 /// 
 /// The output is arithmetic coded. The code for a string s with probability
 /// p(s) is a number between Q and Q+p(x) where Q is the total probability of 
 /// all strings lexicographically preceding s. The number is coded as a big-
 /// -endian base-256 fraction.
 struct Predictor {
-    pr:   i32,            // Prediction
-    wm:   WordModel,      // Lowercase unigram word model
-    mm:   MatchModel,     // Match model
-    cm1:  ContextModelO1, // Order 1 context model
-    cm2:  ContextModelO2, // Order 2 context model 
-    cm3:  ContextModelO3, // Order 3 context model
-    cm4:  ContextModelO4, // Order 4 context model
-    cm6:  ContextModelO6, // Order 6 context model
-    mxr:  Mixer,          // For weighted averaging of independent predictions
-    apm1: Apm,            // Adaptive Probability Map for refining Mixer output
-    apm2: Apm,            //
+    pr:     i32,                        // Prediction
+    wm:     Option<WordModel>,          // Lowercase unigram word model
+    mm:     Option<MatchModel>,         // Match model
+    cm1:    ContextModelO1,             // Order 1 context model
+    cm2:    Option<ContextModelO2>,     // Order 2 context model 
+    cm3:    Option<ContextModelO3>,     // Order 3 context model
+    cm4:    Option<ContextModelO4>,     // Order 4 context model
+    cm6:    Option<ContextModelO6>,     // Order 6 context model
+    sparse: Option<ContextModelSparse>, // Order 2 context model skipping the previous byte
+    align4: Option<ContextModelAligned>, // Position in 4 byte records and the byte 4 back
+    align8: Option<ContextModelAligned>, // Position in 8 byte records and the byte 8 back
+    mxr:    Mixer,                      // For weighted averaging of independent predictions
+    apm1:   Apm,                        // Adaptive Probability Map for refining Mixer output
+    apm2:   Apm,                        //
+    apm3:   Option<Apm>,                // Keyed by order and match length, see MATCH_APM
+    mm_len: bool,                       // Add the match length as a Mixer input, see MATCH_LEN
+    ht:     HashTable,                  // Maps context hashes to state arrays, shared between models
+    stats:  Option<ModelStats>,         // Per model statistics, if requested
 }
 
 impl Predictor {
-    fn new() -> Predictor {
-        // Hash table for mapping context hashes to state arrays.
-        // Shared between models.
-        let ht = Rc::new(RefCell::new(HashTable::new(MEM*2)));
-
+    fn new(models: ModelSet, level: u8, states: &'static StateTable, match_apm: bool, match_len: bool) -> Predictor {
+        let mem = mem(level);
+        let mm_len = match_len && models.has(ModelSet::MATCH);
         let mut p = Predictor {           
-            pr:   2048,         
-            cm1:  ContextModelO1::new(),
-            cm2:  ContextModelO2::new(Rc::clone(&ht)),
-            cm3:  ContextModelO3::new(Rc::clone(&ht)),
-            cm4:  ContextModelO4::new(Rc::clone(&ht)),
-            cm6:  ContextModelO6::new(Rc::clone(&ht)),
-            wm:   WordModel::new(Rc::clone(&ht)),
-            mm:   MatchModel::new(MEM),
-            mxr:  Mixer::new(7, 80),
-            apm1: Apm::new(256),
-            apm2: Apm::new(16384),
+            pr:     2048,         
+            cm1:    ContextModelO1::new(states),
+            cm2:    models.has(ModelSet::ORDER2).then(|| ContextModelO2::new(states)),
+            cm3:    models.has(ModelSet::ORDER3).then(|| ContextModelO3::new(states)),
+            cm4:    models.has(ModelSet::ORDER4).then(|| ContextModelO4::new(states)),
+            cm6:    models.has(ModelSet::ORDER6).then(|| ContextModelO6::new(states)),
+            sparse: models.has(ModelSet::SPARSE).then(|| ContextModelSparse::new(states)),
+            align4: models.has(ModelSet::ALIGN4).then(|| ContextModelAligned::new(4, states)),
+            align8: models.has(ModelSet::ALIGN8).then(|| ContextModelAligned::new(8, states)),
+            wm:     models.has(ModelSet::WORD).then(|| WordModel::new(states)),
+            mm:     models.has(ModelSet::MATCH).then(|| MatchModel::new(mem)),
+            mxr:    Mixer::new(models.len() + mm_len as usize, 80),
+            apm1:   Apm::new(256),
+            apm2:   Apm::new(16384),
+            apm3:   match_apm.then(|| Apm::new(5 * 8 * 2 * 256)),
+            mm_len,
+            ht:     HashTable::new(mem * 2),
+            stats:  None,
         };
         
-        p.wm.state  = &mut p.cm1.t0[0];
         p.cm1.state = &mut p.cm1.t0[0];
-        p.cm2.state = &mut p.cm1.t0[0];
-        p.cm3.state = &mut p.cm1.t0[0];
-        p.cm4.state = &mut p.cm1.t0[0];
-        p.cm6.state = &mut p.cm1.t0[0];
+        if let Some(wm)     = &mut p.wm     { wm.state     = &mut p.cm1.t0[0]; }
+        if let Some(cm2)    = &mut p.cm2    { cm2.state    = &mut p.cm1.t0[0]; }
+        if let Some(cm3)    = &mut p.cm3    { cm3.state    = &mut p.cm1.t0[0]; }
+        if let Some(cm4)    = &mut p.cm4    { cm4.state    = &mut p.cm1.t0[0]; }
+        if let Some(cm6)    = &mut p.cm6    { cm6.state    = &mut p.cm1.t0[0]; }
+        if let Some(sparse) = &mut p.sparse { sparse.state = &mut p.cm1.t0[0]; }
+        if let Some(align4) = &mut p.align4 { align4.state = &mut p.cm1.t0[0]; }
+        if let Some(align8) = &mut p.align8 { align8.state = &mut p.cm1.t0[0]; }
         p
     }
 
+    /// Number of non-zero bit histories in the order 2 to 6 context 
+    /// models (0 to 4).
+    fn histories(&self) -> u32 {
+        let mut order = 0u32;
+        unsafe {
+            if self.cm2.as_ref().is_some_and(|cm| *cm.state != 0) { order += 1; }
+            if self.cm3.as_ref().is_some_and(|cm| *cm.state != 0) { order += 1; }
+            if self.cm4.as_ref().is_some_and(|cm| *cm.state != 0) { order += 1; }
+            if self.cm6.as_ref().is_some_and(|cm| *cm.state != 0) { order += 1; }
+        }
+        order
+    }
+
+    /// Context for the third SSE stage: the number of bit histories,
+    /// the match length, quantized, the bit the match expects, and the
+    /// bits of the current byte so far.
+    fn match_cxt(&self) -> usize {
+        let (len, expected) = match &self.mm {
+            Some(mm) if mm.len() > 0 => (mm.len(), mm.expected_bit()),
+            _ => (0, 0),
+        };
+        let len = match len {
+            0       => 0,
+            1..=7   => 1,
+            8..=11  => 2,
+            12..=15 => 3,
+            16..=23 => 4,
+            24..=31 => 5,
+            32..=47 => 6,
+            _       => 7,
+        };
+        (((self.histories() as usize * 8 + len) * 2 + expected) << 8) + self.cm1.cxt as usize
+    }
+
+    /// Determine order from match model length or number
+    /// of non-zero bit histories.
+    fn order(&mut self, len: usize) -> u32 {
+        // If len is 0, order is determined from 
+        // number of non-zero bit histories
+        if len == 0 {
+            return self.histories();
+        }
+        5 +
+        if len >= 8  { 1 } else { 0 } +
+        if len >= 12 { 1 } else { 0 } +
+        if len >= 16 { 1 } else { 0 } +
+        if len >= 32 { 1 } else { 0 }
+    }
+}
+
+
+impl Model for Predictor {
     /// Return current prediction.
-    fn p(&mut self) -> i32 {
+    fn p(&mut self) -> u16 {
         assert!(self.pr >= 0 && self.pr < 4096);
-        self.pr
+        // Keep a 1 from being coded with probability 0
+        (if self.pr < 2048 { self.pr + 1 } else { self.pr }) as u16
     }
 
     /// Update contexts and states, map states to predictions, and mix
     /// predictions in Mixer.
-    fn update(&mut self, bit: i32) {
+    fn update(&mut self, bit: u8) {
         assert!(bit == 0 || bit == 1);
+        let bit = bit as i32;
         
+        if let Some(stats) = &mut self.stats {
+            stats.record(&self.mxr, self.pr, bit);
+        }
         self.mxr.update(bit);
         
         // Add independent predictions to mixer
-        self.mxr.add(stretch(self.mm.p(bit)));
-        self.mxr.add(stretch(self.wm.p(bit)));
+        if let Some(mm) = &mut self.mm { 
+            self.mxr.add(stretch(mm.p(bit)));
+            if self.mm_len {
+                self.mxr.add(mm.expected());
+            }
+        }
+        if let Some(wm) = &mut self.wm { self.mxr.add(stretch(wm.p(bit, &mut self.ht))); }
         self.mxr.add(stretch(self.cm1.p(bit)));
-        self.mxr.add(stretch(self.cm2.p(bit)));
-        self.mxr.add(stretch(self.cm3.p(bit)));
-        self.mxr.add(stretch(self.cm4.p(bit)));
-        self.mxr.add(stretch(self.cm6.p(bit)));
+        if let Some(cm2) = &mut self.cm2 { self.mxr.add(stretch(cm2.p(bit, &mut self.ht))); }
+        if let Some(cm3) = &mut self.cm3 { self.mxr.add(stretch(cm3.p(bit, &mut self.ht))); }
+        if let Some(cm4) = &mut self.cm4 { self.mxr.add(stretch(cm4.p(bit, &mut self.ht))); }
+        if let Some(cm6) = &mut self.cm6 { self.mxr.add(stretch(cm6.p(bit, &mut self.ht))); }
+        if let Some(sparse) = &mut self.sparse { self.mxr.add(stretch(sparse.p(bit, &mut self.ht))); }
+        if let Some(align4) = &mut self.align4 { self.mxr.add(stretch(align4.p(bit, &mut self.ht))); }
+        if let Some(align8) = &mut self.align8 { self.mxr.add(stretch(align8.p(bit, &mut self.ht))); }
         
         // Set weights to be used during mixing
-        let order = self.order(self.mm.len());
+        let order = self.order(self.mm.as_ref().map_or(0, |mm| mm.len()));
         self.mxr.set(order + 10 * (self.cm1.o1cxt >> 13));
 
         // Mix
@@ -900,180 +1197,444 @@ impl Predictor {
 
         let cxt = (self.cm1.cxt ^ self.cm1.o1cxt >> 2) as usize;
         self.pr = (self.pr + 3 * self.apm2.p(bit, 7, self.pr, cxt)) >> 2;
+
+        // A third stage keyed by order and match length, which corrects
+        // the Mixer where it trusts long matches too much or too little.
+        let cxt = self.match_cxt();
+        if let Some(apm3) = &mut self.apm3 {
+            self.pr = (self.pr + 3 * apm3.p(bit, 7, self.pr, cxt)) >> 2;
+        }
     }
+}
 
-    /// Determine order from match model length or number
-    /// of non-zero bit histories.
-    fn order(&mut self, len: usize) -> u32 {
-        let mut order = 0u32;
 
-        // If len is 0, order is determined from 
-        // number of non-zero bit histories
-        if len == 0 {
-            unsafe {
-                if *self.cm2.state != 0 { order += 1; }
-                if *self.cm3.state != 0 { order += 1; }
-                if *self.cm4.state != 0 { order += 1; }
-                if *self.cm6.state != 0 { order += 1; }
-            }
+// Model statistics -------------------------------------------------
+//
+// With --model-stats, each predictor records for every model input to
+// the Mixer the average absolute weight it was given, its average share
+// of the absolute weighted sum (how much of the Mixer's output it
+// accounts for), and the bits per byte it would have cost if its
+// prediction were coded alone. The cost of the final prediction is
+// recorded too, so a model that costs much more alone than the mix, but
+// still gets a large share, is one the others rely on.
+pub struct ModelStats {
+    names:  Vec<&'static str>, // Model of each Mixer input, in input order
+    weight: Vec<f64>,          // Sum of absolute weights
+    share:  Vec<f64>,          // Sum of shares of the absolute weighted sum
+    cost:   Vec<f64>,          // Sum of bits each input's prediction alone would cost
+    mixed:  f64,               // Sum of bits the final prediction cost
+    bits:   u64,               // Number of bits recorded
+}
+
+impl ModelStats {
+    fn new(models: ModelSet, match_len: bool) -> ModelStats {
+        // The order in which Predictor::update adds inputs to the Mixer.
+        let mut names = [
+            ("match",  ModelSet::MATCH),
+            ("word",   ModelSet::WORD),
+            ("o1",     0),
+            ("o2",     ModelSet::ORDER2),
+            ("o3",     ModelSet::ORDER3),
+            ("o4",     ModelSet::ORDER4),
+            ("o6",     ModelSet::ORDER6),
+            ("sparse", ModelSet::SPARSE),
+            ("align4", ModelSet::ALIGN4),
+            ("align8", ModelSet::ALIGN8),
+        ]
+        .iter()
+        .filter(|(_, bit)| *bit == 0 || models.has(*bit))
+        .map(|(name, _)| *name)
+        .collect::<Vec<_>>();
+        // With MATCH_LEN, the match length follows the match model.
+        if match_len {
+            names.insert(1, "match-len");
         }
-        else {
-            order = 5 +
-            if len >= 8  { 1 } else { 0 } +
-            if len >= 12 { 1 } else { 0 } +
-            if len >= 16 { 1 } else { 0 } +
-            if len >= 32 { 1 } else { 0 };
+        let n = names.len();
+        ModelStats {
+            names,
+            weight: vec![0.0; n],
+            share:  vec![0.0; n],
+            cost:   vec![0.0; n],
+            mixed:  0.0,
+            bits:   0,
         }
-        order
+    }
+
+    /// Record the Mixer's inputs and weights for the bit just coded with
+    /// final prediction pr.
+    fn record(&mut self, mxr: &Mixer, pr: i32, bit: i32) {
+        // Before the first bit there are no inputs yet.
+        if mxr.len == 0 {
+            return;
+        }
+        let cost = |p: i32| {
+            let p = p.clamp(1, 4095) as f64 / 4096.0;
+            -(if bit == 1 { p } else { 1.0 - p }).log2()
+        };
+        let inputs = &mxr.inputs[..mxr.len];
+        let weights = &mxr.weights[mxr.wht_set..mxr.wht_set + mxr.len];
+        let total = inputs.iter().zip(weights.iter())
+            .map(|(i, w)| (*i as f64 * *w as f64).abs())
+            .sum::<f64>();
+
+        for (k, (i, w)) in inputs.iter().zip(weights.iter()).enumerate() {
+            self.weight[k] += (*w as f64 / 65536.0).abs();
+            if total > 0.0 {
+                self.share[k] += (*i as f64 * *w as f64).abs() / total;
+            }
+            self.cost[k] += cost(squash(*i));
+        }
+        self.mixed += cost(pr);
+        self.bits += 1;
+    }
+
+    /// Number of bits recorded. With switching, a predictor that saw
+    /// no blocks of its type records none.
+    pub fn bits(&self) -> u64 {
+        self.bits
+    }
+
+    /// Averages for each Mixer input, in input order.
+    pub fn inputs(&self) -> Vec<InputStats> {
+        let bits = self.bits.max(1) as f64;
+        self.names.iter().enumerate().map(|(k, name)| InputStats {
+            name,
+            weight: self.weight[k] / bits,
+            share:  self.share[k] / bits,
+            cost:   self.cost[k] / bits * 8.0,
+        })
+        .collect()
+    }
+
+    /// Bits per byte the final prediction cost.
+    pub fn mixed(&self) -> f64 {
+        self.mixed / self.bits.max(1) as f64 * 8.0
     }
 }
 
+/// Averages over the bits recorded for one Mixer input.
+pub struct InputStats {
+    pub name:   &'static str, // Model giving the input
+    pub weight: f64,          // Absolute weight
+    pub share:  f64,          // Share of the absolute weighted sum, 0 to 1
+    pub cost:   f64,          // Bits per byte its prediction alone would cost
+}
 
-struct Encoder {
-    high:      u32,
-    low:       u32,
-    predictor: Predictor,
-    archive:   BufWriter<File>,
+
+// Model selection --------------------------------------------------
+//
+// The order 1 context model is always used, since its contexts also
+// select the Mixer weights and the SSE stages. The other models can be
+// turned on and off, given as a comma separated list of
+//
+//   o2      order 2 context model
+//   o3      order 3 context model
+//   o4      order 4 context model
+//   o6      order 6 context model
+//   word    lowercase unigram word model
+//   match   match model
+//   sparse  order 2 context model skipping the previous byte
+//   align4  position in 4 byte records and the byte 4 back
+//   align8  position in 8 byte records and the byte 8 back
+//
+// or "o1" for none of them. The default is every model but sparse, 
+// which is lpaq1 as published. The set is stored in the stream header, 
+// so the decoder builds the same models.
+//
+// With switching, there are two predictors, one for text and one for
+// binary data, and each block is coded with one of them depending on
+// whether it looks like text. The chosen model set is used for text,
+// and BINARY_MODELS for binary data. Each block starts with a bit
+// giving its type, and the header stores both model sets.
+//
+// BWT_MODELS are for the output of the Burrows-Wheeler transform, as
+// -sortrans codes it. Sorting has already brought bytes with the same
+// following context together, so only short contexts predict, and
+// there are no words or long matches left to find.
+const DEFAULT_MODELS: &str = "o2,o3,o4,o6,word,match";
+const BINARY_MODELS: &str = "o2,o3,o4,match,sparse,align4,align8";
+const BWT_MODELS: &str = "o2,o3,o4";
+const SWITCH: u8 = 0x80; // Header flag, set if a second model set follows
+const TEXT:   usize = 0;
+const BINARY: usize = 1;
+
+// The memory level sets the size of the hash table and match model, as
+// MEM = 2^(level + 20) bytes, for about 3 * MEM bytes per predictor. It
+// follows the model sets in the header. DEFAULT_LEVEL uses about 30 MB,
+// and lower levels trade compression for memory.
+//
+// The same header byte has RUN_STATES set if the context models use the
+// run length aware bit history states of zpaq instead of lpaq1's own,
+// MORE_MODELS set if the model sets include models past the 7 that fit
+// in their header bytes, which follow, one byte per model set, and
+// MATCH_APM set if the predictors have the third SSE stage keyed by
+// order and match length, and MATCH_LEN set if the match model's length
+// is also a Mixer input of its own, as in later lpaq versions. Both are
+// always set now; streams from before they were added decode without
+// them.
+pub const DEFAULT_LEVEL: u8 = 3;
+pub const MAX_LEVEL:     u8 = 9;
+const RUN_STATES: u8 = 0x80;
+const MORE_MODELS: u8 = 0x40;
+const MATCH_APM: u8 = 0x20;
+const MATCH_LEN: u8 = 0x10;
+const FIXED_MEMORY: u64 = 6 << 20; // Memory used apart from MEM: small tables, I/O buffers
+
+fn mem(level: u8) -> usize {
+    1 << (level as usize + 20)
 }
 
-impl Encoder {
-    fn new(archive: BufWriter<File>) -> Encoder {
-        let mut enc = Encoder {
-            high: 0xFFFFFFFF, 
-            low: 0, 
-            predictor: Predictor::new(), 
-            archive
-        };   
-        enc.archive.write_u64(0u64);
-        enc.archive.write_u64(0u64);
-        enc.archive.write_u64(0u64);
-        enc
-    }
-
-    fn encode_bit(&mut self, bit: i32) {
-        let mut p = self.predictor.p() as u32;
-        if p < 2048 { p += 1; }
-
-        let range = self.high - self.low;
-        let mid: u32 = self.low + (range >> 12) * p
-                       + ((range & 0x0FFF) * p >> 12);
-                       
-        if bit == 1 {
-            self.high = mid;
-        } 
-        else {
-            self.low = mid + 1;
-        }
-        self.predictor.update(bit);
-        
-        while ( (self.high ^ self.low) & 0xFF000000) == 0 {
-            self.archive.write_u8_forced(self.high >> 24);
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8;  
-        }
+/// Approximate memory needed to compress or decompress at a level, with
+/// one predictor, or two if switching.
+pub fn memory(level: u8, switch: bool) -> u64 {
+    let predictors = if switch { 2 } else { 1 };
+    predictors * (3 * mem(level) as u64 + FIXED_MEMORY)
+}
+
+/// The highest level up to DEFAULT_LEVEL that fits in max_memory bytes,
+/// or None if even level 0 doesn't.
+pub fn level_for(max_memory: u64, switch: bool) -> Option<u8> {
+    (0..=DEFAULT_LEVEL).rev().find(|&level| memory(level, switch) <= max_memory)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ModelSet {
+    mask: u16,
+}
+
+impl Default for ModelSet {
+    fn default() -> ModelSet {
+        ModelSet::parse(DEFAULT_MODELS).unwrap()
+    }
+}
+
+impl ModelSet {
+    /// The models lpaq1 --switch uses for binary blocks.
+    pub fn binary() -> ModelSet {
+        ModelSet::parse(BINARY_MODELS).unwrap()
     }
 
-    fn flush(&mut self) {
-        while ( (self.high ^ self.low) & 0xFF000000) == 0 {
-            self.archive.write_u8_forced(self.high >> 24);
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8;
+    /// The models -sortrans uses for the output of the Burrows-Wheeler
+    /// transform.
+    pub fn bwt() -> ModelSet {
+        ModelSet::parse(BWT_MODELS).unwrap()
+    }
+
+    const ORDER2: u16 = 1;
+    const ORDER3: u16 = 2;
+    const ORDER4: u16 = 4;
+    const ORDER6: u16 = 8;
+    const WORD:   u16 = 16;
+    const MATCH:  u16 = 32;
+    const SPARSE: u16 = 64;
+    const ALIGN4: u16 = 128;
+    const ALIGN8: u16 = 256;
+
+    const NAMES: [(&'static str, u16); 9] = [
+        ("o2",     ModelSet::ORDER2),
+        ("o3",     ModelSet::ORDER3),
+        ("o4",     ModelSet::ORDER4),
+        ("o6",     ModelSet::ORDER6),
+        ("word",   ModelSet::WORD),
+        ("match",  ModelSet::MATCH),
+        ("sparse", ModelSet::SPARSE),
+        ("align4", ModelSet::ALIGN4),
+        ("align8", ModelSet::ALIGN8),
+    ];
+
+    // Parse a list of models as described above.
+    pub fn parse(spec: &str) -> Result<ModelSet, String> {
+        let mut mask = 0;
+        for name in spec.split(',').filter(|name| *name != "o1") {
+            mask |= ModelSet::NAMES.iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, bit)| *bit)
+                .ok_or_else(|| format!("unknown lpaq1 model '{}'", name))?;
         }
-        self.archive.write_u8_forced(self.high >> 24);
-        self.archive.flush_buffer();
+        Ok(ModelSet { mask })
     }
 
-    fn encode_block(&mut self, block: &[u8]) {
-        for byte in block.iter() {
-            for i in (0..=7).rev() {
-                self.encode_bit(((*byte >> i) & 1) as i32);
-            }   
+    fn from_mask(mask: u16) -> io::Result<ModelSet> {
+        if mask & !ModelSet::NAMES.iter().fold(0, |all, (_, bit)| all | bit) != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unknown lpaq1 models"));
         }
+        Ok(ModelSet { mask })
+    }
+
+    fn has(&self, model: u16) -> bool {
+        self.mask & model != 0
+    }
+
+    // Number of models, including order 1.
+    fn len(&self) -> usize {
+        1 + self.mask.count_ones() as usize
     }
 
-    // Write 24 byte block data header
-    fn write_block_data(&mut self, data: BlockData) {
-        self.archive.get_ref().rewind().unwrap();
-        self.archive.write_u64(data.final_size);
-        self.archive.write_u64(data.base_size);
-        self.archive.write_u64(data.count);    
+    // The models that fit in the model set byte of the header, and
+    // those that follow the memory level with MORE_MODELS.
+    fn low(&self) -> u8 {
+        (self.mask & 0x7F) as u8
+    }
+
+    fn high(&self) -> u8 {
+        (self.mask >> 7) as u8
     }
 }
 
 
-struct Decoder {
-    high:      u32,
-    low:       u32,
-    predictor: Predictor,
-    archive:   BufReader<File>,
-    x:         u32, 
+/// The predictors for each block type, of which only the active one
+/// sees the input. Without switching there's just one.
+struct Predictors {
+    predictors: Vec<Predictor>,
+    active:     usize,
 }
 
-impl Decoder {
-    fn new(archive: BufReader<File>) -> Self {
-        Self {
-            high: 0xFFFFFFFF, 
-            low: 0, 
-            x: 0, 
-            predictor: Predictor::new(), 
-            archive,
+impl Predictors {
+    fn new(models: &[ModelSet], level: u8, run_states: bool, match_apm: bool, match_len: bool) -> Predictors {
+        let states = if run_states { &RUN_STATE_TABLE } else { &STATE_TABLE };
+        Predictors {
+            predictors: models.iter().map(|models| Predictor::new(*models, level, states, match_apm, match_len)).collect(),
+            active:     TEXT,
         }
     }
 
-    fn decode_bit(&mut self) -> i32 {
-        let mut p = self.predictor.p() as u32;
-        if p < 2048 { p += 1; }
+    fn switching(&self) -> bool {
+        self.predictors.len() > 1
+    }
 
-        let range = self.high - self.low;
-        let mid = self.low + (range >> 12) * p + ((range & 0x0FFF) * p >> 12);
+    fn collect_stats(&mut self, models: &[ModelSet]) {
+        for (predictor, models) in self.predictors.iter_mut().zip(models.iter()) {
+            predictor.stats = Some(ModelStats::new(*models, predictor.mm_len));
+        }
+    }
 
-        let mut bit: i32 = 0;
-        if self.x <= mid {
-            bit = 1;
-            self.high = mid;
-        } 
+    fn take_stats(&mut self) -> Vec<ModelStats> {
+        self.predictors.iter_mut().filter_map(|predictor| predictor.stats.take()).collect()
+    }
+}
+
+impl Model for Predictors {
+    fn p(&mut self) -> u16 {
+        self.predictors[self.active].p()
+    }
+
+    fn update(&mut self, bit: u8) {
+        self.predictors[self.active].update(bit);
+    }
+}
+
+/// Guess whether a block is text: at least 15/16 of its bytes are 
+/// printable ASCII, tabs or line breaks.
+pub(crate) fn is_text(block: &[u8]) -> bool {
+    let text = block.iter()
+        .filter(|byte| matches!(byte, 0x20..=0x7E | b'\t' | b'\n' | b'\r'))
+        .count();
+    text * 16 >= block.len() * 15
+}
+
+
+struct Encoder<W: Write + Seek> {
+    coder: BitEncoder<Predictors, BufWriter<W>>,
+}
+
+impl<W: Write + Seek> Encoder<W> {
+    fn new(mut archive: BufWriter<W>, models: &[ModelSet], level: u8, run_states: bool) -> io::Result<Encoder<W>> {
+        archive.write_u64(0u64)?;
+        archive.write_u64(0u64)?;
+        archive.write_u64(0u64)?;
+        if let [text, binary] = models {
+            archive.write_u8(text.low() | SWITCH)?;
+            archive.write_u8(binary.low())?;
+        }
         else {
-            self.low = mid + 1;
+            archive.write_u8(models[0].low())?;
         }
-        self.predictor.update(bit);
-        
-        while ( (self.high ^ self.low) & 0xFF000000) == 0 {
-            self.high = (self.high << 8) + 255;
-            self.low <<= 8; 
-            self.x = (self.x << 8) + self.archive.read_u8() as u32; 
+        let more_models = models.iter().any(|models| models.high() != 0);
+        archive.write_u8(level 
+            | if run_states { RUN_STATES } else { 0 } 
+            | if more_models { MORE_MODELS } else { 0 }
+            | MATCH_APM
+            | MATCH_LEN)?;
+        if more_models {
+            for models in models.iter() {
+                archive.write_u8(models.high())?;
+            }
         }
-        bit
+        Ok(Encoder {
+            coder: BitEncoder::new(Predictors::new(models, level, run_states, true, true), archive),
+        })
     }
 
-    fn decode_block(&mut self, block_size: u64) -> Vec<u8> {
-        let mut block = Vec::with_capacity(block_size as usize); 
-        while block.len() < block.capacity() {
-            let mut byte = 1;
-            while byte < 256 {
-                byte += byte + self.decode_bit();
-            }
-            byte -= 256;
-            block.push(byte as u8); 
+    fn flush(&mut self) -> io::Result<()> {
+        self.coder.flush()
+    }
+
+    fn encode_block(&mut self, block: &[u8]) -> io::Result<()> {
+        if self.coder.model_mut().switching() {
+            let block_type = if is_text(block) { TEXT } else { BINARY };
+            self.coder.encode_flat(block_type as u8)?;
+            self.coder.model_mut().active = block_type;
+        }
+        for byte in block.iter() {
+            self.coder.encode_byte(*byte)?;
         }
-        block
+        Ok(())
     }
 
-    // Read 24 byte block data header
-    fn read_block_data(&mut self) -> BlockData {
-        BlockData::from(
-            self.archive.read_u64(),
-            self.archive.read_u64(),
-            self.archive.read_u64()
-        )
+    // Write 24 byte block data header, then return to the end, where
+    // the container's trailer goes.
+    fn write_block_data(&mut self, data: BlockData) -> io::Result<()> {
+        let archive = self.coder.get_mut();
+        let end = archive.stream_position()?;
+        archive.rewind()?;
+        archive.write_u64(data.final_size)?;
+        archive.write_u64(data.base_size)?;
+        archive.write_u64(data.count)?;
+        archive.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+
+struct Decoder<R: Read> {
+    coder: BitDecoder<Predictors, BufReader<R>>,
+}
+
+impl<R: Read> Decoder<R> {
+    // Call after reading the header
+    fn new(archive: BufReader<R>, models: &[ModelSet], level: u8, run_states: bool, match_apm: bool, match_len: bool) -> io::Result<Self> {
+        Ok(Self {
+            coder: BitDecoder::new(Predictors::new(models, level, run_states, match_apm, match_len), archive)?,
+        })
     }
 
-    fn init_x(&mut self) {
-        for _ in 0..4 {
-            self.x = (self.x << 8) + self.archive.read_u8() as u32;
+    // Decode a block, returning an error if the input runs out first.
+    fn decode_block(&mut self, block_size: u64) -> io::Result<Vec<u8>> {
+        if self.coder.model_mut().switching() {
+            let block_type = self.coder.decode_flat()? as usize;
+            self.coder.model_mut().active = block_type;
         }
+        // The size isn't trusted enough to allocate up front.
+        let mut block = Vec::new(); 
+        while (block.len() as u64) < block_size {
+            block.push(self.coder.decode_byte()?); 
+            if self.coder.overrun() {
+                return Err(io::Error::new(ErrorKind::InvalidData, "truncated lpaq1 stream"));
+            }
+        }
+        Ok(block)
     }
 }
 
+// Read 24 byte block data header, or None if the input ends first.
+fn read_block_data<R: Read>(archive: &mut BufReader<R>) -> io::Result<Option<BlockData>> {
+    let Some(header) = archive.read_checked::<24>()? else {
+        return Ok(None);
+    };
+    let field = |i: usize| u64::from_le_bytes(header[i * 8..i * 8 + 8].try_into().unwrap());
+    Ok(Some(BlockData::from(field(0), field(1), field(2))))
+}
+
 
 struct BlockData {
     base_size:  u64,
@@ -1103,28 +1664,101 @@ impl BlockData {
     }
 }
 
-pub fn lpaq1_compress(mut file_in: BufReader<File>, file_out: BufWriter<File>) {
+// Compress with the given models, and if switch is set, a second 
+// predictor for binary blocks, at the given memory level, with the run
+// length aware bit history states if run_states is set.
+pub fn lpaq1_compress<R: Read, W: Write + Seek>(file_in: BufReader<R>, file_out: BufWriter<W>, models: ModelSet, switch: bool, 
+    level: u8, run_states: bool) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, models, switch, level, run_states, None))
+}
+
+// As lpaq1_compress, also returning statistics on each model, one
+// ModelStats per predictor: text blocks first, then binary blocks if
+// switch is set.
+pub fn lpaq1_compress_with_stats<R: Read, W: Write + Seek>(file_in: BufReader<R>, file_out: BufWriter<W>, models: ModelSet, 
+    switch: bool, level: u8, run_states: bool) -> error::Result<(Sizes, Vec<ModelStats>)> {
+    let mut stats = Vec::new();
+    let sizes = tracked(file_in, file_out, |file_in, file_out| 
+        compress(file_in, file_out, models, switch, level, run_states, Some(&mut stats))
+    )?;
+    Ok((sizes, stats))
+}
+
+fn compress<R: Read, W: Write + Seek>(mut file_in: BufReader<R>, file_out: BufWriter<W>, models: ModelSet, switch: bool, 
+    level: u8, run_states: bool, mut stats: Option<&mut Vec<ModelStats>>) -> io::Result<()> {
     let mut data = BlockData::new(file_in.capacity() as u64);
-    let mut enc = Encoder::new(file_out);
+    let mut models = vec![models];
+    if switch {
+        models.push(ModelSet::parse(BINARY_MODELS).unwrap());
+    }
+    let mut enc = Encoder::new(file_out, &models, level, run_states)?;
+    if stats.is_some() {
+        enc.coder.model_mut().collect_stats(&models);
+    }
 
-    while !file_in.fill_buffer().is_eof() {
-        data.update(file_in.buffer().len() as u64);
-        enc.encode_block(&file_in.buffer());
+    let block_size = file_in.capacity();
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+        data.update(block.len() as u64);
+        enc.encode_block(&block)?;
     } 
-    enc.flush();
-    enc.write_block_data(data);
+    enc.flush()?;
+    enc.write_block_data(data)?;
+    if let Some(stats) = stats.as_mut() {
+        **stats = enc.coder.model_mut().take_stats();
+    }
+    Ok(())
 }
 
-pub fn lpaq1_decompress(file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let mut dec = Decoder::new(file_in);
-    let data = dec.read_block_data();
+// Decompress an lpaq1 stream, returning an InvalidData error if the
+// header is invalid, names models this version doesn't have, or the
+// stream ends early, and an OutOfMemory error if its memory level needs
+// more than max_memory bytes.
+pub fn lpaq1_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out, max_memory))
+}
 
-    // Call after reading header
-    dec.init_x();
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, max_memory: Option<u64>) -> io::Result<()> {
+    let truncated = || io::Error::new(ErrorKind::InvalidData, "truncated lpaq1 header");
+    let data = read_block_data(&mut file_in)?.ok_or_else(truncated)?;
+    if data.final_size > data.base_size || (data.count == 0) != (data.final_size == 0) {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid lpaq1 block sizes"));
+    }
+    let mask = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+    let mut masks = vec![(mask & !SWITCH) as u16];
+    if mask & SWITCH != 0 {
+        // Only the first model set byte has a flag in its top bit.
+        let mask = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+        if mask & 0x80 != 0 {
+            return Err(io::Error::new(ErrorKind::InvalidData, "unknown lpaq1 models"));
+        }
+        masks.push(mask as u16);
+    }
+    let level = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+    let run_states = level & RUN_STATES != 0;
+    let more_models = level & MORE_MODELS != 0;
+    let match_apm = level & MATCH_APM != 0;
+    let match_len = level & MATCH_LEN != 0;
+    let level = level & !(RUN_STATES | MORE_MODELS | MATCH_APM | MATCH_LEN);
+    if level > MAX_LEVEL {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid lpaq1 memory level"));
+    }
+    if more_models {
+        for mask in masks.iter_mut() {
+            *mask |= (file_in.read_u8_checked()?.ok_or_else(truncated)? as u16) << 7;
+        }
+    }
+    let models = masks.into_iter().map(ModelSet::from_mask).collect::<io::Result<Vec<_>>>()?;
+    let need = memory(level, models.len() > 1);
+    if max_memory.is_some_and(|max| need > max) {
+        return Err(io::Error::new(ErrorKind::OutOfMemory, 
+            format!("lpaq1 stream needs {} bytes of memory, more than --max-memory", need)));
+    }
+    let mut dec = Decoder::new(file_in, &models, level, run_states, match_apm, match_len)?;
 
-    for _ in 0..(data.count - 1) {
-        file_out.write_all(&dec.decode_block(data.base_size)).unwrap();
+    for i in 0..data.count {
+        let size = if i + 1 == data.count { data.final_size } else { data.base_size };
+        file_out.write_bytes(&dec.decode_block(size)?)?;
     }
-    file_out.write_all(&dec.decode_block(data.final_size)).unwrap();
-    file_out.flush_buffer();
+    file_out.flush()
 }
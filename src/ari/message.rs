@@ -0,0 +1,191 @@
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::error;
+use crate::framing::{read_varint, write_varint};
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
+use crate::ari::log::{squash16, stretch16};
+
+// Compression of many small messages, such as RPC payloads or log
+// records, each too small to pay for a header or to warm up a model.
+//
+// A MessageCodec keeps its model from one message to the next, so each
+// message is coded with what was learned from all the ones before it.
+// The model starts out trained on a preset dictionary of typical
+// content, so even the first message compresses. A message is coded
+// as its length, as a varint, then the bits of its bytes:
+//
+//   length  (varint, as in framing.rs)
+//   code    (arithmetic code of the bytes, none if length is 0)
+//
+// The compressing and decompressing codecs must be made with the same
+// dictionary and see the same messages in the same order, as over one
+// connection. A message that fails to decompress leaves the codec out
+// of step, so both sides have to start over with new codecs.
+//
+// The model mixes order 0 to 3 contexts, each mapped to a 16 bit
+// probability, in the stretched domain, with weights selected by the
+// bits of the current byte.
+
+const TABLE_BITS: usize = 20;
+const TABLE_MASK: usize = (1 << TABLE_BITS) - 1;
+const INPUTS: usize = 5; // Orders 0 to 3 and a bias
+const RATE: i32 = 4;
+
+struct Predictor {
+    cxt:     usize,      // 1, then the bits of the current byte so far
+    history: u32,        // Last 4 bytes
+    hashes:  [usize; 2], // Order 2 and 3 contexts of the current byte
+    order0:  Vec<u16>,
+    order1:  Vec<u16>,
+    order2:  Vec<u16>,
+    order3:  Vec<u16>,
+    idx:     [usize; 4], // Current entry of each table
+    st:      [i32; INPUTS],
+    weights: Vec<[i32; INPUTS]>,
+    pr:      i32,        // 16 bit probability of a 1
+}
+
+impl Predictor {
+    fn new() -> Predictor {
+        let mut predictor = Predictor {
+            cxt:     1,
+            history: 0,
+            hashes:  [0; 2],
+            order0:  vec![1 << 15; 256],
+            order1:  vec![1 << 15; 1 << 16],
+            order2:  vec![1 << 15; 1 << TABLE_BITS],
+            order3:  vec![1 << 15; 1 << TABLE_BITS],
+            idx:     [0; 4],
+            st:      [0; INPUTS],
+            weights: vec![[1 << 14; INPUTS]; 256],
+            pr:      1 << 15,
+        };
+        predictor.predict();
+        predictor
+    }
+
+    // Learn byte as though it had been coded.
+    fn train(&mut self, byte: u8) {
+        for i in (0..8).rev() {
+            self.update((byte >> i) & 1);
+        }
+    }
+
+    fn predict(&mut self) {
+        let prev = (self.history & 0xFF) as usize;
+        self.idx = [
+            self.cxt,
+            prev << 8 | self.cxt,
+            (self.hashes[0] ^ self.cxt.wrapping_mul(0x2F0B3C49)) & TABLE_MASK,
+            (self.hashes[1] ^ self.cxt.wrapping_mul(0x2F0B3C49)) & TABLE_MASK,
+        ];
+        let probs = [
+            self.order0[self.idx[0]],
+            self.order1[self.idx[1]],
+            self.order2[self.idx[2]],
+            self.order3[self.idx[3]],
+        ];
+        for (st, p) in self.st.iter_mut().zip(probs) {
+            *st = stretch16(p as i32);
+        }
+        self.st[INPUTS - 1] = 256;
+
+        let weights = &self.weights[self.cxt];
+        let dot = self.st.iter().zip(weights).map(|(st, w)| *st as i64 * *w as i64).sum::<i64>();
+        self.pr = squash16((dot >> 16) as i32);
+    }
+}
+
+impl Model for Predictor {
+    fn p(&mut self) -> u16 {
+        (self.pr >> 4).clamp(1, 4095) as u16
+    }
+
+    fn update(&mut self, bit: u8) {
+        let target = (bit as i32) << 16;
+
+        // Train the mixer toward the bit, then each table.
+        let err = (target - self.pr) as i64 * 7;
+        for (w, st) in self.weights[self.cxt].iter_mut().zip(self.st) {
+            *w += ((st as i64 * err) >> 23) as i32;
+        }
+        let tables = [&mut self.order0, &mut self.order1, &mut self.order2, &mut self.order3];
+        for (table, idx) in tables.into_iter().zip(self.idx) {
+            let p = table[idx] as i32;
+            table[idx] = (p + ((target - p) >> RATE)).clamp(32, 65535 - 32) as u16;
+        }
+
+        self.cxt = self.cxt * 2 + bit as usize;
+        if self.cxt >= 256 {
+            self.history = (self.history << 8) | (self.cxt - 256) as u32;
+            self.hashes = [
+                ((self.history & 0xFFFF).wrapping_mul(0x9E3779B1) >> 8) as usize,
+                ((self.history & 0xFFFFFF).wrapping_mul(0x85EBCA77) >> 8) as usize,
+            ];
+            self.cxt = 1;
+        }
+        self.predict();
+    }
+}
+
+/// Compresses or decompresses a sequence of small messages with a model
+/// kept warm between them. One codec compresses, and another, made with
+/// the same dictionary, decompresses the same messages in the same order.
+pub struct MessageCodec {
+    model: Predictor,
+}
+
+impl MessageCodec {
+    /// A codec whose model has been trained on dictionary, typical
+    /// content of the messages to come. The dictionary isn't stored in
+    /// the messages, so both sides must use the same one.
+    pub fn new(dictionary: &[u8]) -> MessageCodec {
+        let mut model = Predictor::new();
+        for byte in dictionary.iter() {
+            model.train(*byte);
+        }
+        MessageCodec { model }
+    }
+
+    /// Compress msg, learning from it for the messages that follow.
+    pub fn compress_msg(&mut self, msg: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        write_varint(&mut out, msg.len() as u64).expect("memory can't fail to write");
+        if msg.is_empty() {
+            return out;
+        }
+
+        let mut enc = BitEncoder::new(&mut self.model, BufWriter::new(&mut out));
+        msg.iter()
+            .try_for_each(|byte| enc.encode_byte(*byte))
+            .and_then(|_| enc.flush())
+            .expect("memory can't fail to write");
+        drop(enc);
+        out
+    }
+
+    /// Decompress a message from compress_msg, returning an InvalidData
+    /// error if it's truncated or damaged. After an error the codec no
+    /// longer matches the one that compressed, and should be replaced.
+    pub fn decompress_msg(&mut self, data: &[u8]) -> error::Result<Vec<u8>> {
+        let mut code = data;
+        let len = read_varint(&mut code)?;
+
+        // The length isn't trusted enough to allocate up front.
+        let mut msg = Vec::new();
+        if len == 0 {
+            return Ok(msg);
+        }
+        let mut dec = BitDecoder::new(&mut self.model, BufReader::new(code))?;
+        for _ in 0..len {
+            msg.push(dec.decode_byte()?);
+            if dec.overrun() {
+                return Err(error::Error::InvalidData("truncated message".to_string()));
+            }
+        }
+        Ok(msg)
+    }
+}
@@ -1,4 +1,11 @@
+pub mod coder;
 pub mod fpaq;
+pub mod fpaq0;
+pub mod message;
+pub mod sr;
 pub mod lpaq1;
+pub mod ppm;
+pub mod rans;
+pub mod fse;
 pub mod log;
 pub mod state;
\ No newline at end of file
@@ -0,0 +1,375 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::mem::size_of;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
+
+// Prediction by partial matching, as in Cleary and Witten's PPM with
+// Howard's escape method D. Each byte is predicted by the counts of the
+// bytes that followed the last order bytes before it, and if it hasn't
+// followed them yet, an escape is coded and the next shorter context
+// tried, down to order 0, then order -1, where every byte is equally
+// likely. A context with n different bytes seen s times in all gives a
+// byte seen c times a weight of 2c - 1, and the escape n, out of 2s.
+//
+// Bytes in a context that escaped are excluded from the shorter ones,
+// since the byte isn't any of them. Once coded, a byte is counted in
+// the context it was found in and added to the longer ones, but not
+// counted in the shorter ones (update exclusion).
+//
+// Escapes are coded with probabilities learned from those coded before
+// in similar contexts, starting from method D's (see Escapes).
+//
+// The coder is binary, so a context codes a byte as an escape flag,
+// then a flag for each of its bytes, most frequent first, until one is
+// the byte. Order -1 codes an end flag, then the byte's bits, each with
+// the odds of the bytes not excluded that it leaves.
+//
+// Contexts are kept in a tree, each longer one a child of the one
+// without its first byte, in memory that grows with the input. When it
+// reaches the model size, the tree is cleared and starts over.
+//
+// Output format:
+//
+//   order   (u8, longest context)
+//   memory  (u16, model size in MB)
+//   data    (the bytes, escaping to order -1 and a 1 end flag after
+//            the last)
+
+pub const DEFAULT_ORDER: u8 = 6;
+pub const MAX_ORDER:     u8 = 16;
+
+/// Model size used unless --max-memory sets it, in bytes.
+pub const DEFAULT_MODEL: u64 = 64 << 20;
+const MIN_MODEL: u64 = 1 << 20;
+const MAX_MODEL: u64 = (u16::MAX as u64) << 20;
+const FIXED_MEMORY: u64 = 4 << 20; // I/O buffers
+
+// Counts are halved when one passes this, so a context follows changes
+// in the input.
+const MAX_COUNT: u16 = 255;
+
+const LIMIT: u32 = 255; // Escape adaptation slows to 1/LIMIT, then stays there
+
+/// Approximate memory needed to compress or decompress with a model of
+/// model bytes.
+pub fn memory(model: u64) -> u64 {
+    model + FIXED_MEMORY
+}
+
+/// The largest model size that fits in max_memory bytes, in whole MB,
+/// or None if even the smallest doesn't.
+pub fn model_for(max_memory: u64) -> Option<u64> {
+    let model = (max_memory.saturating_sub(FIXED_MEMORY) & !(MIN_MODEL - 1)).min(MAX_MODEL);
+    (model >= MIN_MODEL).then_some(model)
+}
+
+// The probability of each decision, set before it's coded.
+struct Probability {
+    p: u16,
+}
+
+impl Model for Probability {
+    fn p(&mut self) -> u16 {
+        self.p
+    }
+
+    fn update(&mut self, _: u8) {}
+}
+
+// p of a 1 with the odds w in total, which aren't 0.
+fn odds(w: u32, total: u32) -> u16 {
+    ((w as u64 * 4096 / total as u64) as u16).clamp(1, 4095)
+}
+
+// A decision, coded with p the probability of a 1. The encoder codes
+// bit and returns it; the decoder ignores it and returns the bit it
+// decodes, so one walk of the model serves both.
+trait Decide {
+    fn decide(&mut self, bit: u8, p: u16) -> io::Result<u8>;
+}
+
+impl<W: BufferedWrite> Decide for BitEncoder<Probability, W> {
+    fn decide(&mut self, bit: u8, p: u16) -> io::Result<u8> {
+        self.model_mut().p = p;
+        self.encode(bit)?;
+        Ok(bit)
+    }
+}
+
+impl<R: BufferedRead> Decide for BitDecoder<Probability, R> {
+    fn decide(&mut self, _: u8, p: u16) -> io::Result<u8> {
+        self.model_mut().p = p;
+        self.decode()
+    }
+}
+
+// Escape probabilities learned from the escapes coded, as in PPMd's
+// secondary escape estimation, by order, the number of bytes in the
+// context (up to 8) and method D's estimate in 32 steps, which each
+// starts at. Method D alone overestimates escapes from long contexts
+// seen only a few times, where most bytes are coded.
+struct Escapes {
+    pr: Vec<u32>, // High 22 bits probability of an escape, low 10 bits count
+}
+
+impl Escapes {
+    fn new() -> Escapes {
+        let pr = (0..(MAX_ORDER as u32 + 1) * 8 * 32).map(|i| ((i % 32) * 128 + 64) << 20).collect();
+        Escapes { pr }
+    }
+
+    fn index(order: usize, n: u32, estimate: u16) -> usize {
+        ((order * 8 + n.min(8) as usize - 1) << 5) + (estimate >> 7) as usize
+    }
+
+    fn p(&self, i: usize) -> u16 {
+        ((self.pr[i] >> 20) as u16).clamp(1, 4095)
+    }
+
+    // As in sr, adapting at 1/(count + 2) until count reaches LIMIT.
+    fn update(&mut self, i: usize, bit: u8) {
+        let entry = &mut self.pr[i];
+        let count = *entry & 1023;
+        let pr = (*entry >> 10) as i32;
+        let target = if bit == 1 { (1 << 22) - 1 } else { 0 };
+        let pr = pr + (target - pr) / (count as i32 + 2);
+        *entry = ((pr as u32) << 10) | (count + (count < LIMIT) as u32);
+    }
+}
+
+// A byte seen in a context, how often, and the context that it ends.
+#[derive(Clone, Copy)]
+struct Entry {
+    child:  u32, // Node of the context followed by symbol, or 0 at the longest order
+    count:  u16,
+    symbol: u8,
+}
+
+#[derive(Default)]
+struct Node {
+    entries: Vec<Entry>, // Most frequent first
+}
+
+struct Ppm {
+    nodes:    Vec<Node>,     // The contexts, the order 0 one first
+    cxt:      [u32; MAX_ORDER as usize + 1], // Node of each order's context
+    valid:    usize,         // Longest order with a context, which grows to order after a restart
+    order:    usize,
+    size:     u64,           // Approximate bytes used by nodes
+    limit:    u64,
+    excluded: [u64; 4],      // Bytes in the contexts escaped from
+    escapes:  Escapes,
+}
+
+impl Ppm {
+    fn new(order: u8, limit: u64) -> Ppm {
+        let mut ppm = Ppm {
+            nodes:    Vec::new(),
+            cxt:      [0; MAX_ORDER as usize + 1],
+            valid:    0,
+            order:    order as usize,
+            size:     0,
+            limit,
+            excluded: [0; 4],
+            escapes:  Escapes::new(),
+        };
+        ppm.restart();
+        ppm
+    }
+
+    fn restart(&mut self) {
+        self.nodes.clear();
+        self.nodes.push(Node::default());
+        self.size = (self.nodes.capacity() * size_of::<Node>()) as u64;
+        self.valid = 0;
+    }
+
+    fn is_excluded(&self, symbol: u8) -> bool {
+        self.excluded[symbol as usize >> 6] & (1 << (symbol & 63)) != 0
+    }
+
+    // Code byte, or the end of the stream if None, returning what was
+    // coded, which for the decoder is what it decoded.
+    fn code<C: Decide>(&mut self, coder: &mut C, byte: Option<u8>) -> io::Result<Option<u8>> {
+        self.excluded = [0; 4];
+        for k in (0..=self.valid).rev() {
+            let entries = &self.nodes[self.cxt[k] as usize].entries;
+            let (n, sum) = entries.iter()
+                .filter(|e| !self.is_excluded(e.symbol))
+                .fold((0, 0), |(n, sum), e| (n + 1, sum + e.count as u32));
+            if n == 0 {
+                continue;
+            }
+            let found = entries.iter().any(|e| Some(e.symbol) == byte && !self.is_excluded(e.symbol));
+            let see = Escapes::index(k, n, odds(n, 2 * sum));
+            let escape = coder.decide(!found as u8, self.escapes.p(see))?;
+            self.escapes.update(see, escape);
+            let entries = &self.nodes[self.cxt[k] as usize].entries;
+            if escape == 0 {
+                // The last byte not excluded is what's left when the others
+                // aren't it, so needs no flag.
+                let mut left = 2 * sum - n;
+                let mut coded = 0;
+                for (i, e) in entries.iter().enumerate().filter(|(_, e)| !self.is_excluded(e.symbol)) {
+                    coded = i;
+                    let w = 2 * e.count as u32 - 1;
+                    if w == left || coder.decide((Some(e.symbol) == byte) as u8, odds(w, left))? == 1 {
+                        break;
+                    }
+                    left -= w;
+                }
+                let symbol = entries[coded].symbol;
+                self.update(symbol, Some((k, coded)));
+                return Ok(Some(symbol));
+            }
+            for e in entries.iter() {
+                self.excluded[e.symbol as usize >> 6] |= 1 << (e.symbol & 63);
+            }
+        }
+
+        if coder.decide(byte.is_none() as u8, 1)? == 1 {
+            return Ok(None);
+        }
+        // The byte's bits, high first, each splitting the bytes not
+        // excluded that the bits so far leave.
+        let mut symbol = 0;
+        for i in (0..8).rev() {
+            let not_excluded = |prefix: usize| (prefix << i..(prefix + 1) << i).filter(|&s| !self.is_excluded(s as u8)).count() as u32;
+            let (n0, n1) = (not_excluded(symbol * 2), not_excluded(symbol * 2 + 1));
+            let bit = match byte {
+                Some(byte) => (byte >> i) & 1,
+                None => 0,
+            };
+            let bit = if n0 == 0 || n1 == 0 { (n1 > 0) as u8 } else { coder.decide(bit, odds(n1, n0 + n1))? };
+            symbol = symbol * 2 + bit as usize;
+        }
+        self.update(symbol as u8, None);
+        Ok(Some(symbol as u8))
+    }
+
+    // Count symbol, found at entry i of the context of order k, or at
+    // order -1 if None, and move to the contexts that end with it.
+    fn update(&mut self, symbol: u8, found: Option<(usize, usize)>) {
+        let mut next = [0; MAX_ORDER as usize + 1];
+        for k in 0..=self.valid {
+            let node = self.cxt[k] as usize;
+            let i = match found {
+                Some((order, i)) if order == k => {
+                    self.count(node, i)
+                }
+                _ => match self.nodes[node].entries.iter().position(|e| e.symbol == symbol) {
+                    Some(i) => i,
+                    None => self.add(node, symbol, k < self.order),
+                },
+            };
+            if k < self.order {
+                next[k + 1] = self.nodes[node].entries[i].child;
+            }
+        }
+        self.cxt = next;
+        self.valid = (self.valid + 1).min(self.order);
+        if self.size > self.limit {
+            self.restart();
+        }
+    }
+
+    // Add one to the count of entry i of node, halving the counts if it
+    // gets too large, and return where it moves to, keeping the most
+    // frequent first.
+    fn count(&mut self, node: usize, mut i: usize) -> usize {
+        let entries = &mut self.nodes[node].entries;
+        entries[i].count += 1;
+        if entries[i].count > MAX_COUNT {
+            for e in entries.iter_mut() {
+                e.count = e.count.div_ceil(2);
+            }
+        }
+        while i > 0 && entries[i - 1].count < entries[i].count {
+            entries.swap(i - 1, i);
+            i -= 1;
+        }
+        i
+    }
+
+    // Add symbol to node with a count of 1, and a new node for the
+    // context it ends if it's shorter than the longest, and return where
+    // it was added.
+    fn add(&mut self, node: usize, symbol: u8, child: bool) -> usize {
+        let child = if child {
+            self.nodes.push(Node::default());
+            self.size += size_of::<Node>() as u64;
+            (self.nodes.len() - 1) as u32
+        }
+        else {
+            0
+        };
+        let entries = &mut self.nodes[node].entries;
+        let capacity = entries.capacity();
+        entries.push(Entry { child, count: 1, symbol });
+        self.size += ((entries.capacity() - capacity) * size_of::<Entry>()) as u64;
+        entries.len() - 1
+    }
+}
+
+pub fn ppm_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, order: u8, model: u64) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, order, model))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, order: u8, model: u64) -> io::Result<()> {
+    assert!(order <= MAX_ORDER);
+    let mb = (model >> 20).clamp(MIN_MODEL >> 20, MAX_MODEL >> 20);
+    file_out.write_u8(order)?;
+    file_out.write_u16(mb as u16)?;
+
+    let mut enc = BitEncoder::new(Probability { p: 2048 }, file_out);
+    let mut ppm = Ppm::new(order, mb << 20);
+    while let Some(byte) = file_in.read_u8_checked()? {
+        ppm.code(&mut enc, Some(byte))?;
+    }
+    ppm.code(&mut enc, None)?;
+    enc.flush()
+}
+
+// Decompress a ppm stream, returning an InvalidData error if it ends
+// early, and an OutOfMemory error if its model needs more than
+// max_memory bytes.
+pub fn ppm_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out, max_memory))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, max_memory: Option<u64>) -> io::Result<()> {
+    let truncated = || io::Error::new(ErrorKind::InvalidData, "truncated ppm stream");
+    let order = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+    let mb = file_in.read_u16_checked()?.ok_or_else(truncated)? as u64;
+    if order > MAX_ORDER || mb == 0 {
+        return Err(io::Error::new(ErrorKind::InvalidData, "invalid ppm header"));
+    }
+    let need = memory(mb << 20);
+    if max_memory.is_some_and(|max| need > max) {
+        return Err(io::Error::new(ErrorKind::OutOfMemory,
+            format!("ppm stream needs {} bytes of memory, more than --max-memory", need)));
+    }
+
+    let mut dec = BitDecoder::new(Probability { p: 2048 }, file_in)?;
+    let mut ppm = Ppm::new(order, mb << 20);
+    while let Some(byte) = ppm.code(&mut dec, None)? {
+        if dec.overrun() {
+            return Err(truncated());
+        }
+        file_out.write_u8(byte)?;
+    }
+    if dec.overrun() {
+        return Err(truncated());
+    }
+    file_out.flush()
+}
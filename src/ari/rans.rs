@@ -0,0 +1,298 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+
+// Order 0 byte-wise rANS, as in Giesen's rans_byte: a 32 bit state, kept
+// in [RANS_L, RANS_L << 8) by moving whole bytes in and out, so there's
+// no bit-by-bit coding as in the binary arithmetic coders. Frequencies
+// sum to 1 << SCALE_BITS, and decoding finds a symbol from the low bits
+// of the state with one table lookup.
+//
+// rANS decodes in the reverse order it encodes, so the input is coded a
+// block at a time, backwards, and each block is written out once coded.
+//
+// The model is either adaptive, counts of the bytes so far, carried from
+// block to block and turned into a new table every so often, or static,
+// a table of each block's own counts, stored before it.
+//
+// Output format:
+//
+//   flags          (1 byte, STATIC for static tables)
+//   blocks, each:
+//     length       (u32, bytes of input in the block, at most BLOCK_SIZE)
+//     frequencies  (256 u16s, if STATIC)
+//     size         (u32, bytes of coded data)
+//     data         (final state as a u32, then the bytes renormalizing
+//                   shifted out, last first)
+//   end            (u32 0)
+//
+// Once the last symbol of a block is decoded, the state is back to its
+// initial RANS_L, which the decoder checks.
+
+pub const STATIC: u8 = 1;
+
+const SCALE_BITS: u32 = 14;
+const SCALE: u32 = 1 << SCALE_BITS;
+const RANS_L: u32 = 1 << 23;
+const BLOCK_SIZE: usize = 1 << 20;
+
+/// Approximate memory needed to compress or decompress: a block, the
+/// range of each of its bytes, its coded data, and I/O buffers.
+pub const MEMORY: u64 = 12 << 20;
+
+// An adaptive model makes a new table after this many bytes, at first
+// sooner, so it learns the input's statistics quickly.
+const MAX_INTERVAL: usize = 4096;
+// Counts are halved when their total reaches this, so the model follows
+// changes in the input.
+const MAX_TOTAL: u32 = 1 << 16;
+
+// Frequencies scaled to sum to SCALE, and where each symbol's range
+// starts. Only the decoder needs the slot table.
+struct Table {
+    freq:  [u32; 256],
+    start: [u32; 256],
+    slots: Vec<u8>,    // The symbol for each of the SCALE slots
+}
+
+impl Table {
+    fn new(freq: [u32; 256], slots: bool) -> Table {
+        let mut start = [0; 256];
+        let mut cum = 0;
+        for (start, freq) in start.iter_mut().zip(freq.iter()) {
+            *start = cum;
+            cum += freq;
+        }
+        let mut table = Table { freq, start, slots: Vec::new() };
+        if slots {
+            table.slots = vec![0; SCALE as usize];
+            for s in 0..256 {
+                let range = table.start[s] as usize..(table.start[s] + table.freq[s]) as usize;
+                table.slots[range].fill(s as u8);
+            }
+        }
+        table
+    }
+
+    // The table for counts, with every byte counted given at least 1.
+    fn from_counts(counts: &[u32; 256], slots: bool) -> Table {
+        Table::new(normalize(counts, SCALE), slots)
+    }
+}
+
+// Scale counts to sum to scale, keeping every nonzero count at least 1.
+// Rounding errors are taken from or given to the largest frequencies,
+// which with at most 256 symbols are always well above 1. fse shares
+// this, with a smaller scale.
+pub(crate) fn normalize(counts: &[u32; 256], scale: u32) -> [u32; 256] {
+    let total: u64 = counts.iter().map(|&count| count as u64).sum();
+    let mut freq = [0u32; 256];
+    if total == 0 {
+        return freq;
+    }
+    for (freq, &count) in freq.iter_mut().zip(counts.iter()) {
+        if count > 0 {
+            *freq = ((count as u64 * scale as u64 / total) as u32).max(1);
+        }
+    }
+    let mut sum: u32 = freq.iter().sum();
+    while sum > scale {
+        let largest = (0..256).max_by_key(|&s| freq[s]).unwrap();
+        let excess = (sum - scale).min(freq[largest] - 1);
+        freq[largest] -= excess;
+        sum -= excess;
+    }
+    if sum < scale {
+        let largest = (0..256).max_by_key(|&s| freq[s]).unwrap();
+        freq[largest] += scale - sum;
+    }
+    freq
+}
+
+// Counts of the bytes so far, all starting at 1 since any byte can come
+// next, and the table made from them.
+struct Adaptive {
+    counts:   [u32; 256],
+    total:    u32,
+    table:    Table,
+    interval: usize,
+    left:     usize,     // Bytes until the next table
+    slots:    bool,
+}
+
+impl Adaptive {
+    fn new(slots: bool) -> Adaptive {
+        let counts = [1; 256];
+        Adaptive {
+            counts,
+            total: 256,
+            table: Table::from_counts(&counts, slots),
+            interval: 16,
+            left: 16,
+            slots,
+        }
+    }
+
+    fn update(&mut self, byte: u8) {
+        self.counts[byte as usize] += 1;
+        self.total += 1;
+        if self.total >= MAX_TOTAL {
+            self.total = 0;
+            for count in self.counts.iter_mut() {
+                *count = count.div_ceil(2);
+                self.total += *count;
+            }
+        }
+        self.left -= 1;
+        if self.left == 0 {
+            self.table = Table::from_counts(&self.counts, self.slots);
+            self.interval = (self.interval * 2).min(MAX_INTERVAL);
+            self.left = self.interval;
+        }
+    }
+}
+
+pub fn rans_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, static_model: bool) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, static_model))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, static_model: bool) -> io::Result<()> {
+    file_out.write_u8(if static_model { STATIC } else { 0 })?;
+
+    let mut model = Adaptive::new(false);
+    let mut ranges = Vec::with_capacity(BLOCK_SIZE);
+    let mut data = Vec::with_capacity(BLOCK_SIZE + BLOCK_SIZE / 2);
+    for block in Blocks::new(&mut file_in, BLOCK_SIZE) {
+        let block = block?;
+        file_out.write_u32(block.len() as u32)?;
+
+        // The range each byte is coded with, found going forwards, since
+        // an adaptive model can't be run backwards.
+        ranges.clear();
+        if static_model {
+            let mut counts = [0; 256];
+            for &byte in block.iter() {
+                counts[byte as usize] += 1;
+            }
+            let table = Table::from_counts(&counts, false);
+            for freq in table.freq.iter() {
+                file_out.write_u16(*freq as u16)?;
+            }
+            ranges.extend(block.iter().map(|&byte| (table.start[byte as usize] as u16, table.freq[byte as usize] as u16)));
+        }
+        else {
+            for &byte in block.iter() {
+                ranges.push((model.table.start[byte as usize] as u16, model.table.freq[byte as usize] as u16));
+                model.update(byte);
+            }
+        }
+
+        data.clear();
+        let mut x = RANS_L;
+        for &(start, freq) in ranges.iter().rev() {
+            let (start, freq) = (start as u32, freq as u32);
+            let x_max = ((RANS_L >> SCALE_BITS) << 8) * freq;
+            while x >= x_max {
+                data.push(x as u8);
+                x >>= 8;
+            }
+            x = ((x / freq) << SCALE_BITS) + (x % freq) + start;
+        }
+        data.extend_from_slice(&x.to_be_bytes());
+        data.reverse();
+
+        file_out.write_u32(data.len() as u32)?;
+        file_out.write_bytes(&data)?;
+    }
+    file_out.write_u32(0u32)?;
+    file_out.flush_buffer()
+}
+
+// Decompress a rans stream, returning an InvalidData error if it ends
+// early or doesn't decode to what was coded.
+#[allow(clippy::redundant_closure)]
+pub fn rans_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let flags = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+    let static_model = flags & STATIC != 0;
+
+    let mut model = Adaptive::new(true);
+    let mut data = Vec::new();
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    loop {
+        let len = file_in.read_u32_checked()?.ok_or_else(truncated)? as usize;
+        if len == 0 {
+            break;
+        }
+        if len > BLOCK_SIZE {
+            return Err(corrupt("block too large"));
+        }
+        let table = if static_model {
+            let mut freq = [0; 256];
+            for freq in freq.iter_mut() {
+                *freq = file_in.read_u16_checked()?.ok_or_else(truncated)? as u32;
+            }
+            if freq.iter().sum::<u32>() != SCALE {
+                return Err(corrupt("bad frequency table"));
+            }
+            Some(Table::new(freq, true))
+        }
+        else {
+            None
+        };
+
+        // Each byte takes at most SCALE_BITS bits.
+        let size = file_in.read_u32_checked()?.ok_or_else(truncated)? as usize;
+        if size < 4 || size > 4 + len * 2 {
+            return Err(corrupt("bad block size"));
+        }
+        data.clear();
+        file_in.by_ref().take(size as u64).read_to_end(&mut data)?;
+        if data.len() < size {
+            return Err(truncated());
+        }
+
+        let mut x = u32::from_le_bytes(data[..4].try_into().unwrap());
+        let mut pos = 4;
+        block.clear();
+        for _ in 0..len {
+            let table = table.as_ref().unwrap_or(&model.table);
+            let slot = x & (SCALE - 1);
+            let byte = table.slots[slot as usize];
+            let s = byte as usize;
+            x = table.freq[s] * (x >> SCALE_BITS) + slot - table.start[s];
+            while x < RANS_L {
+                let &next = data.get(pos).ok_or_else(|| corrupt("block overrun"))?;
+                x = (x << 8) | next as u32;
+                pos += 1;
+            }
+            block.push(byte);
+            if !static_model {
+                model.update(byte);
+            }
+        }
+        if x != RANS_L || pos != size {
+            return Err(corrupt("block doesn't decode to its end"));
+        }
+        file_out.write_bytes(&block)?;
+    }
+    file_out.flush()
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "truncated rans stream")
+}
+
+fn corrupt(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, format!("corrupt rans stream: {}", msg))
+}
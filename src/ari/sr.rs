@@ -0,0 +1,192 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::coder::Model;
+use crate::ari::coder::BitEncoder;
+use crate::ari::coder::BitDecoder;
+
+// Symbol ranking, as in Mahoney's srank and sr2. Each order 3 context
+// (hashed) keeps the last 3 different bytes seen in it, most recent
+// first, and how many times in a row the first was right. A byte is
+// coded as a hit on rank 1, 2 or 3, which takes a fraction of a bit
+// when the ranks predict well, or a miss followed by the byte itself,
+// coded with an order 1 model. The byte then moves to the front.
+//
+// The stream is the coded bytes, then three misses and an end flag,
+// which follows every miss.
+
+const TABLE_BITS: u32 = 22;
+
+/// Approximate memory needed to compress or decompress: the context
+/// table, the probabilities, and I/O buffers.
+pub const MEMORY: u64 = (4 << TABLE_BITS) + (4 << 20);
+
+// Probabilities for rank decisions, the end flag, and literal bits, in
+// separate ranges of one table. Each is coded with whichever the coder
+// selects, so one Model serves them all.
+const RANKS:    usize = 0;                   // Rank, run count, previous byte
+const END:      usize = RANKS + 3 * 16 * 256;
+const LITERALS: usize = END + 1;             // Previous byte, bits so far
+const SIZE:     usize = LITERALS + 256 * 256;
+
+const LIMIT: u32 = 255; // Adaptation slows to 1/LIMIT, then stays there
+
+struct Probabilities {
+    cxt: usize,
+    pr:  Vec<u32>, // High 22 bits probability of a 1, low 10 bits count
+}
+
+impl Probabilities {
+    fn new() -> Probabilities {
+        Probabilities { cxt: 0, pr: vec![1 << 31; SIZE] }
+    }
+}
+
+impl Model for Probabilities {
+    fn p(&mut self) -> u16 {
+        ((self.pr[self.cxt] >> 20).clamp(1, 4095)) as u16
+    }
+
+    fn update(&mut self, bit: u8) {
+        let entry = &mut self.pr[self.cxt];
+        let count = *entry & 1023;
+        let pr = (*entry >> 10) as i32;
+        let target = if bit == 1 { (1 << 22) - 1 } else { 0 };
+        let pr = pr + (target - pr) / (count as i32 + 2);
+        *entry = ((pr as u32) << 10) | (count + (count < LIMIT) as u32);
+    }
+}
+
+// A context's ranked bytes in the low 3 bytes, and its run count in
+// the high byte.
+struct Ranks {
+    table: Vec<u32>,
+    hash:  usize,
+    last:  u32, // Last 3 bytes
+}
+
+impl Ranks {
+    fn new() -> Ranks {
+        Ranks { table: vec![0; 1 << TABLE_BITS], hash: 0, last: 0 }
+    }
+
+    fn get(&self) -> u32 {
+        self.table[self.hash]
+    }
+
+    fn prev(&self) -> usize {
+        (self.last & 0xFF) as usize
+    }
+
+    // Move byte, found at rank (0 to 2, or 3 if not found), to the
+    // front, and move on to the next context.
+    fn update(&mut self, byte: u8, rank: usize) {
+        let entry = self.table[self.hash];
+        let ranked = entry & 0xFFFFFF;
+        let byte = byte as u32;
+        self.table[self.hash] = match rank {
+            0 => (((entry >> 24) + 1).min(255) << 24) | ranked,
+            1 => (ranked & 0xFF0000) | (ranked & 0xFF) << 8 | byte,
+            _ => (ranked & 0xFFFF) << 8 | byte,
+        };
+        self.last = (self.last << 8 | byte) & 0xFFFFFF;
+        self.hash = (self.last.wrapping_mul(0x2F0B4F35) >> (32 - TABLE_BITS)) as usize;
+    }
+}
+
+// Context for the decision whether the next byte has the given rank.
+fn rank_cxt(entry: u32, rank: usize, prev: usize) -> usize {
+    let run = (entry >> 24).min(15) as usize;
+    RANKS + ((rank * 16 + run) << 8) + prev
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn sr_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, file_out: BufWriter<W>) -> io::Result<()> {
+    let mut enc = BitEncoder::new(Probabilities::new(), file_out);
+    let mut ranks = Ranks::new();
+
+    while let Some(byte) = file_in.read_u8_checked()? {
+        let entry = ranks.get();
+        let rank = (0..3).find(|&i| (entry >> (i * 8)) as u8 == byte).unwrap_or(3);
+        for i in 0..3.min(rank + 1) {
+            enc.model_mut().cxt = rank_cxt(entry, i, ranks.prev());
+            enc.encode((i == rank) as u8)?;
+        }
+        if rank == 3 {
+            enc.model_mut().cxt = END;
+            enc.encode(0)?;
+            let mut partial = 1;
+            for i in (0..8).rev() {
+                let bit = (byte >> i) & 1;
+                enc.model_mut().cxt = LITERALS + (ranks.prev() << 8) + partial;
+                enc.encode(bit)?;
+                partial = partial * 2 + bit as usize;
+            }
+        }
+        ranks.update(byte, rank);
+    }
+    let entry = ranks.get();
+    for i in 0..3 {
+        enc.model_mut().cxt = rank_cxt(entry, i, ranks.prev());
+        enc.encode(0)?;
+    }
+    enc.model_mut().cxt = END;
+    enc.encode(1)?;
+    enc.flush()
+}
+
+// Decompress an sr stream, returning an InvalidData error if it ends
+// early.
+#[allow(clippy::redundant_closure)]
+pub fn sr_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut dec = BitDecoder::new(Probabilities::new(), file_in)?;
+    let mut ranks = Ranks::new();
+
+    loop {
+        let entry = ranks.get();
+        let mut rank = 3;
+        for i in 0..3 {
+            dec.model_mut().cxt = rank_cxt(entry, i, ranks.prev());
+            if dec.decode()? == 1 {
+                rank = i;
+                break;
+            }
+        }
+        let byte = if rank < 3 {
+            (entry >> (rank * 8)) as u8
+        }
+        else {
+            dec.model_mut().cxt = END;
+            if dec.decode()? == 1 {
+                break;
+            }
+            let mut partial = 1;
+            for _ in 0..8 {
+                dec.model_mut().cxt = LITERALS + (ranks.prev() << 8) + partial;
+                partial = partial * 2 + dec.decode()? as usize;
+            }
+            partial as u8
+        };
+        if dec.overrun() {
+            return Err(io::Error::new(ErrorKind::InvalidData, "truncated sr stream"));
+        }
+        file_out.write_u8(byte)?;
+        ranks.update(byte, rank);
+    }
+    file_out.flush()
+}
@@ -1,4 +1,8 @@
-const STATE_TABLE: [[u8; 2]; 256] = [
+/// Next state for each state and bit.
+pub type StateTable = [[u8; 2]; 256];
+
+/// The bit history states of lpaq1.
+pub static STATE_TABLE: StateTable = [
 [  1,  2],[  3,  5],[  4,  6],[  7, 10],[  8, 12],[  9, 13],[ 11, 14], // 0
 [ 15, 19],[ 16, 23],[ 17, 24],[ 18, 25],[ 20, 27],[ 21, 28],[ 22, 29], // 7
 [ 26, 30],[ 31, 33],[ 32, 35],[ 32, 35],[ 32, 35],[ 32, 35],[ 34, 37], // 14
@@ -39,4 +43,95 @@ const STATE_TABLE: [[u8; 2]; 256] = [
 
 pub fn next_state(state: u8, bit: i32) -> u8 {
     STATE_TABLE[state as usize][bit as usize]
-}
\ No newline at end of file
+}
+
+/// Run-length aware states, generated as in zpaq. Each state stands for
+/// a count of zeros n0 and ones n1 seen in a context, and while both are
+/// small, for which bit came last. When a bit is seen, the count of the
+/// other bit is cut back, so after a long run of one bit the state
+/// predicts it strongly, and a run of up to 48 can be counted past a
+/// single exception.
+pub static RUN_STATE_TABLE: StateTable = run_states();
+
+// Largest n0 with n1 = i, and the other way around.
+const BOUND: [usize; 6] = [20, 48, 15, 8, 6, 5];
+const MAX_COUNT: usize = 50;
+
+// Number of states with counts n0 and n1: 2 if they also record the
+// last bit, 1 if not, 0 if the counts are out of bounds.
+const fn num_states(n0: usize, n1: usize) -> usize {
+    if n0 < n1 {
+        return num_states(n1, n0);
+    }
+    if n1 >= BOUND.len() || n0 > BOUND[n1] {
+        return 0;
+    }
+    1 + (n1 > 0 && n0 + n1 <= 17) as usize
+}
+
+// New count of a bit after the other bit is seen.
+const fn discount(n: usize) -> usize {
+    (n >= 1) as usize + (n >= 2) as usize + (n >= 3) as usize + (n >= 4) as usize
+    + (n >= 5) as usize + (n >= 7) as usize + (n >= 8) as usize
+}
+
+// Counts after seeing bit, scaled back down into bounds.
+const fn next_counts(n0: usize, n1: usize, bit: usize) -> (usize, usize) {
+    if n0 < n1 {
+        let (n1, n0) = next_counts(n1, n0, 1 - bit);
+        return (n0, n1);
+    }
+    let (mut n0, mut n1) = if bit == 1 { (discount(n0), n1 + 1) } else { (n0 + 1, discount(n1)) };
+    while num_states(n0, n1) == 0 {
+        if n1 < 2 {
+            n0 -= 1;
+        }
+        else {
+            n0 = (n0 * (n1 - 1) + n1 / 2) / n1;
+            n1 -= 1;
+        }
+    }
+    (n0, n1)
+}
+
+// Number the states by increasing total count, so state 0 is the empty
+// history and higher states have seen more, then link them. Of a pair
+// of states recording the last bit, the first is for 0.
+const fn run_states() -> StateTable {
+    let mut first = [[0usize; MAX_COUNT]; MAX_COUNT];
+    let mut state = 0;
+    let mut total = 0;
+    while total < MAX_COUNT {
+        let mut n1 = 0;
+        while n1 <= total {
+            let n0 = total - n1;
+            if num_states(n0, n1) > 0 {
+                first[n0][n1] = state;
+                state += num_states(n0, n1);
+            }
+            n1 += 1;
+        }
+        total += 1;
+    }
+    assert!(state <= 256);
+
+    let mut table = [[0u8; 2]; 256];
+    let mut n0 = 0;
+    while n0 < MAX_COUNT {
+        let mut n1 = 0;
+        while n1 < MAX_COUNT {
+            let mut last = 0;
+            while last < num_states(n0, n1) {
+                let s = first[n0][n1] + last;
+                let (a, b) = next_counts(n0, n1, 0);
+                table[s][0] = first[a][b] as u8;
+                let (a, b) = next_counts(n0, n1, 1);
+                table[s][1] = (first[a][b] + num_states(a, b) - 1) as u8;
+                last += 1;
+            }
+            n1 += 1;
+        }
+        n0 += 1;
+    }
+    table
+}
@@ -0,0 +1,444 @@
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+use std::io;
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::ops::Range;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::bufio::*;
+use crate::bytes;
+use crate::container;
+use crate::container::Algorithm;
+use crate::error;
+use crate::stats::Sizes;
+
+// Compression of independent blocks on several threads. The input is cut
+// into blocks of a fixed size, each compressed on its own by any
+// algorithm, and the compressed blocks are written in input order:
+//
+//   block size  (u64, no block is longer)
+//   blocks      (original length (u64), compressed length (u64), and
+//                the compressed block, for each block)
+//   end         (u64 0)
+//   index       (with --index, the offset of each block from the start
+//                of the stream (u64), then the number of blocks (u64))
+//
+// The top bit of the block size marks a stream with an index. Every
+// block but the last is full, so block i holds the input from
+// i * block size, and the index, found from the end of the stream,
+// gives where it starts. IndexedReader uses it to decompress only the
+// blocks a range of the input is in.
+//
+// Block boundaries depend only on the input, and each block's output
+// only on the block, so the stream is the same for any number of
+// threads.
+//
+// Blocks go through a pipeline: the calling thread reads blocks and
+// writes outputs while worker threads code them, so even with one
+// worker the codec doesn't wait on I/O. Up to 2 blocks per worker are
+// in flight between reading and writing, which bounds memory.
+//
+// Blocks compress a little worse than the whole input would, since each
+// starts with a cold model and can't match data in the others.
+
+pub const DEFAULT_BLOCK_SIZE: usize = 4 << 20;
+pub const MIN_BLOCK_SIZE: usize = 1 << 12;
+
+// Top bit of the block size, set if the stream ends with an index.
+const INDEXED: u64 = 1 << 63;
+
+/// Approximate memory needed beyond the codec's for each thread: a
+/// block and its compressed output.
+pub fn memory(block_size: usize) -> u64 {
+    2 * block_size as u64
+}
+
+/// Most threads to decompress on by default. Each thread's codec can
+/// need as much memory as the whole compressor did, which the stream
+/// doesn't record, so one per CPU on a large machine could need many
+/// times that.
+pub const MAX_DEFAULT_THREADS: usize = 4;
+
+/// The number of threads to decompress on: threads if given, otherwise
+/// one per CPU up to MAX_DEFAULT_THREADS, unless memory is limited.
+/// Splitting max_memory between threads could leave each too little for
+/// blocks that compressed in all of it, so then it's one.
+pub fn decompress_threads(threads: Option<usize>, max_memory: Option<u64>) -> usize {
+    match (threads, max_memory) {
+        (Some(threads), _) => threads,
+        (None, Some(_)) => 1,
+        (None, None) => thread::available_parallelism().map_or(1, |n| n.get()).min(MAX_DEFAULT_THREADS),
+    }
+}
+
+/// How much of sample repeats itself: the fraction of its 32 byte
+/// strings, at every 32nd position, that appear anywhere before.
+pub fn repetitiveness(sample: &[u8]) -> f64 {
+    let mut seen = HashSet::new();
+    let (mut checked, mut repeats) = (0, 0);
+    for (pos, string) in sample.windows(32).enumerate() {
+        if pos > 0 && pos % 32 == 0 {
+            checked += 1;
+            repeats += seen.contains(string) as usize;
+        }
+        seen.insert(string);
+    }
+    if checked == 0 { 0.0 } else { repeats as f64 / checked as f64 }
+}
+
+/// A power of two block size for size bytes of input with the given
+/// repetitiveness. Blocks can't refer to each other, so the more the
+/// input repeats, the larger they are, but no larger than the input
+/// needs.
+pub fn auto_block_size(size: u64, repetitiveness: f64) -> usize {
+    let target = match repetitiveness {
+        r if r >= 0.5 => 64 << 20,
+        r if r >= 0.1 => 16 << 20,
+        _ => DEFAULT_BLOCK_SIZE,
+    };
+    let size = usize::try_from(size.next_power_of_two()).unwrap_or(usize::MAX);
+    target.min(size).max(MIN_BLOCK_SIZE)
+}
+
+/// Compress file_in in blocks of block_size bytes with codec, on up to
+/// threads threads, ending with an index of the blocks if index is set.
+pub fn compress_blocks<R, W, F>(file_in: BufReader<R>, file_out: BufWriter<W>, block_size: usize, threads: usize, index: bool, codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: Fn(BufReader<Cursor<&[u8]>>, BufWriter<&mut Cursor<Vec<u8>>>) -> error::Result<Sizes> + Sync,
+{
+    assert!(block_size >= MIN_BLOCK_SIZE);
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, block_size, threads, index, codec))
+}
+
+fn compress<R, W, F>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, block_size: usize, threads: usize, index: bool, codec: F) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+    F: Fn(BufReader<Cursor<&[u8]>>, BufWriter<&mut Cursor<Vec<u8>>>) -> error::Result<Sizes> + Sync,
+{
+    file_out.write_u64(block_size as u64 | if index { INDEXED } else { 0 })?;
+    let mut offsets = Vec::new();
+    let mut pos = 8;
+    let next = || {
+        let mut block = Vec::new();
+        file_in.by_ref().take(block_size as u64).read_to_end(&mut block)?;
+        Ok((!block.is_empty()).then_some(block))
+    };
+    let code = |block: &Vec<u8>| {
+        let mut out = Cursor::new(Vec::new());
+        codec(BufReader::new(Cursor::new(&block[..])), BufWriter::new(&mut out))?;
+        Ok(out.into_inner())
+    };
+    pipeline(threads, next, code, |block, output| {
+        offsets.push(pos);
+        pos += 16 + output.len() as u64;
+        file_out.write_u64(block.len() as u64)?;
+        file_out.write_u64(output.len() as u64)?;
+        file_out.write_bytes(&output)?;
+        Ok(())
+    })?;
+    file_out.write_u64(0u64)?;
+    if index {
+        for offset in offsets.iter() {
+            file_out.write_u64(*offset)?;
+        }
+        file_out.write_u64(offsets.len() as u64)?;
+    }
+    file_out.flush()
+}
+
+/// Decompress a stream from compress_blocks with codec, on up to threads
+/// threads, returning an InvalidData error if it's truncated, a block
+/// doesn't decompress to its original length, or its index doesn't
+/// match the blocks.
+pub fn decompress_blocks<R, W, F>(file_in: BufReader<R>, file_out: BufWriter<W>, threads: usize, codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: Fn(BufReader<Watched<&[u8]>>, BufWriter<Watched<&mut Vec<u8>>>) -> error::Result<Sizes> + Sync,
+{
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out, threads, codec))
+}
+
+fn decompress<R, W, F>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, threads: usize, codec: F) -> io::Result<()>
+where
+    R: Read,
+    W: Write,
+    F: Fn(BufReader<Watched<&[u8]>>, BufWriter<Watched<&mut Vec<u8>>>) -> error::Result<Sizes> + Sync,
+{
+    let block_size = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+    let (block_size, index) = (block_size & !INDEXED, block_size & INDEXED != 0);
+    let mut offsets = Vec::new();
+    let mut pos = 8;
+    let next = || {
+        let len = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        if len > block_size {
+            return Err(invalid_data(format!("block of {} bytes is larger than the block size, {}", len, block_size)));
+        }
+        let compressed_len = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+        let mut block = Vec::new();
+        file_in.by_ref().take(compressed_len).read_to_end(&mut block)?;
+        if (block.len() as u64) < compressed_len {
+            return Err(truncated());
+        }
+        offsets.push(pos);
+        pos += 16 + compressed_len;
+        Ok(Some((len, block)))
+    };
+    let code = |(len, block): &(u64, Vec<u8>)| {
+        let mut out = Vec::new();
+        watchdog(&block[..], &mut out, *len, |file_in, file_out| codec(BufReader::new(file_in), BufWriter::new(file_out)))?;
+        Ok(out)
+    };
+    pipeline(threads, next, code, |(len, _), output| {
+        if output.len() as u64 != *len {
+            return Err(invalid_data(format!("block decompressed to {} bytes, not {}", output.len(), len)));
+        }
+        file_out.write_bytes(&output)?;
+        Ok(())
+    })?;
+    if index {
+        for offset in offsets.iter().chain([&(offsets.len() as u64)]) {
+            if file_in.read_u64_checked()?.ok_or_else(truncated)? != *offset {
+                return Err(invalid_data("index doesn't match the blocks".to_string()));
+            }
+        }
+    }
+    file_out.flush()
+}
+
+/// Where each block of the stream from compress_blocks, from file_in's
+/// position to end, starts in the original input, and the range of
+/// file_in its compressed block is in, found without decompressing the
+/// blocks. Returns an InvalidData error if the stream is truncated.
+pub fn block_spans<R: Read + Seek>(file_in: &mut R, end: u64) -> error::Result<Vec<(u64, Range<u64>)>> {
+    let block_size = read_u64(file_in)? & !INDEXED;
+    let mut spans = Vec::new();
+    let mut offset = 0;
+    loop {
+        let len = read_u64(file_in)?;
+        if len == 0 {
+            return Ok(spans);
+        }
+        if len > block_size {
+            return Err(invalid_data(format!("block of {} bytes is larger than the block size, {}", len, block_size)).into());
+        }
+        let compressed_len = read_u64(file_in)?;
+        let start = file_in.stream_position()?;
+        if compressed_len > end.saturating_sub(start) {
+            return Err(truncated().into());
+        }
+        file_in.seek(SeekFrom::Start(start + compressed_len))?;
+        spans.push((offset, start..start + compressed_len));
+        offset += len;
+    }
+}
+
+/// Random access to a stream compressed in blocks with an index, as with
+/// --index. A range of the original input is read by decompressing only
+/// the blocks it's in. file_in holds the stream, container header
+/// included, up to its end, as one member.
+pub struct IndexedReader<R> {
+    file_in:    R,
+    algorithm:  Algorithm,
+    max_memory: Option<u64>,
+    size:       u64,      // Size of the original input
+    block_size: u64,
+    offsets:    Vec<u64>, // Position in file_in of each block
+}
+
+impl<R: Read + Seek> IndexedReader<R> {
+    /// Read the headers and the index of a stream compressed with
+    /// algorithm. Returns an InvalidData error if the stream has no
+    /// index, was compressed with another algorithm, or the index is
+    /// damaged. Blocks are decompressed within max_memory.
+    pub fn open(mut file_in: R, algorithm: Algorithm, max_memory: Option<u64>) -> error::Result<IndexedReader<R>> {
+        let [member] = container::members(&mut file_in)?[..] else {
+            return Err(invalid_data("stream has more than one member".to_string()).into());
+        };
+        let (start, end) = (member.start, member.end);
+        let header = container::Header::read(&mut file_in)?;
+        if header.stored || !header.blocks {
+            return Err(no_index().into());
+        }
+        if header.algorithm != algorithm {
+            return Err(invalid_data(format!("stream was compressed with -{}, not -{}",
+                header.algorithm.name(), algorithm.name())).into());
+        }
+        let block_size = read_u64(&mut file_in)?;
+        if block_size & INDEXED == 0 {
+            return Err(no_index().into());
+        }
+        let block_size = block_size & !INDEXED;
+        if block_size == 0 {
+            return Err(invalid_data("block size is 0".to_string()).into());
+        }
+
+        // The block size, the end and the index, at least.
+        let blocks = header.size.div_ceil(block_size);
+        let index_len = blocks.saturating_add(1).saturating_mul(8);
+        let base = start + header.encoded_len();
+        if end.saturating_sub(base) < index_len.saturating_add(16) {
+            return Err(truncated().into());
+        }
+        file_in.seek(SeekFrom::Start(end - index_len))?;
+        let mut offsets = Vec::with_capacity(blocks as usize);
+        let mut min = 8;
+        for _ in 0..blocks {
+            let offset = read_u64(&mut file_in)?;
+            if offset < min || offset.saturating_add(16) > end - base - index_len - 8 {
+                return Err(invalid_data("damaged index".to_string()).into());
+            }
+            offsets.push(base + offset);
+            min = offset + 16;
+        }
+        if read_u64(&mut file_in)? != blocks {
+            return Err(invalid_data("index doesn't match the size in the header".to_string()).into());
+        }
+        Ok(IndexedReader { file_in, algorithm, max_memory, size: header.size, block_size, offsets })
+    }
+
+    /// Size of the original input.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Decompress len bytes of the original input from offset, or fewer
+    /// if the input ends first. Returns an InvalidData error if a block
+    /// they're in is damaged.
+    pub fn read_range(&mut self, offset: u64, len: u64) -> error::Result<Vec<u8>> {
+        let end = offset.saturating_add(len).min(self.size);
+        let mut range = Vec::new();
+        if offset >= end {
+            return Ok(range);
+        }
+        for i in offset / self.block_size..=(end - 1) / self.block_size {
+            let block = self.read_block(i)?;
+            let start = i * self.block_size;
+            range.extend_from_slice(&block[offset.saturating_sub(start) as usize..(end - start).min(block.len() as u64) as usize]);
+        }
+        Ok(range)
+    }
+
+    // Decompress block i.
+    fn read_block(&mut self, i: u64) -> error::Result<Vec<u8>> {
+        let expected = self.block_size.min(self.size - i * self.block_size);
+        self.file_in.seek(SeekFrom::Start(self.offsets[i as usize]))?;
+        let len = read_u64(&mut self.file_in)?;
+        if len != expected {
+            return Err(invalid_data(format!("block {} has {} bytes, not {}", i, len, expected)).into());
+        }
+        let compressed_len = read_u64(&mut self.file_in)?;
+        let mut block = Vec::new();
+        self.file_in.by_ref().take(compressed_len).read_to_end(&mut block)?;
+        if (block.len() as u64) < compressed_len {
+            return Err(truncated().into());
+        }
+        let (algorithm, max_memory) = (self.algorithm, self.max_memory);
+        let mut output = Vec::new();
+        watchdog(&block[..], &mut output, len, |file_in, file_out| {
+            bytes::decode(algorithm, max_memory, BufReader::new(file_in), BufWriter::new(file_out))
+        })?;
+        if output.len() as u64 != len {
+            return Err(invalid_data(format!("block decompressed to {} bytes, not {}", output.len(), len)).into());
+        }
+        Ok(output)
+    }
+}
+
+// Read a u64 from a stream that isn't buffered.
+fn read_u64<R: Read>(file_in: &mut R) -> io::Result<u64> {
+    let mut n = [0u8; 8];
+    match file_in.read_exact(&mut n) {
+        Ok(()) => Ok(u64::from_le_bytes(n)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(truncated()),
+        Err(e) => Err(e),
+    }
+}
+
+fn no_index() -> io::Error {
+    invalid_data("stream has no index; compress with --index".to_string())
+}
+
+// Read blocks with next until it returns None, code each with code on
+// one of threads worker threads, and pass each block and its output to
+// write, in the order they were read. A panic in code is resumed here.
+fn pipeline<T, N, C, W>(threads: usize, mut next: N, code: C, mut write: W) -> io::Result<()>
+where
+    T: Send,
+    N: FnMut() -> io::Result<Option<T>>,
+    C: Fn(&T) -> error::Result<Vec<u8>> + Sync,
+    W: FnMut(&T, Vec<u8>) -> io::Result<()>,
+{
+    let threads = threads.max(1);
+    let (work_tx, work_rx) = mpsc::channel::<(usize, T)>();
+    let (done_tx, done_rx) = mpsc::channel();
+    let work_rx = Mutex::new(work_rx);
+    thread::scope(|scope| {
+        for _ in 0..threads {
+            let (work_rx, code, done_tx) = (&work_rx, &code, done_tx.clone());
+            scope.spawn(move || loop {
+                // Hold the lock only while taking the next block.
+                let Ok((i, block)) = work_rx.lock().unwrap().recv() else {
+                    break;
+                };
+                let output = panic::catch_unwind(AssertUnwindSafe(|| code(&block)));
+                if done_tx.send((i, block, output)).is_err() {
+                    break;
+                }
+            });
+        }
+
+        // Returning, or unwinding, drops work_tx, which stops the workers
+        // once they've coded what they have.
+        let work_tx = work_tx;
+        let (mut read, mut written, mut eof) = (0, 0, false);
+        let mut finished = BTreeMap::new();
+        loop {
+            while !eof && read - written < 2 * threads {
+                match next()? {
+                    Some(block) => {
+                        work_tx.send((read, block)).expect("workers run until work_tx is dropped");
+                        read += 1;
+                    }
+                    None => eof = true,
+                }
+            }
+            if written == read {
+                return Ok(());
+            }
+            while !finished.contains_key(&written) {
+                let (i, block, output) = done_rx.recv().expect("workers run until work_tx is dropped");
+                finished.insert(i, (block, output));
+            }
+            let (block, output) = finished.remove(&written).unwrap();
+            let output = output.unwrap_or_else(|panic| panic::resume_unwind(panic))?;
+            write(&block, output)?;
+            written += 1;
+        }
+    })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    invalid_data("truncated block stream".to_string())
+}
@@ -1,12 +1,19 @@
-use std::fs::File;
+use std::io;
 use std::io::Write;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::BufRead;
 use std::io::Read;
 use std::io::ErrorKind;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::convert::TryInto;
-use std::mem;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+use crate::error;
+use crate::stats::Sizes;
 
 #[derive(PartialEq, Eq)]
 pub enum BufferState {
@@ -20,218 +27,524 @@ impl BufferState {
     }
 }
 
+// Errors reading the input are returned, except for reaching eof: read_*
+// functions give 0s past eof, and read_*_checked functions give None.
 pub trait BufferedRead {
-    fn read_<const N: usize>(&mut self) -> [u8; N];
-    fn read_checked<const N: usize>(&mut self) -> Option<[u8; N]>;
-    fn read_u8(&mut self) -> u8;
-    fn read_u16(&mut self) -> u16;
-    fn read_u32(&mut self) -> u32;
-    fn read_u64(&mut self) -> u64;
-    fn read_u8_checked(&mut self) -> Option<u8>;
-    fn read_u16_checked(&mut self) -> Option<u16>;
-    fn read_u32_checked(&mut self) -> Option<u32>;
-    fn read_u64_checked(&mut self) -> Option<u64>;
-    fn fill_buffer(&mut self) -> BufferState;
-}
-
-impl BufferedRead for BufReader<File> {
-    fn read_<const N: usize>(&mut self) -> [u8; N] {
+    fn read_<const N: usize>(&mut self) -> io::Result<[u8; N]>;
+    fn read_checked<const N: usize>(&mut self) -> io::Result<Option<[u8; N]>>;
+    fn read_u8(&mut self) -> io::Result<u8>;
+    fn read_u16(&mut self) -> io::Result<u16>;
+    fn read_u32(&mut self) -> io::Result<u32>;
+    fn read_u64(&mut self) -> io::Result<u64>;
+    fn read_u8_checked(&mut self) -> io::Result<Option<u8>>;
+    fn read_u16_checked(&mut self) -> io::Result<Option<u16>>;
+    fn read_u32_checked(&mut self) -> io::Result<Option<u32>>;
+    fn read_u64_checked(&mut self) -> io::Result<Option<u64>>;
+    fn fill_buffer(&mut self) -> io::Result<BufferState>;
+}
+
+impl<R: Read> BufferedRead for BufReader<R> {
+    fn read_<const N: usize>(&mut self) -> io::Result<[u8; N]> {
         let mut bytes = [0u8; N];
 
-        match self.read(&mut bytes) {
-            Ok(len) => {
-                // If read attempt is partially cut off by end of 
-                // buffer, refill buffer and read remaining bytes.
-                if self.buffer().is_empty() {
-                    self.consume(self.capacity());
-                    self.fill_buf().unwrap();
-                    if len > 1 && len < N {
-                        self.read_exact(&mut bytes).unwrap();
-                    }
+        let len = self.read(&mut bytes)?;
+        // If read attempt is partially cut off by end of 
+        // buffer, refill buffer and read remaining bytes.
+        if self.buffer().is_empty() {
+            crate::throttle::throttle();
+            self.consume(self.capacity());
+            self.fill_buf()?;
+            // The rest of the value may take more than one read,
+            // and past eof stays 0.
+            let mut len = len;
+            while len > 0 && len < N {
+                match self.read(&mut bytes[len..])? {
+                    0 => break,
+                    n => len += n,
                 }
             }
-
-            Err(e) => {
-                panic!("{}", e);
-            }
         }
-        bytes
+        Ok(bytes)
     }
 
     // Read bytes, returning None if not enough bytes could be read.
-    // This function is intended for checking if eof has been reached,
-    // and will panic upon encountering any errors not caused by reaching eof.
-    fn read_checked<const N: usize>(&mut self) -> Option<[u8; N]> {
+    // This function is intended for checking if eof has been reached.
+    fn read_checked<const N: usize>(&mut self) -> io::Result<Option<[u8; N]>> {
         let mut bytes = [0u8; N];
 
-        let len = self.read(&mut bytes).unwrap();
+        let len = self.read(&mut bytes)?;
         if self.buffer().is_empty() {
+            crate::throttle::throttle();
             self.consume(self.capacity());
-            self.fill_buf().unwrap();
+            self.fill_buf()?;
             if len < N {
                 match self.read_exact(&mut bytes[len..]) {
                     Ok(_) => {}
 
                     Err(e) => {
                         if e.kind() == ErrorKind::UnexpectedEof {
-                            return None;
+                            return Ok(None);
                         }
                         else {
-                            panic!("{}", e);
+                            return Err(e);
                         }
                     }
                 }
             }
         }
-        Some(bytes)
+        Ok(Some(bytes))
     }
 
-    fn read_u8(&mut self) -> u8 {
-        u8::from_le_bytes(self.read_::<1>())
+    fn read_u8(&mut self) -> io::Result<u8> {
+        self.read_::<1>().map(u8::from_le_bytes)
     }
 
-    fn read_u16(&mut self) -> u16 {
-        u16::from_le_bytes(self.read_::<2>())
+    fn read_u16(&mut self) -> io::Result<u16> {
+        self.read_::<2>().map(u16::from_le_bytes)
     }
 
-    fn read_u32(&mut self) -> u32 {
-        u32::from_le_bytes(self.read_::<4>())
+    fn read_u32(&mut self) -> io::Result<u32> {
+        self.read_::<4>().map(u32::from_le_bytes)
     }
     
-    fn read_u64(&mut self) -> u64 {
-        u64::from_le_bytes(self.read_::<8>())
+    fn read_u64(&mut self) -> io::Result<u64> {
+        self.read_::<8>().map(u64::from_le_bytes)
     }
 
-    fn read_u8_checked(&mut self) -> Option<u8> {
-        self.read_checked::<1>().map(u8::from_le_bytes)
+    fn read_u8_checked(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.read_checked::<1>()?.map(u8::from_le_bytes))
     }
 
-    fn read_u16_checked(&mut self) -> Option<u16> {
-        self.read_checked::<2>().map(u16::from_le_bytes)
+    fn read_u16_checked(&mut self) -> io::Result<Option<u16>> {
+        Ok(self.read_checked::<2>()?.map(u16::from_le_bytes))
     }
 
-    fn read_u32_checked(&mut self) -> Option<u32> {
-        self.read_checked::<4>().map(u32::from_le_bytes)
+    fn read_u32_checked(&mut self) -> io::Result<Option<u32>> {
+        Ok(self.read_checked::<4>()?.map(u32::from_le_bytes))
     }
 
-    fn read_u64_checked(&mut self) -> Option<u64> {
-        self.read_checked::<8>().map(u64::from_le_bytes)
+    fn read_u64_checked(&mut self) -> io::Result<Option<u64>> {
+        Ok(self.read_checked::<8>()?.map(u64::from_le_bytes))
     }
 
-    fn fill_buffer(&mut self) -> BufferState {
+    fn fill_buffer(&mut self) -> io::Result<BufferState> {
+        crate::throttle::throttle();
         self.consume(self.capacity());
-        self.fill_buf().unwrap();
+        self.fill_buf()?;
         if self.buffer().is_empty() {
-            return BufferState::Empty;
+            return Ok(BufferState::Empty);
         }
-        BufferState::NotEmpty
+        Ok(BufferState::NotEmpty)
+    }
+}
+
+// An iterator over the blocks of an input, each size bytes except the 
+// last, which holds whatever remains. Blocks are owned, so they can be 
+// kept or passed on after the next one is read. A read that fails ends
+// the iteration with its error.
+//
+// for block in Blocks::new(&mut file_in, 1 << 20) {
+//     let block = block?;
+//     ...
+// }
+pub struct Blocks<R: Read> {
+    reader: R,
+    size:   usize,
+    done:   bool,
+}
+
+impl<R: Read> Blocks<R> {
+    pub fn new(reader: R, size: usize) -> Blocks<R> {
+        assert!(size > 0);
+        Blocks { reader, size, done: false }
     }
 }
 
-fn force_truncate<Src, Dst>(a: Src) -> Dst {
-    assert!(mem::size_of::<Src>() > mem::size_of::<Dst>());
-    unsafe {
-        mem::transmute_copy::<Src, Dst>(&a)
+impl<R: Read> Iterator for Blocks<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        crate::throttle::throttle();
+        let mut block = Vec::with_capacity(self.size);
+        if let Err(e) = self.reader.by_ref().take(self.size as u64).read_to_end(&mut block) {
+            self.done = true;
+            return Some(Err(e));
+        }
+        if block.len() < self.size {
+            self.done = true;
+        }
+        if block.is_empty() { None } else { Some(Ok(block)) }
     }
 }
 
-// Convenience functions for buffered writing
+// Convenience functions for buffered writing, each returning any error
+// writing the output.
 //
 // write_* functions are ideal, but only work with specific types.
 //
-// write_*_checked functions are for fallible conversions.
+// write_*_checked functions are for fallible conversions, and return an
+// InvalidInput error if the value doesn't fit.
 //
 // write_*_forced is mostly equivalent to 'as', and should only be used 
 // in situations where write_* doesn't work but the input is guaranteed to
-// fit in the smaller type.
+// fit in the smaller type. The value is truncated arithmetically (keeping
+// the low bytes), so the result doesn't depend on host endianness.
 //
 // Examples:
 //
 // let x: u64 = 150;
 //
-// file.write_u8(x);                  Compile time error: u8 does not implement From<u64>
-// file.write_u8_checked(x).unwrap(); No error: x < 255
-// file.write_u8_forced(x);           x is interpreted as u8 and byte 150 is written to file
+// file.write_u8(x)?;         Compile time error: u8 does not implement From<u64>
+// file.write_u8_checked(x)?; No error: x < 255
+// file.write_u8_forced(x)?;  x is interpreted as u8 and byte 150 is written to file
 //
 // let y: u64 = 500;
 //
-// file.write_u8(y);                  Compile time error: u8 does not implement From<u64>
-// file.write_u8_checked(y).unwrap(); Runtime error: y > 255
-// file.write_u8_forced(y);           y is interpreted as u8 and byte 244(!) is written to file
+// file.write_u8(y)?;         Compile time error: u8 does not implement From<u64>
+// file.write_u8_checked(y)?; Runtime error: y > 255
+// file.write_u8_forced(y)?;  y is interpreted as u8 and byte 244(!) is written to file
 pub trait BufferedWrite {
-    fn write_<const N: usize>(&mut self, output: [u8; N]);
-    fn write_u8<T: Into<u8>>(&mut self, output: T);
-    fn write_u16<T: Into<u16>>(&mut self, output: T);
-    fn write_u32<T: Into<u32>>(&mut self, output: T);
-    fn write_u64<T: Into<u64>>(&mut self, output: T);
-    fn write_u8_checked<T: TryInto<u8>>(&mut self, output: T) -> Result<(), <T as TryInto<u8>>::Error>;
-    fn write_u16_checked<T: TryInto<u16>>(&mut self, output: T) -> Result<(), <T as TryInto<u16>>::Error>;
-    fn write_u32_checked<T: TryInto<u32>>(&mut self, output: T) -> Result<(), <T as TryInto<u32>>::Error>;
-    fn write_u64_checked<T: TryInto<u64>>(&mut self, output: T) -> Result<(), <T as TryInto<u64>>::Error>;
-    fn write_u8_forced<T>(&mut self, output: T);
-    fn write_u16_forced<T>(&mut self, output: T);
-    fn write_u32_forced<T>(&mut self, output: T);
-    fn flush_buffer(&mut self);
-}
-
-impl BufferedWrite for BufWriter<File> {
-    fn write_<const N: usize>(&mut self, output: [u8; N]) {
-        self.write(&output[..]).unwrap();
-        
-        if self.buffer().len() >= self.capacity() {
-            self.flush().unwrap();
-        }
+    // Write all of bytes, however many writes it takes.
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()>;
+    fn flush_buffer(&mut self) -> io::Result<()>;
+
+    fn write_<const N: usize>(&mut self, output: [u8; N]) -> io::Result<()> {
+        self.write_bytes(&output[..])
     }
 
-    fn write_u8<T: Into<u8>>(&mut self, output: T) {
-        self.write_(output.into().to_le_bytes());
+    fn write_u8<T: Into<u8>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.into().to_le_bytes())
     }
 
-    fn write_u16<T: Into<u16>>(&mut self, output: T) {
-        self.write_(output.into().to_le_bytes());
+    fn write_u16<T: Into<u16>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.into().to_le_bytes())
     }
 
-    fn write_u32<T: Into<u32>>(&mut self, output: T) {
-        self.write_(output.into().to_le_bytes());
+    fn write_u32<T: Into<u32>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.into().to_le_bytes())
     }
 
-    fn write_u64<T: Into<u64>>(&mut self, output: T) {
-        self.write_(output.into().to_le_bytes());
+    fn write_u64<T: Into<u64>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.into().to_le_bytes())
     }
 
-    fn write_u8_checked<T: TryInto<u8>>(&mut self, output: T) -> Result<(), <T as TryInto<u8>>::Error> {
-        self.write_(output.try_into()?.to_le_bytes());
-        Ok(())
+    fn write_u8_checked<T: TryInto<u8>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.try_into().map_err(|_| out_of_range())?.to_le_bytes())
     }
 
-    fn write_u16_checked<T: TryInto<u16>>(&mut self, output: T) -> Result<(), <T as TryInto<u16>>::Error> {
-        self.write_(output.try_into()?.to_le_bytes());
-        Ok(())
+    fn write_u16_checked<T: TryInto<u16>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.try_into().map_err(|_| out_of_range())?.to_le_bytes())
+    }
+
+    fn write_u32_checked<T: TryInto<u32>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.try_into().map_err(|_| out_of_range())?.to_le_bytes())
+    }
+
+    fn write_u64_checked<T: TryInto<u64>>(&mut self, output: T) -> io::Result<()> {
+        self.write_(output.try_into().map_err(|_| out_of_range())?.to_le_bytes())
+    }
+
+    fn write_u8_forced<T: Into<u64>>(&mut self, output: T) -> io::Result<()> {
+        self.write_((output.into() as u8).to_le_bytes())
     }
 
-    fn write_u32_checked<T: TryInto<u32>>(&mut self, output: T) -> Result<(), <T as TryInto<u32>>::Error> {
-        self.write_(output.try_into()?.to_le_bytes());
+    fn write_u16_forced<T: Into<u64>>(&mut self, output: T) -> io::Result<()> {
+        self.write_((output.into() as u16).to_le_bytes())
+    }
+
+    fn write_u32_forced<T: Into<u64>>(&mut self, output: T) -> io::Result<()> {
+        self.write_((output.into() as u32).to_le_bytes())
+    }
+}
+
+fn out_of_range() -> io::Error {
+    io::Error::new(ErrorKind::InvalidInput, "value too large for the field it's written to")
+}
+
+// BufWriter flushes itself when a write doesn't fit in its buffer, and 
+// passes writes larger than the buffer straight through, so there's 
+// nothing to do here beyond retrying short writes.
+impl<W: Write> BufferedWrite for BufWriter<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
+
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+// A writer that discards all output, keeping track of how many bytes 
+// would have been written. Seeking is supported so that codecs which 
+// rewind to patch a header can be measured too; the size is the furthest 
+// position ever written.
+#[derive(Default)]
+pub struct NullSink {
+    pos:  u64,
+    size: u64,
+}
+
+impl NullSink {
+    pub fn new() -> NullSink {
+        NullSink::default()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Write for NullSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.pos += buf.len() as u64;
+        self.size = self.size.max(self.pos);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
+
+impl Seek for NullSink {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_to(pos, self.pos, self.size)?;
+        Ok(self.pos)
+    }
+}
+
+// The position pos seeks to, from current in a stream of len bytes, for
+// streams that keep their own position.
+pub(crate) fn seek_to(pos: SeekFrom, current: u64, len: u64) -> io::Result<u64> {
+    let new_pos = match pos {
+        SeekFrom::Start(offset)   => Some(offset),
+        SeekFrom::End(offset)     => len.checked_add_signed(offset),
+        SeekFrom::Current(offset) => current.checked_add_signed(offset),
+    };
+    new_pos.ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid seek to a negative position"))
+}
+
+impl BufferedWrite for NullSink {
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
 
-    fn write_u64_checked<T: TryInto<u64>>(&mut self, output: T) -> Result<(), <T as TryInto<u64>>::Error> {
-        self.write_(output.try_into()?.to_le_bytes());
+    fn flush_buffer(&mut self) -> io::Result<()> {
         Ok(())
     }
+}
+
+// A writer that passes output through to another, counting the bytes 
+// written. Useful for measuring output size without asking the 
+// destination, which may not be a file.
+pub struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner, count: 0 }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.count += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> BufferedWrite for CountingWriter<W> {
+    fn write_bytes(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write_all(bytes)
+    }
 
-    fn write_u8_forced<T>(&mut self, output: T) {
-        self.write_(force_truncate::<T, u8>(output).to_le_bytes());
+    fn flush_buffer(&mut self) -> io::Result<()> {
+        self.flush()
     }
+}
+
+// A reader or writer that passes everything through to another, keeping
+// track of the furthest position reached, so the bytes read and written
+// by a codec can be reported without asking the file, which may not be
+// one.
+pub struct Tracked<T> {
+    inner:  T,
+    pos:    u64,
+    size:   u64,
+    origin: Option<u64>, // Position of inner when tracking started, found on the first seek
+}
 
-    fn write_u16_forced<T>(&mut self, output: T) {
-        self.write_(force_truncate::<T, u16>(output).to_le_bytes());
+impl<T> Tracked<T> {
+    pub fn new(inner: T) -> Tracked<T> {
+        Tracked { inner, pos: 0, size: 0, origin: None }
     }
 
-    fn write_u32_forced<T>(&mut self, output: T) {
-        self.write_(force_truncate::<T, u32>(output).to_le_bytes());
+    // The furthest position read or written.
+    pub fn size(&self) -> u64 {
+        self.size
     }
 
-    fn flush_buffer(&mut self) {
-        self.flush().unwrap();
+    fn advance(&mut self, len: usize) {
+        self.pos += len as u64;
+        self.size = self.size.max(self.pos);
     }
 }
+
+impl<R: Read> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.advance(len);
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Tracked<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.advance(len);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Positions are relative to where inner was when tracking started, so
+// a codec that seeks back to 0 finds the start of its own stream, even
+// if something was written or read before it.
+impl<S: Seek> Seek for Tracked<S> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let origin = match self.origin {
+            Some(origin) => origin,
+            None => {
+                let inner_pos = self.inner.stream_position()?;
+                *self.origin.insert(inner_pos.saturating_sub(self.pos))
+            }
+        };
+        let pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(origin + offset),
+            pos => pos,
+        };
+        self.pos = self.inner.seek(pos)?.saturating_sub(origin);
+        Ok(self.pos)
+    }
+}
+
+// Run codec over file_in and file_out, each wrapped in a Tracked, and
+// return the number of bytes read and written, or the codec's error.
+// The new buffers have the same capacity as the old, which pass reads
+// and writes of that size straight through, so nothing is copied twice.
+//
+// codec has to be a closure: a generic fn can't take buffers borrowing
+// the Trackeds here for any lifetime, though clippy suggests passing one.
+pub(crate) fn tracked<R, W, F>(file_in: BufReader<R>, file_out: BufWriter<W>, codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(BufReader<&mut Tracked<BufReader<R>>>, BufWriter<&mut Tracked<BufWriter<W>>>) -> io::Result<()>,
+{
+    let (in_capacity, out_capacity) = (file_in.capacity(), file_out.capacity());
+    let mut input = Tracked::new(file_in);
+    let mut output = Tracked::new(file_out);
+    codec(
+        BufReader::with_capacity(in_capacity, &mut input), 
+        BufWriter::with_capacity(out_capacity, &mut output)
+    )?;
+    output.flush()?;
+    Ok(Sizes { input: input.size(), output: output.size() })
+}
+
+// A watchdog over a decoder's input and output, for damaged streams
+// that would keep a decoder going forever: one that writes more than the
+// stream decompresses to, such as a match copying the same bytes over
+// and over, or that keeps reading at the end of its input without
+// writing anything. Either makes the read or write fail with an
+// InvalidData error, which the decoder returns like any other, so it
+// stops at its next read or write without checking for either itself.
+
+// Reads at the end of the input, with nothing written since, before the
+// decoder is taken to be stuck. Valid streams read at most a few bytes
+// past their end, and a decoder writing to a 1 MB buffer reads about
+// that much between flushes at worst.
+const MAX_IDLE_READS: u64 = 1 << 24;
+
+// Progress shared between the reader and writer of one decoder, which may
+// be on different threads.
+#[derive(Default)]
+struct Progress {
+    written:    AtomicU64,
+    idle_reads: AtomicU64,  // Reads at the end of the input since the last write
+}
+
+pub struct Watched<T> {
+    inner:    T,
+    progress: Arc<Progress>,
+    limit:    u64,       // Bytes the stream decompresses to
+}
+
+impl<R: Read> Read for Watched<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if len == 0 && !buf.is_empty()
+            && self.progress.idle_reads.fetch_add(1, Ordering::Relaxed) >= MAX_IDLE_READS {
+            return Err(io::Error::new(ErrorKind::InvalidData, "decoder stalled at the end of its input"));
+        }
+        Ok(len)
+    }
+}
+
+impl<W: Write> Write for Watched<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.progress.written.load(Ordering::Relaxed);
+        if written.saturating_add(buf.len() as u64) > self.limit {
+            return Err(io::Error::new(ErrorKind::InvalidData, 
+                format!("stream decodes to more than the {} bytes expected", self.limit)));
+        }
+        let len = self.inner.write(buf)?;
+        self.progress.written.fetch_add(len as u64, Ordering::Relaxed);
+        self.progress.idle_reads.store(0, Ordering::Relaxed);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Run codec, a decoder expected to write limit bytes, or u64::MAX if
+// that isn't known, over file_in and file_out, each wrapped in a
+// Watched. An InvalidData error from the watchdog comes back from codec.
+pub(crate) fn watchdog<R, W, T, F>(file_in: R, file_out: W, limit: u64, codec: F) -> error::Result<T>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(Watched<R>, Watched<W>) -> error::Result<T>,
+{
+    let progress = Arc::new(Progress::default());
+    let file_in = Watched { inner: file_in, progress: Arc::clone(&progress), limit };
+    let file_out = Watched { inner: file_out, progress, limit };
+    codec(file_in, file_out)
+}
@@ -1,90 +1,239 @@
+use std::io;
 use std::io::Write;
+use std::io::ErrorKind;
+use std::io::Read;
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::fs::File;
 use std::cmp::Ordering;
 use std::cmp::min;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::bwt::suffix_array::suffix_array;
+
+// Output format:
+//
+//   block size (u64)
+//   blocks     (u64 block header, then the transformed bytes)
+//
+// A block header is normally the primary index, and the block is as 
+// long as the block size, except for the last, which may be shorter. 
+//
+// If the top bit of the header is set, the block is a sentinel block, 
+// and the rest of the header is its length. Before the transform, ESC
+// and SENTINEL in the input are escaped as (ESC, 0) and (ESC, 1), then 
+// a single SENTINEL is appended. Because it's unique, its position in 
+// the transformed block marks the original rotation, so no primary 
+// index is needed, and rotations sort the same as suffixes.
+const SENTINEL_BLOCK: u64 = 1 << 63;
+const ESC: u8 = 0xFE;
+const SENTINEL: u8 = 0xFF;
+
+pub const DEFAULT_BLOCK_SIZE: usize = 1 << 20;
+pub const MIN_BLOCK_SIZE: usize = 1 << 12;
+
+// Bytes of memory per byte of block. Sorting takes the block, its
+// indices and the sort's buffer. Sentinel blocks are up to twice as long
+// after escaping, and the suffix array construction works on usize
+// symbols. Inverting takes the block, the transform vector and output.
+const MEMORY_PER_BYTE: u64 = 12;
+const SENTINEL_MEMORY_PER_BYTE: u64 = 96;
+const INVERSE_MEMORY_PER_BYTE: u64 = 8;
+const FIXED_MEMORY: u64 = 4 << 20; // I/O buffers
+
+/// Approximate memory needed to transform blocks of block_size bytes.
+pub fn memory(block_size: u64, sentinel: bool) -> u64 {
+    let per_byte = if sentinel { SENTINEL_MEMORY_PER_BYTE } else { MEMORY_PER_BYTE };
+    block_size.saturating_mul(per_byte).saturating_add(FIXED_MEMORY)
+}
+
+/// Approximate memory needed to invert blocks of block_size bytes. A 
+/// sentinel block holds at most twice the block size once escaped, plus
+/// the sentinel.
+pub fn inverse_memory(block_size: u64) -> u64 {
+    let len = block_size.saturating_mul(2).saturating_add(1);
+    len.saturating_mul(INVERSE_MEMORY_PER_BYTE).saturating_add(FIXED_MEMORY)
+}
+
+/// The largest power of two block size up to DEFAULT_BLOCK_SIZE that
+/// can be transformed and inverted in max_memory bytes, or None if even
+/// MIN_BLOCK_SIZE can't.
+pub fn block_size_for(max_memory: u64, sentinel: bool) -> Option<usize> {
+    (MIN_BLOCK_SIZE.trailing_zeros()..=DEFAULT_BLOCK_SIZE.trailing_zeros()).rev()
+        .map(|bits| 1 << bits)
+        .find(|&size| memory(size as u64, sentinel).max(inverse_memory(size as u64)) <= max_memory)
+}
+
+/// The largest power of two block size up to blocks::auto_block_size
+/// that fits in max_memory, and whether to use sentinel blocks, for
+/// size bytes of input with the given repetitiveness. Long repeats make
+/// the rotation sort take time quadratic in the block size, so input
+/// that repeats gets sentinel blocks, which are sorted with a suffix
+/// array. Returns None if even MIN_BLOCK_SIZE doesn't fit.
+pub fn auto_block(size: u64, repetitiveness: f64, max_memory: u64) -> Option<(usize, bool)> {
+    let sentinel = repetitiveness >= SENTINEL_REPETITIVENESS;
+    let target = crate::blocks::auto_block_size(size, repetitiveness);
+    (MIN_BLOCK_SIZE.trailing_zeros()..=target.trailing_zeros()).rev()
+        .map(|bits| 1 << bits)
+        .find(|&size| memory(size as u64, sentinel).max(inverse_memory(size as u64)) <= max_memory)
+        .map(|size| (size, sentinel))
+}
+
+// Repetitiveness from which auto_block uses sentinel blocks. A 1 MB
+// block of text that's 10% repeats still sorts in well under a second.
+const SENTINEL_REPETITIVENESS: f64 = 0.1;
+
+pub fn bwt_transform<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, sentinel: bool, block_size: usize) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| transform(file_in, file_out, sentinel, block_size))
+}
 
-pub fn bwt_transform(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    file_out.write_u64(file_in.capacity() as u64);
+fn transform<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, sentinel: bool, block_size: usize) -> io::Result<()> {
+    file_out.write_u64(block_size as u64)?;
 
-    loop {
-        if file_in.fill_buffer().is_eof() { 
-            break; 
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+
+        if sentinel {
+            sentinel_transform(&block, &mut file_out)?;
+            continue;
         }
 
-        let len = file_in.buffer().len();
+        let len = block.len();
 
         let mut indices = (0..len as u32).collect::<Vec<u32>>();
 
         indices.sort_by(|a, b| {
-            block_cmp(*a as usize, *b as usize, file_in.buffer())
+            block_cmp(*a as usize, *b as usize, &block)
         });
 
-        let mut primary_index = None;
+        // The primary index is the position of the rotation starting at 
+        // index 1, which wraps around to 0 for a single byte block.
+        let primary = 1 % len as u32;
+        let primary_index = indices.iter().position(|&idx| idx == primary).unwrap();
+        file_out.write_u64(primary_index as u64)?;
 
-        let bwt = indices.iter().enumerate().map(|(i, &idx)| {
-            match idx {
-                0 => {
-                    file_in.buffer()[len - 1]
-                }
+        // Write the last byte of each rotation straight from the sorted 
+        // indices, rather than collecting the transformed block first.
+        for &idx in indices.iter() {
+            file_out.write_u8(block[(idx as usize + len - 1) % len])?;
+        }
+    }  
+    file_out.flush_buffer()
+}
 
-                1 => {
-                    primary_index = Some(i);
-                    file_in.buffer()[idx as usize - 1]
-                }
+fn sentinel_transform<W: Write>(input: &[u8], file_out: &mut BufWriter<W>) -> io::Result<()> {
+    let mut block = Vec::with_capacity(input.len() + 1);
+    for &byte in input.iter() {
+        match byte {
+            ESC      => { block.extend([ESC, 0]); }
+            SENTINEL => { block.extend([ESC, 1]); }
+            _        => { block.push(byte); }
+        }
+    }
+    block.push(SENTINEL);
 
-                _ => {
-                    file_in.buffer()[idx as usize - 1]
-                }
-            }  
-        })
-        .collect::<Vec<u8>>();
-    
-        file_out.write_u64(primary_index.unwrap() as u64);
-        file_out.write_all(&bwt).unwrap();
-    }  
-    file_out.flush_buffer();
+    let len = block.len();
+    let indices = suffix_array(&block);
+
+    file_out.write_u64(SENTINEL_BLOCK | len as u64)?;
+    for &idx in indices.iter() {
+        file_out.write_u8(block[(idx as usize + len - 1) % len])?;
+    }
+    Ok(())
 }
 
-pub fn bwt_inverse_transform(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let mut transform = vec![0u32; file_in.capacity()];
+// Invert the transform, returning an InvalidData error if the stream
+// is truncated or a block header doesn't match its block, and an
+// OutOfMemory error if its block size needs more than max_memory bytes.
+pub fn bwt_inverse_transform<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| inverse_transform(file_in, file_out, max_memory))
+}
 
-    loop {
-        if file_in.fill_buffer().is_eof() { 
-            break; 
+fn inverse_transform<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, max_memory: Option<u64>) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+    let block_size = file_in.read_u64_checked()?.ok_or_else(|| invalid("truncated bwt stream"))?;
+    let need = inverse_memory(block_size);
+    if max_memory.is_some_and(|max| need > max) {
+        return Err(io::Error::new(ErrorKind::OutOfMemory,
+            format!("bwt stream needs {} bytes of memory, more than --max-memory", need)));
+    }
+    let mut block = Vec::new();
+    let mut transform = Vec::new();
+
+    while let Some(header) = file_in.read_u64_checked()? {
+        block.clear();
+        if header & SENTINEL_BLOCK != 0 {
+            let len = header & !SENTINEL_BLOCK;
+            if len > block_size.saturating_mul(2).saturating_add(1) {
+                return Err(invalid("invalid bwt block length"));
+            }
+            file_in.by_ref().take(len).read_to_end(&mut block)?;
+            if block.len() as u64 != len {
+                return Err(invalid("truncated bwt stream"));
+            }
+
+            // The row ending in the sentinel is the rotation starting at 
+            // 0, so starting there outputs the sentinel, then the input.
+            let row = block.iter().position(|&byte| byte == SENTINEL)
+                .ok_or_else(|| invalid("bwt sentinel block has no sentinel"))?;
+            let output = inverse(&block, row, &mut transform);
+
+            let mut bytes = output[1..].iter();
+            while let Some(&byte) = bytes.next() {
+                if byte == ESC {
+                    match bytes.next() {
+                        Some(&escaped) if escaped <= 1 => file_out.write_u8(ESC + escaped)?,
+                        _ => return Err(invalid("invalid bwt escape")),
+                    }
+                }
+                else {
+                    file_out.write_u8(byte)?;
+                }
+            }
         }
-    
-        let mut index = file_in.read_u64() as usize;
+        else {
+            file_in.by_ref().take(block_size).read_to_end(&mut block)?;
+            if header >= block.len() as u64 {
+                return Err(invalid("invalid bwt primary index"));
+            }
+            for byte in inverse(&block, header as usize, &mut transform) {
+                file_out.write_u8(byte)?;
+            }
+        }
+    }
+    file_out.flush()
+}
 
-        let mut count = [0u32; 256];
-        let mut cumul = [0u32; 256];
+// Invert a transformed block, starting from row index.
+fn inverse(block: &[u8], mut index: usize, transform: &mut Vec<u32>) -> Vec<u8> {
+    let mut count = [0u32; 256];
+    let mut cumul = [0u32; 256];
 
-        for byte in file_in.buffer().iter() {
-            count[*byte as usize] += 1;    
-        }
+    for byte in block.iter() {
+        count[*byte as usize] += 1;    
+    }
 
-        let mut sum = 0;
-        for i in 0..256 {
-            cumul[i] = sum;
-            sum += count[i];
-            count[i] = 0;
-        }
+    let mut sum = 0;
+    for i in 0..256 {
+        cumul[i] = sum;
+        sum += count[i];
+        count[i] = 0;
+    }
 
-        for (i, byte) in file_in.buffer().iter().enumerate() {
-            let byte = *byte as usize;
-            transform[(count[byte] + cumul[byte]) as usize] = i as u32;
-            count[byte] += 1;
-        }
+    transform.resize(block.len(), 0);
+    for (i, byte) in block.iter().enumerate() {
+        let byte = *byte as usize;
+        transform[(count[byte] + cumul[byte]) as usize] = i as u32;
+        count[byte] += 1;
+    }
 
-        for _ in 0..file_in.buffer().len() { 
-            file_out.write_u8(file_in.buffer()[index]);
-            index = transform[index] as usize;
-        }
+    let mut output = Vec::with_capacity(block.len());
+    for _ in 0..block.len() { 
+        output.push(block[index]);
+        index = transform[index] as usize;
     }
-    file_out.flush().unwrap();
+    output
 }
 
 fn block_cmp(a: usize, b: usize, block: &[u8]) -> Ordering {
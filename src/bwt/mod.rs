@@ -1 +1,4 @@
-pub mod bwt;
\ No newline at end of file
+#[allow(clippy::module_inception)]
+pub mod bwt;
+pub mod mtf;
+pub mod suffix_array;
@@ -0,0 +1,83 @@
+use std::io;
+use std::io::Write;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+
+// Move-to-front coding with zero run lengths, the usual stage between a
+// BWT and an order 0 entropy coder. Each byte is replaced by its rank in
+// a list of all 256 byte values, most recently seen first, so the runs
+// of alike bytes a BWT leaves become runs of 0. A run of up to MAX_RUN
+// zero ranks is coded as (0, run length - 1), other ranks as themselves.
+const MAX_RUN: usize = 256;
+
+/// Move-to-front and zero run length code file_in.
+#[allow(clippy::redundant_closure)]
+pub fn mtf_encode<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| encode(file_in, file_out))
+}
+
+fn encode<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+    let mut run = 0;
+
+    let block_size = file_in.capacity();
+    for block in Blocks::new(&mut file_in, block_size) {
+        for &byte in block?.iter() {
+            let rank = order.iter().position(|&b| b == byte).unwrap();
+            if rank == 0 {
+                run += 1;
+                if run == MAX_RUN {
+                    file_out.write_bytes(&[0, (run - 1) as u8])?;
+                    run = 0;
+                }
+                continue;
+            }
+            if run > 0 {
+                file_out.write_bytes(&[0, (run - 1) as u8])?;
+                run = 0;
+            }
+            file_out.write_u8(rank as u8)?;
+            order.copy_within(0..rank, 1);
+            order[0] = byte;
+        }
+    }
+    if run > 0 {
+        file_out.write_bytes(&[0, (run - 1) as u8])?;
+    }
+    file_out.flush_buffer()?;
+    Ok(())
+}
+
+/// Reproduce the input of mtf_encode, returning an InvalidData error if
+/// the stream ends inside a run.
+#[allow(clippy::redundant_closure)]
+pub fn mtf_decode<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decode(file_in, file_out))
+}
+
+fn decode<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut order: [u8; 256] = std::array::from_fn(|i| i as u8);
+
+    while let Some(rank) = file_in.read_u8_checked()? {
+        let rank = rank as usize;
+        if rank == 0 {
+            let run = file_in.read_u8_checked()?.ok_or_else(||
+                io::Error::new(ErrorKind::InvalidData, "truncated move-to-front run")
+            )?;
+            file_out.write_bytes(&[order[0]; MAX_RUN][..run as usize + 1])?;
+            continue;
+        }
+        let byte = order[rank];
+        file_out.write_u8(byte)?;
+        order.copy_within(0..rank, 1);
+        order[0] = byte;
+    }
+    file_out.flush_buffer()?;
+    Ok(())
+}
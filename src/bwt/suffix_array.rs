@@ -0,0 +1,166 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+
+// Suffix array construction by induced sorting (SA-IS), from
+// G. Nong, S. Zhang and W. H. Chan, "Two Efficient Algorithms for
+// Linear Time Suffix Array Construction", 2011. Runs in O(n) time.
+
+/// Return the start positions of the suffixes of data in sorted order.
+/// A suffix that is a prefix of another sorts first.
+pub fn suffix_array(data: &[u8]) -> Vec<u32> {
+    assert!(data.len() <= u32::MAX as usize, "input too large for a 32 bit suffix array");
+    let s = data.iter().map(|&byte| byte as usize).collect::<Vec<usize>>();
+    sa_is(&s, 255).into_iter().map(|i| i as u32).collect()
+}
+
+/// Write the suffix array of the input as little endian u32s.
+#[allow(clippy::redundant_closure)]
+pub fn write_suffix_array<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| write(file_in, file_out))
+}
+
+fn write<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut data = Vec::new();
+    file_in.read_to_end(&mut data)?;
+    for i in suffix_array(&data) {
+        file_out.write_u32(i)?;
+    }
+    file_out.flush_buffer()
+}
+
+// Suffix array of s, where every symbol is at most upper.
+fn sa_is(s: &[usize], upper: usize) -> Vec<usize> {
+    let n = s.len();
+    match n {
+        0 => return vec![],
+        1 => return vec![0],
+        2 => return if s[0] < s[1] { vec![0, 1] } else { vec![1, 0] },
+        _ => {}
+    }
+
+    // Classify suffixes as S-type (smaller than the next suffix) or
+    // L-type (larger). The last suffix is L-type.
+    let mut ls = vec![false; n];
+    for i in (0..n - 1).rev() {
+        ls[i] = if s[i] == s[i + 1] { ls[i + 1] } else { s[i] < s[i + 1] };
+    }
+
+    // Bucket boundaries: sum_l[c] is the start of the L-type suffixes
+    // beginning with c, sum_s[c] the start of the S-type ones.
+    let mut sum_l = vec![0; upper + 1];
+    let mut sum_s = vec![0; upper + 1];
+    for i in 0..n {
+        if ls[i] {
+            sum_l[s[i] + 1] += 1;
+        }
+        else {
+            sum_s[s[i]] += 1;
+        }
+    }
+    for i in 0..=upper {
+        sum_s[i] += sum_l[i];
+        if i < upper {
+            sum_l[i + 1] += sum_s[i];
+        }
+    }
+
+    // Entries of sa are positions + 1, with 0 meaning empty.
+    let mut sa = vec![0; n];
+
+    // Sort all suffixes from the order of the given LMS suffixes.
+    let induce = |sa: &mut [usize], lms: &[usize]| {
+        sa.fill(0);
+        let mut buf = sum_s.clone();
+        for &d in lms.iter() {
+            if d == n {
+                continue;
+            }
+            sa[buf[s[d]]] = d + 1;
+            buf[s[d]] += 1;
+        }
+
+        // L-type suffixes, left to right.
+        buf.copy_from_slice(&sum_l);
+        sa[buf[s[n - 1]]] = n;
+        buf[s[n - 1]] += 1;
+        for i in 0..n {
+            let v = sa[i];
+            if v >= 2 && !ls[v - 2] {
+                sa[buf[s[v - 2]]] = v - 1;
+                buf[s[v - 2]] += 1;
+            }
+        }
+
+        // S-type suffixes, right to left.
+        buf.copy_from_slice(&sum_l);
+        for i in (0..n).rev() {
+            let v = sa[i];
+            if v >= 2 && ls[v - 2] {
+                buf[s[v - 2] + 1] -= 1;
+                sa[buf[s[v - 2] + 1]] = v - 1;
+            }
+        }
+    };
+
+    // Leftmost S-type (LMS) positions, and their rank + 1 by position.
+    let lms = (1..n).filter(|&i| !ls[i - 1] && ls[i]).collect::<Vec<usize>>();
+    let m = lms.len();
+    let mut lms_map = vec![0; n + 1];
+    for (j, &i) in lms.iter().enumerate() {
+        lms_map[i] = j + 1;
+    }
+
+    induce(&mut sa, &lms);
+
+    if m > 0 {
+        // LMS substrings in sorted order.
+        let mut sorted_lms = sa.iter()
+            .map(|&v| v - 1)
+            .filter(|&i| lms_map[i] != 0)
+            .collect::<Vec<usize>>();
+
+        // Name each LMS substring by its rank, giving equal substrings
+        // the same name.
+        let mut rec_s = vec![0; m];
+        let mut rec_upper = 0;
+        for i in 1..m {
+            let mut l = sorted_lms[i - 1];
+            let mut r = sorted_lms[i];
+            let end_l = if lms_map[l] < m { lms[lms_map[l]] } else { n };
+            let end_r = if lms_map[r] < m { lms[lms_map[r]] } else { n };
+            let same = if end_l - l != end_r - r {
+                false
+            }
+            else {
+                while l < end_l && s[l] == s[r] {
+                    l += 1;
+                    r += 1;
+                }
+                l != n && s[l] == s[r]
+            };
+            if !same {
+                rec_upper += 1;
+            }
+            rec_s[lms_map[sorted_lms[i]] - 1] = rec_upper;
+        }
+
+        // Sort the LMS suffixes by sorting the string of names.
+        let rec_sa = sa_is(&rec_s, rec_upper);
+        for (i, &j) in rec_sa.iter().enumerate() {
+            sorted_lms[i] = lms[j];
+        }
+        induce(&mut sa, &sorted_lms);
+    }
+
+    for v in sa.iter_mut() {
+        *v -= 1;
+    }
+    sa
+}
@@ -0,0 +1,123 @@
+// Compress and decompress slices in memory, with each algorithm's
+// default options, as the command line uses them. The buffers are the
+// same size as the command line's, so the output is the same too, as
+// with --no-mtime, since there are no file times to record.
+
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+
+use crate::blocks;
+use crate::container;
+use crate::error;
+use crate::stats::Sizes;
+use crate::lz::{lz77, lzw, flzp};
+use crate::ari::{sr, fpaq0, fpaq, lpaq1, ppm, rans, fse};
+use crate::huffman::{encoder, decoder};
+use crate::bwt::bwt;
+use crate::remap;
+use crate::split;
+use crate::segment;
+use crate::sortrans;
+
+pub use crate::container::Algorithm;
+
+const BUF_SIZE: usize = 1 << 20;
+
+/// Compress input with an algorithm's default options.
+pub fn compress_bytes(input: &[u8], algorithm: Algorithm) -> Vec<u8> {
+    let size = input.len() as u64;
+    run(input, |file_in, file_out| container::compress(file_in, file_out, algorithm, size, container::Times::default(), false,
+        |file_in, file_out| encode(algorithm, file_in, file_out)))
+    .expect("memory can't fail to read or write")
+}
+
+// Compress with an algorithm's default options, without a header.
+pub(crate) fn encode<R: Read + Seek, W: Write + Seek>(algorithm: Algorithm,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    match algorithm {
+        Algorithm::Lz77     => lz77::lz77_compress(file_in, file_out),
+        Algorithm::Lzw      => lzw::lzw_compress(file_in, file_out),
+        Algorithm::Flzp     => flzp::flzp_compress(file_in, file_out,
+            flzp::Effort::Greedy, false, flzp::DEFAULT_CXT_LEN, false),
+        Algorithm::Sr       => sr::sr_compress(file_in, file_out),
+        Algorithm::Fpaq0    => fpaq0::fpaq0_compress(file_in, file_out),
+        Algorithm::Fpaq     => fpaq::fpaq_compress(file_in, file_out, &fpaq::SseProfile::default()),
+        Algorithm::Lpaq1    => lpaq1::lpaq1_compress(file_in, file_out,
+            lpaq1::ModelSet::default(), false, lpaq1::DEFAULT_LEVEL, false),
+        Algorithm::Ppm      => ppm::ppm_compress(file_in, file_out, ppm::DEFAULT_ORDER, ppm::DEFAULT_MODEL),
+        Algorithm::Rans     => rans::rans_compress(file_in, file_out, false),
+        Algorithm::Fse      => fse::fse_compress(file_in, file_out),
+        Algorithm::Huffman  => encoder::compress(file_in, file_out),
+        Algorithm::Bwt      => bwt::bwt_transform(file_in, file_out, false, bwt::DEFAULT_BLOCK_SIZE),
+        Algorithm::Remap    => remap::remap(file_in, file_out),
+        Algorithm::Split    => split::split(file_in, file_out, false),
+        Algorithm::Segment  => segment::segment_compress(file_in, file_out, lpaq1::DEFAULT_LEVEL, false),
+        Algorithm::Sortrans => sortrans::sortrans_compress(file_in, file_out, sortrans::Profile::Max,
+            false, bwt::DEFAULT_BLOCK_SIZE, lpaq1::DEFAULT_LEVEL, false),
+    }
+}
+
+/// Decompress input from compress_bytes, or from the command line with
+/// any options, returning an InvalidData error if it's damaged or was
+/// compressed with another algorithm, and an OutOfMemory error if lpaq1
+/// or bwt would need more than max_memory. A stream compressed with
+/// --threads decompresses on one thread per CPU unless max_memory is
+/// given.
+pub fn decompress_bytes(input: &[u8], algorithm: Algorithm, max_memory: Option<u64>) -> error::Result<Vec<u8>> {
+    run(input, |file_in, file_out| container::decompress(file_in, file_out, algorithm, |header, file_in, file_out| {
+        decode_stream(algorithm, max_memory, header, file_in, file_out)
+    }))
+}
+
+// Decompress the stream after header, in blocks if it was compressed in
+// them.
+pub(crate) fn decode_stream<R: Read, W: Write>(algorithm: Algorithm, max_memory: Option<u64>, header: &container::Header,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    if !header.blocks {
+        return decode(algorithm, max_memory, file_in, file_out);
+    }
+    let max_memory = max_memory.map(|max| max.saturating_sub(blocks::memory(blocks::DEFAULT_BLOCK_SIZE)));
+    let threads = blocks::decompress_threads(None, max_memory);
+    blocks::decompress_blocks(file_in, file_out, threads, |file_in, file_out| decode(algorithm, max_memory, file_in, file_out))
+}
+
+// Decompress a stream from encode, or from any options, without a header.
+pub(crate) fn decode<R: Read, W: Write>(algorithm: Algorithm, max_memory: Option<u64>,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    match algorithm {
+        Algorithm::Lz77     => lz77::lz77_decompress(file_in, file_out),
+        Algorithm::Lzw      => lzw::lzw_decompress(file_in, file_out),
+        Algorithm::Flzp     => flzp::flzp_decompress(file_in, file_out),
+        Algorithm::Sr       => sr::sr_decompress(file_in, file_out),
+        Algorithm::Fpaq0    => fpaq0::fpaq0_decompress(file_in, file_out),
+        Algorithm::Fpaq     => fpaq::fpaq_decompress(file_in, file_out),
+        Algorithm::Lpaq1    => lpaq1::lpaq1_decompress(file_in, file_out, max_memory),
+        Algorithm::Ppm      => ppm::ppm_decompress(file_in, file_out, None),
+        Algorithm::Rans     => rans::rans_decompress(file_in, file_out),
+        Algorithm::Fse      => fse::fse_decompress(file_in, file_out),
+        Algorithm::Huffman  => decoder::decompress(file_in, file_out),
+        Algorithm::Bwt      => bwt::bwt_inverse_transform(file_in, file_out, max_memory),
+        Algorithm::Remap    => remap::unmap(file_in, file_out),
+        Algorithm::Split    => split::join(file_in, file_out),
+        Algorithm::Segment  => segment::segment_decompress(file_in, file_out, max_memory),
+        Algorithm::Sortrans => sortrans::sortrans_decompress(file_in, file_out, max_memory),
+    }
+}
+
+// Run a codec from input to a new Vec. Huffman compression seeks in its
+// input, and lpaq1 compression in its output, so both are Cursors.
+pub(crate) fn run<F>(input: &[u8], codec: F) -> error::Result<Vec<u8>>
+where
+    F: FnOnce(BufReader<Cursor<&[u8]>>, BufWriter<&mut Cursor<Vec<u8>>>) -> error::Result<Sizes>,
+{
+    let mut output = Cursor::new(Vec::new());
+    codec(
+        BufReader::with_capacity(BUF_SIZE, Cursor::new(input)),
+        BufWriter::with_capacity(BUF_SIZE, &mut output),
+    )?;
+    Ok(output.into_inner())
+}
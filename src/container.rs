@@ -0,0 +1,498 @@
+// The header in front of every compressed stream: MAGIC, the format
+// VERSION, the algorithm's id, flags, and the size of the original input
+// as a u64, then the algorithm's own stream. Decompressing checks each
+// field before running the decoder, and the decoded size against the
+// header after, so input that isn't a stream, or was compressed with
+// another algorithm, is reported instead of decoded into garbage.
+//
+// Flags mark the original file's modification and creation times, if
+// they were recorded, each following the size as a u64 of nanoseconds
+// since the Unix epoch, modification time first, and streams compressed
+// in independent blocks, see blocks.rs.
+//
+// A trailer follows each stream: the length of the whole member, header,
+// stream and trailer, as a u64, then END. Streams don't all say where
+// they end, so this is what lets files compressed separately be
+// concatenated, as with gzip, and decompressed to the concatenation of
+// the originals. Decompressing reads forward: a member's stream ends
+// where END follows the length of the member up to there, and the next
+// member, if any, starts after it, so the input needn't be seekable.
+
+use std::fs::File;
+use std::fs::FileTimes;
+use std::fs::Metadata;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use crate::bufio::{watchdog, Watched};
+use crate::error;
+use crate::stats::Sizes;
+use crate::stored;
+
+pub const MAGIC: [u8; 4] = *b"CMPR";
+pub const VERSION: u8 = 2;
+/// Size of a header without times. Each recorded time adds 8 bytes.
+pub const HEADER_SIZE: u64 = 15;
+/// The last 4 bytes of every member.
+pub const END: [u8; 4] = *b"CEND";
+/// Size of the trailer: the member's length, then END.
+pub const TRAILER_SIZE: u64 = 12;
+
+// Bytes read ahead of a member's stream at a time, looking for its
+// trailer.
+const LOOKAHEAD: usize = 1 << 16;
+
+// Flags
+const STORED:   u8 = 1; // The input follows unchanged, see --max-expand
+const MODIFIED: u8 = 2; // Modification time follows the size
+const CREATED:  u8 = 4; // Creation time follows the size
+const BLOCKS:   u8 = 8; // The stream is in blocks, see --threads
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Algorithm {
+    Lz77     = 1,
+    Lzw      = 2,
+    Flzp     = 3,
+    Fpaq     = 4,
+    Lpaq1    = 5,
+    Huffman  = 6,
+    Bwt      = 7,
+    Remap    = 8,
+    Split    = 9,
+    Sr       = 10,
+    Fpaq0    = 11,
+    Segment  = 12,
+    Sortrans = 13,
+    Rans     = 14,
+    Fse      = 15,
+    Ppm      = 16,
+}
+
+impl Algorithm {
+    pub const ALL: [Algorithm; 16] = [
+        Algorithm::Lz77, Algorithm::Lzw, Algorithm::Flzp, Algorithm::Sr, Algorithm::Fpaq0, Algorithm::Fpaq, Algorithm::Lpaq1,
+        Algorithm::Ppm, Algorithm::Rans, Algorithm::Fse, Algorithm::Huffman, Algorithm::Bwt, Algorithm::Remap, Algorithm::Split, Algorithm::Segment, Algorithm::Sortrans,
+    ];
+
+    /// The algorithm's id in the header. Ids are never reused.
+    pub fn id(self) -> u8 {
+        self as u8
+    }
+
+    pub fn from_id(id: u8) -> Option<Algorithm> {
+        Algorithm::ALL.into_iter().find(|algorithm| algorithm.id() == id)
+    }
+
+    /// The algorithm's name as the command line spells it, without the '-'.
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Lz77     => "lz77",
+            Algorithm::Lzw      => "lzw",
+            Algorithm::Flzp     => "flzp",
+            Algorithm::Sr       => "sr",
+            Algorithm::Fpaq0    => "fpaq0",
+            Algorithm::Fpaq     => "fpaq",
+            Algorithm::Lpaq1    => "lpaq1",
+            Algorithm::Ppm      => "ppm",
+            Algorithm::Rans     => "rans",
+            Algorithm::Fse      => "fse",
+            Algorithm::Huffman  => "huffman",
+            Algorithm::Bwt      => "bwt",
+            Algorithm::Remap    => "remap",
+            Algorithm::Split    => "split",
+            Algorithm::Segment  => "segment",
+            Algorithm::Sortrans => "sortrans",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Algorithm> {
+        Algorithm::ALL.into_iter().find(|algorithm| algorithm.name() == name)
+    }
+}
+
+/// Times of the original file, in nanoseconds since the Unix epoch, if
+/// they were recorded.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct Times {
+    pub modified: Option<u64>,
+    pub created:  Option<u64>,
+}
+
+impl Times {
+    /// The times of a file, as far as the platform reports them.
+    /// Times before the epoch aren't recorded.
+    pub fn of(metadata: &Metadata) -> Times {
+        let nanos = |time: io::Result<SystemTime>| time.ok()
+            .and_then(|time| time.duration_since(SystemTime::UNIX_EPOCH).ok())
+            .and_then(|time| u64::try_from(time.as_nanos()).ok());
+        Times { modified: nanos(metadata.modified()), created: nanos(metadata.created()) }
+    }
+
+    /// Number of bytes these take in a header.
+    pub fn encoded_len(&self) -> u64 {
+        8 * (self.modified.is_some() as u64 + self.created.is_some() as u64)
+    }
+
+    /// Set file's times to these. Creation times can only be set on
+    /// Windows, and are ignored elsewhere.
+    pub fn restore(&self, file: &File) -> io::Result<()> {
+        let time = |nanos| SystemTime::UNIX_EPOCH + Duration::from_nanos(nanos);
+        let mut times = FileTimes::new();
+        if let Some(modified) = self.modified {
+            times = times.set_modified(time(modified));
+        }
+        if let Some(created) = self.created {
+            times = set_created(times, time(created));
+        }
+        file.set_times(times)
+    }
+}
+
+#[cfg(windows)]
+fn set_created(times: FileTimes, created: SystemTime) -> FileTimes {
+    use std::os::windows::fs::FileTimesExt;
+    times.set_created(created)
+}
+
+#[cfg(not(windows))]
+fn set_created(times: FileTimes, _created: SystemTime) -> FileTimes {
+    times
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Header {
+    pub algorithm: Algorithm,
+    pub stored:    bool,  // The input follows unchanged instead of compressed
+    pub blocks:    bool,  // The stream is in independent blocks
+    pub size:      u64,   // Size of the original input
+    pub times:     Times, // Times of the original file
+}
+
+impl Header {
+    /// Size of the header in bytes, with its times.
+    pub fn encoded_len(&self) -> u64 {
+        HEADER_SIZE + self.times.encoded_len()
+    }
+
+    fn write<W: Write>(&self, file_out: &mut W) -> error::Result<()> {
+        let mut flags = 0;
+        if self.stored { flags |= STORED; }
+        if self.blocks { flags |= BLOCKS; }
+        if self.times.modified.is_some() { flags |= MODIFIED; }
+        if self.times.created.is_some() { flags |= CREATED; }
+
+        let mut header = Vec::with_capacity(self.encoded_len() as usize);
+        header.extend_from_slice(&MAGIC);
+        header.push(VERSION);
+        header.push(self.algorithm.id());
+        header.push(flags);
+        header.extend_from_slice(&self.size.to_le_bytes());
+        for time in [self.times.modified, self.times.created].into_iter().flatten() {
+            header.extend_from_slice(&time.to_le_bytes());
+        }
+        file_out.write_all(&header)?;
+        Ok(())
+    }
+
+    /// Read and check a header, returning an InvalidData error if
+    /// file_in doesn't start with one this version can read.
+    pub fn read<R: Read>(file_in: &mut R) -> error::Result<Header> {
+        let mut header = [0u8; HEADER_SIZE as usize];
+        if let Err(e) = file_in.read_exact(&mut header) {
+            return match e.kind() {
+                ErrorKind::UnexpectedEof => Err(invalid_data("not a compressed stream".to_string())),
+                _ => Err(e.into()),
+            };
+        }
+        if header[..4] != MAGIC {
+            return Err(invalid_data("not a compressed stream".to_string()));
+        }
+        if header[4] != VERSION {
+            return Err(invalid_data(format!("unsupported container version {}", header[4])));
+        }
+        let algorithm = Algorithm::from_id(header[5])
+            .ok_or_else(|| invalid_data(format!("unknown algorithm id {}", header[5])))?;
+        let flags = header[6];
+        if flags & !(STORED | MODIFIED | CREATED | BLOCKS) != 0 {
+            return Err(invalid_data(format!("unknown container flags {:#04x}", flags)));
+        }
+        let mut read_time = |flag| -> error::Result<Option<u64>> {
+            if flags & flag == 0 {
+                return Ok(None);
+            }
+            let mut time = [0u8; 8];
+            match file_in.read_exact(&mut time) {
+                Ok(()) => Ok(Some(u64::from_le_bytes(time))),
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => Err(invalid_data("truncated container header".to_string())),
+                Err(e) => Err(e.into()),
+            }
+        };
+        let times = Times { modified: read_time(MODIFIED)?, created: read_time(CREATED)? };
+        Ok(Header {
+            algorithm,
+            stored: flags & STORED != 0,
+            blocks: flags & BLOCKS != 0,
+            size:   u64::from_le_bytes(header[7..].try_into().unwrap()),
+            times,
+        })
+    }
+}
+
+/// Where one member is in the input, see members.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Member {
+    pub start: u64, // Position of its header
+    pub end:   u64, // Position of its trailer
+}
+
+/// Write a header for size bytes of input compressed with algorithm,
+/// from a file with the given times, then compress file_in with codec,
+/// then write the trailer. blocks marks a codec that compresses in
+/// blocks with blocks.rs.
+pub fn compress<R, W, F>(file_in: BufReader<R>, file_out: BufWriter<W>,
+    algorithm: Algorithm, size: u64, times: Times, blocks: bool, codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(BufReader<R>, BufWriter<&mut W>) -> error::Result<Sizes>,
+{
+    let header = Header { algorithm, stored: false, blocks, size, times };
+    write_member(&header, file_in, file_out, codec)
+}
+
+/// Write a header marking size bytes of input as stored, then copy it,
+/// then write the trailer.
+pub fn store<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>,
+    algorithm: Algorithm, size: u64, times: Times) -> error::Result<Sizes> {
+    let header = Header { algorithm, stored: true, blocks: false, size, times };
+    write_member(&header, file_in, file_out, |file_in, file_out| stored::copy(file_in, file_out))
+}
+
+// Write header, the stream from codec, and the trailer. The codec writes
+// straight to the inner writer, with a buffer of the same size.
+fn write_member<R, W, F>(header: &Header, file_in: BufReader<R>, mut file_out: BufWriter<W>, codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: FnOnce(BufReader<R>, BufWriter<&mut W>) -> error::Result<Sizes>,
+{
+    header.write(&mut file_out)?;
+    file_out.flush()?;
+    let capacity = file_out.capacity();
+    let sizes = codec(file_in, BufWriter::with_capacity(capacity, file_out.get_mut()))?;
+    let len = header.encoded_len() + sizes.output + TRAILER_SIZE;
+    file_out.write_all(&len.to_le_bytes())?;
+    file_out.write_all(&END)?;
+    file_out.flush()?;
+    Ok(Sizes { input: sizes.input, output: len })
+}
+
+/// Decompress each member of file_in with codec, or copy it if it was
+/// stored, writing them one after another to file_out. The codec is
+/// given the member's header, to see if the stream is in blocks, and
+/// exactly the member's stream. Returns an InvalidData error if a member
+/// was compressed with another algorithm, has no trailer, or doesn't
+/// decode to the size in its header, stopping the codec as soon as it
+/// writes more, or if it stalls at the end of the stream.
+pub fn decompress<R, W, F>(file_in: BufReader<R>, mut file_out: BufWriter<W>,
+    algorithm: Algorithm, mut codec: F) -> error::Result<Sizes>
+where
+    R: Read,
+    W: Write,
+    F: FnMut(&Header, BufReader<Watched<&mut MemberStream<R>>>, BufWriter<Watched<&mut W>>) -> error::Result<Sizes>,
+{
+    let (in_capacity, out_capacity) = (file_in.capacity(), file_out.capacity());
+    let mut file_in = MemberStream::new(file_in);
+    let mut output = 0;
+    loop {
+        let header = file_in.read_header()?;
+        if !header.stored && header.algorithm != algorithm {
+            return Err(invalid_data(format!("stream was compressed with -{}, not -{}",
+                header.algorithm.name(), algorithm.name())));
+        }
+        let sizes = watchdog(&mut file_in, file_out.get_mut(), header.size, |stream, stream_out| {
+            let stream = BufReader::with_capacity(in_capacity, stream);
+            let stream_out = BufWriter::with_capacity(out_capacity, stream_out);
+            if header.stored {
+                stored::copy(stream, stream_out)
+            }
+            else {
+                codec(&header, stream, stream_out)
+            }
+        })?;
+        if sizes.output != header.size {
+            return Err(invalid_data(format!("decompressed {} bytes, but the header says {}",
+                sizes.output, header.size)));
+        }
+        output += sizes.output;
+        if !file_in.next_member()? {
+            break;
+        }
+    }
+    file_out.flush()?;
+    Ok(Sizes { input: file_in.position(), output })
+}
+
+/// The stream of one member of the input to decompress, read forward up
+/// to its trailer. Bytes are returned once they can't be the start of
+/// the trailer, so up to TRAILER_SIZE - 1 are held back until more of
+/// the input is read. The stream fails with an InvalidData error if the
+/// input ends before a trailer.
+pub struct MemberStream<R> {
+    file_in:  BufReader<R>,
+    window:   Vec<u8>,       // Input read, from base, the first start bytes already returned
+    start:    usize,
+    base:     u64,           // Position in the input of window[0]
+    member:   u64,           // Position in the input of the member's header
+    checked:  usize,         // Positions in window before this can't start the trailer
+    end:      Option<usize>, // Position in window of the trailer, once found
+    scanning: bool,          // Reading the stream, not the header
+}
+
+impl<R: Read> MemberStream<R> {
+    fn new(file_in: BufReader<R>) -> MemberStream<R> {
+        MemberStream {
+            file_in,
+            window:   Vec::new(),
+            start:    0,
+            base:     0,
+            member:   0,
+            checked:  0,
+            end:      None,
+            scanning: false,
+        }
+    }
+
+    // Position in the input of the next byte to return.
+    fn position(&self) -> u64 {
+        self.base + self.start as u64
+    }
+
+    // Read the header of the member starting here, then look for its
+    // trailer after it.
+    fn read_header(&mut self) -> error::Result<Header> {
+        self.member = self.position();
+        let header = Header::read(self)?;
+        self.scanning = true;
+        self.checked = self.start;
+        self.scan();
+        Ok(header)
+    }
+
+    // Skip the rest of the member's stream, which its codec may not have
+    // read, and its trailer, then return whether another member follows.
+    fn next_member(&mut self) -> io::Result<bool> {
+        io::copy(self, &mut io::sink())?;
+        self.start += TRAILER_SIZE as usize;
+        self.end = None;
+        self.scanning = false;
+        Ok(self.start < self.window.len() || self.fill()? > 0)
+    }
+
+    // Drop the bytes already returned, then read more of the input into
+    // window. Returns the number of bytes read, 0 at the end of the input.
+    fn fill(&mut self) -> io::Result<usize> {
+        self.window.drain(..self.start);
+        self.base += self.start as u64;
+        self.checked = self.checked.saturating_sub(self.start);
+        self.start = 0;
+        let len = self.window.len();
+        self.window.resize(len + LOOKAHEAD, 0);
+        let read = self.file_in.read(&mut self.window[len..]);
+        self.window.truncate(len + *read.as_ref().unwrap_or(&0));
+        read
+    }
+
+    // Check the positions in window not yet checked for the trailer: END,
+    // after the length of the member up to the end of the trailer.
+    fn scan(&mut self) {
+        let size = TRAILER_SIZE as usize;
+        while self.end.is_none() && self.checked + size <= self.window.len() {
+            let trailer = &self.window[self.checked..self.checked + size];
+            let len = self.base + (self.checked + size) as u64 - self.member;
+            if trailer[8..] == END && u64::from_le_bytes(trailer[..8].try_into().unwrap()) == len {
+                self.end = Some(self.checked);
+            }
+            else {
+                self.checked += 1;
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for MemberStream<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let ready = match (self.scanning, self.end) {
+                (false, _) => self.window.len(),
+                (true, Some(end)) => end,
+                (true, None) => self.checked,
+            };
+            if ready > self.start || self.end.is_some() || buf.is_empty() {
+                let len = (ready - self.start).min(buf.len());
+                buf[..len].copy_from_slice(&self.window[self.start..self.start + len]);
+                self.start += len;
+                return Ok(len);
+            }
+            if self.fill()? == 0 {
+                if !self.scanning {
+                    return Ok(0);
+                }
+                return Err(io::Error::new(ErrorKind::InvalidData, "truncated stream"));
+            }
+            self.scan();
+        }
+    }
+}
+
+/// Find the members of a stream from file_in's position to its end, in
+/// order, from the end, each trailer giving the start of its member,
+/// leaving file_in where it was. Only the trailers are read, so a member
+/// can still turn out not to be a stream. Returns an InvalidData error if
+/// the trailers don't lead back to the start.
+pub fn members<R: Read + Seek>(file_in: &mut R) -> io::Result<Vec<Member>> {
+    let start = file_in.stream_position()?;
+    let mut end = file_in.seek(SeekFrom::End(0))?;
+    let mut members = Vec::new();
+    while end > start {
+        let member = match read_trailer(file_in, end)? {
+            Some(len) if len <= end - start => Member { start: end - len, end: end - TRAILER_SIZE },
+            _ => return Err(io::Error::new(ErrorKind::InvalidData, "truncated stream")),
+        };
+        members.push(member);
+        end = member.start;
+    }
+    if members.is_empty() {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a compressed stream"));
+    }
+    members.reverse();
+    file_in.seek(SeekFrom::Start(start))?;
+    Ok(members)
+}
+
+// The member length in a trailer ending at end, if there is one.
+fn read_trailer<R: Read + Seek>(file_in: &mut R, end: u64) -> io::Result<Option<u64>> {
+    if end < TRAILER_SIZE {
+        return Ok(None);
+    }
+    file_in.seek(SeekFrom::Start(end - TRAILER_SIZE))?;
+    let mut trailer = [0u8; TRAILER_SIZE as usize];
+    file_in.read_exact(&mut trailer)?;
+    let len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    if trailer[8..] != END || len < HEADER_SIZE + TRAILER_SIZE {
+        return Ok(None);
+    }
+    Ok(Some(len))
+}
+
+fn invalid_data(msg: String) -> error::Error {
+    error::Error::InvalidData(msg)
+}
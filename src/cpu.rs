@@ -0,0 +1,336 @@
+use std::io;
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering;
+
+// Kernels with vector versions for the instruction sets the CPU has,
+// chosen once at run time: the lpaq1 mixer's dot product and training,
+// and the match length comparison of the LZ77 window. Every version
+// gives the same results, wrapping on overflow as the vector
+// instructions do, so which one ran never changes the output, only the
+// speed.
+//
+// The level is found from the CPU the first time a kernel runs, unless
+// set() chose one first, as --cpu-features does to compare them. Code
+// elsewhere calls the kernels here rather than checking features
+// itself.
+//
+// Copies and checksums aren't here: copies go through copy_within and
+// copy_from_slice, whose memcpy already picks the best version for the
+// CPU, and crc32 and xxh64 are limited by their serial dependencies
+// rather than by instructions.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Scalar = 1,
+    Sse2   = 2,
+    Avx2   = 3,
+    Neon   = 4,
+}
+
+impl Level {
+    pub fn from_name(name: &str) -> Option<Level> {
+        match name {
+            "scalar" => Some(Level::Scalar),
+            "sse2"   => Some(Level::Sse2),
+            "avx2"   => Some(Level::Avx2),
+            "neon"   => Some(Level::Neon),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Scalar => "scalar",
+            Level::Sse2   => "sse2",
+            Level::Avx2   => "avx2",
+            Level::Neon   => "neon",
+        }
+    }
+
+    // True if this CPU can run code for level.
+    pub fn supported(self) -> bool {
+        match self {
+            Level::Scalar => true,
+            #[cfg(target_arch = "x86_64")]
+            Level::Sse2 => true,
+            #[cfg(target_arch = "x86_64")]
+            Level::Avx2 => is_x86_feature_detected!("avx2"),
+            #[cfg(target_arch = "aarch64")]
+            Level::Neon => true,
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+}
+
+// The chosen level, or 0 until one is chosen.
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+// The best level this CPU supports.
+pub fn detect() -> Level {
+    [Level::Avx2, Level::Sse2, Level::Neon].into_iter().find(|level| level.supported()).unwrap_or(Level::Scalar)
+}
+
+// Run the kernels at level from now on, or return an Unsupported error
+// if this CPU can't.
+pub fn set(level: Level) -> io::Result<()> {
+    if !level.supported() {
+        let msg = format!("this cpu doesn't support {}", level.name());
+        return Err(io::Error::new(ErrorKind::Unsupported, msg));
+    }
+    LEVEL.store(level as u8, Ordering::Relaxed);
+    Ok(())
+}
+
+// The level the kernels run at.
+pub fn level() -> Level {
+    match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Scalar,
+        2 => Level::Sse2,
+        3 => Level::Avx2,
+        4 => Level::Neon,
+        _ => {
+            let level = detect();
+            LEVEL.store(level as u8, Ordering::Relaxed);
+            level
+        }
+    }
+}
+
+// The sum of the products of inputs and the first inputs.len() weights.
+pub fn dot_product(inputs: &[i32], weights: &[i32]) -> i32 {
+    let weights = &weights[..inputs.len()];
+    match level() {
+        // SAFETY: the level is only set to one the CPU supports.
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::dot_product_avx2(inputs, weights) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse2 => unsafe { x86::dot_product_sse2(inputs, weights) },
+        #[cfg(target_arch = "aarch64")]
+        Level::Neon => unsafe { arm::dot_product_neon(inputs, weights) },
+        _ => scalar::dot_product(inputs, weights),
+    }
+}
+
+// Add (input * error + 0x8000) >> 16 to the weight of each input.
+pub fn train(inputs: &[i32], weights: &mut [i32], error: i32) {
+    let weights = &mut weights[..inputs.len()];
+    match level() {
+        // SAFETY: as in dot_product.
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::train_avx2(inputs, weights, error) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse2 => unsafe { x86::train_sse2(inputs, weights, error) },
+        #[cfg(target_arch = "aarch64")]
+        Level::Neon => unsafe { arm::train_neon(inputs, weights, error) },
+        _ => scalar::train(inputs, weights, error),
+    }
+}
+
+// The number of bytes a and b have in common from the start. Most
+// matches tried end within a few bytes, so the first 8 are compared
+// before choosing a version, which costs more than that comparison.
+#[inline]
+pub fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    let len = a.len().min(b.len());
+    let (a, b) = (&a[..len], &b[..len]);
+    if len >= 8 {
+        let x = u64::from_le_bytes(a[..8].try_into().unwrap());
+        let y = u64::from_le_bytes(b[..8].try_into().unwrap());
+        if x != y {
+            return ((x ^ y).trailing_zeros() / 8) as usize;
+        }
+    }
+    else {
+        return scalar::common_prefix(a, b);
+    }
+    8 + match level() {
+        // SAFETY: as in dot_product.
+        #[cfg(target_arch = "x86_64")]
+        Level::Avx2 => unsafe { x86::common_prefix_avx2(&a[8..], &b[8..]) },
+        #[cfg(target_arch = "x86_64")]
+        Level::Sse2 => unsafe { x86::common_prefix_sse2(&a[8..], &b[8..]) },
+        #[cfg(target_arch = "aarch64")]
+        Level::Neon => unsafe { arm::common_prefix_neon(&a[8..], &b[8..]) },
+        _ => scalar::common_prefix(&a[8..], &b[8..]),
+    }
+}
+
+// The versions for any CPU, which the vector versions also use for what's
+// left over after their last full vector. Slices are the same length.
+mod scalar {
+    pub fn dot_product(inputs: &[i32], weights: &[i32]) -> i32 {
+        inputs.iter().zip(weights.iter()).fold(0i32, |sum, (i, w)| sum.wrapping_add(i.wrapping_mul(*w)))
+    }
+
+    pub fn train(inputs: &[i32], weights: &mut [i32], error: i32) {
+        for (input, weight) in inputs.iter().zip(weights.iter_mut()) {
+            *weight = weight.wrapping_add(input.wrapping_mul(error).wrapping_add(0x8000) >> 16);
+        }
+    }
+
+    // Compared 8 bytes at a time.
+    pub fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+        let mut len = 0;
+        for (a, b) in a.chunks_exact(8).zip(b.chunks_exact(8)) {
+            let a = u64::from_le_bytes(a.try_into().unwrap());
+            let b = u64::from_le_bytes(b.try_into().unwrap());
+            if a != b {
+                return len + ((a ^ b).trailing_zeros() / 8) as usize;
+            }
+            len += 8;
+        }
+        len + a[len..].iter().zip(b[len..].iter()).take_while(|(a, b)| a == b).count()
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+    use std::arch::x86_64::*;
+    use super::scalar;
+
+    // The mixer has fewer inputs than a vector holds, or not many more,
+    // so the last vector is loaded and stored through a mask rather than
+    // leaving the rest to the scalar version.
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn dot_product_avx2(inputs: &[i32], weights: &[i32]) -> i32 {
+        let mut sum = _mm256_setzero_si256();
+        for i in (0..inputs.len()).step_by(8) {
+            let mask = tail_mask(inputs.len() - i);
+            let a = _mm256_maskload_epi32(inputs.as_ptr().add(i), mask);
+            let b = _mm256_maskload_epi32(weights.as_ptr().add(i), mask);
+            sum = _mm256_add_epi32(sum, _mm256_mullo_epi32(a, b));
+        }
+        add_lanes(_mm_add_epi32(_mm256_castsi256_si128(sum), _mm256_extracti128_si256::<1>(sum)))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn dot_product_sse2(inputs: &[i32], weights: &[i32]) -> i32 {
+        let n = inputs.len() & !3;
+        let mut sum = _mm_setzero_si128();
+        for i in (0..n).step_by(4) {
+            let a = _mm_loadu_si128(inputs.as_ptr().add(i) as *const __m128i);
+            let b = _mm_loadu_si128(weights.as_ptr().add(i) as *const __m128i);
+            sum = _mm_add_epi32(sum, mullo_sse2(a, b));
+        }
+        add_lanes(sum).wrapping_add(scalar::dot_product(&inputs[n..], &weights[n..]))
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn train_avx2(inputs: &[i32], weights: &mut [i32], error: i32) {
+        let (error, round) = (_mm256_set1_epi32(error), _mm256_set1_epi32(0x8000));
+        for i in (0..inputs.len()).step_by(8) {
+            let mask = tail_mask(inputs.len() - i);
+            let a = _mm256_maskload_epi32(inputs.as_ptr().add(i), mask);
+            let w = weights.as_mut_ptr().add(i);
+            let delta = _mm256_srai_epi32::<16>(_mm256_add_epi32(_mm256_mullo_epi32(a, error), round));
+            _mm256_maskstore_epi32(w, mask, _mm256_add_epi32(_mm256_maskload_epi32(w, mask), delta));
+        }
+    }
+
+    // All lanes, or the first left of them.
+    #[target_feature(enable = "avx2")]
+    unsafe fn tail_mask(left: usize) -> __m256i {
+        _mm256_cmpgt_epi32(_mm256_set1_epi32(left.min(8) as i32), _mm256_setr_epi32(0, 1, 2, 3, 4, 5, 6, 7))
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn train_sse2(inputs: &[i32], weights: &mut [i32], error: i32) {
+        let n = inputs.len() & !3;
+        let (error_v, round) = (_mm_set1_epi32(error), _mm_set1_epi32(0x8000));
+        for i in (0..n).step_by(4) {
+            let a = _mm_loadu_si128(inputs.as_ptr().add(i) as *const __m128i);
+            let w = weights.as_mut_ptr().add(i) as *mut __m128i;
+            let delta = _mm_srai_epi32::<16>(_mm_add_epi32(mullo_sse2(a, error_v), round));
+            _mm_storeu_si128(w, _mm_add_epi32(_mm_loadu_si128(w), delta));
+        }
+        scalar::train(&inputs[n..], &mut weights[n..], error);
+    }
+
+    #[target_feature(enable = "avx2")]
+    pub unsafe fn common_prefix_avx2(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len() & !31;
+        for i in (0..n).step_by(32) {
+            let x = _mm256_loadu_si256(a.as_ptr().add(i) as *const __m256i);
+            let y = _mm256_loadu_si256(b.as_ptr().add(i) as *const __m256i);
+            let equal = _mm256_movemask_epi8(_mm256_cmpeq_epi8(x, y)) as u32;
+            if equal != u32::MAX {
+                return i + equal.trailing_ones() as usize;
+            }
+        }
+        n + scalar::common_prefix(&a[n..], &b[n..])
+    }
+
+    #[target_feature(enable = "sse2")]
+    pub unsafe fn common_prefix_sse2(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len() & !15;
+        for i in (0..n).step_by(16) {
+            let x = _mm_loadu_si128(a.as_ptr().add(i) as *const __m128i);
+            let y = _mm_loadu_si128(b.as_ptr().add(i) as *const __m128i);
+            let equal = _mm_movemask_epi8(_mm_cmpeq_epi8(x, y)) as u32;
+            if equal != 0xFFFF {
+                return i + equal.trailing_ones() as usize;
+            }
+        }
+        n + scalar::common_prefix(&a[n..], &b[n..])
+    }
+
+    // The low 32 bits of the products of a and b, which SSE2 lacks an
+    // instruction for: lanes 0 and 2, then 1 and 3, are multiplied to 64
+    // bits, and the low halves put back in order.
+    #[target_feature(enable = "sse2")]
+    unsafe fn mullo_sse2(a: __m128i, b: __m128i) -> __m128i {
+        let even = _mm_mul_epu32(a, b);
+        let odd = _mm_mul_epu32(_mm_srli_si128::<4>(a), _mm_srli_si128::<4>(b));
+        _mm_unpacklo_epi32(_mm_shuffle_epi32::<0b1000>(even), _mm_shuffle_epi32::<0b1000>(odd))
+    }
+
+    #[target_feature(enable = "sse2")]
+    unsafe fn add_lanes(v: __m128i) -> i32 {
+        let v = _mm_add_epi32(v, _mm_shuffle_epi32::<0b01_00_11_10>(v));
+        let v = _mm_add_epi32(v, _mm_shuffle_epi32::<0b10_11_00_01>(v));
+        _mm_cvtsi128_si32(v)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+    use std::arch::aarch64::*;
+    use super::scalar;
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn dot_product_neon(inputs: &[i32], weights: &[i32]) -> i32 {
+        let n = inputs.len() & !3;
+        let mut sum = vdupq_n_s32(0);
+        for i in (0..n).step_by(4) {
+            sum = vmlaq_s32(sum, vld1q_s32(inputs.as_ptr().add(i)), vld1q_s32(weights.as_ptr().add(i)));
+        }
+        vaddvq_s32(sum).wrapping_add(scalar::dot_product(&inputs[n..], &weights[n..]))
+    }
+
+    #[target_feature(enable = "neon")]
+    pub unsafe fn train_neon(inputs: &[i32], weights: &mut [i32], error: i32) {
+        let n = inputs.len() & !3;
+        let (error_v, round) = (vdupq_n_s32(error), vdupq_n_s32(0x8000));
+        for i in (0..n).step_by(4) {
+            let w = weights.as_mut_ptr().add(i);
+            let delta = vshrq_n_s32::<16>(vmlaq_s32(round, vld1q_s32(inputs.as_ptr().add(i)), error_v));
+            vst1q_s32(w, vaddq_s32(vld1q_s32(w), delta));
+        }
+        scalar::train(&inputs[n..], &mut weights[n..], error);
+    }
+
+    // A vector with a mismatch is left to the scalar version to find it.
+    #[target_feature(enable = "neon")]
+    pub unsafe fn common_prefix_neon(a: &[u8], b: &[u8]) -> usize {
+        let n = a.len() & !15;
+        for i in (0..n).step_by(16) {
+            let equal = vceqq_u8(vld1q_u8(a.as_ptr().add(i)), vld1q_u8(b.as_ptr().add(i)));
+            if vminvq_u8(equal) != 0xFF {
+                return i + scalar::common_prefix(&a[i..i + 16], &b[i..i + 16]);
+            }
+        }
+        n + scalar::common_prefix(&a[n..], &b[n..])
+    }
+}
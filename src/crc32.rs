@@ -0,0 +1,50 @@
+// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320), as used by zip and gzip.
+
+const TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            j += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Default for Crc32 {
+    fn default() -> Crc32 {
+        Crc32::new()
+    }
+}
+
+impl Crc32 {
+    pub fn new() -> Crc32 {
+        Crc32 { crc: 0xFFFFFFFF }
+    }
+
+    pub fn update_u8(&mut self, byte: u8) {
+        self.crc = TABLE[((self.crc ^ byte as u32) & 0xFF) as usize] ^ (self.crc >> 8);
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.update_u8(*byte);
+        }
+    }
+
+    // CRC of all bytes so far.
+    pub fn value(&self) -> u32 {
+        !self.crc
+    }
+}
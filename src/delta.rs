@@ -0,0 +1,111 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::temp;
+use crate::xxh64::Xxh64;
+use crate::ari::lpaq1::{lpaq1_compress, lpaq1_decompress, ModelSet};
+
+// Compress a file relative to a similar reference file, such as an
+// earlier snapshot of the same disk image or database.
+//
+// Each byte of the input is XORed with the byte at the same position in
+// the reference, so everything that didn't change becomes 0, and the
+// result is compressed with lpaq1. Past the end of the reference, input
+// bytes are kept as they are. The output is the reference's length and
+// XXH64 (u64 each), then an ordinary lpaq1 stream of the XORed input,
+// which records its length, so the input can be longer or shorter than
+// the reference. Decompressing checks the reference against both before
+// XORing, since any other file would give wrong output without an error.
+
+pub fn delta_compress<R: Read + Seek, S: Read, W: Write + Seek>(mut reference: BufReader<R>, file_in: BufReader<S>,
+    mut file_out: BufWriter<W>, models: ModelSet, switch: bool, level: u8, run_states: bool) -> error::Result<Sizes> {
+    let (ref_len, ref_hash) = digest(&mut reference)?;
+    file_out.write_u64(ref_len)?;
+    file_out.write_u64(ref_hash)?;
+    // lpaq1 seeks back to its own start, after the reference's digest.
+    file_out.flush()?;
+    let file_out = BufWriter::with_capacity(1 << 20, Tracked::new(file_out));
+
+    let (temp, temp_file) = temp::create("delta", "xor")?;
+    let result = tracked(file_in, BufWriter::with_capacity(1 << 20, temp_file), |file_in, xored| {
+        xor(reference, file_in, xored)
+    })
+    .and_then(|_| {
+        lpaq1_compress(BufReader::with_capacity(1 << 20, File::open(&temp)?), file_out, models, switch, level, run_states)
+    })
+    .map(|sizes| Sizes { input: sizes.input, output: 16 + sizes.output });
+    fs::remove_file(&temp)?;
+    result
+}
+
+// Reproduce the input of delta_compress from its output and the same
+// reference, returning an InvalidData error if the reference isn't the
+// one it was made with or the lpaq1 stream is invalid, or an error if
+// that needs more than max_memory bytes.
+pub fn delta_decompress<R: Read + Seek, S: Read, W: Write>(mut reference: BufReader<R>, mut file_in: BufReader<S>,
+    file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    let truncated = || io::Error::new(ErrorKind::InvalidData, "truncated delta stream");
+    let ref_len = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+    let ref_hash = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+    let (len, hash) = digest(&mut reference)?;
+    if len != ref_len || hash != ref_hash {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("reference doesn't match: {} bytes with XXH64 {:016x}, delta was made against {} bytes with XXH64 {:016x}",
+                len, hash, ref_len, ref_hash)
+        ).into());
+    }
+
+    let (temp, temp_file) = temp::create("delta", "xor")?;
+    let result = lpaq1_decompress(file_in, BufWriter::with_capacity(1 << 20, temp_file), max_memory)
+        .and_then(|sizes| {
+            tracked(BufReader::with_capacity(1 << 20, File::open(&temp)?), file_out, |xored, file_out| {
+                xor(reference, xored, file_out)
+            })
+            .map(|restored| Sizes { input: 16 + sizes.input, output: restored.output })
+        });
+    fs::remove_file(&temp)?;
+    result
+}
+
+// The length and XXH64 of reference, leaving it rewound to the start.
+fn digest<R: Read + Seek>(reference: &mut BufReader<R>) -> io::Result<(u64, u64)> {
+    let mut hash = Xxh64::default();
+    let mut len = 0;
+    let block_size = reference.capacity();
+    for block in Blocks::new(&mut *reference, block_size) {
+        let block = block?;
+        hash.update(&block);
+        len += block.len() as u64;
+    }
+    reference.rewind()?;
+    Ok((len, hash.digest()))
+}
+
+// Write each byte of file_in XORed with the byte at the same position in
+// reference, or unchanged past the end of reference.
+fn xor<R: Read, S: Read, W: Write>(mut reference: BufReader<R>, mut file_in: BufReader<S>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let mut ref_block = Vec::new();
+
+    let block_size = file_in.capacity();
+    for block in Blocks::new(&mut file_in, block_size) {
+        let mut block = block?;
+        ref_block.clear();
+        reference.by_ref().take(block.len() as u64).read_to_end(&mut ref_block)?;
+        for (byte, ref_byte) in block.iter_mut().zip(ref_block.iter()) {
+            *byte ^= ref_byte;
+        }
+        file_out.write_bytes(&block)?;
+    }
+    file_out.flush()
+}
@@ -0,0 +1,65 @@
+use std::fmt;
+use std::io;
+use std::io::ErrorKind;
+
+// Errors from the compression and decompression entry points. Inside
+// the codecs, errors are io::Errors, with ErrorKind::InvalidData for
+// damaged streams and ErrorKind::OutOfMemory for streams that need more
+// than --max-memory, and From sorts them into these.
+
+#[derive(Debug)]
+pub enum Error {
+    /// Reading the input or writing the output failed.
+    Io(io::Error),
+    /// The input isn't a valid stream: truncated, damaged, or from a
+    /// version with features this one doesn't have.
+    InvalidData(String),
+    /// The stream needs more memory than the limit allows.
+    OutOfMemory(String),
+    /// A job was cancelled before it finished.
+    Cancelled,
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e)            => write!(f, "{}", e),
+            Error::InvalidData(msg) => write!(f, "{}", msg),
+            Error::OutOfMemory(msg) => write!(f, "{}", msg),
+            Error::Cancelled        => write!(f, "cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+// Back into an io::Error, for codecs built on others, keeping the kind.
+impl From<Error> for io::Error {
+    fn from(e: Error) -> io::Error {
+        match e {
+            Error::Io(e)            => e,
+            Error::InvalidData(msg) => io::Error::new(ErrorKind::InvalidData, msg),
+            Error::OutOfMemory(msg) => io::Error::new(ErrorKind::OutOfMemory, msg),
+            Error::Cancelled        => io::Error::other("cancelled"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        match e.kind() {
+            ErrorKind::InvalidData => Error::InvalidData(e.to_string()),
+            ErrorKind::OutOfMemory => Error::OutOfMemory(e.to_string()),
+            _ => Error::Io(e),
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+
+use crate::bufio::watchdog;
+use crate::bytes;
+use crate::bytes::Algorithm;
+use crate::error;
+
+// Framing for compressed streams embedded in another format. Each
+// algorithm's stream ends in its own way: some at an end marker, some
+// where the input does, so a stream followed by other data can't always
+// be told apart from it. A member is one stream, without the container
+// header, framed in one of three ways:
+//
+//   Raw             the stream alone. The caller records where it ends
+//                   and gives read_member exactly its bytes.
+//   LengthPrefixed  the stream's length as a varint, then the stream.
+//   SelfDelimiting  the stream in chunks of up to CHUNK_SIZE bytes, each
+//                   preceded by its length as a varint, then a 0 length.
+//                   A reader can skip it without knowing the total
+//                   length, and a writer can produce it without
+//                   seeking back.
+//
+// Varints are LEB128: 7 bits a byte, low bits first, with the high bit
+// set on all but the last byte.
+
+const CHUNK_SIZE: usize = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Framing {
+    Raw,
+    LengthPrefixed,
+    SelfDelimiting,
+}
+
+/// Compress input with algorithm's default options and write it to
+/// file_out as one member in the given framing.
+pub fn write_member<W: Write>(file_out: &mut W, input: &[u8], algorithm: Algorithm, framing: Framing) -> error::Result<()> {
+    let stream = bytes::run(input, |file_in, file_out| bytes::encode(algorithm, file_in, file_out))?;
+    match framing {
+        Framing::Raw => file_out.write_all(&stream)?,
+        Framing::LengthPrefixed => {
+            write_varint(file_out, stream.len() as u64)?;
+            file_out.write_all(&stream)?;
+        }
+        Framing::SelfDelimiting => {
+            for chunk in stream.chunks(CHUNK_SIZE) {
+                write_varint(file_out, chunk.len() as u64)?;
+                file_out.write_all(chunk)?;
+            }
+            write_varint(file_out, 0)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read one member in the given framing from file_in and decompress it,
+/// leaving file_in just after the member. A Raw member takes the rest of
+/// file_in. Returns an InvalidData error if the member is truncated or
+/// damaged, and an OutOfMemory error if lpaq1 or bwt would need more
+/// than max_memory.
+pub fn read_member<R: Read>(file_in: &mut R, algorithm: Algorithm, framing: Framing, max_memory: Option<u64>) -> error::Result<Vec<u8>> {
+    let mut stream = Vec::new();
+    match framing {
+        Framing::Raw => {
+            file_in.read_to_end(&mut stream)?;
+        }
+        Framing::LengthPrefixed => {
+            let len = read_varint(file_in)?;
+            read_exact(file_in, len, &mut stream)?;
+        }
+        Framing::SelfDelimiting => loop {
+            let len = read_varint(file_in)?;
+            if len == 0 {
+                break;
+            }
+            read_exact(file_in, len, &mut stream)?;
+        },
+    }
+    // The original size isn't recorded, so only a stall is caught.
+    let mut output = Vec::new();
+    watchdog(&stream[..], &mut output, u64::MAX, |file_in, file_out| {
+        bytes::decode(algorithm, max_memory, BufReader::new(file_in), BufWriter::new(file_out))
+    })?;
+    Ok(output)
+}
+
+/// Write n as a LEB128 varint.
+pub fn write_varint<W: Write>(file_out: &mut W, mut n: u64) -> io::Result<()> {
+    let mut buf = [0u8; 10];
+    let mut len = 0;
+    while n >= 0x80 {
+        buf[len] = n as u8 | 0x80;
+        n >>= 7;
+        len += 1;
+    }
+    buf[len] = n as u8;
+    file_out.write_all(&buf[..=len])
+}
+
+/// Read a LEB128 varint, one byte at a time so nothing after it is
+/// consumed. Returns an InvalidData error if it's truncated or doesn't
+/// fit in a u64.
+pub fn read_varint<R: Read>(file_in: &mut R) -> io::Result<u64> {
+    let mut n = 0u64;
+    for i in 0..10 {
+        let mut byte = [0u8; 1];
+        if let Err(e) = file_in.read_exact(&mut byte) {
+            return match e.kind() {
+                ErrorKind::UnexpectedEof => Err(invalid_data("truncated length")),
+                _ => Err(e),
+            };
+        }
+        let byte = byte[0];
+        if i == 9 && byte > 1 {
+            break;
+        }
+        n |= ((byte & 0x7F) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+    }
+    Err(invalid_data("length overflows"))
+}
+
+// Append exactly len bytes of file_in to buf. The length isn't trusted
+// enough to allocate up front.
+fn read_exact<R: Read>(file_in: &mut R, len: u64, buf: &mut Vec<u8>) -> io::Result<()> {
+    let start = buf.len();
+    file_in.take(len).read_to_end(buf)?;
+    if ((buf.len() - start) as u64) < len {
+        return Err(invalid_data("truncated member"));
+    }
+    Ok(())
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
@@ -1,101 +1,66 @@
-use std::collections::HashMap;
-use std::collections::BinaryHeap;
+use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::io::BufRead;
-use std::fs::File;
-use std::array;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
 
-use crate::huffman::huffman::Node;
-use crate::huffman::huffman::NodeType;
+use crate::huffman::huffman::build_codes;
+use crate::huffman::huffman::invert;
+use crate::huffman::order1;
 
-pub fn decompress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let file_in_size = file_in.get_ref().metadata().unwrap().len();
-    let padding = file_in.read_u8();
-
-    let frequencies: [u32; 256] = array::from_fn(|_| file_in.read_u32());
+// Decompress a huffman stream, returning an InvalidData error if it 
+// ends before all symbols are decoded.
+#[allow(clippy::redundant_closure)]
+pub fn decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decode(file_in, file_out))
+}
 
-    let mut heap = BinaryHeap::with_capacity(512);
-    for (i, frequency) in frequencies.iter().enumerate() {                                               
-        heap.push(                                                  
-            Node::new(
-                *frequency,
-                NodeType::Leaf(i as u8)
-            )
-        );
-    }   
+fn decode<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let flags = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+    let count = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+    if flags & order1::ORDER1 != 0 {
+        return order1::decode(file_in, file_out, count);
+    }
 
-    build_tree(&mut heap); 
+    let mut frequencies = [0u32; 256];
+    for freq in frequencies.iter_mut() {
+        *freq = file_in.read_u32()?;
+    }
 
-    let mut codes = HuffmanCodeMap::new();
-    gen_codes(heap.peek().unwrap(), vec![], &mut codes);
+    let codes = invert(build_codes(&frequencies));
 
     let mut curr_code: Vec<u8> = Vec::with_capacity(8);
-    let mut pos = 1026;
-    file_in.fill_buf().unwrap();
+    let mut remaining = count;
+    let block_size = file_in.capacity();
+    let mut blocks = Blocks::new(&mut file_in, block_size);
     
-    loop {
-        for byte in file_in.buffer().iter() {
-            if pos >= file_in_size {
-                for j in (0..=(7 - padding)).rev() {
-                    curr_code.push((*byte >> j) & 1);
-                    if let Some(byte) = codes.get(&curr_code) {
-                        file_out.write_u8(*byte);
-                        curr_code.clear();
-                    }
-                }
-            } 
-            else {
-                for j in (0..=7).rev() {
-                    curr_code.push((*byte >> j) & 1);
-                    if let Some(byte) = codes.get(&curr_code) {
-                        file_out.write_u8(*byte);
-                        curr_code.clear();
+    while remaining > 0 {
+        let block = blocks.next().ok_or_else(truncated)??;
+        for byte in block.iter() {
+            for j in (0..=7).rev() {
+                curr_code.push((*byte >> j) & 1);
+                if let Some(byte) = codes.get(&curr_code) {
+                    file_out.write_u8(*byte)?;
+                    curr_code.clear();
+                    remaining -= 1;
+                    if remaining == 0 {
+                        break;
                     }
                 }
             }
-            pos += 1;
-        }
-        if file_in.fill_buffer() == BufferState::Empty {
-            file_out.flush_buffer();
-            break;
+            if remaining == 0 {
+                break;
+            }
         }
     }   
+    file_out.flush()
 }
 
-type HuffmanCodeMap = HashMap<Vec<u8>, u8>;
-
-fn gen_codes(node: &Node, prefix: Vec<u8>, codes: &mut HuffmanCodeMap) {
-    match node.node_type {
-        NodeType::Internal(ref left_child, ref right_child) => {
-            let mut left_prefix = prefix.clone();
-            left_prefix.push(0);
-            gen_codes(left_child, left_prefix, codes);
-
-            let mut right_prefix = prefix;
-            right_prefix.push(1);
-            gen_codes(right_child, right_prefix, codes);
-        }
-        NodeType::Leaf(byte) => {
-            codes.insert(prefix, byte);
-        }
-    }
+pub fn truncated() -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, "truncated huffman stream")
 }
-
-fn build_tree(heap: &mut BinaryHeap<Node>) {
-    while heap.len() > 1 {
-        let left_child = heap.pop().unwrap();
-        let right_child = heap.pop().unwrap();
-        heap.push(
-            Node::new(
-                left_child.frequency + right_child.frequency, 
-                NodeType::Internal(
-                    Box::new(left_child), 
-                    Box::new(right_child)
-                )
-            )
-        );
-    }
-}
\ No newline at end of file
@@ -1,54 +1,60 @@
-use std::collections::HashMap;
-use std::collections::BinaryHeap;
+use std::io;
 use std::io::BufReader;
 use std::io::BufWriter;
+use std::io::Write;
+use std::io::Read;
 use std::io::Seek;
-use std::fs::File;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Stats;
+use crate::stats::Sizes;
+
+use crate::huffman::huffman::build_codes;
+
+// Output format:
+//
+//   flags        (1 byte, 0 for order 0 coding)
+//   symbol count (u64)
+//   frequencies  (256 u32s)
+//   data         (codes packed MSB first, last byte padded with 0 bits)
+//
+// The decoder stops after the symbol count, so it doesn't need to know 
+// where the stream ends.
+#[allow(clippy::redundant_closure)]
+pub fn compress<R: Read + Seek, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| encode(file_in, file_out))
+}
 
-use crate::huffman::huffman::Node;
-use crate::huffman::huffman::NodeType;
-
-pub fn compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    file_out.write_u8(0);
+fn encode<R: Read + Seek, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    // Model data to get frequency distribution. Every byte gets a 
+    // count of at least 1 so that it has a code.
+    let stats = Stats::collect(&mut file_in, false)?;
+    let frequencies: [u32; 256] = stats.order0.map(|count| count as u32 + 1);
 
-    // Model data to get frequency distribution
-    let frequencies: [u32; 256] = model(&mut file_in);
+    file_out.write_u8(0)?;
+    file_out.write_u64(stats.total)?;
      
     // Include model as compressed data header
     for freq in frequencies.iter() {                                
-        file_out.write_u32(*freq);                                                                                               
+        file_out.write_u32(*freq)?;                                                                                               
     }                                                               
     
-    // Add leaf nodes to heap
-    let mut heap: BinaryHeap<Node> = BinaryHeap::new();
-    for i in 0..256 {                                               
-        heap.push(
-            Node::new(
-                frequencies[i], 
-                NodeType::Leaf(i as u8)
-            )      
-        );                                                          
-    }                                                               
-
-    build_tree(&mut heap);       
-
-    // Walk down tree and generate codes
-    let mut codes = HuffmanCodeMap::new();                          
-    gen_codes(heap.peek().unwrap(), vec![], &mut codes);
+    let codes = build_codes(&frequencies);
 
-    file_in.rewind().unwrap();
+    file_in.rewind()?;
 
     let mut packed_codes: u8 = 0;
     let mut bits: u8 = 0;
 
-    while file_in.fill_buffer() == BufferState::NotEmpty {
-        for byte in file_in.buffer().iter() { 
+    let block_size = file_in.capacity();
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+        for byte in block.iter() { 
             // Get huffman code corresponding to current byte and write bits to output
-            for bit in codes.get(byte).unwrap() {
+            for bit in codes[*byte as usize].iter() {
                 if bits >= 8 {
-                    file_out.write_u8(packed_codes);
+                    file_out.write_u8(packed_codes)?;
                     packed_codes = 0;
                     bits = 0;
                 }
@@ -59,60 +65,7 @@ pub fn compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
     } 
     // Write remaining code
     if bits > 0 {
-        file_out.write_u8(packed_codes);
-    }
-    file_out.flush_buffer();
-    file_out.rewind().unwrap();
-
-    // Write number of padding bits
-    file_out.write_u8(8 - bits);
-}
-
-// Model data to get frequency distribution
-fn model(file_in: &mut BufReader<File>) -> [u32; 256] {
-    let mut frequencies = [1u32; 256];
-    while file_in.fill_buffer() == BufferState::NotEmpty {
-        for byte in file_in.buffer().iter() {
-            frequencies[*byte as usize] += 1;
-        }
+        file_out.write_u8(packed_codes << (8 - bits))?;
     }
-    frequencies
+    file_out.flush_buffer()
 }
-
-// Build tree from leaf nodes
-fn build_tree(heap: &mut BinaryHeap<Node>) {
-    while heap.len() > 1 {
-        let left_child = heap.pop().unwrap();
-        let right_child = heap.pop().unwrap();
-        heap.push(
-            Node::new(
-                left_child.frequency + right_child.frequency, 
-                NodeType::Internal(
-                    Box::new(left_child), 
-                    Box::new(right_child)
-                )
-            )
-        );
-    }
-}
-
-
-// Walk down every branch of tree to get codes for every byte
-type HuffmanCodeMap = HashMap<u8, Vec<u8>>;
-
-fn gen_codes(node: &Node, prefix: Vec<u8>, codes: &mut HuffmanCodeMap) {
-    match node.node_type {
-        NodeType::Internal(ref left_child, ref right_child) => {
-            let mut left_prefix = prefix.clone();
-            left_prefix.push(0);
-            gen_codes(left_child, left_prefix, codes);
-
-            let mut right_prefix = prefix;
-            right_prefix.push(1);
-            gen_codes(right_child, right_prefix, codes);
-        }
-        NodeType::Leaf(byte) => {
-            codes.insert(byte, prefix);
-        }
-    }
-}
\ No newline at end of file
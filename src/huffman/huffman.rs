@@ -1,4 +1,6 @@
 use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::collections::BinaryHeap;
 
 #[derive(Eq, PartialEq)]
 pub enum NodeType {
@@ -8,22 +10,28 @@ pub enum NodeType {
 
 #[derive(Eq, PartialEq)]
 pub struct Node {
-    pub frequency: u32,
+    pub frequency: u64, // Sum of up to 256 u32s, so it can't overflow
+    pub order:     u32, // Tie breaker: byte for leaves, 256 + creation order for internal nodes
     pub node_type: NodeType,
 }
 
 impl Node {
-    pub fn new(frequency: u32, node_type: NodeType) -> Node {
+    pub fn leaf(frequency: u32, byte: u8) -> Node {
         Node { 
-            frequency, 
-            node_type 
+            frequency: frequency as u64, 
+            order:     byte as u32,
+            node_type: NodeType::Leaf(byte),
         }
     }
 }
 
+// Nodes with lower frequencies are greater, so they're popped from the 
+// heap first. Equal frequencies are ordered by the tie breaker, so the 
+// tree depends only on the frequencies and not on the heap's internals.
 impl Ord for Node {
     fn cmp(&self, rhs: &Self) -> Ordering {
         rhs.frequency.cmp(&self.frequency)
+            .then(rhs.order.cmp(&self.order))
     }
 } 
 
@@ -32,3 +40,81 @@ impl PartialOrd for Node {
         Some(self.cmp(rhs))
     }
 }
+
+// Code of each byte, indexed by byte, or empty for bytes with no code.
+// A table rather than a hash map, so anything that walks the codes sees
+// them in byte order.
+pub type HuffmanCodeMap = Vec<Vec<u8>>;
+
+// Generate codes for the bytes with non zero frequencies. A lone byte
+// gets a 1 bit code, since the tree has no branches. The encoder and 
+// decoder both derive their codes from the stored frequencies with this
+// function, so they always agree.
+pub fn build_codes(frequencies: &[u32; 256]) -> HuffmanCodeMap {
+    let mut heap: BinaryHeap<Node> = BinaryHeap::new();
+    for (i, frequency) in frequencies.iter().enumerate() {
+        if *frequency > 0 {
+            heap.push(Node::leaf(*frequency, i as u8));
+        }
+    }
+
+    let mut codes = vec![Vec::new(); 256];
+    if heap.len() == 1 {
+        if let NodeType::Leaf(byte) = heap.peek().unwrap().node_type {
+            codes[byte as usize] = vec![0];
+        }
+        return codes;
+    }
+    build_tree(&mut heap);
+    if let Some(root) = heap.peek() {
+        gen_codes(root, vec![], &mut codes);
+    }
+    codes
+}
+
+// Map codes back to bytes, for decoding.
+pub fn invert(codes: HuffmanCodeMap) -> HashMap<Vec<u8>, u8> {
+    codes.into_iter()
+        .enumerate()
+        .filter(|(_, code)| !code.is_empty())
+        .map(|(byte, code)| (code, byte as u8))
+        .collect()
+}
+
+// Build tree from leaf nodes
+fn build_tree(heap: &mut BinaryHeap<Node>) {
+    let mut order = 256;
+    while heap.len() > 1 {
+        let left_child = heap.pop().unwrap();
+        let right_child = heap.pop().unwrap();
+        heap.push(
+            Node {
+                frequency: left_child.frequency + right_child.frequency, 
+                order,
+                node_type: NodeType::Internal(
+                    Box::new(left_child), 
+                    Box::new(right_child)
+                )
+            }
+        );
+        order += 1;
+    }
+}
+
+// Walk down every branch of tree to get codes for every byte
+fn gen_codes(node: &Node, prefix: Vec<u8>, codes: &mut HuffmanCodeMap) {
+    match node.node_type {
+        NodeType::Internal(ref left_child, ref right_child) => {
+            let mut left_prefix = prefix.clone();
+            left_prefix.push(0);
+            gen_codes(left_child, left_prefix, codes);
+
+            let mut right_prefix = prefix;
+            right_prefix.push(1);
+            gen_codes(right_child, right_prefix, codes);
+        }
+        NodeType::Leaf(byte) => {
+            codes[byte as usize] = prefix;
+        }
+    }
+}
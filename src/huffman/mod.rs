@@ -1,3 +1,5 @@
 pub mod encoder;
 pub mod decoder;
+#[allow(clippy::module_inception)]
 pub mod huffman;
+pub mod order1;
@@ -0,0 +1,212 @@
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::Seek;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Stats;
+use crate::stats::Sizes;
+
+use crate::huffman::huffman::HuffmanCodeMap;
+use crate::huffman::huffman::build_codes;
+use crate::huffman::huffman::invert;
+use crate::huffman::decoder::truncated;
+
+// Order 1 static Huffman coding: each byte is coded with a table
+// selected by the previous byte (0 for the first byte). A context only
+// gets its own table if that's estimated to save more than the table
+// costs to store, compared to coding it with order 0 codes. The other
+// contexts share a fallback table built from the bytes in all of them.
+//
+// This helps most on text, where the previous byte predicts the next 
+// well; on random data it degrades to roughly order 0 coding.
+//
+// Format:
+//
+//   flags          (1 byte, ORDER1)
+//   symbol count   (u64)
+//   context bitmap (32 bytes, bit set if the context has its own table)
+//   fallback table
+//   tables         (one per set bit, in context order)
+//   data           (codes packed MSB first, last byte padded with 0 bits)
+//
+// A table is a 32 byte bitmap of the bytes it codes, followed by the
+// count of each as a varint. Codes are derived from the counts with the
+// same tree building as order 0 coding.
+pub const ORDER1: u8 = 0x80;
+
+#[allow(clippy::redundant_closure)]
+pub fn compress<R: Read + Seek, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| encode(file_in, file_out))
+}
+
+fn encode<R: Read + Seek, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let stats = Stats::collect(&mut file_in, true)?;
+    file_out.write_u8(ORDER1)?;
+    file_out.write_u64(stats.total)?;
+
+    // Choose which contexts get their own table
+    let order0 = build_codes(&stats.order0.map(|count| count as u32));
+    let mut own = [0u8; 32];
+    let mut fallback = [0u32; 256];
+    for cxt in 0..256 {
+        let counts = stats.context(cxt as u8);
+        let own_bits = coded_bits(counts, &build_codes(counts)) + table_size(counts) * 8;
+        if own_bits < coded_bits(counts, &order0) {
+            own[cxt >> 3] |= 1 << (cxt & 7);
+        }
+        else {
+            for (freq, count) in fallback.iter_mut().zip(counts.iter()) {
+                *freq += count;
+            }
+        }
+    }
+    file_out.write_bytes(&own)?;
+
+    // Write tables and generate codes
+    let fallback_codes = write_table(&fallback, &mut file_out)?;
+    let mut codes = vec![None; 256];
+    for (cxt, codes) in codes.iter_mut().enumerate() {
+        if own[cxt >> 3] & (1 << (cxt & 7)) != 0 {
+            *codes = Some(write_table(stats.context(cxt as u8), &mut file_out)?);
+        }
+    }
+
+    file_in.rewind()?;
+
+    let mut packed_codes: u8 = 0;
+    let mut bits: u8 = 0;
+    let mut prev = 0usize;
+
+    let block_size = file_in.capacity();
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+        for byte in block.iter() {
+            let table = codes[prev].as_ref().unwrap_or(&fallback_codes);
+            for bit in table[*byte as usize].iter() {
+                if bits >= 8 {
+                    file_out.write_u8(packed_codes)?;
+                    packed_codes = 0;
+                    bits = 0;
+                }
+                packed_codes = (packed_codes << 1) + bit;
+                bits += 1;
+            }
+            prev = *byte as usize;
+        }
+    }
+    // Write remaining code
+    if bits > 0 {
+        file_out.write_u8(packed_codes << (8 - bits))?;
+    }
+    file_out.flush_buffer()
+}
+
+// Decompress the rest of an order 1 stream, after the symbol count.
+pub(crate) fn decode<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, count: u64) -> io::Result<()> {
+    let own: [u8; 32] = file_in.read_checked()?.ok_or_else(truncated)?;
+
+    let fallback = invert(read_table(&mut file_in)?);
+    let mut tables = Vec::with_capacity(256);
+    for cxt in 0..256 {
+        tables.push(
+            if own[cxt >> 3] & (1 << (cxt & 7)) != 0 {
+                Some(invert(read_table(&mut file_in)?))
+            }
+            else {
+                None
+            }
+        );
+    }
+
+    let mut curr_code: Vec<u8> = Vec::with_capacity(8);
+    let mut prev = 0usize;
+    let mut remaining = count;
+
+    while remaining > 0 {
+        let byte = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+        for j in (0..=7).rev() {
+            curr_code.push((byte >> j) & 1);
+            let table = tables[prev].as_ref().unwrap_or(&fallback);
+            if let Some(byte) = table.get(&curr_code) {
+                file_out.write_u8(*byte)?;
+                prev = *byte as usize;
+                curr_code.clear();
+                remaining -= 1;
+                if remaining == 0 {
+                    break;
+                }
+            }
+        }
+    }
+    file_out.flush()
+}
+
+// Write a table and return its codes.
+fn write_table<W: Write>(freqs: &[u32; 256], file_out: &mut BufWriter<W>) -> io::Result<HuffmanCodeMap> {
+    let mut present = [0u8; 32];
+    for (i, freq) in freqs.iter().enumerate() {
+        if *freq > 0 {
+            present[i >> 3] |= 1 << (i & 7);
+        }
+    }
+    file_out.write_bytes(&present)?;
+    for freq in freqs.iter().filter(|freq| **freq > 0) {
+        write_varint(*freq, file_out)?;
+    }
+    Ok(build_codes(freqs))
+}
+
+// Read a table and return its codes.
+fn read_table<R: Read>(file_in: &mut BufReader<R>) -> io::Result<HuffmanCodeMap> {
+    let present: [u8; 32] = file_in.read_checked()?.ok_or_else(truncated)?;
+    let mut freqs = [0u32; 256];
+    for (i, freq) in freqs.iter_mut().enumerate() {
+        if present[i >> 3] & (1 << (i & 7)) != 0 {
+            *freq = read_varint(file_in)?;
+        }
+    }
+    Ok(build_codes(&freqs))
+}
+
+// Size of a stored table in bytes.
+fn table_size(freqs: &[u32; 256]) -> u64 {
+    let varints = freqs.iter().filter(|freq| **freq > 0).map(|freq| {
+        (32 - freq.leading_zeros() as u64).div_ceil(7)
+    });
+    32 + varints.sum::<u64>()
+}
+
+// Number of bits needed to code bytes with the given frequencies.
+fn coded_bits(freqs: &[u32; 256], codes: &HuffmanCodeMap) -> u64 {
+    codes.iter().zip(freqs.iter()).map(|(code, freq)| *freq as u64 * code.len() as u64).sum()
+}
+
+// Write n 7 bits at a time, low bits first, with the top bit set on
+// all but the last byte.
+fn write_varint<W: Write>(mut n: u32, file_out: &mut BufWriter<W>) -> io::Result<()> {
+    while n >= 0x80 {
+        file_out.write_u8((n as u8 & 0x7F) | 0x80)?;
+        n >>= 7;
+    }
+    file_out.write_u8(n as u8)
+}
+
+fn read_varint<R: Read>(file_in: &mut BufReader<R>) -> io::Result<u32> {
+    let mut n = 0u32;
+    let mut shift = 0;
+    loop {
+        let byte = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+        n |= ((byte & 0x7F) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(n);
+        }
+        shift += 7;
+        if shift >= 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid huffman table"));
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::thread::JoinHandle;
+
+use crate::bytes;
+use crate::bytes::Algorithm;
+use crate::container;
+use crate::error;
+use crate::stats::Sizes;
+
+// Compression and decompression of files on a background thread, for
+// programs that need to show progress and let the user stop a long
+// lpaq1 run without killing the process.
+//
+// Cancelling is cooperative. The job's input fails every read once it's
+// cancelled, and the codec stops and returns that as a read error, which
+// takes as long as coding one buffer of about 1 MB. wait then returns
+// Error::Cancelled in place of the read error, and removes the partial
+// output.
+
+const BUF_SIZE: usize = 1 << 20;
+
+// State shared between a Job and its thread.
+#[derive(Default)]
+struct State {
+    pos:     AtomicU64,  // Position in the input
+    cancel:  AtomicBool, // Set by Job::cancel
+    stopped: AtomicBool, // Set once a read has failed because of it
+}
+
+// A file that records its position in state, and fails reads once the
+// job is cancelled.
+struct Input {
+    file:  File,
+    state: Arc<State>,
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.state.cancel.load(Ordering::Relaxed) {
+            self.state.stopped.store(true, Ordering::Relaxed);
+            return Err(io::Error::other("cancelled"));
+        }
+        let len = self.file.read(buf)?;
+        self.state.pos.fetch_add(len as u64, Ordering::Relaxed);
+        Ok(len)
+    }
+}
+
+// Huffman compression reads its input twice, so progress starts over.
+impl Seek for Input {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = self.file.seek(pos)?;
+        self.state.pos.store(pos, Ordering::Relaxed);
+        Ok(pos)
+    }
+}
+
+/// A compression or decompression running on its own thread, with an
+/// algorithm's default options, as bytes::compress_bytes and
+/// bytes::decompress_bytes do for slices. Dropping a Job without waiting
+/// for it lets it run to the end.
+pub struct Job {
+    state:    Arc<State>,
+    size:     u64,     // Size of the input
+    path_out: PathBuf,
+    thread:   JoinHandle<error::Result<Sizes>>,
+}
+
+impl Job {
+    /// Start compressing the file at path_in to path_out. No file times
+    /// are recorded. Returns an error if either file can't be opened.
+    pub fn compress<P: AsRef<Path>, Q: AsRef<Path>>(path_in: P, path_out: Q, algorithm: Algorithm) -> io::Result<Job> {
+        Job::start(path_in.as_ref(), path_out.as_ref(), move |size, file_in, file_out| {
+            container::compress(file_in, file_out, algorithm, size, container::Times::default(), false,
+                |file_in, file_out| bytes::encode(algorithm, file_in, file_out))
+        })
+    }
+
+    /// Start decompressing the file at path_in to path_out. The job fails
+    /// as bytes::decompress_bytes does if the stream is damaged or needs
+    /// more than max_memory. Returns an error if either file can't be
+    /// opened.
+    pub fn decompress<P: AsRef<Path>, Q: AsRef<Path>>(path_in: P, path_out: Q, algorithm: Algorithm,
+        max_memory: Option<u64>) -> io::Result<Job> {
+        Job::start(path_in.as_ref(), path_out.as_ref(), move |_, file_in, file_out| {
+            container::decompress(file_in, file_out, algorithm, |header, file_in, file_out| {
+                bytes::decode_stream(algorithm, max_memory, header, file_in, file_out)
+            })
+        })
+    }
+
+    fn start<F>(path_in: &Path, path_out: &Path, codec: F) -> io::Result<Job>
+    where
+        F: FnOnce(u64, BufReader<Input>, BufWriter<File>) -> error::Result<Sizes> + Send + 'static,
+    {
+        let file = File::open(path_in)?;
+        let size = file.metadata()?.len();
+        let file_out = File::create(path_out)?;
+        let state = Arc::new(State::default());
+        let file_in = Input { file, state: Arc::clone(&state) };
+        let thread = thread::spawn(move || {
+            codec(size, BufReader::with_capacity(BUF_SIZE, file_in), BufWriter::with_capacity(BUF_SIZE, file_out))
+        });
+        Ok(Job { state, size, path_out: path_out.to_path_buf(), thread })
+    }
+
+    /// The number of bytes of input read so far, and the size of the
+    /// input.
+    pub fn progress(&self) -> (u64, u64) {
+        (self.state.pos.load(Ordering::Relaxed).min(self.size), self.size)
+    }
+
+    /// Ask the job to stop. wait then returns a Cancelled error, unless
+    /// the job had already read all of its input.
+    pub fn cancel(&self) {
+        self.state.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// True once the job has finished, and wait won't block.
+    pub fn is_finished(&self) -> bool {
+        self.thread.is_finished()
+    }
+
+    /// Wait for the job to finish, and return the number of bytes read
+    /// and written. If it was cancelled or failed, the output file is
+    /// removed.
+    pub fn wait(self) -> error::Result<Sizes> {
+        let result = self.thread.join().unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+        let result = if self.state.stopped.load(Ordering::Relaxed) { Err(error::Error::Cancelled) } else { result };
+        if result.is_err() {
+            let _ = fs::remove_file(&self.path_out);
+        }
+        result
+    }
+}
@@ -0,0 +1,57 @@
+//! Compression algorithms: LZ77, LZW and flzp, symbol ranking, the
+//! context mixing compressors fpaq and lpaq1, PPM, rANS and tANS,
+//! huffman coding, the Burrows-Wheeler transform, a few transforms that
+//! help them, segmentation of mixed files by content type, and lpaq1
+//! over sorted blocks.
+//!
+//! Each algorithm reads from a `BufReader` over any reader and writes
+//! to a `BufWriter` over any writer, so files, in-memory buffers, pipes
+//! and sockets all work. Two need to seek: huffman coding reads its
+//! input twice, and lpaq1 compression goes back to fill in its header
+//! once the output is written.
+//!
+//! Each returns the `stats::Sizes` read and written, or an
+//! `error::Error`. An I/O error partway through stops the algorithm
+//! where it is and is returned, rather than panicking.
+//!
+//! The command line wraps each stream in a `container` header naming
+//! the algorithm and the original size, which decompressing checks.
+//!
+//! `bytes::compress_bytes` and `bytes::decompress_bytes` run any
+//! algorithm with its default options on slices in memory,
+//! `framing` writes and reads streams embedded in other formats, and
+//! `ari::message::MessageCodec` codes a sequence of small messages with
+//! a model kept warm between them. `job::Job` compresses or decompresses
+//! a file on a background thread that can be polled and cancelled.
+//! `blocks::IndexedReader` decompresses ranges of a stream compressed in
+//! blocks with an index, without the rest. `crc32::Crc32` and
+//! `xxh64::Xxh64` hash data fed to them in pieces. `volume` writes a
+//! stream as a series of size-limited files and reads them back as one.
+//! `cpu` picks the vector instructions the hottest loops run with, and
+//! `temp` creates temporary files no other call is using.
+
+pub mod bufio;
+pub mod error;
+pub mod lz;
+pub mod ari;
+pub mod huffman;
+pub mod bwt;
+pub mod crc32;
+pub mod xxh64;
+pub mod stats;
+pub mod stored;
+pub mod container;
+pub mod remap;
+pub mod split;
+pub mod segment;
+pub mod sortrans;
+pub mod blocks;
+pub mod framing;
+pub mod delta;
+pub mod patch;
+pub mod throttle;
+pub mod bytes;
+pub mod job;
+pub mod volume;
+pub mod cpu;
+pub mod temp;
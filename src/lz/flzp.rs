@@ -126,25 +126,127 @@ where output_match() is defined
     Output BUF[P - 1]  (as a literal)
   LEN <- 0
 
+Stream format
+
+This implementation adds a 2 byte stream header, an end of stream 
+marker and optional block checksums to the format above:
+
+  effort level (1 byte, see below)
+  flags        (1 byte, bit 0 set if blocks have a CRC)
+  blocks       (32 byte header, 1 byte context length L, data, EOB, 
+                then a little endian CRC-32 of the block's output if 
+                enabled)
+  end marker   (32 bytes of 0xFF)
+  block stats  (optional: the input and output size of each block as
+                u32s, then the block count (u32) and "BLKS")
+
+A block's output size covers its header, data, EOB and CRC. Decoders 
+stop at the end marker, so the block stats are ignored when 
+decompressing, including by decoders older than them.
+
+L may be 2 to 8, and H is the hash of exactly the last L bytes. With 
+S = ceil(20/L) (20 = log2(HN)), M = 3 * 2^S shifts a byte out of H 
+after L more bytes whenever ceil(20/S) = L, which holds for L = 2, 3, 
+4, 5 and 7. This gives M = 96 for the default L = 4. Otherwise (L = 6 
+and 8), M = 3 * 2^floor(20/L) would keep a byte in H for longer, so 
+update_hash also takes out the byte leaving the context:
+
+  H <- (H * M) + c - BUF[P - L] * D (mod HN), where D = M^L (mod HN)
+
+D is 0 in the first case, so the same update serves both. Longer 
+contexts tend to predict better on large text, shorter ones on binary 
+data.
+
+A real header always has at least 33 0 bits, so a header with none, 
+which leaves no code for EOB, marks the end of the stream. Input that 
+ends anywhere else is truncated, and is reported as an error rather 
+than silently producing short output.
+
+Effort levels
+
+The effort level selects how the predicted match position (HT[H] 
+above) is found. Both the compressor and decompressor make the same 
+prediction:
+
+  Greedy (0): HT[H], as described above.
+  Normal (1): HT keeps the last 2 positions for each hash. The most 
+              recent one whose preceding L bytes equal the last L bytes 
+              output is used, falling back to the most recent position 
+              if neither matches. This avoids most false predictions 
+              caused by hash collisions.
+  Max    (2): As Normal, but first try a second table indexed by a hash 
+              of the last 8 bytes (H2 <- H2 * 24 + c, as in lpaq1's 
+              match model), used if its preceding 8 bytes match.
+
 */
 
-use std::fs::File;
+use std::io;
 use std::io::Write;
-use std::io::BufReader;
-use std::io::BufWriter;
+use std::io::Read;
+use std::io::ErrorKind;
 use std::io::Seek;
 use std::io::SeekFrom;
+use std::io::BufReader;
+use std::io::BufWriter;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::crc32::Crc32;
 
 const BUF_SIZE: usize = 1 << 22;
 const HT_SIZE: usize = BUF_SIZE / 4;
+pub const MIN_CXT_LEN: usize = 2;     // Context length range covered by hash
+pub const MAX_CXT_LEN: usize = 8;
+pub const DEFAULT_CXT_LEN: usize = 4;
+const LONG_CXT_LEN: usize = 8; // Context length covered by long hash
+const FLAG_CRC: u8 = 1;        // Blocks are followed by a CRC-32
+const END_MARKER: [u8; 32] = [0xFF; 32];
+const STATS_TAG: [u8; 4] = *b"BLKS";
+
+/// How much work goes into predicting matches. Higher levels give better
+/// compression at some cost in speed.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Effort {
+    Greedy,
+    Normal,
+    Max,
+}
+
+/// Approximate memory needed to compress or decompress at an effort
+/// level: the buffer, a hash table per effort level, and I/O buffers.
+pub fn memory(effort: Effort) -> u64 {
+    let tables = match effort {
+        Effort::Greedy => 1,
+        Effort::Normal => 2,
+        Effort::Max    => 3,
+    };
+    (BUF_SIZE + tables * HT_SIZE * 4) as u64 + (3 << 20)
+}
+
+impl Effort {
+    fn from_u8(level: u8) -> Option<Effort> {
+        match level {
+            0 => Some(Effort::Greedy),
+            1 => Some(Effort::Normal),
+            2 => Some(Effort::Max),
+            _ => None,
+        }
+    }
+}
 
 struct Buffer {
     buf:     Vec<u8>,    // Rotating buffer of BUF_SIZE bytes
     ht:      Vec<u32>,   // Hash table: hash -> matched context
+    ht_prev: Vec<u32>,   // Hash table: hash -> previous matched context (Normal, Max)
+    ht_long: Vec<u32>,   // Hash table: long hash -> matched context (Max)
+    effort:  Effort,     // Prediction method
     enc:     [u8; 256],  // Encoding table: -1 = LITERAL, 0 = EOB, 1..max_len = m_pos
     hash:    usize,      // Context hash
+    hash_l:  usize,      // Long context hash
+    cxt_len: usize,      // Context length covered by hash
+    mult:    usize,      // Hash multiplier
+    drop:    usize,      // mult^cxt_len, to remove the byte leaving the context
     m_pos:   usize,      // Position of match
     m_len:   usize,      // Length of match
     max_len: usize,      // Max length
@@ -152,12 +254,21 @@ struct Buffer {
 }
 
 impl Buffer {
-    fn new() -> Buffer {
+    fn new(effort: Effort) -> Buffer {
+        let prev_size = if effort == Effort::Greedy { 0 } else { HT_SIZE };
+        let long_size = if effort == Effort::Max { HT_SIZE } else { 0 };
         Buffer {
             buf:     vec![0; BUF_SIZE],
             ht:      vec![0; HT_SIZE],
+            ht_prev: vec![0; prev_size],
+            ht_long: vec![0; long_size],
+            effort,
             enc:     [0; 256],
             hash:    0,
+            hash_l:  0,
+            cxt_len: DEFAULT_CXT_LEN,
+            mult:    96,
+            drop:    0,
             m_pos:   0,
             m_len:   0,
             max_len: 0,
@@ -167,46 +278,114 @@ impl Buffer {
 
     fn update(&mut self, byte: u8) {
         // Map hash of last L bytes to current buffer position
+        if self.effort != Effort::Greedy {
+            self.ht_prev[self.hash] = self.ht[self.hash];
+        }
         self.ht[self.hash] = self.p as u32;      
+        if self.effort == Effort::Max {
+            self.ht_long[self.hash_l] = self.p as u32;
+            self.hash_l = (self.hash_l * 24 + byte as usize) % HT_SIZE;
+        }
         // Update hash                                   
-        self.hash = (self.hash * 96 + byte as usize) % HT_SIZE; 
+        let out = self.buf[self.p.wrapping_sub(self.cxt_len) % BUF_SIZE] as usize;
+        self.hash = (self.hash * self.mult + byte as usize).wrapping_sub(out * self.drop) % HT_SIZE; 
         // Update buffer
         self.buf[self.p % BUF_SIZE] = byte; 
         self.p += 1;                                    
     }
 
-    fn update_and_maybe_flush(&mut self, byte: u8, file_out: &mut BufWriter<File>) {
+    // Set the context length for the following bytes. A multiplier with
+    // s low zero bits shifts a byte out of the hash after ceil(bits/s)
+    // bytes, which no s makes 6 or 8 for 20 bits. For those the shift is
+    // smaller and the byte len back is subtracted instead.
+    fn set_cxt_len(&mut self, len: usize) {
+        assert!((MIN_CXT_LEN..=MAX_CXT_LEN).contains(&len));
+        let bits = HT_SIZE.trailing_zeros() as usize;
+        let mut shift = bits.div_ceil(len);
+        if bits.div_ceil(shift) != len {
+            shift = bits / len;
+        }
+        self.cxt_len = len;
+        self.mult = 3 << shift;
+        self.drop = (0..len).fold(1, |d, _| d * self.mult % HT_SIZE);
+    }
+
+    fn update_and_maybe_flush<W: Write>(&mut self, byte: u8, file_out: &mut BufWriter<W>) -> io::Result<()> {
         self.update(byte);   
         // Flush buffer if full                       
-        if (self.p % BUF_SIZE) == 0 {  
-            file_out.write_all(&self.buf[0..BUF_SIZE]).unwrap();                                    
+        if self.p.is_multiple_of(BUF_SIZE) {  
+            file_out.write_bytes(&self.buf[0..BUF_SIZE])?;                                    
         }                                           
+        Ok(())
     }
 
-    fn flush(&mut self, file_out: &mut BufWriter<File>) {
+    fn flush<W: Write>(&mut self, file_out: &mut BufWriter<W>) -> io::Result<()> {
         // Flush remaining bytes
-        if (self.p % BUF_SIZE) != 0 {  
-            file_out.write_all(&self.buf[0..(self.p % BUF_SIZE)]).unwrap();                                      
+        if !self.p.is_multiple_of(BUF_SIZE) {  
+            file_out.write_bytes(&self.buf[0..(self.p % BUF_SIZE)])?;                                      
         }                      
+        Ok(())
+    }
+
+    // Position of the byte predicted to come next.
+    fn predict(&self) -> usize {
+        match self.effort {
+            Effort::Greedy => {
+                self.ht[self.hash] as usize
+            }
+            Effort::Normal => {
+                self.verified_match()
+            }
+            Effort::Max => {
+                let pos = self.ht_long[self.hash_l] as usize;
+                if self.context_matches(pos, LONG_CXT_LEN) {
+                    pos
+                }
+                else {
+                    self.verified_match()
+                }
+            }
+        }
+    }
+
+    // Most recent position for the context hash whose context matches, 
+    // or the most recent one if neither does.
+    fn verified_match(&self) -> usize {
+        let recent = self.ht[self.hash] as usize;
+        let prev = self.ht_prev[self.hash] as usize;
+        if !self.context_matches(recent, self.cxt_len) && self.context_matches(prev, self.cxt_len) {
+            prev
+        }
+        else {
+            recent
+        }
+    }
+
+    // Check if the len bytes before pos match the last len bytes.
+    fn context_matches(&self, pos: usize, len: usize) -> bool {
+        (1..=len).all(|i| 
+            self.buf[pos.wrapping_sub(i) % BUF_SIZE] == self.buf[self.p.wrapping_sub(i) % BUF_SIZE]
+        )
     }
 
-    fn output_match(&mut self, file_out: &mut BufWriter<File>) {
+    fn output_match<W: Write>(&mut self, file_out: &mut BufWriter<W>) -> io::Result<()> {
         if self.m_len > 0 {
             if self.m_len == 1 {
                 // Output literal
-                file_out.write_u8(self.buf[(self.p - 1) % BUF_SIZE]);
+                file_out.write_u8(self.buf[(self.p - 1) % BUF_SIZE])?;
             } 
             else {
                 // Output match
-                file_out.write_u8(self.enc[self.m_len]);
+                file_out.write_u8(self.enc[self.m_len])?;
             }
             self.m_len = 0;
         }
+        Ok(())
     }
 
-    fn compress(&mut self, byte: u8, file_out: &mut BufWriter<File>) {
+    fn compress<W: Write>(&mut self, byte: u8, file_out: &mut BufWriter<W>) -> io::Result<()> {
         if self.m_len == 0 {
-            self.m_pos = self.ht[self.hash] as usize;
+            self.m_pos = self.predict();
         }
 
         // If subsequent byte matches, increase match length
@@ -215,33 +394,53 @@ impl Buffer {
             self.m_len += 1;
         } 
         else {
-            self.output_match(file_out);
-            self.m_pos = self.ht[self.hash] as usize;
+            self.output_match(file_out)?;
+            self.m_pos = self.predict();
             if self.buf[self.m_pos % BUF_SIZE] == byte {
                 self.m_len = 1;
             } 
             else {
-                file_out.write_u8(byte);
+                file_out.write_u8(byte)?;
             }
         }
         self.update(byte);
+        Ok(())
     }
 }
 
-pub fn flzp_compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let mut buf = Buffer::new();
+pub fn flzp_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, 
+    effort: Effort, crc: bool, cxt_len: usize, block_stats: bool) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| {
+        let capacity = file_out.capacity();
+        let file_out = BufWriter::with_capacity(capacity, CountingWriter::new(file_out));
+        compress(file_in, file_out, effort, crc, cxt_len, block_stats)
+    })
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<CountingWriter<W>>, 
+    effort: Effort, crc: bool, cxt_len: usize, block_stats: bool) -> io::Result<()> {
+    // Bytes written so far, including those still in the buffer.
+    let position = |file_out: &BufWriter<CountingWriter<W>>| {
+        file_out.get_ref().count() + file_out.buffer().len() as u64
+    };
+    let mut stats = Vec::new();
+    let mut buf = Buffer::new(effort);
+    buf.set_cxt_len(cxt_len);
+    file_out.write_u8(effort as u8)?;
+    file_out.write_u8(if crc { FLAG_CRC } else { 0 })?;
+    let mut block = Vec::with_capacity(1 << 16);
     
     loop {
         // Pass 1
         let mut dec = [0u8; 32];
-        let mut block_size = 0i64;
+        block.clear();
         buf.max_len = 255;
         
         // Stop if 32 or less unused bytes remain or if block size is greater than 64K.
-        while buf.max_len > 32 && block_size < (1 << 16) {
-            match file_in.read_u8_checked() {
+        while buf.max_len > 32 && block.len() < (1 << 16) {
+            match file_in.read_u8_checked()? {
                 Some(byte) => {
-                    block_size += 1;
+                    block.push(byte);
                     // If byte has not been encountered  
                     // before, store in dec.
                     if (dec[byte as usize >> 3] & (1 << (byte & 7))) == 0 {
@@ -254,7 +453,7 @@ pub fn flzp_compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>
                 }
             }
         }
-        if block_size < 1 { 
+        if block.is_empty() { 
             break; 
         }
 
@@ -266,26 +465,81 @@ pub fn flzp_compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>
                 j += 1;
             }
         }
-        assert!(j == (buf.max_len + 1) as usize);
+        assert!(j == buf.max_len + 1);
 
         // Pass 2
-        // Seek back to beginning of block
-        file_in.seek(SeekFrom::Current(-block_size)).unwrap();
-
-        // Output decoding table as header
-        file_out.write_all(&dec[..]).unwrap();
+        // Output decoding table and context length as header
+        let start = position(&file_out);
+        file_out.write_bytes(&dec[..])?;
+        file_out.write_u8(buf.cxt_len as u8)?;
 
         // Compress
-        for _ in 0..block_size {
-            buf.compress(file_in.read_u8(), &mut file_out);
+        let mut block_crc = Crc32::new();
+        for &byte in block.iter() {
+            block_crc.update_u8(byte);
+            buf.compress(byte, &mut file_out)?;
         }
 
         // Output remaining matches
-        buf.output_match(&mut file_out);
+        buf.output_match(&mut file_out)?;
 
         // End of block code
-        file_out.write_u8(buf.enc[0]);
+        file_out.write_u8(buf.enc[0])?;
+        if crc {
+            file_out.write_u32(block_crc.value())?;
+        }
+        stats.push((block.len() as u32, (position(&file_out) - start) as u32));
+    }
+    file_out.write_bytes(&END_MARKER)?;
+
+    if block_stats {
+        for &(input, output) in stats.iter() {
+            file_out.write_u32(input)?;
+            file_out.write_u32(output)?;
+        }
+        file_out.write_u32(stats.len() as u32)?;
+        file_out.write_bytes(&STATS_TAG)?;
+    }
+    file_out.flush_buffer()
+}
+
+/// The input and output size of each block of the flzp stream from
+/// file_in's position to end, from the block stats at its end, or None
+/// if it has none. Returns an InvalidData error if the block stats don't
+/// add up to the stream.
+pub fn block_stats<R: Read + Seek>(file_in: &mut R, end: u64) -> error::Result<Option<Vec<Sizes>>> {
+    let invalid = || invalid_data("damaged flzp block stats".to_string());
+    let start = file_in.stream_position()?;
+    let len = end.saturating_sub(start);
+    if len < 8 {
+        return Ok(None);
+    }
+    let mut tail = [0u8; 8];
+    file_in.seek(SeekFrom::Start(end - 8))?;
+    file_in.read_exact(&mut tail)?;
+    if tail[4..] != STATS_TAG {
+        return Ok(None);
+    }
+    let count = u32::from_le_bytes(tail[..4].try_into().unwrap()) as u64;
+    let stats_len = count * 8 + 8;
+    if stats_len > len {
+        return Err(invalid().into());
     }
+
+    let mut entries = vec![0u8; (count * 8) as usize];
+    file_in.seek(SeekFrom::Start(start + len - stats_len))?;
+    file_in.read_exact(&mut entries)?;
+    let stats = entries.chunks_exact(8).map(|entry| Sizes {
+        input:  u32::from_le_bytes(entry[..4].try_into().unwrap()) as u64,
+        output: u32::from_le_bytes(entry[4..].try_into().unwrap()) as u64,
+    }).collect::<Vec<Sizes>>();
+
+    // The stream header, the blocks and the end marker come first.
+    let blocks = stats.iter().map(|sizes| sizes.output).sum::<u64>();
+    if 2 + blocks + END_MARKER.len() as u64 + stats_len != len {
+        return Err(invalid().into());
+    }
+    Ok(Some(stats))
 }
 
 #[derive(PartialEq, Eq)]
@@ -294,18 +548,47 @@ enum State {
     Data,
 } 
 
-pub fn flzp_decompress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
-    let mut buf = Buffer::new();
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    invalid_data("truncated flzp stream".to_string())
+}
+
+// Decompress an flzp stream, returning an InvalidData error if the 
+// header isn't recognized, the stream ends before the end marker, or 
+// a block fails its CRC.
+#[allow(clippy::redundant_closure)]
+pub fn flzp_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let [level, flags] = file_in.read_checked::<2>()?.ok_or_else(truncated)?;
+    let effort = Effort::from_u8(level).ok_or_else(|| 
+        invalid_data(format!("unknown flzp effort level {}", level))
+    )?;
+    if flags & !FLAG_CRC != 0 {
+        return Err(invalid_data(format!("unknown flzp flags {:#04x}", flags)));
+    }
+    let crc = flags & FLAG_CRC != 0;
+
+    let mut buf = Buffer::new(effort);
+    let mut block_crc = Crc32::new();
     let mut state = State::Header;
     let mut dec = [0i32; 256];
 
     loop {
         if state == State::Header {
+            let header = file_in.read_checked::<32>()?.ok_or_else(truncated)?;
+            if header == END_MARKER {
+                break;
+            }
             // Initialize max_len to -1 to store first 0 bit as end of block
             // and subsequent 0 bits as match lengths
             let mut max_len = -1i32;
-            for i in 0..32 {
-                let byte = file_in.read_u8();
+            for (i, &byte) in header.iter().enumerate() {
                 // Read bits
                 for j in 0..8 {
                     dec[i*8 + j] = 
@@ -320,32 +603,44 @@ pub fn flzp_decompress(mut file_in: BufReader<File>, mut file_out: BufWriter<Fil
                     }
                 }
             }
+            let cxt_len = file_in.read_u8_checked()?.ok_or_else(truncated)? as usize;
+            if !(MIN_CXT_LEN..=MAX_CXT_LEN).contains(&cxt_len) {
+                return Err(invalid_data(format!("unsupported flzp context length {}", cxt_len)));
+            }
+            buf.set_cxt_len(cxt_len);
             state = State::Data;
         } 
         else {
-            match file_in.read_u8_checked() {
-                Some(mut byte) => {
-                    let d = dec[byte as usize];
-                    // End of block
-                    if d == 0 { 
-                        state = State::Header; 
-                    }
-                    else if d < 0 {
-                        buf.update_and_maybe_flush(byte, &mut file_out);
-                    } 
-                    else {
-                        let mch = buf.ht[buf.hash] as usize;
-                        for i in 0..d {
-                            byte = buf.buf[(mch + i as usize) % BUF_SIZE];
-                            buf.update_and_maybe_flush(byte, &mut file_out);
-                        }
+            let mut byte = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+            let d = dec[byte as usize];
+            // End of block
+            if d == 0 { 
+                if crc {
+                    let expected = file_in.read_u32_checked()?.ok_or_else(truncated)?;
+                    if block_crc.value() != expected {
+                        return Err(invalid_data(format!(
+                            "flzp block CRC mismatch (expected {:08x}, got {:08x})", 
+                            expected, block_crc.value()
+                        )));
                     }
+                    block_crc = Crc32::new();
                 }
-                None => {
-                    break;
+                state = State::Header; 
+            }
+            else if d < 0 {
+                block_crc.update_u8(byte);
+                buf.update_and_maybe_flush(byte, &mut file_out)?;
+            } 
+            else {
+                let mch = buf.predict();
+                for i in 0..d {
+                    byte = buf.buf[(mch + i as usize) % BUF_SIZE];
+                    block_crc.update_u8(byte);
+                    buf.update_and_maybe_flush(byte, &mut file_out)?;
                 }
             }
         }
     }
-    buf.flush(&mut file_out);
+    buf.flush(&mut file_out)?;
+    file_out.flush()
 }
@@ -1,8 +1,14 @@
+use std::io;
+use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
 use std::io::BufReader;
 use std::io::BufWriter;
-use std::fs::File;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::lz::window::Window;
 
 struct Match {
     pub offset: u16,
@@ -17,51 +23,41 @@ impl Match {
     }
 }
 
-struct Window {
-    data: Vec<u8>,
-    pos:  usize,
-    size: usize,
-}
-impl Window {
-    fn new(size: usize) -> Self {
-        Self {
-            data: vec![0; size],
-            pos:  0,
-            size,
-        }
-    }
-
-    fn add_byte(&mut self, byte: u8) {
-        self.data[self.pos % self.size] = byte;
-        self.pos += 1;
-    }
-
-    fn add_bytes(&mut self, bytes: &[u8]) {
-        for byte in bytes.iter() {
-            self.add_byte(*byte);
-        }
-    }
-
-    fn get_byte(&self, pos: usize) -> u8 {
-        self.data[pos % self.size]
-    }
+// A match is coded as a 16 bit token holding a window offset in the high
+// WINDOW_BITS and a length in the low LEN_BITS. A literal is coded as a 
+// zero byte followed by the literal, so offsets start at MIN_OFFSET to 
+// keep the high byte of every match token nonzero.
+//
+// The stream starts with a 2 byte header storing WINDOW_BITS and LEN_BITS,
+// which the decoder uses to size its window and split tokens. The high bits
+// of the first byte hold flags. OVERLAP marks a stream whose matches may
+// run into the bytes they add; older decoders see it as an unsupported
+// token format rather than misdecoding those matches.
+const WINDOW_BITS: u8 = 11;
+const OVERLAP:     u8 = 0x80;
+const FLAGS:       u8 = 0xF0;
+const LEN_BITS:    u8 = 5;
+const WINDOW_SIZE: usize = 1 << WINDOW_BITS;
+const MAX_LEN:     u16 = (1 << LEN_BITS) - 1;
+const MIN_OFFSET:  usize = 256 >> LEN_BITS;
+const MAX_MATCHES: usize = 512;
 
-    fn len(&self) -> usize {
-        self.data.len()
-    }
+pub fn lz77_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| Lz77::new(file_in, file_out).compress())
 }
 
-const WINDOW_SIZE: usize = 2048;
-const MAX_MATCHES: usize = 512;
+pub fn lz77_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| Lz77::new(file_in, file_out).decompress())
+}
 
-pub struct Lz77 {
+struct Lz77<R: Read, W: Write> {
     window:   Window,
     buf_pos:  usize,
-    file_in:  BufReader<File>,
-    file_out: BufWriter<File>,
+    file_in:  BufReader<R>,
+    file_out: BufWriter<W>,
 }
-impl Lz77 {
-    pub fn new(file_in: BufReader<File>, file_out: BufWriter<File>) -> Lz77 {
+impl<R: Read, W: Write> Lz77<R, W> {
+    fn new(file_in: BufReader<R>, file_out: BufWriter<W>) -> Lz77<R, W> {
         Lz77 {
             window:   Window::new(WINDOW_SIZE),
             buf_pos:  0,
@@ -70,24 +66,21 @@ impl Lz77 {
         }
     }
 
-    pub fn compress(&mut self) {
-        self.file_in.fill_buffer();
+    fn compress(&mut self) -> io::Result<()> {
+        self.file_out.write_u8(WINDOW_BITS | OVERLAP)?;
+        self.file_out.write_u8(LEN_BITS)?;
+
+        if self.file_in.fill_buffer()?.is_eof() {
+            return self.file_out.flush_buffer();
+        }
         let mut matches = Vec::<Match>::with_capacity(MAX_MATCHES);
         loop {
-            for i in (8..self.window.len()).rev() {
-                if self.window.get_byte(i) == self.file_in.buffer()[self.buf_pos] {
-                    let mut m = Match::new(i as u16, 1);
-
-                    for c in self.file_in.buffer().iter().skip(self.buf_pos + 1).take(30) {
-                        if *c == self.window.get_byte((m.offset + m.len) as usize) {
-                            m.len += 1;
-                        } 
-                        else { 
-                            break; 
-                        }  
-                    }
-                    if m.len > 1 {
-                        matches.push(m);
+            let pending = &self.file_in.buffer()[self.buf_pos..];
+            for i in (MIN_OFFSET..self.window.len()).rev() {
+                if self.window.get_byte(i) == pending[0] {
+                    let len = self.window.match_len(self.window.dist(i), pending, MAX_LEN as usize);
+                    if len > 1 {
+                        matches.push(Match::new(i as u16, len as u16));
                     }
                 }
                 if matches.len() == MAX_MATCHES {
@@ -99,69 +92,116 @@ impl Lz77 {
             });
 
             if let Some(m) = best_match {
-                let ptr = ((m.offset & 0x7FF) << 5) + (m.len & 31);
-                self.file_out.write_u8_forced(ptr >> 8);
-                self.file_out.write_u8_forced(ptr & 0x00FF);
+                let ptr = (m.offset << LEN_BITS) + m.len;
+                self.file_out.write_u8_forced(ptr >> 8)?;
+                self.file_out.write_u8_forced(ptr & 0x00FF)?;
 
                 let match_bytes = self.buf_pos..self.buf_pos + m.len as usize;
                 self.window.add_bytes(&self.file_in.buffer()[match_bytes]); 
 
-                if self.advance(m.len as usize).is_eof() { 
+                if self.advance(m.len as usize)?.is_eof() { 
                     break; 
                 } 
             }
             else {
-                self.file_out.write_u8(0);
-                self.file_out.write_u8(self.file_in.buffer()[self.buf_pos]);
+                self.file_out.write_u8(0)?;
+                self.file_out.write_u8(self.file_in.buffer()[self.buf_pos])?;
                 self.window.add_byte(self.file_in.buffer()[self.buf_pos]);
                 
-                if self.advance(1).is_eof() { 
+                if self.advance(1)?.is_eof() { 
                     break; 
                 }
             }
             matches.clear();
         } 
-        self.file_out.flush_buffer();
+        self.file_out.flush_buffer()
     }
 
-    pub fn decompress(&mut self) { 
-        self.file_in.fill_buffer(); 
-        let mut pending = Vec::new();
+    // Decompress an lz77 stream, returning an InvalidData error if the 
+    // header describes a token format this decoder can't read or the 
+    // stream ends partway through a token.
+    //
+    // Matches are decoded by copying from the window one byte at a time, 
+    // adding each byte before reading the next, so the window is the only
+    // state needed and overlapping matches repeat the bytes they add.
+    fn decompress(&mut self) -> io::Result<()> { 
+        if self.file_in.fill_buffer()?.is_eof() {
+            return Ok(());
+        }
+        let (len_bits, overlap) = self.read_header()?;
+        if self.file_in.buffer().is_empty() && self.file_in.fill_buffer()?.is_eof() {
+            return Ok(());
+        }
+        let len_mask = (1 << len_bits) - 1;
+
         loop {
-            let mut ptr = (self.file_in.buffer()[self.buf_pos] as u16) * 256;
-            if self.advance(1).is_eof() { 
-                break; 
+            let high = self.file_in.buffer()[self.buf_pos];
+            if self.advance(1)?.is_eof() { 
+                return Err(io::Error::new(ErrorKind::InvalidData, "truncated lz77 token"));
             }
-            ptr += self.file_in.buffer()[self.buf_pos] as u16;
+            let low = self.file_in.buffer()[self.buf_pos];
 
-            if (ptr >> 8) == 0 {
-                self.file_out.write_u8_forced(ptr & 0x00FF);
-                self.window.add_byte(self.file_in.buffer()[self.buf_pos]);
+            if high == 0 {
+                self.file_out.write_u8(low)?;
+                self.window.add_byte(low);
             } 
             else { 
-                let m = Match::new(ptr >> 5, ptr & 31);
-
-                for i in 0..m.len {
-                    let byte = self.window.get_byte((m.offset + i) as usize);
-                    self.file_out.write_u8(byte);
-                    pending.push(byte);
+                let ptr = u16::from_be_bytes([high, low]);
+                let m = Match::new(ptr >> len_bits, ptr & len_mask);
+
+                let dist = self.window.dist(m.offset as usize);
+                if !overlap && m.len as usize > dist {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        "overlapping lz77 match in a stream without the overlap flag"
+                    ));
                 }
-                self.window.add_bytes(&pending);
-                pending.clear();
+                self.window.copy_match(dist, m.len as usize, &mut self.file_out)?;
             }
-            if self.advance(1).is_eof() { 
+            if self.advance(1)?.is_eof() { 
                 break; 
             }
         }
-        self.file_out.flush_buffer();
+        self.file_out.flush_buffer()?;
+        Ok(())
+    }
+
+    // Read and validate the stream header, allocate a window of the size
+    // it specifies and return the number of length bits in a match token.
+    // The header may be split across reads, so it's read with 
+    // read_checked, and tokens start at the front of the buffer after it.
+    // Returns the length bits and whether matches may overlap.
+    fn read_header(&mut self) -> io::Result<(u8, bool)> {
+        let [first, len_bits] = self.file_in.read_checked::<2>()?.ok_or_else(|| 
+            io::Error::new(ErrorKind::InvalidData, "truncated lz77 header")
+        )?;
+        self.buf_pos = 0;
+
+        let flags = first & FLAGS;
+        if flags & !OVERLAP != 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown lz77 header flags 0x{:02x}", flags)
+            ));
+        }
+        let window_bits = first & !FLAGS;
+
+        if window_bits < 1 || len_bits < 1 || window_bits as u32 + len_bits as u32 != 16 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported lz77 token format ({} window bits, {} length bits)", window_bits, len_bits)
+            ));
+        }
+        self.window = Window::new(1 << window_bits);
+        Ok((len_bits, flags & OVERLAP != 0))
     }
 
-    fn advance(&mut self, len: usize) -> BufferState {
+    fn advance(&mut self, len: usize) -> io::Result<BufferState> {
         self.buf_pos += len; 
         if self.buf_pos >= self.file_in.buffer().len() {
             self.buf_pos = 0;
-            return self.file_in.fill_buffer()
+            return self.file_in.fill_buffer();
         }
-        BufferState::NotEmpty
+        Ok(BufferState::NotEmpty)
     }
 }
\ No newline at end of file
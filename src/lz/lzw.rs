@@ -1,80 +1,193 @@
 use std::collections::HashMap;
-use std::fs::File;
+use std::io;
 use std::io::Write;
+use std::io::Read;
+use std::io::ErrorKind;
 use std::io::BufReader;
 use std::io::BufWriter;
 
 use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
 
 const MAX_CODE: u16 = 65535;
 
-pub fn lzw_compress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
+/// Approximate memory needed to compress size_in bytes. The dictionary
+/// keeps every string it codes until it fills, and they add up to at most
+/// the input. Decompressing needs little memory.
+pub fn memory(size_in: u64) -> u64 {
+    (8 << 20) + size_in
+}
+
+#[allow(clippy::redundant_closure)]
+pub fn lzw_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
     let mut dict_code = 256;
 
+    // Only ever looked up, never iterated, so its order can't affect
+    // the output.
     let mut dict = (0..256)
     .map(|i| (vec![i as u8], i))
     .collect::<HashMap<Vec<u8>, u16>>();
     
-    let mut string = vec![file_in.read_u8()]; 
+    // Empty input gives an empty stream.
+    let mut string = match file_in.read_u8_checked()? {
+        Some(byte) => vec![byte],
+        None => return Ok(()),
+    };
 
     loop {
         while dict.contains_key(&string) {
-            if let Some(byte) = file_in.read_u8_checked() {
+            if let Some(byte) = file_in.read_u8_checked()? {
                 string.push(byte); 
             }
             else {
                 // EOF reached.
                 // Current string is guaranteed to be in dictionary.
-                file_out.write_u16(*dict.get(&string).unwrap());
-                file_out.flush().unwrap();
-                return;
+                file_out.write_u16(*dict.get(&string).unwrap())?;
+                return file_out.flush();
             }  
         }
         dict.insert(string.clone(), dict_code); 
         dict_code += 1;
 
         let last_char = string.pop().unwrap();
-        file_out.write_u16(*dict.get(&string).unwrap());
+        file_out.write_u16(*dict.get(&string).unwrap())?;
 
         string.clear();
         string.push(last_char); 
 
-        if dict_code >= MAX_CODE {
+        if dict_code == MAX_CODE {
             dict_code = 256;
             dict.retain(|_, i| *i < 256);
         }
     }
 }
 
-pub fn lzw_decompress(mut file_in: BufReader<File>, mut file_out: BufWriter<File>) {
+// Decoder dictionary. Each entry is stored as a (prefix code, last byte) 
+// pair rather than a whole string, so every code costs a fixed amount of 
+// memory and strings are rebuilt by walking the prefix chain back to one 
+// of the 256 single byte codes.
+//
+// When the dictionary is reset, the next entry extends the last string 
+// decoded before the reset, whose code is no longer valid. That string 
+// is kept in 'carry' and referred to with CARRY_CODE, which is never 
+// assigned to a real entry.
+const CARRY_CODE: u16 = MAX_CODE;
+
+struct Dictionary {
+    prefix: Vec<u16>, // Code of the string minus its last byte
+    suffix: Vec<u8>,  // Last byte of the string
+    first:  Vec<u8>,  // First byte of the string
+    carry:  Vec<u8>,  // Last string decoded before a reset
+}
+
+impl Dictionary {
+    fn new() -> Dictionary {
+        let size = MAX_CODE as usize + 1;
+        let mut dict = Dictionary {
+            prefix: vec![0; size],
+            suffix: vec![0; size],
+            first:  vec![0; size],
+            carry:  Vec::new(),
+        };
+        for i in 0..256 {
+            dict.suffix[i] = i as u8;
+            dict.first[i] = i as u8;
+        }
+        dict
+    }
+
+    fn add(&mut self, code: u16, prefix: u16, byte: u8) {
+        self.prefix[code as usize] = prefix;
+        self.suffix[code as usize] = byte;
+        self.first[code as usize] = self.first(prefix);
+    }
+
+    fn first(&self, code: u16) -> u8 {
+        if code == CARRY_CODE {
+            self.carry[0]
+        }
+        else {
+            self.first[code as usize]
+        }
+    }
+
+    // Write the string for code into stack, replacing its contents.
+    fn string(&self, mut code: u16, stack: &mut Vec<u8>) {
+        stack.clear();
+        while code >= 256 && code != CARRY_CODE {
+            stack.push(self.suffix[code as usize]);
+            code = self.prefix[code as usize];
+        }
+        if code == CARRY_CODE {
+            stack.extend(self.carry.iter().rev());
+        }
+        else {
+            stack.push(code as u8);
+        }
+        stack.reverse();
+    }
+
+    // Keep the string for code across a reset and return the code
+    // that refers to it afterwards.
+    fn carry(&mut self, code: u16, stack: &mut Vec<u8>) -> u16 {
+        if code < 256 {
+            return code;
+        }
+        self.string(code, stack);
+        self.carry.clear();
+        self.carry.extend_from_slice(stack);
+        CARRY_CODE
+    }
+}
+
+// Decompress an LZW stream, returning an InvalidData error if it refers 
+// to a code that hasn't been defined.
+#[allow(clippy::redundant_closure)]
+pub fn lzw_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
     let mut dict_code = 256;
-    
-    let mut dict = (0..256)
-    .map(|i| (i, vec![i as u8]))
-    .collect::<HashMap<u16, Vec<u8>>>();
+    let mut dict = Dictionary::new();
 
-    let mut prev_string = Vec::<u8>::with_capacity(64);
+    let mut prev_code: Option<u16> = None;
+    let mut string = Vec::<u8>::with_capacity(64);
 
-    while let Some(code) = file_in.read_u16_checked() {
-        if !dict.contains_key(&code) {
-            prev_string.push(prev_string[0]);
-            dict.insert(code, prev_string);
-            dict_code += 1;      
+    while let Some(code) = file_in.read_u16_checked()? {
+        if code > dict_code || (code == dict_code && prev_code.is_none()) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("corrupt LZW stream: code {} is not defined (next code is {})", code, dict_code)
+            ));
         }
-        else if !prev_string.is_empty() {
-            prev_string.push((&dict.get(&code).unwrap())[0]);
-            dict.insert(dict_code, prev_string);
+
+        if code == dict_code {
+            // Code is being defined by this step (KwKwK case), so its
+            // string is the previous string plus its own first byte.
+            let prev = prev_code.unwrap();
+            dict.add(dict_code, prev, dict.first(prev));
+            dict_code += 1;
+        }
+        else if let Some(prev) = prev_code {
+            dict.add(dict_code, prev, dict.first(code));
             dict_code += 1;
         }
 
-        let string = dict.get(&code).unwrap();
-        file_out.write(&string).unwrap();
+        dict.string(code, &mut string);
+        file_out.write_bytes(&string)?;
 
-        prev_string = string.to_vec();
+        prev_code = Some(code);
         
-        if dict_code >= MAX_CODE {
+        if dict_code == MAX_CODE {
             dict_code = 256;
-            dict.retain(|i, _| *i < 256);
+            prev_code = Some(dict.carry(code, &mut string));
         }
     }  
-}
\ No newline at end of file
+    file_out.flush()
+}
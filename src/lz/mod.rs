@@ -1,3 +1,4 @@
 pub mod lz77;
 pub mod lzw;
-pub mod flzp;
\ No newline at end of file
+pub mod flzp;
+pub mod window;
\ No newline at end of file
@@ -0,0 +1,129 @@
+use std::io;
+use std::io::Write;
+use std::cmp::min;
+
+use crate::cpu;
+
+/// A sliding window over the most recent bytes of a stream, stored in a
+/// ring buffer whose size is a power of two. Positions in the ring are
+/// addressed either by index (0..size) or by distance back from the
+/// current position (1..=size), where a distance of 1 is the byte added
+/// last and a distance of size is the oldest byte, about to be replaced.
+///
+/// Matches follow the usual LZ77 rules: bytes are copied one at a time
+/// and each is added to the window before the next is read, so a match
+/// whose distance is shorter than its length repeats the bytes it adds.
+pub struct Window {
+    data: Vec<u8>,
+    pos:  usize, // Number of bytes added
+    mask: usize, // Size - 1
+}
+
+impl Window {
+    /// Create a window of size bytes, initially all 0.
+    pub fn new(size: usize) -> Window {
+        assert!(size.is_power_of_two());
+        Window {
+            data: vec![0; size],
+            pos:  0,
+            mask: size - 1,
+        }
+    }
+
+    pub fn add_byte(&mut self, byte: u8) {
+        self.data[self.pos & self.mask] = byte;
+        self.pos += 1;
+    }
+
+    pub fn add_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes.iter() {
+            self.add_byte(*byte);
+        }
+    }
+
+    /// Byte at ring index i (taken modulo the window size).
+    pub fn get_byte(&self, i: usize) -> u8 {
+        self.data[i & self.mask]
+    }
+
+    /// Size of the window in bytes.
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Ring index the next byte will be added at.
+    pub fn index(&self) -> usize {
+        self.pos & self.mask
+    }
+
+    /// Distance back from the current position to ring index i.
+    pub fn dist(&self, i: usize) -> usize {
+        let dist = self.pos.wrapping_sub(i) & self.mask;
+        if dist == 0 { self.len() } else { dist }
+    }
+
+    /// Length of the match between the bytes starting dist back and
+    /// 'pending', the bytes that would be added next, up to max_len.
+    /// Where the match overlaps the bytes it would add, they are taken
+    /// from 'pending'.
+    pub fn match_len(&self, dist: usize, pending: &[u8], max_len: usize) -> usize {
+        assert!(dist >= 1 && dist <= self.len());
+        let max_len = min(max_len, pending.len());
+        let start = self.pos.wrapping_sub(dist) & self.mask;
+
+        // Bytes still in the window, in at most two pieces where they
+        // wrap around the end of the ring.
+        let in_window = min(max_len, dist);
+        let first = min(in_window, self.len() - start);
+        let mut len = cpu::common_prefix(&self.data[start..start + first], &pending[..first]);
+        if len == first && first < in_window {
+            len += cpu::common_prefix(&self.data[..in_window - first], &pending[first..in_window]);
+        }
+        if len < in_window {
+            return len;
+        }
+
+        // Bytes the match would add itself.
+        while len < max_len && pending[len] == pending[len - dist] {
+            len += 1;
+        }
+        len
+    }
+
+    /// Copy len bytes starting dist back to the current position, and
+    /// write them to out.
+    pub fn copy_match<W: Write>(&mut self, dist: usize, len: usize, out: &mut W) -> io::Result<()> {
+        assert!(dist >= 1 && dist <= self.len());
+        assert!(len <= self.len());
+        let src = self.pos.wrapping_sub(dist) & self.mask;
+        let dst = self.index();
+
+        if dist >= len && src + len <= self.len() && dst + len <= self.len() {
+            self.data.copy_within(src..src + len, dst);
+            self.pos += len;
+        }
+        else {
+            for _ in 0..len {
+                let byte = self.data[self.pos.wrapping_sub(dist) & self.mask];
+                self.add_byte(byte);
+            }
+        }
+        self.write_last(len, out)
+    }
+
+    /// Write the last len bytes added to out.
+    pub fn write_last<W: Write>(&self, len: usize, out: &mut W) -> io::Result<()> {
+        let start = self.pos.wrapping_sub(len) & self.mask;
+        if start + len <= self.len() {
+            out.write_all(&self.data[start..start + len])
+        }
+        else {
+            out.write_all(&self.data[start..])?;
+            out.write_all(&self.data[..start + len - self.len()])
+        }
+    }
+}
@@ -1,137 +1,1146 @@
-pub mod bufio;
-pub mod lz;
-pub mod ari;
-pub mod huffman;
-pub mod bwt;
+mod selftest;
+mod sweep;
 
 use std::fs::metadata;
 use std::fs::File;
+use std::io::BufRead;
 use std::io::BufReader;
 use std::io::BufWriter;
 use std::io::Read;
+use std::io::Write;
+use std::io::Seek;
+use std::io::SeekFrom;
 use std::path::Path;
 use std::time::Instant;
 
+use compression::bufio::NullSink;
+use compression::lz::flzp::{Effort, MIN_CXT_LEN, MAX_CXT_LEN};
+use compression::ari::fpaq::SseProfile;
+use compression::ari::lpaq1::ModelSet;
+use compression::sortrans::Profile;
+use compression::container;
+use compression::container::Algorithm;
+use compression::stats::Sizes;
+use compression::stored;
+use compression::volume::{VolumeReader, VolumeWriter};
+
+pub(crate) const ALGORITHMS: [&str; 16] = [
+    "-lz77", "-lzw", "-flzp", "-sr", "-fpaq0", "-fpaq", "-lpaq1", "-ppm", "-rans", "-fse", "-huffman", "-bwt",
+    "-remap", "-split", "-segment", "-sortrans",
+];
+
+// Options that change how an algorithm runs.
+#[derive(Clone)]
+struct Options {
+    effort:   Effort,     // flzp match prediction effort
+    crc:      bool,       // Add a CRC to each flzp block
+    cxt_len:  usize,      // flzp context length
+    block_stats: bool,    // Record flzp block sizes at the end of the stream
+    sentinel: bool,       // Use sentinel blocks for bwt
+    order1:   bool,       // Use order 1 huffman coding
+    static_model: bool,   // Code each rans block with its own static table
+    order:    u8,         // Longest ppm context
+    sse:      SseProfile, // fpaq SSE stages
+    fast:     bool,       // Use the fast sortrans profile
+    models:   ModelSet,   // lpaq1 models
+    switch:   bool,       // Switch lpaq1 predictors between text and binary blocks
+    run_states: bool,     // Use run length aware lpaq1 bit history states
+    model_stats: bool,    // Print lpaq1 model statistics
+    big_endian: bool,     // Split big endian 16 bit samples
+    max_memory: Option<u64>, // Memory budget in bytes
+    no_mtime: bool,       // Don't record or restore file times
+    threads:  Option<usize>, // Compress in blocks on this many threads
+    block_size: usize,    // Size of those blocks
+    index:    bool,       // Compress in blocks, followed by an index
+    auto_block: bool,     // Choose block sizes from the input
+    bwt_block_size: Option<usize>, // bwt block size chosen by --auto-block
+}
+
 fn main() {
     let time = Instant::now();
-    let args = std::env::args().skip(1).collect::<Vec<String>>();
-    if args.len() != 4 {
+
+    let mut dry_run = false;
+    let mut max_expand = None;
+    let mut volume_size = None;
+    let mut sweep_algorithm = Algorithm::Lpaq1;
+    let mut older_than = std::time::Duration::ZERO;
+    let mut opts = Options {
+        effort:   Effort::Greedy,
+        crc:      false,
+        cxt_len:  compression::lz::flzp::DEFAULT_CXT_LEN,
+        block_stats: false,
+        sentinel: false,
+        order1:   false,
+        static_model: false,
+        order:    compression::ari::ppm::DEFAULT_ORDER,
+        sse:      SseProfile::default(),
+        fast:     false,
+        models:   ModelSet::default(),
+        switch:   false,
+        run_states: false,
+        model_stats: false,
+        big_endian: false,
+        max_memory: None,
+        no_mtime: false,
+        threads:  None,
+        block_size: compression::blocks::DEFAULT_BLOCK_SIZE,
+        index:    false,
+        auto_block: false,
+        bwt_block_size: None,
+    };
+    let mut args = Vec::new();
+    let mut argv = std::env::args().skip(1);
+    while let Some(arg) = argv.next() {
+        match arg.as_str() {
+            "--dry-run" => { dry_run = true; }
+            "--crc" => { opts.crc = true; }
+            "--block-stats" => { opts.block_stats = true; }
+            "--sentinel" => { opts.sentinel = true; }
+            "--auto-block" => { opts.auto_block = true; }
+            "--index" => { opts.index = true; }
+            "--order1" => { opts.order1 = true; }
+            "--static" => { opts.static_model = true; }
+            "--fast" => { opts.sse = SseProfile::fast(); opts.fast = true; }
+            "--switch" => { opts.switch = true; }
+            "--run-states" => { opts.run_states = true; }
+            "--model-stats" => { opts.model_stats = true; }
+            "--big-endian" => { opts.big_endian = true; }
+            "--no-mtime" => { opts.no_mtime = true; }
+            "--sse" => {
+                let spec = argv.next().unwrap_or_else(|| print_usage());
+                opts.sse = SseProfile::parse(&spec).unwrap_or_else(|e| {
+                    exit_with_error(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+                });
+            }
+            "--models" => {
+                let spec = argv.next().unwrap_or_else(|| print_usage());
+                opts.models = ModelSet::parse(&spec).unwrap_or_else(|e| {
+                    exit_with_error(std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+                });
+            }
+            "--max-expand" => {
+                max_expand = match argv.next().and_then(|n| n.trim_end_matches('%').parse::<u64>().ok()) {
+                    Some(n) => Some(n),
+                    None => print_usage(),
+                };
+            }
+            "--volume-size" => {
+                volume_size = match argv.next().and_then(|size| parse_size(&size)) {
+                    Some(size) if size > 0 => Some(size),
+                    _ => print_usage(),
+                };
+            }
+            "--algo" => {
+                sweep_algorithm = match argv.next().and_then(|name| Algorithm::from_name(&name)) {
+                    Some(algorithm) => algorithm,
+                    None => print_usage(),
+                };
+            }
+            "--older-than" => {
+                older_than = match argv.next().and_then(|age| crate::sweep::parse_age(&age)) {
+                    Some(age) => age,
+                    None => print_usage(),
+                };
+            }
+            "--max-memory" => {
+                opts.max_memory = match argv.next().and_then(|size| parse_size(&size)) {
+                    Some(size) => Some(size),
+                    None => print_usage(),
+                };
+            }
+            "--threads" => {
+                opts.threads = match argv.next().and_then(|n| n.parse().ok()) {
+                    Some(n @ 1..) => Some(n),
+                    _ => print_usage(),
+                };
+            }
+            "--block-size" => {
+                opts.block_size = match argv.next().and_then(|size| parse_size(&size)).and_then(|size| usize::try_from(size).ok()) {
+                    Some(size) if size >= compression::blocks::MIN_BLOCK_SIZE => size,
+                    _ => print_usage(),
+                };
+            }
+            "--nice" => {
+                let n = argv.next().and_then(|n| n.parse().ok()).unwrap_or_else(|| print_usage());
+                if let Err(e) = compression::throttle::nice(n) {
+                    exit_with_error(e);
+                }
+            }
+            "--cpu-limit" => {
+                match argv.next().and_then(|p| p.trim_end_matches('%').parse().ok()) {
+                    Some(percent @ 1..=100) => compression::throttle::set_cpu_limit(percent),
+                    _ => print_usage(),
+                }
+            }
+            "--cpu-features" => {
+                let level = argv.next().and_then(|name| compression::cpu::Level::from_name(&name)).unwrap_or_else(|| print_usage());
+                if let Err(e) = compression::cpu::set(level) {
+                    exit_with_error(e);
+                }
+            }
+            "--order" => {
+                opts.order = match argv.next().and_then(|order| order.parse().ok()) {
+                    Some(order) if order <= compression::ari::ppm::MAX_ORDER => order,
+                    _ => print_usage(),
+                };
+            }
+            "--context" => {
+                opts.cxt_len = match argv.next().and_then(|len| len.parse().ok()) {
+                    Some(len) if (MIN_CXT_LEN..=MAX_CXT_LEN).contains(&len) => len,
+                    _ => print_usage(),
+                };
+            }
+            "--effort" => {
+                opts.effort = match argv.next().as_deref() {
+                    Some("greedy") => Effort::Greedy,
+                    Some("normal") => Effort::Normal,
+                    Some("max")    => Effort::Max,
+                    _ => print_usage(),
+                };
+            }
+            _ => { args.push(arg); }
+        }
+    }
+
+    // Write the suffix array of a file: sa [INPUT] [OUTPUT]
+    if args.len() == 3 && args[0] == "sa" {
+        let file_in = BufReader::with_capacity(
+            1 << 20, 
+            File::open(&args[1])
+            .unwrap_or_else(|_| panic!("Could not open input file {}\n", &args[1]))
+        ); 
+        let file_out = BufWriter::with_capacity(
+            1 << 20, 
+            File::create(&args[2])
+            .unwrap_or_else(|_| panic!("Could not open output file {}\n", &args[2]))
+        ); 
+        if let Err(e) = compression::bwt::suffix_array::write_suffix_array(file_in, file_out) {
+            exit_with_error(e);
+        }
+        return;
+    }
+
+    // Compress a file relative to a reference: delta [-d] -ref [REFERENCE] [INPUT] [OUTPUT]
+    if !args.is_empty() && args[0] == "delta" {
+        let args = args.iter().map(String::as_str).collect::<Vec<&str>>();
+        let (decompress, paths) = match args.as_slice() {
+            [_, "-ref", paths @ ..] if paths.len() == 3 => (false, paths),
+            [_, "-d", "-ref", paths @ ..] if paths.len() == 3 => (true, paths),
+            _ => print_usage(),
+        };
+        let open = |path: &str| BufReader::with_capacity(
+            1 << 20,
+            File::open(path)
+            .unwrap_or_else(|_| panic!("Could not open input file {}\n", path))
+        );
+        let file_out = BufWriter::with_capacity(
+            1 << 20,
+            File::create(paths[2])
+            .unwrap_or_else(|_| panic!("Could not open output file {}\n", paths[2]))
+        );
+        let result = if decompress {
+            compression::delta::delta_decompress(open(paths[0]), open(paths[1]), file_out, opts.max_memory)
+        }
+        else {
+            compression::delta::delta_compress(open(paths[0]), open(paths[1]), file_out, opts.models, opts.switch, lpaq1_level(&opts, 0), opts.run_states)
+        };
+        if let Err(e) = result {
+            exit_with_error(e);
+        }
+        println!("{} bytes -> {} bytes in {:.2?}",
+            metadata(paths[1]).unwrap().len(),
+            metadata(paths[2]).unwrap().len(),
+            time.elapsed()
+        );
+        return;
+    }
+
+    // Make a binary patch: diff [OLD] [NEW] [PATCH]
+    // Apply a binary patch: patch [OLD] [PATCH] [NEW]
+    if args.len() == 4 && (args[0] == "diff" || args[0] == "patch") {
+        let read = |path: &str| std::fs::read(path)
+            .unwrap_or_else(|_| panic!("Could not open input file {}\n", path));
+        let file_out = BufWriter::with_capacity(
+            1 << 20,
+            File::create(&args[3])
+            .unwrap_or_else(|_| panic!("Could not open output file {}\n", &args[3]))
+        );
+        let old = read(&args[1]);
+        let result = if args[0] == "diff" {
+            let new = read(&args[2]);
+            let need = compression::patch::memory(old.len() as u64, new.len() as u64);
+            if opts.max_memory.is_some_and(|max| need > max) {
+                exit_with_error(memory_error("diff", need));
+            }
+            // Both files stay in memory while lpaq1 runs.
+            let level = lpaq1_level(&opts, (old.len() + new.len()) as u64);
+            compression::patch::diff(&old, &new, file_out, opts.models, opts.switch, level, opts.run_states)
+        }
+        else {
+            let file_in = BufReader::with_capacity(
+                1 << 20,
+                File::open(&args[2])
+                .unwrap_or_else(|_| panic!("Could not open input file {}\n", &args[2]))
+            );
+            let max_memory = opts.max_memory.map(|max| max.saturating_sub(old.len() as u64));
+            compression::patch::apply(&old, file_in, file_out, max_memory)
+        };
+        if let Err(e) = result {
+            exit_with_error(e);
+        }
+        println!("{} bytes -> {} bytes in {:.2?}",
+            metadata(&args[2]).unwrap().len(),
+            metadata(&args[3]).unwrap().len(),
+            time.elapsed()
+        );
+        return;
+    }
+
+    // Compress old files in a directory in place: sweep [OPTIONS] [DIR]
+    if args.len() == 2 && args[0] == "sweep" {
+        if !crate::sweep::sweep(Path::new(&args[1]), sweep_algorithm, older_than, &opts) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // Hash files: hash --crc32|--xxh64 [INPUT]...
+    if args.len() >= 3 && args[0] == "hash" {
+        if !matches!(args[1].as_str(), "--crc32" | "--xxh64") {
+            print_usage();
+        }
+        if !hash(&args[1], &args[2..]) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // List compressed files from their headers: -l [INPUT]...
+    if args.len() >= 2 && args[0] == "-l" {
+        if !list(&args[1..]) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // List the block sizes of an flzp stream: info --blocks [INPUT]
+    if args.len() == 3 && args[0] == "info" && args[1] == "--blocks" {
+        let mut file_in = File::open(&args[2])
+            .unwrap_or_else(|_| panic!("Could not open input file {}\n", &args[2]));
+        let end = match container::members(&mut file_in).as_deref() {
+            Ok([member]) => member.end,
+            Ok(_) => exit_with_error(format!("{} has more than one member", &args[2])),
+            Err(e) => exit_with_error(e),
+        };
+        let header = match container::Header::read(&mut file_in) {
+            Ok(header) if header.algorithm == Algorithm::Flzp && !header.stored => header,
+            Ok(_) => exit_with_error(format!("{} isn't an flzp stream", &args[2])),
+            Err(e) => exit_with_error(e),
+        };
+        // With --threads or --index, each block of the framing is an flzp
+        // stream with block stats of its own.
+        let start = file_in.stream_position().unwrap();
+        let streams = if header.blocks {
+            compression::blocks::block_spans(&mut file_in, end).unwrap_or_else(|e| exit_with_error(e))
+        } else {
+            vec![(0, start..end)]
+        };
+        let mut stats = Vec::new();
+        for (offset, range) in streams {
+            file_in.seek(SeekFrom::Start(range.start)).unwrap();
+            match compression::lz::flzp::block_stats(&mut file_in, range.end) {
+                Ok(Some(sizes)) => stats.extend(sizes.into_iter().scan(offset, |offset, sizes| {
+                    *offset += sizes.input;
+                    Some((*offset - sizes.input, sizes))
+                })),
+                Ok(None) => exit_with_error(format!("{} has no block stats; compress with -flzp --block-stats", &args[2])),
+                Err(e) => exit_with_error(e),
+            }
+        }
+        println!("{:>6} {:>12} {:>8} {:>8} {:>8}", "block", "offset", "input", "output", "ratio");
+        for (i, (offset, sizes)) in stats.iter().enumerate() {
+            println!("{:>6} {:>12} {:>8} {:>8} {:>7.2}%", i, offset, sizes.input, sizes.output, ratio(sizes.input, sizes.output));
+        }
+        return;
+    }
+
+    // Round trip every algorithm on generated data: selftest [SIZE]
+    if (args.len() == 1 || args.len() == 2) && args[0] == "selftest" {
+        let size = match args.get(1) {
+            Some(size) => match size.parse() {
+                Ok(size) if size > 0 => size,
+                _ => print_usage(),
+            },
+            None => crate::selftest::DEFAULT_SIZE,
+        };
+        if !crate::selftest::selftest(&opts, size) {
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    // The output path can be omitted in a dry run since nothing is
+    // written, and a test has none.
+    let test = args.get(1).is_some_and(|mode| mode == "-t");
+    if !(args.len() == 4 && !test || args.len() == 3 && (dry_run || test)) {
         print_usage();
     }
 
     let algorithm = args[0].as_str();
     let mode = args[1].as_str();
     let file_in_str = &args[2];
-    let file_out_str = &args[3];
+    if volume_size.is_some() && mode != "-c" {
+        print_usage();
+    }
 
+    // Given the first of a set of volumes, read the rest after it.
+    // Anything else is read alone, as a single volume.
+    let volumes = match file_in_str.strip_suffix(".001") {
+        Some(path) if mode != "-c" => VolumeReader::open(path),
+        _ => File::open(file_in_str).and_then(|file| VolumeReader::new(vec![file])),
+    };
     let file_in = BufReader::with_capacity(
         1 << 20, 
-        File::open(file_in_str)
-        .unwrap_or_else(|_| panic!("Could not open input file {}\n", &file_in_str))
-    );
+        volumes.unwrap_or_else(|_| panic!("Could not open input file {}\n", &file_in_str))
+    ); 
 
-    let file_out = BufWriter::with_capacity(
-        1 << 20, 
-        File::create(file_out_str)
-        .unwrap_or_else(|_| panic!("Could not open output file {}\n", &file_out_str))
-    );
+    let file_in_size = file_in.get_ref().size();
+    let file_in_metadata = metadata(file_in_str);
 
-    match (algorithm, mode) {
-        ("-lz77", "-c") => { 
-            crate::lz::lz77::Lz77::new(file_in, file_out).compress(); 
+    if test {
+        // Decompress, checking the stream as usual, but write nothing.
+        let mut sink = NullSink::new();
+        match run(algorithm, mode, &opts, &file_in_metadata, file_in, BufWriter::with_capacity(1 << 20, &mut sink)) {
+            Ok(_) => println!("{}: OK, {} bytes in {:.2?}", file_in_str, sink.size(), time.elapsed()),
+            Err(e) if is_corrupt(&e) => {
+                eprintln!("{}: corrupt: {}", file_in_str, e);
+                std::process::exit(1);
+            }
+            Err(e) => exit_with_error(e),
+        }
+        return;
+    }
+
+    if dry_run {
+        // Run the full algorithm, but count the output instead of writing it.
+        let mut sink = NullSink::new();
+        if let Err(e) = run(algorithm, mode, &opts, &file_in_metadata, file_in, BufWriter::with_capacity(1 << 20, &mut sink)) {
+            exit_with_error(e);
+        }
+
+        let mut size_out = sink.size();
+        if mode == "-c" && max_expand.is_some_and(|n| stored::too_large(file_in_size, size_out, n)) {
+            size_out = file_in_size + container::HEADER_SIZE + container::TRAILER_SIZE + file_times(&metadata(file_in_str), &opts).encoded_len();
+            println!("Output would exceed input by more than {}%, would be stored", max_expand.unwrap());
+        }
+        println!("{} bytes -> {} bytes ({:.2}%) in {:.2?} (dry run)",
+            file_in_size,
+            size_out,
+            ratio(file_in_size, size_out),
+            time.elapsed()
+        );
+        return;
+    }
+
+    let file_out_str = &args[3];
+
+    let result = match volume_size {
+        Some(size) => {
+            let volumes = VolumeWriter::create(file_out_str, size)
+                .unwrap_or_else(|_| panic!("Could not open output file {}\n", &file_out_str));
+            run(algorithm, mode, &opts, &file_in_metadata, file_in, BufWriter::with_capacity(1 << 20, volumes))
+        }
+        None => {
+            let file_out = BufWriter::with_capacity(
+                1 << 20, 
+                File::create(file_out_str)
+                .unwrap_or_else(|_| panic!("Could not open output file {}\n", &file_out_str))
+            ); 
+            run(algorithm, mode, &opts, &file_in_metadata, file_in, file_out)
+        }
+    };
+    if let Err(e) = result {
+        exit_with_error(e);
+    }
+
+    // Replace output that grew too much with the input, stored.
+    if let Some(n) = max_expand {
+        let (size_out, _) = output_size(file_out_str, volume_size.is_some());
+        if mode == "-c" && stored::too_large(file_in_size, size_out, n) {
+            let file_in = BufReader::new(File::open(file_in_str).unwrap());
+            let id = Algorithm::from_name(&algorithm[1..]).unwrap();
+            let times = file_times(&file_in_metadata, &opts);
+            let result = match volume_size {
+                Some(size) => VolumeWriter::create(file_out_str, size)
+                    .map_err(compression::error::Error::Io)
+                    .and_then(|volumes| container::store(file_in, BufWriter::new(volumes), id, file_in_size, times)),
+                None => container::store(file_in, BufWriter::new(File::create(file_out_str).unwrap()), id, file_in_size, times),
+            };
+            if let Err(e) = result {
+                exit_with_error(e);
+            }
+            println!("Output exceeded input by more than {}%, stored instead", n);
+        }
+    }
+
+    // Give the output the times of the file that was compressed.
+    if mode == "-d" && !opts.no_mtime {
+        let times = File::open(file_in_str)
+            .map_err(compression::error::Error::Io)
+            .and_then(|mut file_in| container::Header::read(&mut file_in))
+            .map(|header| header.times);
+        let result = times.and_then(|times| Ok(times.restore(&File::options().write(true).open(file_out_str)?)?));
+        if let Err(e) = result {
+            exit_with_error(e);
+        }
+    }
+
+    match output_size(file_out_str, volume_size.is_some()) {
+        (size_out, Some(count)) => println!("{} bytes -> {} bytes ({} volume{}) in {:.2?}", 
+            file_in_size,
+            size_out, 
+            count,
+            if count == 1 { "" } else { "s" },
+            time.elapsed()
+        ), 
+        (size_out, None) => println!("{} bytes -> {} bytes in {:.2?}", 
+            file_in_size,
+            size_out, 
+            time.elapsed()
+        ), 
+    }
+}
+
+// The size of the output at path, and with --volume-size, the number of
+// volumes it was split into.
+fn output_size(path: &str, volumes: bool) -> (u64, Option<usize>) {
+    if volumes {
+        let volumes = VolumeReader::open(path).unwrap_or_else(|e| exit_with_error(e));
+        return (volumes.size(), Some(volumes.count()));
+    }
+    (metadata(Path::new(path)).unwrap().len(), None)
+}
+
+// Compress (-c), or decompress (-d or -t) file_in to file_out.
+// metadata is INPUT's, for the times to record.
+fn run<R: Read + Seek, W: Write + Seek>(algorithm: &str, mode: &str, opts: &Options,
+    metadata: &std::io::Result<std::fs::Metadata>, mut file_in: BufReader<R>, file_out: BufWriter<W>) -> compression::error::Result<Sizes> {
+    let size_in = file_in.seek(SeekFrom::End(0))?;
+    file_in.rewind()?;
+    let Some(id) = algorithm.strip_prefix('-').and_then(Algorithm::from_name) else {
+        print_usage();
+    };
+    let auto_opts;
+    let opts = if opts.auto_block && mode == "-c" {
+        let repetitiveness = compression::blocks::repetitiveness(file_in.fill_buf()?);
+        auto_opts = auto_block(opts, size_in, repetitiveness);
+        &auto_opts
+    }
+    else {
+        opts
+    };
+    let threads = opts.threads.unwrap_or(1);
+    let thread_opts = if in_blocks(opts) { per_thread(opts, threads) } else { opts.clone() };
+    if let (Some(max), Some(need)) = (thread_opts.max_memory, fixed_memory(algorithm, mode, &thread_opts, size_in)) {
+        if need > max {
+            exit_with_error(memory_error(algorithm, need * threads as u64));
+        }
+    }
+    match mode {
+        "-c" => container::compress(file_in, file_out, id, size_in, file_times(metadata, opts), in_blocks(opts), |file_in, file_out| {
+            if !in_blocks(opts) {
+                return compress(id, opts, size_in, file_in, file_out);
+            }
+            compression::blocks::compress_blocks(file_in, file_out, opts.block_size, threads, opts.index, |file_in, file_out| {
+                let size_in = file_in.get_ref().get_ref().len() as u64;
+                compress(id, &thread_opts, size_in, file_in, file_out)
+            })
+        }),
+        "-d" | "-t" => container::decompress(file_in, file_out, id, |header, file_in, file_out| {
+            decompress_stream(id, opts, header, file_in, file_out)
+        }),
+        _ => print_usage(),
+    }
+}
+
+// True if compressing in blocks, for --threads or --index.
+fn in_blocks(opts: &Options) -> bool {
+    opts.threads.is_some() || opts.index
+}
+
+// The options for each of threads threads working on blocks, which
+// share --max-memory. Decompressing assumes blocks of --block-size,
+// since the stream's isn't known yet.
+fn per_thread(opts: &Options, threads: usize) -> Options {
+    let max_memory = opts.max_memory.map(|max| {
+        (max / threads as u64).saturating_sub(compression::blocks::memory(opts.block_size))
+    });
+    Options { max_memory, ..opts.clone() }
+}
+
+// Memory --auto-block plans bwt blocks for without --max-memory.
+const AUTO_MEMORY: u64 = 1 << 30;
+
+// Options with block sizes for --auto-block, chosen for size_in bytes of
+// input whose start has the given repetitiveness: the --threads block
+// size, then the bwt block size and whether to use sentinel blocks
+// within each thread's memory.
+fn auto_block(opts: &Options, size_in: u64, repetitiveness: f64) -> Options {
+    use compression::blocks::{auto_block_size, memory, MIN_BLOCK_SIZE};
+    let threads = opts.threads.unwrap_or(1) as u64;
+    let mut block_size = auto_block_size(size_in, repetitiveness);
+    if let Some(max) = opts.max_memory {
+        while block_size > MIN_BLOCK_SIZE && memory(block_size) * threads > max / 2 {
+            block_size /= 2;
+        }
+    }
+    let opts = Options { block_size, ..opts.clone() };
+
+    let (max, size) = if in_blocks(&opts) {
+        (per_thread(&opts, threads as usize).max_memory, block_size as u64)
+    }
+    else {
+        (opts.max_memory, size_in)
+    };
+    // If nothing fits, bwt_block_size reports it.
+    match compression::bwt::bwt::auto_block(size, repetitiveness, max.unwrap_or(AUTO_MEMORY)) {
+        Some((bwt_block_size, sentinel)) => Options { bwt_block_size: Some(bwt_block_size), sentinel, ..opts },
+        None => opts,
+    }
+}
+
+// True if e means the stream is damaged or truncated, rather than that
+// it couldn't be read.
+fn is_corrupt(e: &compression::error::Error) -> bool {
+    match e {
+        compression::error::Error::InvalidData(_) => true,
+        compression::error::Error::Io(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+        compression::error::Error::OutOfMemory(_) => false,
+        compression::error::Error::Cancelled => false,
+    }
+}
+
+// Print --model-stats for each lpaq1 predictor that coded any bits.
+fn print_model_stats(stats: &[compression::ari::lpaq1::ModelStats], switch: bool) {
+    let labels = if switch { ["Text blocks:", "Binary blocks:"] } else { ["Models:", ""] };
+    for (stats, label) in stats.iter().zip(labels.iter()) {
+        if stats.bits() == 0 {
+            continue;
+        }
+        eprintln!("{}", label);
+        eprintln!("{:<10}{:>10}{:>10}{:>12}", "model", "weight", "share", "bits/byte");
+        for input in stats.inputs() {
+            eprintln!("{:<10}{:>10.3}{:>9.1}%{:>12.3}", input.name, input.weight, input.share * 100.0, input.cost);
+        }
+        eprintln!("{:<10}{:>10}{:>10}{:>12.3}", "mixed", "", "", stats.mixed());
+    }
+}
+
+fn compress<R: Read + Seek, W: Write + Seek>(algorithm: Algorithm, opts: &Options, size_in: u64,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> compression::error::Result<Sizes> {
+    match algorithm {
+        Algorithm::Lz77 => {
+            compression::lz::lz77::lz77_compress(file_in, file_out)
+        }
+        Algorithm::Lzw => {
+            compression::lz::lzw::lzw_compress(file_in, file_out)
+        }
+        Algorithm::Flzp => {
+            compression::lz::flzp::flzp_compress(file_in, file_out, opts.effort, opts.crc, opts.cxt_len, opts.block_stats)
         }
-        ("-lz77", "-d") => { 
-            crate::lz::lz77::Lz77::new(file_in, file_out).decompress(); 
+        Algorithm::Sr => {
+            compression::ari::sr::sr_compress(file_in, file_out)
         }
-        ("-lzw", "-c") => { 
-            crate::lz::lzw::lzw_compress(file_in, file_out); 
+        Algorithm::Fpaq0 => {
+            compression::ari::fpaq0::fpaq0_compress(file_in, file_out)
         }
-        ("-lzw", "-d") => { 
-            crate::lz::lzw::lzw_decompress(file_in, file_out); 
+        Algorithm::Fpaq => {
+            compression::ari::fpaq::fpaq_compress(file_in, file_out, &opts.sse)
         }
-        ("-flzp", "-c") => { 
-            crate::lz::flzp::flzp_compress(file_in, file_out); 
+        Algorithm::Lpaq1 if opts.model_stats => {
+            let (sizes, stats) = compression::ari::lpaq1::lpaq1_compress_with_stats(
+                file_in, file_out, opts.models, opts.switch, lpaq1_level(opts, 0), opts.run_states)?;
+            print_model_stats(&stats, opts.switch);
+            Ok(sizes)
         }
-        ("-flzp", "-d") => { 
-            crate::lz::flzp::flzp_decompress(file_in, file_out); 
+        Algorithm::Lpaq1 => {
+            compression::ari::lpaq1::lpaq1_compress(file_in, file_out, opts.models, opts.switch, lpaq1_level(opts, 0), opts.run_states)
         }
-        ("-fpaq", "-c") => { 
-            crate::ari::fpaq::fpaq_compress(file_in, file_out); 
+        Algorithm::Ppm => {
+            compression::ari::ppm::ppm_compress(file_in, file_out, opts.order, ppm_model(opts))
         }
-        ("-fpaq", "-d") => { 
-            crate::ari::fpaq::fpaq_decompress(file_in, file_out); 
+        Algorithm::Rans => {
+            compression::ari::rans::rans_compress(file_in, file_out, opts.static_model)
         }
-        ("-lpaq1", "-c") => { 
-            crate::ari::lpaq1::lpaq1_compress(file_in, file_out); 
+        Algorithm::Fse => {
+            compression::ari::fse::fse_compress(file_in, file_out)
         }
-        ("-lpaq1", "-d") => { 
-            crate::ari::lpaq1::lpaq1_decompress(file_in, file_out); 
+        Algorithm::Huffman => {
+            if opts.order1 {
+                compression::huffman::order1::compress(file_in, file_out)
+            }
+            else {
+                compression::huffman::encoder::compress(file_in, file_out)
+            }
         }
-        ("-huffman", "-c") => { 
-            crate::huffman::encoder::compress(file_in, file_out); 
+        Algorithm::Bwt => {
+            compression::bwt::bwt::bwt_transform(file_in, file_out, opts.sentinel, bwt_block_size(opts))
         }
-        ("-huffman", "-d") => { 
-            crate::huffman::decoder::decompress(file_in, file_out); 
+        Algorithm::Remap => {
+            compression::remap::remap(file_in, file_out)
         }
-        ("-bwt", "-c") => { 
-            crate::bwt::bwt::bwt_transform(file_in, file_out); 
+        Algorithm::Split => {
+            compression::split::split(file_in, file_out, opts.big_endian)
         }
-        ("-bwt", "-d") => {
-            // When computing BWT transform, the block size is equal to 
-            // the input file buffer size.
-            //
-            // Because the BWT inverse transform must use the same block 
-            // size, this size must be known before creating the BufReader, 
-            // but it can't be known before reading it from the file.
-            //
-            // To get around this, create the file, read first 8 bytes 
-            // containing block size, and then wrap it in a BufReader.
-            let mut file_in = File::open(file_in_str).unwrap();
-            let mut a = [0u8; 8];
-            file_in.read(&mut a).unwrap();
-            let block_size = u64::from_le_bytes(a) as usize;
+        Algorithm::Segment => {
+            let level = lpaq1_level(opts, compression::segment::memory(size_in));
+            compression::segment::segment_compress(file_in, file_out, level, opts.run_states)
+        }
+        // bwt and lpaq1 run one after the other, so each has all of
+        // --max-memory.
+        Algorithm::Sortrans => {
+            let profile = if opts.fast { Profile::Fast } else { Profile::Max };
+            compression::sortrans::sortrans_compress(file_in, file_out, profile,
+                opts.sentinel, bwt_block_size(opts), lpaq1_level(opts, 0), opts.run_states)
+        }
+    }
+}
 
-            let file_in = BufReader::with_capacity(
-                block_size + 8, // Add 8 for primary index size
-                file_in
-            );
-            crate::bwt::bwt::bwt_inverse_transform(file_in, file_out); 
+// Decompress the stream after header, in blocks if it was compressed in
+// them.
+fn decompress_stream<R: Read, W: Write>(algorithm: Algorithm, opts: &Options, header: &container::Header,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> compression::error::Result<Sizes> {
+    if !header.blocks {
+        return decompress(algorithm, opts, file_in, file_out);
+    }
+    let threads = compression::blocks::decompress_threads(opts.threads, opts.max_memory);
+    let opts = per_thread(opts, threads);
+    compression::blocks::decompress_blocks(file_in, file_out, threads, |file_in, file_out| {
+        decompress(algorithm, &opts, file_in, file_out)
+    })
+}
+
+fn decompress<R: Read, W: Write>(algorithm: Algorithm, opts: &Options,
+    file_in: BufReader<R>, file_out: BufWriter<W>) -> compression::error::Result<Sizes> {
+    match algorithm {
+        Algorithm::Lz77 => {
+            compression::lz::lz77::lz77_decompress(file_in, file_out)
+        }
+        Algorithm::Lzw => {
+            compression::lz::lzw::lzw_decompress(file_in, file_out)
+        }
+        Algorithm::Flzp => {
+            compression::lz::flzp::flzp_decompress(file_in, file_out)
         }
-        _ => { 
-            print_usage(); 
+        Algorithm::Sr => {
+            compression::ari::sr::sr_decompress(file_in, file_out)
+        }
+        Algorithm::Fpaq0 => {
+            compression::ari::fpaq0::fpaq0_decompress(file_in, file_out)
+        }
+        Algorithm::Fpaq => {
+            compression::ari::fpaq::fpaq_decompress(file_in, file_out)
+        }
+        Algorithm::Lpaq1 => {
+            compression::ari::lpaq1::lpaq1_decompress(file_in, file_out, opts.max_memory)
+        }
+        Algorithm::Ppm => {
+            compression::ari::ppm::ppm_decompress(file_in, file_out, opts.max_memory)
+        }
+        Algorithm::Rans => {
+            compression::ari::rans::rans_decompress(file_in, file_out)
+        }
+        Algorithm::Fse => {
+            compression::ari::fse::fse_decompress(file_in, file_out)
+        }
+        Algorithm::Huffman => {
+            compression::huffman::decoder::decompress(file_in, file_out)
+        }
+        Algorithm::Bwt => {
+            compression::bwt::bwt::bwt_inverse_transform(file_in, file_out, opts.max_memory)
+        }
+        Algorithm::Remap => {
+            compression::remap::unmap(file_in, file_out)
+        }
+        Algorithm::Split => {
+            compression::split::join(file_in, file_out)
+        }
+        Algorithm::Segment => {
+            compression::segment::segment_decompress(file_in, file_out, opts.max_memory)
+        }
+        Algorithm::Sortrans => {
+            compression::sortrans::sortrans_decompress(file_in, file_out, opts.max_memory)
         }
     }
-    
-    println!("{} bytes -> {} bytes in {:.2?}", 
-        metadata(Path::new(file_in_str)).unwrap().len(), 
-        metadata(Path::new(file_out_str)).unwrap().len(), 
-        time.elapsed()
-    ); 
 }
 
-fn print_usage() {
+// Print the algorithm, compressed size, original size and ratio of each
+// file from its container header, without decompressing it. Returns
+// false if any file couldn't be read or isn't a compressed stream.
+fn list(paths: &[String]) -> bool {
+    println!("{:<10}{:>14}{:>14}{:>9}  name", "algorithm", "compressed", "original", "ratio");
+    let mut ok = true;
+    for path in paths {
+        let result = File::open(path)
+            .map_err(compression::error::Error::Io)
+            .and_then(|mut file_in| Ok((container::Header::read(&mut file_in)?, file_in.metadata()?.len())));
+        let (header, size) = match result {
+            Ok(result) => result,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                ok = false;
+                continue;
+            }
+        };
+        let algorithm = if header.stored { "stored".to_string() } else { format!("-{}", header.algorithm.name()) };
+        println!("{:<10}{:>14}{:>14}{:>8.2}%  {}", algorithm, size, header.size, ratio(header.size, size), path);
+    }
+    ok
+}
+
+// Print the CRC-32 (--crc32) or XXH64 (--xxh64) of each file, in hex,
+// as crc32 and xxhsum do.
+fn hash(kind: &str, paths: &[String]) -> bool {
+    let mut ok = true;
+    for path in paths {
+        let result = if kind == "--crc32" {
+            let mut crc = compression::crc32::Crc32::new();
+            read_all(path, |bytes| crc.update(bytes)).map(|()| format!("{:08x}", crc.value()))
+        }
+        else {
+            let mut xxh = compression::xxh64::Xxh64::new(0);
+            read_all(path, |bytes| xxh.update(bytes)).map(|()| format!("{:016x}", xxh.digest()))
+        };
+        match result {
+            Ok(hash) => println!("{}  {}", hash, path),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+// Pass all of a file to f, a buffer at a time.
+fn read_all<F: FnMut(&[u8])>(path: &str, mut f: F) -> std::io::Result<()> {
+    let mut file_in = BufReader::with_capacity(1 << 20, File::open(path)?);
+    loop {
+        let buf = file_in.fill_buf()?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        f(buf);
+        let len = buf.len();
+        file_in.consume(len);
+    }
+}
+
+// The times to record for a file, unless --no-mtime.
+fn file_times(metadata: &std::io::Result<std::fs::Metadata>, opts: &Options) -> container::Times {
+    match metadata {
+        Ok(metadata) if !opts.no_mtime => container::Times::of(metadata),
+        _ => container::Times::default(),
+    }
+}
+
+// Memory needed by algorithms whose memory use doesn't scale with
+// --max-memory, or None for those that scale.
+fn fixed_memory(algorithm: &str, mode: &str, opts: &Options, size_in: u64) -> Option<u64> {
+    match (algorithm, mode) {
+        ("-lpaq1", _) | ("-ppm", _) | ("-bwt", _) | ("-segment", _) | ("-sortrans", _) => None,
+        // The effort, and so the memory, of a stream isn't known until
+        // its header is read, so assume the most.
+        ("-flzp", "-c") => Some(compression::lz::flzp::memory(opts.effort)),
+        ("-flzp", _)    => Some(compression::lz::flzp::memory(Effort::Max)),
+        ("-lzw", "-c")  => Some(compression::lz::lzw::memory(size_in)),
+        ("-sr", _)      => Some(compression::ari::sr::MEMORY),
+        ("-fpaq", _)    => Some(compression::ari::fpaq::MEMORY),
+        ("-rans", _)    => Some(compression::ari::rans::MEMORY),
+        _               => Some(SMALL_MEMORY),
+    }
+}
+
+// Memory used by the algorithms that keep no large tables, mostly the
+// 1 MB input and output buffers.
+const SMALL_MEMORY: u64 = 8 << 20;
+
+// The lpaq1 memory level to compress at within --max-memory, less
+// reserved bytes needed for other things.
+fn lpaq1_level(opts: &Options, reserved: u64) -> u8 {
+    use compression::ari::lpaq1::{level_for, memory, DEFAULT_LEVEL};
+    match opts.max_memory {
+        Some(max) => level_for(max.saturating_sub(reserved), opts.switch)
+            .unwrap_or_else(|| exit_with_error(memory_error("-lpaq1", memory(0, opts.switch)))),
+        None => DEFAULT_LEVEL,
+    }
+}
+
+// The ppm model size to use within --max-memory.
+fn ppm_model(opts: &Options) -> u64 {
+    use compression::ari::ppm::{model_for, memory, DEFAULT_MODEL};
+    match opts.max_memory {
+        Some(max) => model_for(max).unwrap_or_else(|| exit_with_error(memory_error("-ppm", memory(1 << 20)))),
+        None => DEFAULT_MODEL,
+    }
+}
+
+// The bwt block size chosen by --auto-block, or the largest to use
+// within --max-memory.
+fn bwt_block_size(opts: &Options) -> usize {
+    use compression::bwt::bwt::{block_size_for, inverse_memory, memory, DEFAULT_BLOCK_SIZE, MIN_BLOCK_SIZE};
+    if let Some(size) = opts.bwt_block_size {
+        return size;
+    }
+    match opts.max_memory {
+        Some(max) => block_size_for(max, opts.sentinel)
+            .unwrap_or_else(|| {
+                let need = memory(MIN_BLOCK_SIZE as u64, opts.sentinel).max(inverse_memory(MIN_BLOCK_SIZE as u64));
+                exit_with_error(memory_error("-bwt", need))
+            }),
+        None => DEFAULT_BLOCK_SIZE,
+    }
+}
+
+fn memory_error(what: &str, need: u64) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::OutOfMemory,
+        format!("{} needs about {} bytes of memory, more than --max-memory", what, need))
+}
+
+// Parse a size in bytes, with an optional K, M or G suffix.
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.to_ascii_uppercase().chars().last()? {
+        'K' => (&size[..size.len() - 1], 10),
+        'M' => (&size[..size.len() - 1], 20),
+        'G' => (&size[..size.len() - 1], 30),
+        _   => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn exit_with_error(e: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", e);
+    std::process::exit(1);
+}
+
+// Output size as a percentage of input size.
+fn ratio(size_in: u64, size_out: u64) -> f64 {
+    if size_in == 0 {
+        return 0.0;
+    }
+    (size_out as f64 / size_in as f64) * 100.0
+}
+
+fn print_usage() -> ! {
     println!(
         "
-        \rUsage: [PROGRAM_NAME] [ALGORITHM] [MODE] [INPUT] [OUTPUT]
+        \rUsage: [PROGRAM_NAME] [ALGORITHM] [MODE] [OPTIONS] [INPUT] [OUTPUT]
+        \r       [PROGRAM_NAME] sa [INPUT] [OUTPUT]
+        \r       [PROGRAM_NAME] delta [-d] -ref [REFERENCE] [INPUT] [OUTPUT]
+        \r       [PROGRAM_NAME] diff [OLD] [NEW] [PATCH]
+        \r       [PROGRAM_NAME] patch [OLD] [PATCH] [NEW]
+        \r       [PROGRAM_NAME] selftest [OPTIONS] [SIZE]
+        \r       [PROGRAM_NAME] info --blocks [INPUT]
+        \r       [PROGRAM_NAME] -l [INPUT]...
+        \r       [PROGRAM_NAME] hash --crc32|--xxh64 [INPUT]...
+        \r       [PROGRAM_NAME] sweep [OPTIONS] [DIR]
 
         \rALGORITHM:
         \r    -lz77     LZ77 
         \r    -lzw      LZW
         \r    -flzp     LZP
+        \r    -sr       Symbol ranking
+        \r    -fpaq0    Order 0 arithmetic encoder, as a baseline
         \r    -fpaq     Adaptive arithmetic encoder
         \r    -lpaq1    Context mixing arithmetic encoder
+        \r    -ppm      Prediction by partial matching, order 6 by default
+        \r    -rans     Order 0 rANS, adaptive or static
+        \r    -fse      Order 0 tANS (finite state entropy)
         \r    -huffman  Static Huffman coding
         \r    -bwt      Burrows-Wheeler transform
+        \r    -remap    Remap bytes by frequency, most frequent first
+        \r    -split    Split 16 bit samples into high and low bytes
+        \r    -segment  Split into text, x86, binary and random segments,
+        \r              and compress each kind with its own lpaq1 models
+        \r    -sortrans Experimental: bwt, then lpaq1 with models for
+        \r              sorted blocks (o2,o3,o4), or with --fast, bwt,
+        \r              move-to-front and fpaq. bwt OPTIONS apply.
+
+        \rsa writes the suffix array of INPUT to OUTPUT, as a little endian
+        \r32 bit position for each suffix in sorted order.
+
+        \rdelta compresses INPUT relative to a similar REFERENCE file, such as
+        \ran earlier snapshot, by XORing them and compressing the result with
+        \rlpaq1. With -d it reproduces the original from the delta and the
+        \rsame REFERENCE, and fails with any other. lpaq1 OPTIONS apply.
+
+        \rdiff writes a binary patch that turns OLD into NEW, found with a
+        \rsuffix array of OLD as in bsdiff and compressed with lpaq1. patch
+        \rapplies it to OLD to reproduce NEW. lpaq1 OPTIONS apply.
+
+        \rselftest round trips every algorithm on generated random, constant,
+        \rtext and record data of SIZE bytes each (default 262144), and
+        \rreports the ratio and speed of each, then how much smaller or larger
+        \r-sortrans output is than -lpaq1 output, in points of ratio, to show
+        \rwhich suits each kind of data. OPTIONS apply as usual.
+
+        \rinfo --blocks lists the input and compressed size of each block of
+        \ran flzp stream written with --block-stats, also with --threads or
+        \r--index, showing which parts of the input compress poorly.
+
+        \r-l lists the algorithm, compressed size, original size and ratio of
+        \reach INPUT from its header, without decompressing it.
+
+        \rhash prints the CRC-32 or XXH64 of each INPUT in hex, as crc32 and
+        \rxxhsum do, to check files against stored checksums without another
+        \rtool.
+
+        \rsweep compresses each file under DIR to FILE.cmpr, checks that it
+        \rdecompresses to FILE, then removes FILE. Files that don't get
+        \rsmaller are left alone. A journal in DIR lets the next sweep
+        \rfinish one that was interrupted. OPTIONS apply, and:
+        \r    --algo NAME
+        \r              Algorithm to compress with, without the '-'
+        \r              (default lpaq1).
+        \r    --older-than AGE
+        \r              Only sweep files last modified more than AGE
+        \r              ago, as a number and d, h, m or s, such as 30d.
 
         \rMODE:
         \r    -c        Compress
-        \r    -d        Decompress
+        \r    -d        Decompress. Compressed files concatenated into
+        \r              one INPUT decompress to their originals,
+        \r              concatenated.
+        \r    -t        Test: decompress without writing output and
+        \r              report whether INPUT is intact. No OUTPUT.
+
+        \rOPTIONS:
+        \r    --dry-run Run without writing output and report the output
+        \r              size and ratio. OUTPUT may be omitted.
+        \r    --max-expand N%
+        \r              If compressing makes the output more than N%
+        \r              larger than the input, store the input instead.
+        \r              Stored output decompresses with any ALGORITHM.
+        \r    --volume-size SIZE
+        \r              Split the compressed output into volumes of at
+        \r              most SIZE bytes, with an optional K, M or G
+        \r              suffix, named OUTPUT.001, OUTPUT.002 and so on.
+        \r              To decompress or test them, give the first as
+        \r              INPUT and the rest are read after it.
+        \r    --no-mtime
+        \r              Don't record INPUT's modification and creation
+        \r              times when compressing, or restore them when
+        \r              decompressing. Creation times are only restored
+        \r              on Windows.
+        \r    --effort greedy|normal|max
+        \r              flzp match prediction effort (default greedy).
+        \r              Higher is slower but compresses better.
+        \r    --crc     Add a CRC-32 to each flzp block so corruption
+        \r              is detected when decompressing.
+        \r    --context N
+        \r              Number of bytes (2-8) of context flzp predicts
+        \r              matches from (default 4).
+        \r    --block-stats
+        \r              Record the input and compressed size of each
+        \r              flzp block at the end of the stream, for
+        \r              info --blocks. Decompressing ignores them.
+        \r    --sentinel
+        \r              Mark the start of each bwt block with a sentinel
+        \r              symbol instead of storing a primary index.
+        \r    --order1  Code each byte with a huffman table selected by
+        \r              the previous byte.
+        \r    --static  Code each -rans block with a table of its own
+        \r              byte counts, stored with it, instead of adapting.
+        \r    --order N
+        \r              Longest context -ppm predicts from, 0-16
+        \r              (default 6). Longer contexts help text with long
+        \r              repeats but fill the model sooner.
+        \r    --fast    Use only 2 fpaq SSE stages instead of 4.
+        \r              With -sortrans, use the fast profile: bwt blocks
+        \r              of at most 256K, then move-to-front and fpaq
+        \r              instead of lpaq1.
+        \r    --sse SPEC
+        \r              fpaq SSE stages, as CONTEXT:RATE[+RATE]:WEIGHT
+        \r              separated by commas, or none. CONTEXT is o0, o1,
+        \r              o1s or h3. The default is
+        \r              o0:5+9:4,o1:7:4,o1s:7:3,h3:7:2
+        \r    --models LIST
+        \r              lpaq1 models to mix with its order 1 model,
+        \r              separated by commas: o2, o3, o4, o6, word,
+        \r              match, sparse, and align4 and align8 for 4 and
+        \r              8 byte aligned records, or o1 for none. The
+        \r              default is o2,o3,o4,o6,word,match
+        \r    --switch  Use separate lpaq1 predictors for text and binary
+        \r              blocks, chosen per block. --models sets the text
+        \r              models; binary blocks use o2,o3,o4,match,sparse,
+        \r              align4,align8.
+        \r    --run-states
+        \r              Use zpaq's run length aware bit history states
+        \r              in the lpaq1 context models, which adapt faster
+        \r              to long runs of the same bit.
+        \r    --model-stats
+        \r              After lpaq1 compression, print each model's
+        \r              average mixer weight, share of the mixer output
+        \r              and bits per byte if used alone, on stderr.
+        \r    --big-endian
+        \r              Read 16 bit samples for -split as big endian
+        \r              instead of little endian.
+        \r    --max-memory SIZE
+        \r              Limit memory use to about SIZE bytes, with an
+        \r              optional K, M or G suffix. lpaq1 and bwt scale
+        \r              down to fit, and ppm's model takes what's left
+        \r              after its buffers (64M by default), starting over
+        \r              when full; the other algorithms use a fixed
+        \r              amount and fail if it doesn't fit. Decompressing
+        \r              fails if the stream needs more.
+        \r    --threads N
+        \r              Compress in independent blocks on N threads. The
+        \r              output is the same for any N, but a little larger
+        \r              than without --threads. Decompressing such a
+        \r              stream uses N threads, or one per CPU, up to 4,
+        \r              without --threads or --max-memory. --max-memory
+        \r              is shared between them. Reading and writing
+        \r              overlap with coding the blocks, even for N = 1.
+        \r    --block-size SIZE
+        \r              Size of the blocks for --threads and --index, with
+        \r              an optional K, M or G suffix. At least 4K, 4M by
+        \r              default.
+        \r    --index   Compress in blocks, as with --threads, and end
+        \r              the stream with an index of them, so a range of
+        \r              the input can be decompressed without the rest.
+        \r              See IndexedReader under Library.
+        \r    --auto-block
+        \r              Choose block sizes from the input's size and how
+        \r              much its first 1M repeats, with larger blocks for
+        \r              input that repeats, within --max-memory. Sets the
+        \r              --threads block size, the bwt block size, and
+        \r              --sentinel for bwt input that repeats, since
+        \r              sorting long repeats is slow without it.
+        \r    --nice N  Lower the process priority by N, as with the
+        \r              nice command (unix only).
+        \r    --cpu-limit P%
+        \r              Run at most P% of the time, sleeping between
+        \r              reads of input so other work gets the rest. With
+        \r              --threads, each thread keeps to P% on its own.
+        \r    --cpu-features LEVEL
+        \r              Run the vector kernels of the lpaq1 mixer and
+        \r              lz77 match finding at LEVEL: scalar, sse2 or
+        \r              avx2 (x86_64), or neon (aarch64), to compare
+        \r              them. The default is the best the cpu supports.
 
         \rEXAMPLES:
         \r    Compress C:/foo with fpaq and save to C:/bar:
 
         \r    program_name -fpaq -c C:/foo C:/bar
+
+        \r    Report how small C:/foo would get with lpaq1:
+
+        \r    program_name -lpaq1 -c --dry-run C:/foo
+
+        \r    Check that C:/bar decompresses with fpaq:
+
+        \r    program_name -fpaq -t C:/bar
         "
-    );
+    ); 
     std::process::exit(0);
 }
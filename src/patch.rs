@@ -0,0 +1,224 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::temp;
+use crate::ari::lpaq1::{lpaq1_compress, lpaq1_decompress, ModelSet};
+use crate::bwt::suffix_array::suffix_array;
+
+// Binary patches in the style of bsdiff (Colin Percival, "Naive
+// differences of executable code", 2003).
+//
+// Patch format, compressed as a whole with lpaq1:
+//
+//   entries (diff length (u64), extra length (u64), seek (i64),
+//            diff bytes, extra bytes)
+//
+// Each entry adds diff length bytes of the old file, starting at the
+// current old position, to the same number of diff bytes, then copies
+// the extra bytes, then moves the old position by seek. Regions that
+// moved but changed a little, like code with shifted addresses, give
+// diff bytes that are mostly 0, which lpaq1 codes in almost nothing.
+//
+// Matches are found with a suffix array of the old file and extended
+// while at least half their bytes agree, as in bsdiff.
+
+/// Approximate memory needed by diff to find matches: both files, the
+/// patch, which is about as long as new, and the suffix array of old,
+/// whose construction works on usize symbols. lpaq1 runs after the
+/// suffix array is freed.
+pub fn memory(old_len: u64, new_len: u64) -> u64 {
+    old_len.saturating_mul(48).saturating_add(new_len.saturating_mul(2)).saturating_add(4 << 20)
+}
+
+// Write a patch that turns old into new.
+pub fn diff<W: Write + io::Seek>(old: &[u8], new: &[u8], file_out: BufWriter<W>, models: ModelSet, switch: bool, level: u8, run_states: bool) -> error::Result<Sizes> {
+    let mut patch = Vec::new();
+    write_entries(old, new, BufWriter::with_capacity(1 << 20, &mut patch))?;
+    lpaq1_compress(BufReader::with_capacity(1 << 20, Cursor::new(patch)), file_out, models, switch, level, run_states)
+        .map(|sizes| Sizes { input: new.len() as u64, output: sizes.output })
+}
+
+fn write_entries<W: Write>(old: &[u8], new: &[u8], mut patch: BufWriter<W>) -> io::Result<()> {
+    let sa = suffix_array(old);
+
+    let mut scan = 0;
+    let mut len = 0;
+    let mut pos = 0;
+    let mut last_scan = 0;
+    let mut last_pos = 0;
+    let mut last_offset = 0isize;
+
+    while scan < new.len() {
+        // Find the next match that is longer than the old data at the
+        // last offset would give by more than 8 bytes.
+        let mut old_score = 0isize;
+        scan += len;
+        let mut scsc = scan;
+        while scan < new.len() {
+            (pos, len) = search(&sa, old, &new[scan..]);
+            while scsc < scan + len {
+                if agrees(old, new, scsc, last_offset) {
+                    old_score += 1;
+                }
+                scsc += 1;
+            }
+            if (len as isize == old_score && len != 0) || len as isize > old_score + 8 {
+                break;
+            }
+            if agrees(old, new, scan, last_offset) {
+                old_score -= 1;
+            }
+            scan += 1;
+        }
+
+        if len as isize != old_score || scan == new.len() {
+            // Extend the last match forwards and this one backwards, as
+            // long as at least half the bytes agree.
+            let mut s = 0isize;
+            let mut best = 0isize;
+            let mut len_f = 0;
+            let mut i = 0;
+            while last_scan + i < scan && last_pos + i < old.len() {
+                if old[last_pos + i] == new[last_scan + i] {
+                    s += 1;
+                }
+                i += 1;
+                if s * 2 - i as isize > best * 2 - len_f as isize {
+                    best = s;
+                    len_f = i;
+                }
+            }
+
+            let mut len_b = 0;
+            if scan < new.len() {
+                let mut s = 0isize;
+                let mut best = 0isize;
+                let mut i = 1;
+                while scan >= last_scan + i && pos >= i {
+                    if old[pos - i] == new[scan - i] {
+                        s += 1;
+                    }
+                    if s * 2 - i as isize > best * 2 - len_b as isize {
+                        best = s;
+                        len_b = i;
+                    }
+                    i += 1;
+                }
+            }
+
+            // If the extensions overlap, split the overlap where it
+            // matches best.
+            if last_scan + len_f > scan - len_b {
+                let overlap = (last_scan + len_f) - (scan - len_b);
+                let mut s = 0;
+                let mut best = 0;
+                let mut len_s = 0;
+                for i in 0..overlap {
+                    if new[last_scan + len_f - overlap + i] == old[last_pos + len_f - overlap + i] {
+                        s += 1;
+                    }
+                    if new[scan - len_b + i] == old[pos - len_b + i] {
+                        s -= 1;
+                    }
+                    if s > best {
+                        best = s;
+                        len_s = i + 1;
+                    }
+                }
+                len_f = len_f + len_s - overlap;
+                len_b -= len_s;
+            }
+
+            let extra = (scan - len_b) - (last_scan + len_f);
+            let seek = (pos - len_b) as i64 - (last_pos + len_f) as i64;
+            patch.write_u64(len_f as u64)?;
+            patch.write_u64(extra as u64)?;
+            patch.write_u64(seek as u64)?;
+            for i in 0..len_f {
+                patch.write_u8(new[last_scan + i].wrapping_sub(old[last_pos + i]))?;
+            }
+            patch.write_bytes(&new[last_scan + len_f..scan - len_b])?;
+
+            last_scan = scan - len_b;
+            last_pos = pos - len_b;
+            last_offset = pos as isize - scan as isize;
+        }
+    }
+    patch.flush_buffer()
+}
+
+// Apply a patch from diff to old, returning an InvalidData error if the
+// patch is damaged or wasn't made from this old file, or an OutOfMemory
+// error if its lpaq1 stream needs more than max_memory bytes.
+pub fn apply<R: Read, W: Write>(old: &[u8], file_in: BufReader<R>, file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    let (temp, temp_file) = temp::create("patch", "patch")?;
+    let result = lpaq1_decompress(file_in, BufWriter::with_capacity(1 << 20, temp_file), max_memory)
+        .and_then(|sizes| {
+            let patch = BufReader::with_capacity(1 << 20, File::open(&temp)?);
+            tracked(patch, file_out, |patch, file_out| apply_entries(old, patch, file_out))
+                .map(|applied| Sizes { input: sizes.input, output: applied.output })
+        });
+    fs::remove_file(&temp)?;
+    result
+}
+
+fn apply_entries<R: Read, W: Write>(old: &[u8], mut patch: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+    let mut pos = 0i64;
+    let mut bytes = Vec::new();
+
+    while let Some(len_diff) = patch.read_u64_checked()? {
+        let (len_extra, seek) = match (patch.read_u64_checked()?, patch.read_u64_checked()?) {
+            (Some(len_extra), Some(seek)) => (len_extra, seek as i64),
+            _ => return Err(invalid("truncated patch")),
+        };
+        let start = usize::try_from(pos).ok()
+            .filter(|&start| (start as u64).checked_add(len_diff).is_some_and(|end| end <= old.len() as u64))
+            .ok_or_else(|| invalid("patch doesn't match old file"))?;
+
+        bytes.clear();
+        patch.by_ref().take(len_diff.saturating_add(len_extra)).read_to_end(&mut bytes)?;
+        if (bytes.len() as u64) < len_diff.saturating_add(len_extra) {
+            return Err(invalid("truncated patch"));
+        }
+        for (byte, old_byte) in bytes.iter_mut().zip(old[start..].iter()).take(len_diff as usize) {
+            *byte = byte.wrapping_add(*old_byte);
+        }
+        file_out.write_bytes(&bytes)?;
+        pos = pos.wrapping_add(len_diff as i64).wrapping_add(seek);
+    }
+    file_out.flush()
+}
+
+// Whether new[i] agrees with the old byte at offset from it.
+fn agrees(old: &[u8], new: &[u8], i: usize, offset: isize) -> bool {
+    let j = i as isize + offset;
+    j >= 0 && (j as usize) < old.len() && old[j as usize] == new[i]
+}
+
+// Find the longest prefix of data that occurs in old, returning its
+// position in old and its length.
+fn search(sa: &[u32], old: &[u8], data: &[u8]) -> (usize, usize) {
+    let common = |i: u32| old[i as usize..].iter().zip(data.iter()).take_while(|(a, b)| a == b).count();
+    if sa.is_empty() {
+        return (0, 0);
+    }
+    // The longest match is next to where data would sort among the
+    // suffixes.
+    let i = sa.partition_point(|&s| &old[s as usize..] < data);
+    [i.wrapping_sub(1), i].iter()
+        .filter_map(|&i| sa.get(i))
+        .map(|&s| (s as usize, common(s)))
+        .max_by_key(|&(_, len)| len)
+        .unwrap_or((0, 0))
+}
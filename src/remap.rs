@@ -0,0 +1,91 @@
+use std::io;
+use std::io::Write;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+
+// Output format:
+//
+//   blocks (header, then the remapped bytes)
+//
+// A block header is the number of distinct bytes in the block minus 1
+// (u8), those bytes from most to least frequent, then the block length
+// (u32). Each byte is replaced by its rank in that list, so frequent
+// bytes take contiguous low values whatever they were originally, as
+// with the 4 letters of DNA or the 64 characters of base64. This helps
+// models with small contexts, which then see the same few values.
+//
+// Ties in frequency are broken by byte value, so the output depends
+// only on the input.
+
+#[allow(clippy::redundant_closure)]
+pub fn remap<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| remap_blocks(file_in, file_out))
+}
+
+fn remap_blocks<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let block_size = file_in.capacity();
+
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+
+        let mut counts = [0u32; 256];
+        for &byte in block.iter() {
+            counts[byte as usize] += 1;
+        }
+        let mut symbols = (0..=255u8).filter(|&byte| counts[byte as usize] > 0).collect::<Vec<u8>>();
+        symbols.sort_by_key(|&byte| std::cmp::Reverse(counts[byte as usize]));
+
+        let mut rank = [0u8; 256];
+        for (i, &byte) in symbols.iter().enumerate() {
+            rank[byte as usize] = i as u8;
+        }
+
+        file_out.write_u8((symbols.len() - 1) as u8)?;
+        file_out.write_bytes(&symbols)?;
+        file_out.write_u32(block.len() as u32)?;
+        for &byte in block.iter() {
+            file_out.write_u8(rank[byte as usize])?;
+        }
+    }
+    file_out.flush_buffer()
+}
+
+// Undo remap, returning an InvalidData error if the stream is truncated
+// or a byte has no symbol in its block's map.
+#[allow(clippy::redundant_closure)]
+pub fn unmap<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| unmap_blocks(file_in, file_out))
+}
+
+fn unmap_blocks<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+    let mut symbols = Vec::new();
+    let mut block = Vec::new();
+
+    while let Some(count) = file_in.read_u8_checked()? {
+        symbols.clear();
+        file_in.by_ref().take(count as u64 + 1).read_to_end(&mut symbols)?;
+        let len = file_in.read_u32_checked()?;
+        if symbols.len() != count as usize + 1 || len.is_none() {
+            return Err(invalid("truncated remap stream"));
+        }
+
+        block.clear();
+        file_in.by_ref().take(len.unwrap() as u64).read_to_end(&mut block)?;
+        if block.len() != len.unwrap() as usize {
+            return Err(invalid("truncated remap stream"));
+        }
+        for &rank in block.iter() {
+            let byte = symbols.get(rank as usize).ok_or_else(|| invalid("invalid remap symbol"))?;
+            file_out.write_u8(*byte)?;
+        }
+    }
+    file_out.flush_buffer()?;
+    Ok(())
+}
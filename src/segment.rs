@@ -0,0 +1,269 @@
+use std::io;
+use std::io::Cursor;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::ari::lpaq1::{self, lpaq1_compress, lpaq1_decompress, ModelSet};
+
+// Split the input into segments of one kind of content, and code each
+// kind its own way. Mixed files, like installers, executables with
+// resources, or documents with embedded images, get a model suited to
+// each part instead of one that's a compromise between them.
+//
+// The input is classified in WINDOW byte windows:
+//
+//   Random  entropy of at least RANDOM_ENTROPY bits per byte, such as
+//           encrypted data. Stored. Random bytes measure about 7.95
+//           in a window this size, but deflate output only about 7.87,
+//           and lpaq1 still finds some redundancy in it.
+//   Text    as lpaq1 --switch decides. lpaq1 with its default models.
+//   X86     CALL instructions (E8 and a 32 bit offset whose high byte
+//           is 0 or FF) at least once every CALL_SPACING bytes. The
+//           offsets are made absolute, so calls to the same function
+//           look alike, then lpaq1 with the binary models.
+//   Binary  anything else. lpaq1 with the binary models.
+//
+// Runs of windows shorter than MIN_WINDOWS are merged into the segment
+// before them, so noise in the classification doesn't split the input
+// into pieces too small to model. The segments of each kind are then
+// joined and coded as one stream, so the models stay warm from one
+// segment to the next.
+//
+// Output format:
+//
+//   count     (u32 number of segments)
+//   segments  (kind (u8) and length (u64) of each, in input order)
+//   streams   (for each kind that has segments, in the order above,
+//              the u64 length of its stream, then the stream)
+
+const WINDOW: usize = 4096;
+const MIN_WINDOWS: usize = 4;
+const RANDOM_ENTROPY: f64 = 7.93;
+const CALL_SPACING: usize = 256;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Kind {
+    Random = 0,
+    Text   = 1,
+    X86    = 2,
+    Binary = 3,
+}
+
+const KINDS: [Kind; 4] = [Kind::Random, Kind::Text, Kind::X86, Kind::Binary];
+
+impl Kind {
+    fn from_u8(kind: u8) -> Option<Kind> {
+        KINDS.get(kind as usize).copied()
+    }
+
+    fn models(self) -> ModelSet {
+        match self {
+            Kind::Text => ModelSet::default(),
+            _ => ModelSet::binary(),
+        }
+    }
+}
+
+/// Approximate memory needed beyond lpaq1's: the input, its segments
+/// grouped by kind, and the compressed streams.
+pub fn memory(size: u64) -> u64 {
+    3 * size
+}
+
+pub fn segment_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, level: u8, run_states: bool) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| compress(file_in, file_out, level, run_states))
+}
+
+fn compress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, level: u8, run_states: bool) -> io::Result<()> {
+    let mut data = Vec::new();
+    file_in.read_to_end(&mut data)?;
+    let segments = segment(&data);
+
+    file_out.write_u32(segments.len() as u32)?;
+    for (kind, range) in segments.iter() {
+        file_out.write_u8(*kind as u8)?;
+        file_out.write_u64(range.len() as u64)?;
+    }
+    for kind in KINDS {
+        let mut grouped = segments.iter()
+            .filter(|(k, _)| *k == kind)
+            .flat_map(|(_, range)| data[range.clone()].iter().copied())
+            .collect::<Vec<u8>>();
+        if grouped.is_empty() {
+            continue;
+        }
+        let stream = match kind {
+            Kind::Random => grouped,
+            _ => {
+                if kind == Kind::X86 {
+                    x86_encode(&mut grouped);
+                }
+                let mut stream = Cursor::new(Vec::new());
+                lpaq1_compress(BufReader::new(&grouped[..]), BufWriter::new(&mut stream),
+                    kind.models(), false, level, run_states)?;
+                stream.into_inner()
+            }
+        };
+        file_out.write_u64(stream.len() as u64)?;
+        file_out.write_bytes(&stream)?;
+    }
+    file_out.flush()
+}
+
+// Decompress a segment stream, returning an InvalidData error if it's
+// truncated or its streams don't match its segments, and an OutOfMemory
+// error if lpaq1 would need more than max_memory.
+pub fn segment_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, max_memory: Option<u64>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| decompress(file_in, file_out, max_memory))
+}
+
+fn decompress<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, max_memory: Option<u64>) -> io::Result<()> {
+    let count = file_in.read_u32_checked()?.ok_or_else(truncated)?;
+    // The count isn't trusted enough to allocate up front.
+    let mut segments = Vec::new();
+    let mut sizes = [0u64; 4];
+    for _ in 0..count {
+        let kind = file_in.read_u8_checked()?.ok_or_else(truncated)?;
+        let kind = Kind::from_u8(kind).ok_or_else(|| invalid_data(format!("unknown segment kind {}", kind)))?;
+        let len = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+        sizes[kind as usize] = sizes[kind as usize].checked_add(len)
+            .ok_or_else(|| invalid_data("segment lengths overflow".to_string()))?;
+        segments.push((kind, len));
+    }
+
+    let mut grouped = [Vec::new(), Vec::new(), Vec::new(), Vec::new()];
+    for kind in KINDS.into_iter().filter(|kind| sizes[*kind as usize] > 0) {
+        let len = file_in.read_u64_checked()?.ok_or_else(truncated)?;
+        let mut stream = Vec::new();
+        file_in.by_ref().take(len).read_to_end(&mut stream)?;
+        if (stream.len() as u64) < len {
+            return Err(truncated());
+        }
+        let data = &mut grouped[kind as usize];
+        match kind {
+            Kind::Random => *data = stream,
+            _ => {
+                lpaq1_decompress(BufReader::new(&stream[..]), BufWriter::new(&mut *data), max_memory)?;
+            }
+        }
+        if data.len() as u64 != sizes[kind as usize] {
+            return Err(invalid_data(format!("segment stream has {} bytes, but its segments have {}",
+                data.len(), sizes[kind as usize])));
+        }
+        if kind == Kind::X86 {
+            x86_decode(data);
+        }
+    }
+
+    let mut offsets = [0usize; 4];
+    for (kind, len) in segments {
+        let start = offsets[kind as usize];
+        let end = start + len as usize;
+        file_out.write_bytes(&grouped[kind as usize][start..end])?;
+        offsets[kind as usize] = end;
+    }
+    file_out.flush()
+}
+
+// Classify data in windows and merge them into segments.
+fn segment(data: &[u8]) -> Vec<(Kind, std::ops::Range<usize>)> {
+    let mut runs: Vec<(Kind, usize)> = Vec::new();
+    for window in data.chunks(WINDOW) {
+        let kind = classify(window);
+        match runs.last_mut() {
+            Some((last, windows)) if *last == kind => *windows += 1,
+            _ => runs.push((kind, 1)),
+        }
+    }
+
+    let mut segments: Vec<(Kind, std::ops::Range<usize>)> = Vec::new();
+    let mut start = 0;
+    for (kind, windows) in runs {
+        let end = (start + windows * WINDOW).min(data.len());
+        match segments.last_mut() {
+            Some((last, range)) if *last == kind || windows < MIN_WINDOWS => range.end = end,
+            _ => segments.push((kind, start..end)),
+        }
+        start = end;
+    }
+    segments
+}
+
+fn classify(window: &[u8]) -> Kind {
+    if entropy(window) >= RANDOM_ENTROPY {
+        Kind::Random
+    }
+    else if lpaq1::is_text(window) {
+        Kind::Text
+    }
+    else if calls(window) * CALL_SPACING >= window.len() {
+        Kind::X86
+    }
+    else {
+        Kind::Binary
+    }
+}
+
+// Order 0 entropy in bits per byte.
+fn entropy(window: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in window.iter() {
+        counts[*byte as usize] += 1;
+    }
+    let len = window.len() as f64;
+    counts.iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+// Number of likely x86 CALL instructions.
+fn calls(window: &[u8]) -> usize {
+    window.windows(5)
+        .filter(|code| code[0] == 0xE8 && matches!(code[4], 0x00 | 0xFF))
+        .count()
+}
+
+// Replace the low 24 bits of each CALL's relative offset with the low
+// 24 bits of its target, taking the CALL's position as its address.
+// The opcode and high byte are kept, so x86_decode finds the same
+// CALLs.
+fn x86_encode(data: &mut [u8]) {
+    x86_transform(data, u32::wrapping_add);
+}
+
+fn x86_decode(data: &mut [u8]) {
+    x86_transform(data, u32::wrapping_sub);
+}
+
+fn x86_transform(data: &mut [u8], op: fn(u32, u32) -> u32) {
+    let mut i = 0;
+    while i + 5 <= data.len() {
+        if data[i] == 0xE8 && matches!(data[i + 4], 0x00 | 0xFF) {
+            let offset = u32::from_le_bytes([data[i + 1], data[i + 2], data[i + 3], 0]);
+            let offset = op(offset, i as u32) & 0xFFFFFF;
+            data[i + 1..i + 4].copy_from_slice(&offset.to_le_bytes()[..3]);
+            i += 5;
+        }
+        else {
+            i += 1;
+        }
+    }
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn truncated() -> io::Error {
+    invalid_data("truncated segment stream".to_string())
+}
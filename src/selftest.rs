@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::Instant;
+
+use compression::temp;
+
+use crate::Options;
+use crate::ALGORITHMS;
+
+// Round trip every algorithm over a few kinds of generated input, and
+// report the ratio and speed of each. A quick check that a new build
+// works, and roughly how fast it is on this machine.
+//
+// Then, for each input, the difference in ratio between -sortrans and
+// -lpaq1, context mixing with and without sorting the input first,
+// since which is better depends on the input.
+
+pub const DEFAULT_SIZE: usize = 1 << 18;
+
+const SAMPLE: &str = "\
+    the quick brown fox jumps over the lazy dog while the cat sleeps in \
+    the sun and a bird sings in the tree above them, as the wind moves \
+    through the grass of the field behind the old house on the hill. ";
+
+// Run the self test with inputs of size bytes. Returns false if any
+// algorithm failed to reproduce its input.
+pub(crate) fn selftest(opts: &Options, size: usize) -> bool {
+    let inputs = [
+        ("random",   random(size)),
+        ("constant", vec![0; size]),
+        ("text",     markov_text(size)),
+        ("records",  records(size)),
+    ];
+    let (path_in, _)  = temp::create("selftest", "in").unwrap();
+    let (path_cmp, _) = temp::create("selftest", "cmp").unwrap();
+    let (path_out, _) = temp::create("selftest", "out").unwrap();
+
+    println!("{:<10}{:<10}{:>9}{:>14}{:>14}", "input", "algorithm", "ratio", "compress", "decompress");
+    let mut passed = true;
+    let mut deltas = Vec::new();
+    for (name, data) in inputs.iter() {
+        fs::write(&path_in, data).unwrap();
+        let mut ratios = HashMap::new();
+        for algorithm in ALGORITHMS.iter() {
+            let c_time = time(|| run(algorithm, "-c", opts, &path_in, &path_cmp));
+            let d_time = time(|| run(algorithm, "-d", opts, &path_cmp, &path_out));
+            let ok = fs::read(&path_out).unwrap() == *data;
+            passed &= ok;
+            let ratio = crate::ratio(data.len() as u64, fs::metadata(&path_cmp).unwrap().len());
+            ratios.insert(*algorithm, ratio);
+            println!("{:<10}{:<10}{:>8.2}%{:>14}{:>14}{}",
+                name,
+                algorithm,
+                ratio,
+                speed(data.len(), c_time),
+                speed(data.len(), d_time),
+                if ok { "" } else { "  FAILED" }
+            );
+        }
+        deltas.push((name, ratios["-sortrans"] - ratios["-lpaq1"]));
+    }
+
+    println!();
+    println!("{:<10}{:>24}", "input", "-sortrans vs -lpaq1");
+    for (name, delta) in deltas {
+        println!("{:<10}{:>+23.2}%", name, delta);
+    }
+    for path in [path_in, path_cmp, path_out] {
+        let _ = fs::remove_file(path);
+    }
+    passed
+}
+
+fn run(algorithm: &str, mode: &str, opts: &Options, path_in: &PathBuf, path_out: &PathBuf) {
+    let file_in = BufReader::with_capacity(1 << 20, File::open(path_in).unwrap());
+    let file_out = BufWriter::with_capacity(1 << 20, File::create(path_out).unwrap());
+    if let Err(e) = crate::run(algorithm, mode, opts, &fs::metadata(path_in), file_in, file_out) {
+        crate::exit_with_error(e);
+    }
+}
+
+fn time(f: impl FnOnce()) -> Duration {
+    let start = Instant::now();
+    f();
+    start.elapsed()
+}
+
+fn speed(size: usize, time: Duration) -> String {
+    format!("{:.2} MB/s", size as f64 / (1 << 20) as f64 / time.as_secs_f64())
+}
+
+// splitmix64, seeded so every run tests the same data. (The output of
+// simpler generators like xorshift is linear enough that lpaq1 finds
+// patterns in it.)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+fn random(size: usize) -> Vec<u8> {
+    let mut rng = Rng(0x9E3779B97F4A7C15);
+    (0..size).map(|_| rng.next() as u8).collect()
+}
+
+// Text from an order 2 Markov chain trained on SAMPLE: each byte is
+// chosen among the bytes that follow the previous 2 in SAMPLE.
+fn markov_text(size: usize) -> Vec<u8> {
+    let sample = SAMPLE.as_bytes();
+    let mut rng = Rng(0x2545F4914F6CDD1D);
+    let mut text = sample[..2].to_vec();
+    while text.len() < size {
+        let cxt = &text[text.len() - 2..];
+        let next = sample.windows(3)
+            .filter(|w| w[..2] == *cxt)
+            .map(|w| w[2])
+            .collect::<Vec<u8>>();
+        let byte = if next.is_empty() {
+            sample[rng.next() as usize % sample.len()]
+        }
+        else {
+            next[rng.next() as usize % next.len()]
+        };
+        text.push(byte);
+    }
+    text.truncate(size);
+    text
+}
+
+// Fixed size 16 byte records: a tag, a counter, a slowly changing
+// value and some noise.
+fn records(size: usize) -> Vec<u8> {
+    let mut rng = Rng(0xD1B54A32D192ED03);
+    let mut data = Vec::with_capacity(size + 16);
+    let mut value = 1000u32;
+    for i in 0..(size as u32).div_ceil(16) {
+        value = value.wrapping_add((rng.next() % 5) as u32).wrapping_sub(2);
+        data.extend_from_slice(b"REC:");
+        data.extend_from_slice(&i.to_le_bytes());
+        data.extend_from_slice(&value.to_le_bytes());
+        data.extend_from_slice(&(rng.next() as u32 & 0xFF).to_le_bytes());
+    }
+    data.truncate(size);
+    data
+}
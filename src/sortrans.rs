@@ -0,0 +1,119 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Write;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+use crate::temp;
+use crate::ari::fpaq::{fpaq_compress, fpaq_decompress, SseProfile};
+use crate::ari::lpaq1::{lpaq1_compress, lpaq1_decompress, ModelSet};
+use crate::bwt::bwt::{bwt_transform, bwt_inverse_transform};
+use crate::bwt::mtf::{mtf_encode, mtf_decode};
+
+// Sorted block compression, an experiment: the Burrows-Wheeler transform,
+// then lpaq1 with models for its output, ModelSet::bwt, instead of the
+// move-to-front and entropy coding stages usual after a BWT.
+//
+// Context mixing over sorted blocks wants different contexts than over
+// the input itself, since sorting leaves short contexts that predict
+// well and no words or matches. Whether it beats lpaq1 on the input
+// depends on the data; selftest reports the difference.
+//
+// With --fast, the Fast profile instead takes the usual route: smaller
+// blocks, move-to-front with zero run lengths, then fpaq with 2 SSE
+// stages, for several times the speed at a lower ratio.
+//
+// The output is a byte giving the profile, then an ordinary lpaq1 stream
+// of the bwt stream, or an fpaq stream of the move-to-front coded bwt
+// stream. The bwt stream records its block size, so decompressing needs
+// no options.
+
+/// The stages after the transform.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Profile {
+    Max  = 0, // lpaq1 with ModelSet::bwt
+    Fast = 1, // Move-to-front and zero run lengths, then fpaq
+}
+
+/// Largest bwt block size of the Fast profile. Sorting smaller blocks
+/// takes less time per byte.
+pub const FAST_BLOCK_SIZE: usize = 256 << 10;
+
+/// Transform file_in with bwt in blocks of block_size, with sentinels if
+/// sentinel is set, then compress it with the stages of profile, lpaq1
+/// at level for Max.
+pub fn sortrans_compress<R: Read, W: Write + io::Seek>(file_in: BufReader<R>, mut file_out: BufWriter<W>, profile: Profile,
+    sentinel: bool, block_size: usize, level: u8, run_states: bool) -> error::Result<Sizes> {
+    file_out.write_u8(profile as u8)?;
+    let block_size = match profile {
+        Profile::Max => block_size,
+        Profile::Fast => block_size.min(FAST_BLOCK_SIZE),
+    };
+    let (temp, temp_file) = temp::create("sortrans", "bwt")?;
+    let result = bwt_transform(file_in, BufWriter::with_capacity(1 << 20, temp_file), sentinel, block_size)
+        .and_then(|sizes| {
+            let file_in = BufReader::with_capacity(1 << 20, File::open(&temp)?);
+            let compressed = match profile {
+                // lpaq1 seeks back to its own start, after the profile.
+                Profile::Max => {
+                    file_out.flush()?;
+                    let file_out = BufWriter::with_capacity(1 << 20, Tracked::new(file_out));
+                    lpaq1_compress(file_in, file_out, ModelSet::bwt(), false, level, run_states)?
+                }
+                Profile::Fast => fast_compress(file_in, file_out)?,
+            };
+            Ok(Sizes { input: sizes.input, output: 1 + compressed.output })
+        });
+    fs::remove_file(&temp)?;
+    result
+}
+
+fn fast_compress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    let (temp, temp_file) = temp::create("sortrans", "mtf")?;
+    let result = mtf_encode(file_in, BufWriter::with_capacity(1 << 20, temp_file))
+        .and_then(|_| fpaq_compress(BufReader::with_capacity(1 << 20, File::open(&temp)?), file_out, &SseProfile::fast()));
+    fs::remove_file(&temp)?;
+    result
+}
+
+/// Reproduce the input of sortrans_compress, returning an error if the
+/// profile is unknown or a stream is invalid, or lpaq1 or bwt needs more
+/// than max_memory bytes.
+pub fn sortrans_decompress<R: Read, W: Write>(mut file_in: BufReader<R>, file_out: BufWriter<W>,
+    max_memory: Option<u64>) -> error::Result<Sizes> {
+    let profile = match file_in.read_u8_checked()? {
+        Some(0) => Profile::Max,
+        Some(1) => Profile::Fast,
+        Some(profile) => return Err(io::Error::new(ErrorKind::InvalidData, format!("unknown sortrans profile {}", profile)).into()),
+        None => return Err(io::Error::new(ErrorKind::InvalidData, "truncated sortrans stream").into()),
+    };
+    let (temp, temp_file) = temp::create("sortrans", "bwt")?;
+    let result = match profile {
+        Profile::Max => lpaq1_decompress(file_in, BufWriter::with_capacity(1 << 20, temp_file), max_memory),
+        Profile::Fast => fast_decompress(file_in, BufWriter::with_capacity(1 << 20, temp_file)),
+    }
+    .and_then(|sizes| {
+        let file_in = BufReader::with_capacity(1 << 20, File::open(&temp)?);
+        let restored = bwt_inverse_transform(file_in, file_out, max_memory)?;
+        Ok(Sizes { input: 1 + sizes.input, output: restored.output })
+    });
+    fs::remove_file(&temp)?;
+    result
+}
+
+fn fast_decompress<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    let (temp, temp_file) = temp::create("sortrans", "mtf")?;
+    let result = fpaq_decompress(file_in, BufWriter::with_capacity(1 << 20, temp_file))
+        .and_then(|sizes| {
+            let restored = mtf_decode(BufReader::with_capacity(1 << 20, File::open(&temp)?), file_out)?;
+            Ok(Sizes { input: sizes.input, output: restored.output })
+        });
+    fs::remove_file(&temp)?;
+    result
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::io::Write;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::BufReader;
+use std::io::BufWriter;
+
+use crate::bufio::*;
+use crate::error;
+use crate::stats::Sizes;
+
+// Output format:
+//
+//   byte order (u8, 0 for little endian samples, 1 for big endian)
+//   blocks     (u32 block length, then the split bytes)
+//
+// The input is read as 16 bit samples. Each block is written as the
+// high byte of every sample, then the low byte of every sample, then
+// the last byte of the block if its length is odd. In audio, sensor or
+// image data the high bytes change slowly and the low bytes are mostly
+// noise, so apart they suit byte oriented models much better than
+// interleaved.
+
+pub fn split<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>, big_endian: bool) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| split_samples(file_in, file_out, big_endian))
+}
+
+fn split_samples<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>, big_endian: bool) -> io::Result<()> {
+    let block_size = file_in.capacity();
+    // Offset of the high byte within a sample.
+    let high = if big_endian { 0 } else { 1 };
+    file_out.write_u8(big_endian as u8)?;
+
+    for block in Blocks::new(&mut file_in, block_size) {
+        let block = block?;
+
+        file_out.write_u32(block.len() as u32)?;
+        let samples = block.chunks_exact(2);
+        let odd = samples.remainder().first().copied();
+        for sample in samples.clone() {
+            file_out.write_u8(sample[high])?;
+        }
+        for sample in samples {
+            file_out.write_u8(sample[1 - high])?;
+        }
+        if let Some(byte) = odd {
+            file_out.write_u8(byte)?;
+        }
+    }
+    file_out.flush_buffer()
+}
+
+// Undo split, returning an InvalidData error if the stream is truncated.
+#[allow(clippy::redundant_closure)]
+pub fn join<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| join_samples(file_in, file_out))
+}
+
+fn join_samples<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    let invalid = |msg| io::Error::new(ErrorKind::InvalidData, msg);
+    let high = match file_in.read_u8_checked()? {
+        Some(0) => 1,
+        Some(1) => 0,
+        Some(_) => return Err(invalid("invalid split byte order")),
+        None => return Err(invalid("truncated split stream")),
+    };
+    let mut block = Vec::new();
+    let mut samples = Vec::new();
+
+    while let Some(len) = file_in.read_u32_checked()? {
+        block.clear();
+        file_in.by_ref().take(len as u64).read_to_end(&mut block)?;
+        if block.len() != len as usize {
+            return Err(invalid("truncated split stream"));
+        }
+
+        let (highs, rest) = block.split_at(block.len() / 2);
+        let (lows, odd) = rest.split_at(highs.len());
+        samples.clear();
+        for (&h, &l) in highs.iter().zip(lows.iter()) {
+            let mut sample = [0u8; 2];
+            sample[high] = h;
+            sample[1 - high] = l;
+            samples.extend_from_slice(&sample);
+        }
+        samples.extend_from_slice(odd);
+        file_out.write_bytes(&samples)?;
+    }
+    file_out.flush_buffer()?;
+    Ok(())
+}
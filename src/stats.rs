@@ -0,0 +1,55 @@
+use std::io;
+use std::io::BufReader;
+use std::io::Read;
+
+use crate::bufio::*;
+
+/// Bytes read and written by a compressor or decompressor.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Sizes {
+    pub input:  u64,
+    pub output: u64,
+}
+
+// Byte statistics gathered in a single pass over the input, for the
+// compressors that need a model of the whole input up front.
+pub struct Stats {
+    pub total:  u64,             // Number of bytes
+    pub order0: [u64; 256],      // Count of each byte
+    pub order1: Vec<[u32; 256]>, // Count of each byte by previous byte, if collected
+}
+
+impl Stats {
+    // Count bytes, and if order1 is set, also bytes by previous byte.
+    // The first byte is counted in context 0. Reads to the end of the
+    // input without rewinding it.
+    pub fn collect<R: Read>(file_in: &mut BufReader<R>, order1: bool) -> io::Result<Stats> {
+        let mut stats = Stats {
+            total:  0,
+            order0: [0; 256],
+            order1: if order1 { vec![[0; 256]; 256] } else { Vec::new() },
+        };
+        let mut prev = 0usize;
+
+        let block_size = file_in.capacity();
+        for block in Blocks::new(file_in, block_size) {
+            let block = block?;
+            stats.total += block.len() as u64;
+            for byte in block.iter() {
+                stats.order0[*byte as usize] += 1;
+            }
+            if order1 {
+                for byte in block.iter() {
+                    stats.order1[prev][*byte as usize] += 1;
+                    prev = *byte as usize;
+                }
+            }
+        }
+        Ok(stats)
+    }
+
+    // Order 1 counts for bytes following prev.
+    pub fn context(&self, prev: u8) -> &[u32; 256] {
+        &self.order1[prev as usize]
+    }
+}
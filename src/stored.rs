@@ -0,0 +1,31 @@
+// Stored (uncompressed) output, used in place of a compressed stream
+// that came out much larger than its input. The container header marks
+// the input as stored, and it follows unchanged, so decompressing with
+// any algorithm gives it back.
+
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+
+use crate::bufio::tracked;
+use crate::error;
+use crate::stats::Sizes;
+
+// Copy file_in to file_out unchanged.
+#[allow(clippy::redundant_closure)]
+pub fn copy<R: Read, W: Write>(file_in: BufReader<R>, file_out: BufWriter<W>) -> error::Result<Sizes> {
+    tracked(file_in, file_out, |file_in, file_out| copy_stored(file_in, file_out))
+}
+
+fn copy_stored<R: Read, W: Write>(mut file_in: BufReader<R>, mut file_out: BufWriter<W>) -> io::Result<()> {
+    io::copy(&mut file_in, &mut file_out)?;
+    file_out.flush()
+}
+
+// True if a compressed size of size_out is more than max_expand percent
+// larger than size_in.
+pub fn too_large(size_in: u64, size_out: u64, max_expand: u64) -> bool {
+    size_out as u128 * 100 > size_in as u128 * (100 + max_expand as u128)
+}
@@ -0,0 +1,256 @@
+use std::fs;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+use std::time::SystemTime;
+
+use compression::container;
+use compression::container::Algorithm;
+
+use crate::Options;
+
+// Compress old files in a directory in place, for cold storage. Each
+// file FILE is compressed to FILE.sweep-tmp, which is decompressed and
+// compared with FILE, then renamed to FILE.cmpr, and FILE is removed.
+//
+// A journal in the directory records each file as it's begun and done,
+// so a sweep that was interrupted is finished by the next one: a
+// leftover temporary file was never verified and is removed, while a
+// FILE.cmpr next to its FILE was verified before it was renamed, so
+// FILE is removed.
+
+const EXTENSION: &str = "cmpr";
+const TEMP_EXTENSION: &str = "sweep-tmp";
+const JOURNAL: &str = ".sweep-journal";
+
+// Sweep dir, compressing files last modified longer than older_than ago
+// with algorithm. Returns false if any file failed.
+pub(crate) fn sweep(dir: &Path, algorithm: Algorithm, older_than: Duration, opts: &Options) -> bool {
+    let journal_path = dir.join(JOURNAL);
+    if let Err(e) = recover(&journal_path) {
+        eprintln!("{}: {}", journal_path.display(), e);
+        return false;
+    }
+    let mut journal = match OpenOptions::new().create(true).append(true).open(&journal_path) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("{}: {}", journal_path.display(), e);
+            return false;
+        }
+    };
+    let mut files = Vec::new();
+    if let Err(e) = walk(dir, &mut files) {
+        eprintln!("{}: {}", dir.display(), e);
+        return false;
+    }
+    let cutoff = SystemTime::now().checked_sub(older_than).unwrap_or(SystemTime::UNIX_EPOCH);
+
+    let mut ok = true;
+    let (mut size_in, mut size_out) = (0, 0);
+    for path in files {
+        match candidate(&path, cutoff) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                ok = false;
+                continue;
+            }
+        }
+        match sweep_file(&path, algorithm, opts, &mut journal) {
+            Ok(Some((file_in, file_out))) => {
+                println!("{}: {} bytes -> {} bytes", path.display(), file_in, file_out);
+                size_in += file_in;
+                size_out += file_out;
+            }
+            Ok(None) => println!("{}: kept, doesn't compress", path.display()),
+            Err(e) => {
+                eprintln!("{}: {}", path.display(), e);
+                ok = false;
+            }
+        }
+    }
+    drop(journal);
+    if ok {
+        let _ = fs::remove_file(&journal_path);
+    }
+    println!("{} bytes -> {} bytes", size_in, size_out);
+    ok
+}
+
+// Compress, verify and replace one file, returning its size before
+// and after, or None if it didn't get smaller and was left alone.
+fn sweep_file(path: &Path, algorithm: Algorithm, opts: &Options, journal: &mut File) -> io::Result<Option<(u64, u64)>> {
+    let temp = with_extension(path, TEMP_EXTENSION);
+    let compressed = with_extension(path, EXTENSION);
+    let size_in = fs::metadata(path)?.len();
+
+    log(journal, "begin", path)?;
+    let file_in = BufReader::with_capacity(1 << 20, File::open(path)?);
+    let file_out = BufWriter::with_capacity(1 << 20, File::create(&temp)?);
+    if let Err(e) = crate::run(&format!("-{}", algorithm.name()), "-c", opts, &fs::metadata(path), file_in, file_out) {
+        let _ = fs::remove_file(&temp);
+        return Err(io::Error::other(e));
+    }
+    let size_out = fs::metadata(&temp)?.len();
+    if size_out >= size_in {
+        fs::remove_file(&temp)?;
+        log(journal, "done", path)?;
+        return Ok(None);
+    }
+    File::open(&temp)?.sync_all()?;
+
+    if let Err(e) = verify(path, &temp, algorithm, opts) {
+        let _ = fs::remove_file(&temp);
+        return Err(e);
+    }
+    fs::rename(&temp, &compressed)?;
+    fs::remove_file(path)?;
+    log(journal, "done", path)?;
+    Ok(Some((size_in, size_out)))
+}
+
+// Decompress temp and compare it with the original at path.
+fn verify(path: &Path, temp: &Path, algorithm: Algorithm, opts: &Options) -> io::Result<()> {
+    let mut original = Compare {
+        file:   BufReader::with_capacity(1 << 20, File::open(path)?),
+        differ: false,
+    };
+    let file_in = BufReader::with_capacity(1 << 20, File::open(temp)?);
+    let result = container::decompress(file_in, BufWriter::with_capacity(1 << 20, &mut original), algorithm,
+        |header, file_in, file_out| crate::decompress_stream(algorithm, opts, header, file_in, file_out));
+    if let Err(e) = result {
+        return Err(io::Error::other(format!("verify failed: {}", e)));
+    }
+    if original.differ || original.file.read(&mut [0u8; 1])? != 0 {
+        return Err(io::Error::other("verify failed: decompressed output differs"));
+    }
+    Ok(())
+}
+
+// A writer that compares what's written with a file, noting if any of
+// it differs.
+struct Compare {
+    file:   BufReader<File>,
+    differ: bool,
+}
+
+impl Write for Compare {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut expected = vec![0; buf.len()];
+        if self.file.read_exact(&mut expected).is_err() || expected != buf {
+            self.differ = true;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Finish whatever the sweep recorded in the journal had begun but not
+// done, then remove the journal.
+fn recover(journal_path: &Path) -> io::Result<()> {
+    let journal = match fs::read_to_string(journal_path) {
+        Ok(journal) => journal,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    let mut pending = Vec::new();
+    for line in journal.lines() {
+        match line.split_once(' ') {
+            Some(("begin", path)) => pending.push(PathBuf::from(path)),
+            Some(("done", path))  => pending.retain(|p| p != Path::new(path)),
+            _ => {}
+        }
+    }
+    for path in pending {
+        let temp = with_extension(&path, TEMP_EXTENSION);
+        let compressed = with_extension(&path, EXTENSION);
+        if temp.exists() {
+            fs::remove_file(&temp)?;
+        }
+        else if compressed.exists() && path.exists() {
+            fs::remove_file(&path)?;
+        }
+        println!("{}: recovered interrupted sweep", path.display());
+    }
+    fs::remove_file(journal_path)
+}
+
+// Record that path's sweep has begun or is done, durably, before
+// going on.
+fn log(journal: &mut File, event: &str, path: &Path) -> io::Result<()> {
+    writeln!(journal, "{} {}", event, path.display())?;
+    journal.sync_data()
+}
+
+// Every regular file under dir, in sorted order. Symbolic links aren't
+// followed.
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<_>>>()?;
+    entries.sort();
+    for path in entries {
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_dir() {
+            walk(&path, files)?;
+        }
+        else if file_type.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// True if path should be swept: last modified before cutoff, not
+// already compressed, not the sweep's own, and with a name the journal
+// can hold.
+fn candidate(path: &Path, cutoff: SystemTime) -> io::Result<bool> {
+    let name = path.to_string_lossy();
+    if path.file_name().is_some_and(|name| name == JOURNAL)
+        || path.extension().is_some_and(|ext| ext == EXTENSION || ext == TEMP_EXTENSION)
+        || path.to_str().is_none_or(|path| path.contains('\n'))
+        || with_extension(path, EXTENSION).exists() {
+        return Ok(false);
+    }
+    if fs::metadata(path)?.modified()? > cutoff {
+        return Ok(false);
+    }
+    let mut magic = [0u8; 4];
+    let is_stream = File::open(path)?.read_exact(&mut magic).is_ok() && magic == container::MAGIC;
+    if is_stream {
+        println!("{}: skipped, already compressed", name);
+    }
+    Ok(!is_stream)
+}
+
+// path with .ext added after any extension it has.
+fn with_extension(path: &Path, ext: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(".");
+    path.push(ext);
+    PathBuf::from(path)
+}
+
+// Parse an age such as 30d, 12h, 45m or 10s.
+pub(crate) fn parse_age(age: &str) -> Option<Duration> {
+    let unit = match age.chars().last()? {
+        'd' => 24 * 60 * 60,
+        'h' => 60 * 60,
+        'm' => 60,
+        's' => 1,
+        _   => return None,
+    };
+    let n = age[..age.len() - 1].parse::<u64>().ok()?;
+    Some(Duration::from_secs(n.checked_mul(unit)?))
+}
@@ -0,0 +1,31 @@
+use std::env;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+// Temporary files for the algorithms that run one stage after another
+// through a file. Each name has the process id and a count of the files
+// made so far, so calls on other threads, like the blocks of --threads,
+// get files of their own, and a file is only ever created new, never
+// opened if it's already there: a leftover or a file someone else put
+// there under the same name is skipped, not written over.
+
+// Create a new, empty file in the temp directory named after prefix and
+// name, returning its path and the file open for writing. The caller
+// removes it when done.
+pub fn create(prefix: &str, name: &str) -> io::Result<(PathBuf, File)> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    loop {
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("{}-{}-{}-{}", prefix, std::process::id(), n, name));
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(file) => return Ok((path, file)),
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use std::cell::Cell;
+use std::io;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::thread;
+use std::time::Instant;
+
+// Ways to share the machine during a long run: a lower scheduling
+// priority, and a limit on the share of time spent running.
+//
+// The CPU limit works by sleeping. Input is read in buffers of about
+// 1 MB, and each time one is refilled, throttle() sleeps long enough
+// that the time spent working since the last refill is at most the
+// limit's share of the total. Each thread keeps its own time of the last
+// refill and sleeps on its own, so with --threads every worker keeps to
+// the limit, and N workers use at most N times its share of one CPU.
+
+static CPU_LIMIT: AtomicU32 = AtomicU32::new(100);
+
+thread_local! {
+    static LAST: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Limit the time spent running to percent (1-100) of wall time.
+pub fn set_cpu_limit(percent: u32) {
+    CPU_LIMIT.store(percent.clamp(1, 100), Ordering::Relaxed);
+}
+
+/// Sleep as needed to keep to the CPU limit. Called between units of
+/// work; does nothing without a limit.
+pub fn throttle() {
+    let limit = CPU_LIMIT.load(Ordering::Relaxed);
+    if limit >= 100 {
+        return;
+    }
+    if let Some(start) = LAST.get() {
+        let busy = start.elapsed();
+        thread::sleep(busy * (100 - limit) / limit);
+    }
+    LAST.set(Some(Instant::now()));
+}
+
+/// Lower (or with privileges, raise) the scheduling priority of the
+/// process by n, as with the nice command.
+#[cfg(unix)]
+pub fn nice(n: i32) -> io::Result<()> {
+    extern "C" {
+        fn getpriority(which: i32, who: u32) -> i32;
+        fn setpriority(which: i32, who: u32, prio: i32) -> i32;
+    }
+    const PRIO_PROCESS: i32 = 0;
+    // SAFETY: both only read or change the priority of this process.
+    let result = unsafe {
+        let prio = getpriority(PRIO_PROCESS, 0);
+        setpriority(PRIO_PROCESS, 0, (prio + n).clamp(-20, 19))
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn nice(_n: i32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--nice is only supported on unix"))
+}
@@ -0,0 +1,162 @@
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::ErrorKind;
+use std::path::Path;
+use std::path::PathBuf;
+
+use crate::bufio::seek_to;
+
+// A stream split into volumes of at most a fixed size, to fit on
+// size-limited media or under an upload limit. The volumes of out are
+// out.001, out.002 and so on, and are read back in order as one stream,
+// for as long as the next one exists. Nothing marks the last volume: one
+// that's missing leaves the stream truncated, which decompressing finds.
+
+// The path of volume n of path, counting from 1.
+pub fn volume_path(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{:03}", n));
+    PathBuf::from(name)
+}
+
+// The paths of the volumes of path that exist, in order.
+pub fn volumes(path: &Path) -> Vec<PathBuf> {
+    (1..).map(|n| volume_path(path, n)).take_while(|volume| volume.exists()).collect()
+}
+
+// A writer that rolls over to the next volume every size bytes. Seeking
+// is supported, since lpaq1 goes back to fill in its header, but not
+// past the end of what's been written.
+pub struct VolumeWriter {
+    path:    PathBuf,
+    size:    u64,       // Size of every volume but the last
+    volumes: Vec<File>,
+    pos:     u64,
+    len:     u64,
+}
+
+impl VolumeWriter {
+    // Volumes left over from a longer stream are removed first, or they'd
+    // be read back as part of this one.
+    pub fn create<P: AsRef<Path>>(path: P, size: u64) -> io::Result<VolumeWriter> {
+        assert!(size > 0);
+        let path = path.as_ref().to_path_buf();
+        for volume in volumes(&path) {
+            fs::remove_file(volume)?;
+        }
+        Ok(VolumeWriter { path, size, volumes: Vec::new(), pos: 0, len: 0 })
+    }
+
+    // The number of volumes written so far.
+    pub fn count(&self) -> usize {
+        self.volumes.len()
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.pos > self.len {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "write past the end of the volumes"));
+        }
+        let n = (self.pos / self.size) as usize;
+        let offset = self.pos % self.size;
+        while self.volumes.len() <= n {
+            self.volumes.push(File::create(volume_path(&self.path, self.volumes.len() + 1))?);
+        }
+        let volume = &mut self.volumes[n];
+        volume.seek(SeekFrom::Start(offset))?;
+        let len = (self.size - offset).min(buf.len() as u64) as usize;
+        let len = volume.write(&buf[..len])?;
+        self.pos += len as u64;
+        self.len = self.len.max(self.pos);
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        for volume in self.volumes.iter_mut() {
+            volume.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl Seek for VolumeWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_to(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
+
+// A reader over volumes one after the other, as one stream.
+pub struct VolumeReader {
+    volumes: Vec<(File, u64)>, // Each volume and its size
+    pos:     u64,
+    len:     u64,
+}
+
+impl VolumeReader {
+    pub fn new(volumes: Vec<File>) -> io::Result<VolumeReader> {
+        let volumes = volumes.into_iter()
+            .map(|volume| {
+                let len = volume.metadata()?.len();
+                Ok((volume, len))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        let len = volumes.iter().map(|(_, len)| len).sum();
+        Ok(VolumeReader { volumes, pos: 0, len })
+    }
+
+    // The volumes of path, from path.001 on.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<VolumeReader> {
+        let path = path.as_ref();
+        let volumes = volumes(path);
+        if volumes.is_empty() {
+            let msg = format!("no volumes of {} found", path.display());
+            return Err(io::Error::new(ErrorKind::NotFound, msg));
+        }
+        VolumeReader::new(volumes.iter().map(File::open).collect::<io::Result<_>>()?)
+    }
+
+    // The total size of the volumes.
+    pub fn size(&self) -> u64 {
+        self.len
+    }
+
+    // The number of volumes.
+    pub fn count(&self) -> usize {
+        self.volumes.len()
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut start = 0;
+        for (volume, len) in self.volumes.iter_mut() {
+            if self.pos < start + *len {
+                let offset = self.pos - start;
+                volume.seek(SeekFrom::Start(offset))?;
+                let max = (*len - offset).min(buf.len() as u64) as usize;
+                let read = volume.read(&mut buf[..max])?;
+                self.pos += read as u64;
+                return Ok(read);
+            }
+            start += *len;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = seek_to(pos, self.pos, self.len)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,114 @@
+// XXH64, Yann Collet's fast 64 bit non-cryptographic hash, as printed by
+// xxhsum. Input is consumed in 32 byte stripes by four accumulators, and
+// the rest is folded in when the hash is taken, so it can be computed
+// over a stream in pieces of any size.
+
+const PRIME1: u64 = 0x9E3779B185EBCA87;
+const PRIME2: u64 = 0xC2B2AE3D27D4EB4F;
+const PRIME3: u64 = 0x165667B19E3779F9;
+const PRIME4: u64 = 0x85EBCA77C2B2AE63;
+const PRIME5: u64 = 0x27D4EB2F165667C5;
+
+pub struct Xxh64 {
+    seed:  u64,
+    acc:   [u64; 4],
+    buf:   [u8; 32], // Input not yet making up a whole stripe
+    len:   usize,    // Bytes in buf
+    total: u64,      // Bytes hashed so far
+}
+
+impl Default for Xxh64 {
+    fn default() -> Xxh64 {
+        Xxh64::new(0)
+    }
+}
+
+impl Xxh64 {
+    pub fn new(seed: u64) -> Xxh64 {
+        Xxh64 {
+            seed,
+            acc: [
+                seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+                seed.wrapping_add(PRIME2),
+                seed,
+                seed.wrapping_sub(PRIME1),
+            ],
+            buf:   [0; 32],
+            len:   0,
+            total: 0,
+        }
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.total += bytes.len() as u64;
+        if self.len > 0 {
+            let n = bytes.len().min(32 - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+            self.len += n;
+            bytes = &bytes[n..];
+            if self.len < 32 {
+                return;
+            }
+            let stripe = self.buf;
+            self.stripe(&stripe);
+            self.len = 0;
+        }
+        let mut stripes = bytes.chunks_exact(32);
+        for stripe in stripes.by_ref() {
+            self.stripe(stripe);
+        }
+        let rest = stripes.remainder();
+        self.buf[..rest.len()].copy_from_slice(rest);
+        self.len = rest.len();
+    }
+
+    fn stripe(&mut self, stripe: &[u8]) {
+        for (acc, lane) in self.acc.iter_mut().zip(stripe.chunks_exact(8)) {
+            *acc = round(*acc, u64::from_le_bytes(lane.try_into().unwrap()));
+        }
+    }
+
+    /// Hash of all bytes so far.
+    pub fn digest(&self) -> u64 {
+        let mut h = if self.total >= 32 {
+            let [a, b, c, d] = self.acc;
+            let h = a.rotate_left(1)
+                .wrapping_add(b.rotate_left(7))
+                .wrapping_add(c.rotate_left(12))
+                .wrapping_add(d.rotate_left(18));
+            self.acc.iter().fold(h, |h, acc| {
+                (h ^ round(0, *acc)).wrapping_mul(PRIME1).wrapping_add(PRIME4)
+            })
+        }
+        else {
+            self.seed.wrapping_add(PRIME5)
+        };
+        h = h.wrapping_add(self.total);
+
+        let mut rest = &self.buf[..self.len];
+        while rest.len() >= 8 {
+            h ^= round(0, u64::from_le_bytes(rest[..8].try_into().unwrap()));
+            h = h.rotate_left(27).wrapping_mul(PRIME1).wrapping_add(PRIME4);
+            rest = &rest[8..];
+        }
+        if rest.len() >= 4 {
+            h ^= (u32::from_le_bytes(rest[..4].try_into().unwrap()) as u64).wrapping_mul(PRIME1);
+            h = h.rotate_left(23).wrapping_mul(PRIME2).wrapping_add(PRIME3);
+            rest = &rest[4..];
+        }
+        for byte in rest.iter() {
+            h ^= (*byte as u64).wrapping_mul(PRIME5);
+            h = h.rotate_left(11).wrapping_mul(PRIME1);
+        }
+
+        h ^= h >> 33;
+        h = h.wrapping_mul(PRIME2);
+        h ^= h >> 29;
+        h = h.wrapping_mul(PRIME3);
+        h ^ (h >> 32)
+    }
+}
+
+fn round(acc: u64, lane: u64) -> u64 {
+    acc.wrapping_add(lane.wrapping_mul(PRIME2)).rotate_left(31).wrapping_mul(PRIME1)
+}
@@ -0,0 +1,1307 @@
+// Tests of command line behaviour that isn't tied to a specific format.
+
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::path::PathBuf;
+use std::process::Command;
+
+use compression::blocks::IndexedReader;
+use compression::bytes::Algorithm;
+use compression::error::Error;
+
+const EXE: &str = env!("CARGO_BIN_EXE_compression");
+
+// Size of the container header in front of every compressed stream.
+const HEADER: usize = 15;
+
+// Size of the trailer after every compressed stream.
+const TRAILER: usize = 12;
+
+// A container header for a stream of algorithm id that decompresses to
+// size bytes, followed by the stream and the trailer.
+fn with_header(id: u8, size: u64, stream: &[u8]) -> Vec<u8> {
+    let len = (HEADER + stream.len() + TRAILER) as u64;
+    [&b"CMPR"[..], &[2, id, 0], &size.to_le_bytes(), stream, &len.to_le_bytes(), b"CEND"].concat()
+}
+
+// A member with the stream of member cut to len bytes, and a trailer
+// that fits, so the cut stream is what decompressing reads.
+fn cut(member: &[u8], len: usize) -> Vec<u8> {
+    let mut cut = member[..HEADER + len].to_vec();
+    cut.extend((cut.len() as u64 + TRAILER as u64).to_le_bytes());
+    cut.extend(b"CEND");
+    cut
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("cli-{}-{}", std::process::id(), name))
+}
+
+#[test]
+fn dry_run_reports_size_without_writing() {
+    let input = temp_path("dry-run");
+    let output = temp_path("dry-run.out");
+    fs::write(&input, "dry run dry run dry run dry run".repeat(100)).unwrap();
+
+    let status = Command::new(EXE)
+        .args(["-lz77", "-c"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    let size = fs::metadata(&output).unwrap().len();
+    fs::remove_file(&output).unwrap();
+
+    let result = Command::new(EXE)
+        .args(["-lz77", "-c", "--dry-run"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+
+    assert!(result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    assert!(stdout.contains(&format!("-> {} bytes", size)), "{}", stdout);
+    assert!(!output.exists());
+}
+
+#[test]
+fn lzw_rejects_undefined_codes() {
+    let input = temp_path("lzw-corrupt");
+    let output = temp_path("lzw-corrupt.out");
+    // 'a', then code 300, which is past the end of the dictionary.
+    fs::write(&input, with_header(2, 2, &[0x61, 0x00, 0x2C, 0x01])).unwrap();
+
+    let result = Command::new(EXE)
+        .args(["-lzw", "-d"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+    let _ = fs::remove_file(&output);
+
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("corrupt LZW stream"), "{}", stderr);
+}
+
+#[test]
+fn lz77_rejects_unknown_token_format() {
+    let input = temp_path("lz77-header");
+    let output = temp_path("lz77-header.out");
+    // 12 window bits and 5 length bits don't fit in a 16 bit token.
+    fs::write(&input, with_header(1, 1, &[12, 5, 0x00, 0x61])).unwrap();
+
+    let result = Command::new(EXE)
+        .args(["-lz77", "-d"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+    let _ = fs::remove_file(&output);
+
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("unsupported lz77 token format"), "{}", stderr);
+}
+
+#[test]
+fn flzp_effort_levels_round_trip() {
+    let input = temp_path("flzp-effort");
+    let data = "the rain in spain, the plain in maine, the train in spain. ".repeat(200);
+    fs::write(&input, &data).unwrap();
+
+    let mut sizes = Vec::new();
+    for effort in ["greedy", "normal", "max"] {
+        let compressed = temp_path(&format!("flzp-effort.{}.c", effort));
+        let restored = temp_path(&format!("flzp-effort.{}.d", effort));
+
+        let status = Command::new(EXE)
+            .args(["-flzp", "-c", "--effort", effort])
+            .arg(&input)
+            .arg(&compressed)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        // The effort level is read from the stream, not the command line.
+        let status = Command::new(EXE)
+            .args(["-flzp", "-d"])
+            .arg(&compressed)
+            .arg(&restored)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        sizes.push(fs::metadata(&compressed).unwrap().len());
+        assert_eq!(fs::read(&restored).unwrap(), data.as_bytes(), "{}", effort);
+        fs::remove_file(&compressed).unwrap();
+        fs::remove_file(&restored).unwrap();
+    }
+    fs::remove_file(&input).unwrap();
+
+    assert!(sizes[2] <= sizes[0], "{:?}", sizes);
+}
+
+// Compress data, returning the compressed stream. File times aren't
+// recorded, so the stream depends only on data and args, and starts
+// after a HEADER byte header.
+fn compress(algorithm: &str, name: &str, data: &[u8], args: &[&str]) -> Vec<u8> {
+    let input = temp_path(name);
+    let output = temp_path(&format!("{}.c", name));
+    fs::write(&input, data).unwrap();
+    let status = Command::new(EXE)
+        .args([algorithm, "-c", "--no-mtime"])
+        .args(args)
+        .arg(&input)
+        .arg(&output)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let compressed = fs::read(&output).unwrap();
+    fs::remove_file(&input).unwrap();
+    fs::remove_file(&output).unwrap();
+    compressed
+}
+
+// Decompress a stream, returning the output or the error message.
+fn decompress(algorithm: &str, name: &str, data: &[u8]) -> Result<Vec<u8>, String> {
+    let input = temp_path(name);
+    let output = temp_path(&format!("{}.d", name));
+    fs::write(&input, data).unwrap();
+    let result = Command::new(EXE)
+        .args([algorithm, "-d"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap();
+    let restored = fs::read(&output);
+    fs::remove_file(&input).unwrap();
+    let _ = fs::remove_file(&output);
+    if result.status.success() {
+        Ok(restored.unwrap())
+    }
+    else {
+        Err(String::from_utf8(result.stderr).unwrap())
+    }
+}
+
+// Test a stream, returning whether it passed and the output.
+fn test(algorithm: &str, name: &str, data: &[u8]) -> (bool, String) {
+    let input = temp_path(name);
+    fs::write(&input, data).unwrap();
+    let result = Command::new(EXE)
+        .args([algorithm, "-t"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+    let output = [result.stdout, result.stderr].concat();
+    (result.status.success(), String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn test_mode_reports_intact_and_corrupt_streams() {
+    let data = "test me, test me. ".repeat(5000);
+    let compressed = compress("-flzp", "test-mode", data.as_bytes(), &["--crc"]);
+
+    let (ok, output) = test("-flzp", "test-mode", &compressed);
+    assert!(ok, "{}", output);
+    assert!(output.contains(&format!("OK, {} bytes", data.len())), "{}", output);
+
+    let (ok, output) = test("-flzp", "test-mode-truncated", &compressed[..compressed.len() / 2]);
+    assert!(!ok);
+    assert!(output.contains("corrupt: truncated stream"), "{}", output);
+
+    let mut damaged = compressed.clone();
+    damaged[HEADER + 2 + 32 + 1] ^= 1;
+    let (ok, output) = test("-flzp", "test-mode-damaged", &damaged);
+    assert!(!ok);
+    assert!(output.contains("corrupt: flzp block CRC mismatch"), "{}", output);
+
+    let (ok, output) = test("-lz77", "test-mode-algorithm", &compressed);
+    assert!(!ok);
+    assert!(output.contains("corrupt: stream was compressed with -flzp"), "{}", output);
+}
+
+#[test]
+fn list_reads_headers() {
+    let data = "list me, list me. ".repeat(1000);
+    let lzw = temp_path("list.lzw");
+    let plain = temp_path("list.txt");
+    fs::write(&lzw, compress("-lzw", "list", data.as_bytes(), &[])).unwrap();
+    fs::write(&plain, &data).unwrap();
+    let size = fs::metadata(&lzw).unwrap().len();
+
+    let result = Command::new(EXE)
+        .arg("-l")
+        .arg(&lzw)
+        .arg(&plain)
+        .output()
+        .unwrap();
+    fs::remove_file(&lzw).unwrap();
+    fs::remove_file(&plain).unwrap();
+
+    // Listed files are reported even if others fail.
+    assert!(!result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let line = stdout.lines().find(|line| line.ends_with(lzw.to_str().unwrap())).unwrap();
+    let fields = line.split_whitespace().collect::<Vec<_>>();
+    assert_eq!(fields[..3], ["-lzw", &size.to_string(), &data.len().to_string()], "{}", stdout);
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("not a compressed stream"), "{}", stderr);
+}
+
+fn sweep(dir: &PathBuf, args: &[&str]) -> (bool, String) {
+    let result = Command::new(EXE)
+        .arg("sweep")
+        .args(args)
+        .arg(dir)
+        .output()
+        .unwrap();
+    let output = [result.stdout, result.stderr].concat();
+    (result.status.success(), String::from_utf8(output).unwrap())
+}
+
+#[test]
+fn sweep_replaces_old_files_with_verified_streams() {
+    let dir = temp_path("sweep");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let text = "sweep me, sweep me. ".repeat(1000);
+    fs::write(dir.join("a.txt"), &text).unwrap();
+    fs::write(dir.join("sub").join("b.txt"), &text).unwrap();
+    fs::write(dir.join("tiny"), "x").unwrap();
+    let stream = compress("-lzw", "sweep-stream", text.as_bytes(), &[]);
+    fs::write(dir.join("c.lzw"), &stream).unwrap();
+
+    // Nothing is old enough yet.
+    let (ok, output) = sweep(&dir, &["--algo", "lzw", "--older-than", "1d"]);
+    assert!(ok, "{}", output);
+    assert!(dir.join("a.txt").exists() && !dir.join("a.txt.cmpr").exists(), "{}", output);
+
+    let (ok, output) = sweep(&dir, &["--algo", "lzw", "--older-than", "0s"]);
+    assert!(ok, "{}", output);
+    for path in [dir.join("a.txt"), dir.join("sub").join("b.txt")] {
+        assert!(!path.exists(), "{}", output);
+        let compressed = fs::read(format!("{}.cmpr", path.display())).unwrap();
+        assert_eq!(decompress("-lzw", "sweep-check", &compressed).unwrap(), text.as_bytes());
+    }
+    assert!(output.contains("kept, doesn't compress"), "{}", output);
+    assert_eq!(fs::read(dir.join("tiny")).unwrap(), b"x");
+    assert!(output.contains("already compressed"), "{}", output);
+    assert_eq!(fs::read(dir.join("c.lzw")).unwrap(), stream);
+    assert!(!dir.join(".sweep-journal").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sweep_finishes_interrupted_sweep() {
+    let dir = temp_path("sweep-recover");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let text = "recover me, recover me. ".repeat(1000);
+    let stream = compress("-lzw", "sweep-recover-stream", text.as_bytes(), &[]);
+    let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+
+    // a was renamed but not removed. b was never verified.
+    fs::write(&a, &text).unwrap();
+    fs::write(dir.join("a.txt.cmpr"), &stream).unwrap();
+    fs::write(&b, &text).unwrap();
+    fs::write(dir.join("b.txt.sweep-tmp"), &stream[..10]).unwrap();
+    fs::write(dir.join(".sweep-journal"), format!("begin {}\nbegin {}\n", a.display(), b.display())).unwrap();
+
+    let (ok, output) = sweep(&dir, &["--algo", "lzw", "--older-than", "1d"]);
+    assert!(ok, "{}", output);
+    assert!(!a.exists(), "{}", output);
+    assert_eq!(fs::read(dir.join("a.txt.cmpr")).unwrap(), stream);
+    assert_eq!(fs::read(&b).unwrap(), text.as_bytes());
+    assert!(!dir.join("b.txt.sweep-tmp").exists());
+    assert!(!dir.join(".sweep-journal").exists());
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn decompress_restores_modification_time() {
+    let input = temp_path("mtime");
+    let compressed = temp_path("mtime.c");
+    let restored = temp_path("mtime.d");
+    let data = "old news, old news. ".repeat(100);
+    fs::write(&input, &data).unwrap();
+    let mtime = std::time::UNIX_EPOCH + std::time::Duration::new(1_000_000_000, 123_456_789);
+    fs::File::options().write(true).open(&input).unwrap().set_modified(mtime).unwrap();
+
+    assert!(run_with(&["-lzw", "-c"], &input, &compressed).0);
+    assert!(run_with(&["-lzw", "-d"], &compressed, &restored).0);
+    assert_eq!(fs::read(&restored).unwrap(), data.as_bytes());
+    assert_eq!(fs::metadata(&restored).unwrap().modified().unwrap(), mtime);
+
+    assert!(run_with(&["-lzw", "-d", "--no-mtime"], &compressed, &restored).0);
+    assert_ne!(fs::metadata(&restored).unwrap().modified().unwrap(), mtime);
+
+    for path in [input, compressed, restored] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn flzp_rejects_truncated_stream() {
+    let data = "truncate me, truncate me. ".repeat(5000);
+    let compressed = compress("-flzp", "flzp-truncated", data.as_bytes(), &[]);
+    assert_eq!(decompress("-flzp", "flzp-whole", &compressed).unwrap(), data.as_bytes());
+
+    // Cut inside a block, and just before the end marker.
+    let stream = compressed.len() - HEADER - TRAILER;
+    for len in [stream / 2, stream - 1] {
+        let err = decompress("-flzp", "flzp-truncated", &cut(&compressed, len)).unwrap_err();
+        assert!(err.contains("truncated flzp stream"), "{}", err);
+    }
+}
+
+#[test]
+fn flzp_crc_detects_corruption() {
+    let data = "corrupt me, corrupt me. ".repeat(5000);
+    let mut compressed = compress("-flzp", "flzp-crc", data.as_bytes(), &["--crc"]);
+    assert_eq!(decompress("-flzp", "flzp-crc", &compressed).unwrap(), data.as_bytes());
+
+    // Turn the first literal of the first block into a different one.
+    let first = HEADER + 2 + 32 + 1;
+    assert_eq!(compressed[first], b'c');
+    compressed[first] = b'm';
+    let err = decompress("-flzp", "flzp-crc-bad", &compressed).unwrap_err();
+    assert!(err.contains("CRC mismatch"), "{}", err);
+}
+
+#[test]
+fn flzp_context_length_is_recorded_per_block() {
+    let data = "context, context, longer context. ".repeat(3000);
+    for len in [2, 3, 8] {
+        let arg = len.to_string();
+        let mut compressed = compress("-flzp", "flzp-context", data.as_bytes(), &["--context", &arg]);
+        // After the stream header and the first block's decoding table.
+        assert_eq!(compressed[HEADER + 2 + 32], len);
+        assert_eq!(decompress("-flzp", "flzp-context", &compressed).unwrap(), data.as_bytes());
+
+        compressed[HEADER + 2 + 32] = 9;
+        let err = decompress("-flzp", "flzp-context-bad", &compressed).unwrap_err();
+        assert!(err.contains("unsupported flzp context length 9"), "{}", err);
+    }
+}
+
+#[test]
+fn flzp_context_lengths_hash_distinct_contexts() {
+    let words = ["match ", "batch ", "latch ", "patch ", "hatch\n"];
+    // One block, so the recorded context length is the only header difference.
+    let data: Vec<u8> = (0..4000)
+        .flat_map(|i| words[i * 7 % 5].bytes().chain(words[i * 3 % 5].bytes()))
+        .collect();
+    let outputs: Vec<Vec<u8>> = (5..=8).map(|len| {
+        let mut compressed = compress("-flzp", "flzp-distinct", &data, &["--context", &len.to_string()]);
+        assert_eq!(decompress("-flzp", "flzp-distinct", &compressed).unwrap(), data);
+        compressed[HEADER + 2 + 32] = 0;
+        compressed
+    }).collect();
+    for (i, a) in outputs.iter().enumerate() {
+        for b in &outputs[i + 1..] {
+            assert_ne!(a, b);
+        }
+    }
+}
+
+#[test]
+fn bwt_sentinel_blocks_round_trip() {
+    // Includes the bytes that have to be escaped.
+    let mut data = "banana bandana ".repeat(1000).into_bytes();
+    data.extend([0xFE, 0xFF, 0xFF, 0x00, 0xFE, 0xFE]);
+
+    let compressed = compress("-bwt", "bwt-sentinel", &data, &["--sentinel"]);
+    // Block size, then a header with the sentinel flag and block length.
+    let header = u64::from_le_bytes(compressed[HEADER + 8..HEADER + 16].try_into().unwrap());
+    assert_eq!(header >> 63, 1);
+    assert_eq!(header as usize & !(1 << 63), compressed.len() - HEADER - 16 - TRAILER);
+    assert_eq!(decompress("-bwt", "bwt-sentinel", &compressed).unwrap(), data);
+}
+
+#[test]
+fn auto_block_sizes_blocks_from_the_input() {
+    // Repeats of 40K bytes that don't repeat within themselves, which
+    // sort slowly in plain bwt blocks.
+    let chunk = (0..40000).scan(1u64, |x, _| {
+        *x = x.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        Some((*x >> 56) as u8)
+    }).collect::<Vec<u8>>();
+    let data = chunk.repeat(30);
+    let block_size = |compressed: &[u8]| u64::from_le_bytes(compressed[HEADER..HEADER + 8].try_into().unwrap());
+
+    let compressed = compress("-bwt", "auto-block", &data, &["--auto-block"]);
+    // One sentinel block as large as the input needs, not two of 1M.
+    assert_eq!(block_size(&compressed), 2 << 20);
+    let header = u64::from_le_bytes(compressed[HEADER + 8..HEADER + 16].try_into().unwrap());
+    assert_eq!(header >> 63, 1);
+    assert_eq!(decompress("-bwt", "auto-block", &compressed).unwrap(), data);
+
+    // Input that doesn't repeat keeps plain blocks.
+    let compressed = compress("-bwt", "auto-block-plain", &chunk, &["--auto-block"]);
+    assert_eq!(block_size(&compressed), 64 << 10);
+    let header = u64::from_le_bytes(compressed[HEADER + 8..HEADER + 16].try_into().unwrap());
+    assert_eq!(header >> 63, 0);
+    assert_eq!(decompress("-bwt", "auto-block-plain", &compressed).unwrap(), chunk);
+}
+
+#[test]
+fn sa_matches_naive_suffix_sort() {
+    // Small alphabets give long repeats, which exercise the recursion.
+    let mut seed = 12345u64;
+    let mut inputs = vec![Vec::new(), b"a".to_vec(), b"banana".to_vec(), b"aaaaaaaa".to_vec()];
+    for (len, alphabet) in [(100, 2), (1000, 3), (5000, 4), (5000, 256)] {
+        let data = (0..len).map(|_| {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            b'a'.wrapping_add(((seed >> 33) % alphabet) as u8)
+        });
+        inputs.push(data.collect());
+    }
+    inputs.push(b"abracadabra".repeat(300));
+
+    for (n, data) in inputs.iter().enumerate() {
+        let input = temp_path(&format!("sa-{}", n));
+        let output = temp_path(&format!("sa-{}.out", n));
+        fs::write(&input, data).unwrap();
+        let status = Command::new(EXE)
+            .arg("sa")
+            .arg(&input)
+            .arg(&output)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        let sa = fs::read(&output).unwrap()
+            .chunks(4)
+            .map(|i| u32::from_le_bytes(i.try_into().unwrap()) as usize)
+            .collect::<Vec<usize>>();
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let mut expected = (0..data.len()).collect::<Vec<usize>>();
+        expected.sort_by(|&a, &b| data[a..].cmp(&data[b..]));
+        assert_eq!(sa, expected, "input {}", n);
+    }
+}
+
+#[test]
+fn huffman_order1_round_trip() {
+    let text = "she sells sea shells by the sea shore. ".repeat(500).into_bytes();
+    let order0 = compress("-huffman", "huffman-order0", &text, &[]);
+    let order1 = compress("-huffman", "huffman-order1", &text, &["--order1"]);
+    assert!(order1.len() < order0.len(), "{} >= {}", order1.len(), order0.len());
+
+    // The mode is read from the stream.
+    for (name, data) in [("text", text), ("empty", Vec::new()), ("single", vec![7])] {
+        let compressed = compress("-huffman", name, &data, &["--order1"]);
+        assert_eq!(decompress("-huffman", name, &compressed).unwrap(), data, "{}", name);
+    }
+}
+
+#[test]
+fn outputs_larger_than_write_buffer_round_trip() {
+    // Output is written through a 1 MB buffer. Random data makes these
+    // outputs a few MB, with multi byte writes (flzp block CRCs, bwt
+    // block headers) falling across buffer boundaries.
+    let mut seed = 99u64;
+    let data = (0..3 << 20).map(|_| {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 33) as u8
+    })
+    .collect::<Vec<u8>>();
+
+    for (algorithm, args) in [("-flzp", "--crc"), ("-bwt", "--sentinel")] {
+        let compressed = compress(algorithm, "large", &data, &[args]);
+        assert!(compressed.len() > 2 << 20);
+        assert!(decompress(algorithm, "large", &compressed).unwrap() == data, "{}", algorithm);
+    }
+}
+
+#[test]
+fn fpaq_profile_is_read_from_stream() {
+    let data = "an sse profile is stored in the header. ".repeat(500).into_bytes();
+    for args in [&[][..], &["--fast"][..], &["--sse", "none"][..], &["--sse", "h3:3+12:1,o0:20:4"][..]] {
+        let compressed = compress("-fpaq", "fpaq-profile", &data, args);
+        assert_eq!(decompress("-fpaq", "fpaq-profile", &compressed).unwrap(), data, "{:?}", args);
+    }
+
+    // One stage, with an unknown context.
+    let mut compressed = compress("-fpaq", "fpaq-profile", &data, &["--sse", "o1:7:4"]);
+    assert_eq!(compressed[HEADER..HEADER + 5], [1, 1, 7, 0, 4]);
+    compressed[HEADER + 1] = 9;
+    let err = decompress("-fpaq", "fpaq-profile-bad", &compressed).unwrap_err();
+    assert!(err.contains("unknown SSE context 9"), "{}", err);
+}
+
+#[test]
+fn lpaq1_models_are_read_from_stream() {
+    let data = "the models are stored in the header, 1234 5678. ".repeat(500).into_bytes();
+    for models in ["o1", "o2,sparse", "match,word", "o2,align8", "o2,o3,o4,o6,word,match,sparse,align4,align8"] {
+        let compressed = compress("-lpaq1", "lpaq1-models", &data, &["--models", models]);
+        assert_eq!(decompress("-lpaq1", "lpaq1-models", &compressed).unwrap(), data, "{}", models);
+    }
+
+    // The model set follows the 24 byte block header, then the memory
+    // level. Flag a second model set, and give it an unknown model.
+    let mut compressed = compress("-lpaq1", "lpaq1-models", &data, &["--models", "o2"]);
+    assert_eq!(compressed[HEADER + 24..HEADER + 26], [1, 0x20 | 0x10 | 3]);
+    compressed[HEADER + 24] = 0x80;
+    compressed[HEADER + 25] = 0x80;
+    let err = decompress("-lpaq1", "lpaq1-models-bad", &compressed).unwrap_err();
+    assert!(err.contains("unknown lpaq1 models"), "{}", err);
+
+    // Models past the first 7 follow the memory level, flagged there.
+    let mut compressed = compress("-lpaq1", "lpaq1-models", &data, &["--models", "o2,align4"]);
+    assert_eq!(compressed[HEADER + 24..HEADER + 27], [1, 0x40 | 0x20 | 0x10 | 3, 1]);
+    compressed[HEADER + 26] = 0x80;
+    let err = decompress("-lpaq1", "lpaq1-models-bad", &compressed).unwrap_err();
+    assert!(err.contains("unknown lpaq1 models"), "{}", err);
+}
+
+#[test]
+fn lpaq1_switch_round_trip() {
+    let text = "switching between text and binary predictors. ".repeat(500).into_bytes();
+    let binary = (0..20000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect::<Vec<u8>>();
+    for data in [text, binary] {
+        let compressed = compress("-lpaq1", "lpaq1-switch", &data, &["--switch"]);
+        // Both model sets are stored, the first flagged as switching,
+        // and the binary set's align4 and align8 after the memory level.
+        assert_eq!(compressed[HEADER + 24..HEADER + 29], [0x80 | 0x3F, 0x67, 0x40 | 0x20 | 0x10 | 3, 0, 3]);
+        assert_eq!(decompress("-lpaq1", "lpaq1-switch", &compressed).unwrap(), data);
+    }
+}
+
+#[test]
+fn lpaq1_run_states_round_trip() {
+    let mut data = vec![0u8; 30000];
+    for (i, byte) in data.iter_mut().enumerate().filter(|(i, _)| i % 1000 < 40) {
+        *byte = (i * 7) as u8;
+    }
+    let compressed = compress("-lpaq1", "lpaq1-run-states", &data, &["--run-states"]);
+    // Flagged in the memory level byte.
+    assert_eq!(compressed[HEADER + 25], 0x80 | 0x20 | 0x10 | 3);
+    assert_ne!(compressed, compress("-lpaq1", "lpaq1-run-states", &data, &[]));
+    assert_eq!(decompress("-lpaq1", "lpaq1-run-states", &compressed).unwrap(), data);
+}
+
+#[test]
+fn lpaq1_match_apm_is_flagged() {
+    // Written before the match length SSE stage, without its flag in
+    // the memory level byte.
+    let old = [
+        0x4a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x3f, 0x03, 0x9e, 0x91, 0x49, 0x36, 0xe8, 0xa7, 0xcd, 0xf5, 0x67, 0xbd,
+        0xe0, 0xb6, 0xa9, 0x2f, 0x61, 0xa0, 0xe6, 0xb7, 0x00, 0x5d, 0x27, 0x51,
+        0x8b, 0x67, 0x80, 0xba, 0x18, 0xb0, 0x33, 0x5a, 0x18, 0x83, 0x53, 0xdd,
+        0x10, 0xc0, 0xd2, 0xed, 0x38, 0x01, 0xb5, 0x31, 0x0a, 0xfc, 0xa6, 0xa6,
+        0x22, 0xf0, 0xff, 0xba, 0x10, 0x89, 0xce, 0x14, 0x57, 0xbb, 0xdc, 0xda,
+        0x70, 0x21, 0x66,
+    ];
+    let data = b"an lpaq1 stream from before the match length SSE stage, match match match.";
+    let stream = with_header(5, data.len() as u64, &old);
+    assert_eq!(decompress("-lpaq1", "lpaq1-old", &stream).unwrap(), data);
+
+    let compressed = compress("-lpaq1", "lpaq1-match-apm", data, &[]);
+    assert_eq!(compressed[HEADER + 25], 0x20 | 0x10 | 3);
+    assert_ne!(compressed[HEADER + 26..], old[26..]);
+}
+
+#[test]
+fn lpaq1_match_len_is_flagged() {
+    // Written before the match length Mixer input, with MATCH_APM but
+    // without MATCH_LEN in the memory level byte.
+    let old = [
+        0x4c, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x3f, 0x23, 0x9e, 0x91, 0x66, 0x74, 0x1e, 0x1a, 0xfc, 0x05, 0x4e, 0x7c,
+        0xdd, 0x3c, 0xe5, 0x1e, 0xdd, 0xab, 0xc4, 0x25, 0x52, 0x41, 0x50, 0xae,
+        0xce, 0x65, 0x3d, 0x0e, 0xc1, 0x6b, 0x77, 0x1a, 0xf5, 0xc5, 0x7e, 0x09,
+        0xb5, 0x6c, 0xcf, 0x0c, 0x83, 0x69, 0xd0, 0x47, 0x41, 0x12, 0x66, 0xa4,
+        0x2e, 0x3b, 0x12, 0xe0, 0x48, 0x7b, 0x62, 0x77, 0xb6, 0x63, 0x83, 0xa7,
+        0x3f, 0x7a, 0x5e,
+    ];
+    let data = b"an lpaq1 stream from before the match length mixer input, match match match.";
+    let stream = with_header(5, data.len() as u64, &old);
+    assert_eq!(decompress("-lpaq1", "lpaq1-old-len", &stream).unwrap(), data);
+
+    let compressed = compress("-lpaq1", "lpaq1-match-len", data, &[]);
+    assert_eq!(compressed[HEADER + 25], 0x20 | 0x10 | 3);
+    assert_ne!(compressed[HEADER + 26..], old[26..]);
+}
+
+#[test]
+fn selftest_round_trips_every_algorithm() {
+    let output = Command::new(env!("CARGO_BIN_EXE_compression"))
+        .args(["selftest", "4096"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // A row of input, algorithm, ratio and speeds for each.
+    let rows = |algorithm| stdout.lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .filter(|fields| fields.len() >= 3 && fields[1] == algorithm && fields[2].ends_with('%'))
+        .count();
+    for algorithm in ["-lz77", "-lzw", "-flzp", "-sr", "-fpaq0", "-fpaq", "-lpaq1", "-huffman", "-bwt", "-sortrans"] {
+        assert_eq!(rows(algorithm), 4, "{}", stdout);
+    }
+    assert!(!stdout.contains("FAILED"), "{}", stdout);
+
+    // Then -sortrans against -lpaq1 for each input.
+    let deltas = stdout.lines().skip_while(|line| !line.contains("-sortrans vs -lpaq1")).skip(1).collect::<Vec<_>>();
+    assert_eq!(deltas.len(), 4, "{}", stdout);
+    assert!(deltas.iter().all(|line| line.trim_end().ends_with('%')), "{}", stdout);
+}
+
+#[test]
+fn max_expand_stores_incompressible_input() {
+    // splitmix64 output, which lz77 roughly doubles in size.
+    let mut state = 0u64;
+    let data = (0..10000).map(|_| {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        (z ^ (z >> 31)) as u8
+    }).collect::<Vec<u8>>();
+
+    let compressed = compress("-lz77", "max-expand", &data, &[]);
+    assert!(compressed.len() > data.len() * 3 / 2);
+
+    let stored = compress("-lz77", "max-expand", &data, &["--max-expand", "10%"]);
+    // Flagged as stored in the container header.
+    assert_eq!(stored[6], 1);
+    assert_eq!(stored.len(), data.len() + HEADER + TRAILER);
+    for algorithm in ["-lz77", "-lpaq1", "-bwt"] {
+        assert_eq!(decompress(algorithm, "max-expand", &stored).unwrap(), data);
+    }
+
+    // Compressible input is left alone.
+    let text = "expand, expand, expand. ".repeat(500);
+    let compressed = compress("-lz77", "max-expand-text", text.as_bytes(), &["--max-expand", "0%"]);
+    assert_eq!(compressed[6], 0);
+    assert_eq!(decompress("-lz77", "max-expand-text", &compressed).unwrap(), text.as_bytes());
+}
+
+#[test]
+fn split_groups_high_and_low_bytes() {
+    let samples = [0x1234u16, 0x1256, 0x1278];
+    let le = samples.iter().flat_map(|s| s.to_le_bytes()).chain([0xAB]).collect::<Vec<u8>>();
+    let be = samples.iter().flat_map(|s| s.to_be_bytes()).chain([0xAB]).collect::<Vec<u8>>();
+
+    let split_le = compress("-split", "split-le", &le, &[]);
+    let split_be = compress("-split", "split-be", &be, &["--big-endian"]);
+    let planes = [0x12, 0x12, 0x12, 0x34, 0x56, 0x78, 0xAB];
+    assert_eq!(split_le[HEADER..split_le.len() - TRAILER], [&[0, 7, 0, 0, 0][..], &planes].concat());
+    assert_eq!(split_be[HEADER..split_be.len() - TRAILER], [&[1, 7, 0, 0, 0][..], &planes].concat());
+
+    // The byte order is read from the stream.
+    assert_eq!(decompress("-split", "split-le", &split_le).unwrap(), le);
+    assert_eq!(decompress("-split", "split-be", &split_be).unwrap(), be);
+}
+
+#[test]
+fn segment_codes_each_kind_of_content() {
+    let text = "Segments of text, code and noise. ".repeat(600).into_bytes();
+    // splitmix64 output.
+    let mut state = 0u64;
+    let random = (0..20000).map(|_| {
+        state = state.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        (z ^ (z >> 31)) as u8
+    }).collect::<Vec<u8>>();
+    // A CALL to one of a few functions every 16 bytes.
+    let code = (0..1500u32).flat_map(|i| {
+        let target = 0x1000 * (i % 7) as i32 - 16 * i as i32;
+        [[0x55, 0x89, 0xE5, 0x83, 0xEC, 0x10, 0x8B, 0x45, 0x08, 0x50, 0xE8].as_slice(), &target.to_le_bytes(), &[0xC3]].concat()
+    }).collect::<Vec<u8>>();
+    let data = [&text[..], &random, &code].concat();
+
+    let compressed = compress("-segment", "segment", &data, &[]);
+    let segments = &compressed[HEADER..];
+    assert_eq!(segments[..4], 3u32.to_le_bytes());
+    let kinds = (0..3).map(|i| segments[4 + 9 * i]).collect::<Vec<u8>>();
+    // Text, random and x86.
+    assert_eq!(kinds, [1, 0, 2]);
+    assert!(compressed.len() < random.len() + 2000);
+    assert_eq!(decompress("-segment", "segment", &compressed).unwrap(), data);
+}
+
+#[test]
+fn threads_give_the_same_output_for_any_count() {
+    let data = "blocks are compressed on their own, in any order. ".repeat(1000).into_bytes();
+    for algorithm in ["-lpaq1", "-fpaq", "-bwt", "-huffman"] {
+        let one = compress(algorithm, "threads-1", &data, &["--threads", "1", "--block-size", "16K"]);
+        let four = compress(algorithm, "threads-4", &data, &["--threads", "4", "--block-size", "16K"]);
+        assert_eq!(one, four, "{}", algorithm);
+        // Flagged as blocks in the container header.
+        assert_eq!(one[6], 8);
+        assert_eq!(decompress(algorithm, "threads", &four).unwrap(), data, "{}", algorithm);
+    }
+}
+
+#[test]
+fn blocks_decompress_on_any_number_of_threads() {
+    let input = temp_path("threads-d");
+    let compressed = temp_path("threads-d.c");
+    let restored = temp_path("threads-d.d");
+    // 4 blocks, with a short one at the end.
+    let data = "and written out in the order they were read. ".repeat(1200);
+    fs::write(&input, &data).unwrap();
+    assert!(run_with(&["-lpaq1", "-c", "--threads", "2", "--block-size", "16K"], &input, &compressed).0);
+
+    // Fewer, as many, and more threads than blocks.
+    for threads in ["1", "3", "4", "9"] {
+        let _ = fs::remove_file(&restored);
+        assert!(run_with(&["-lpaq1", "-d", "--threads", threads], &compressed, &restored).0, "{}", threads);
+        assert_eq!(fs::read(&restored).unwrap(), data.as_bytes(), "{}", threads);
+    }
+
+    for path in [input, compressed, restored] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn index_reads_ranges_without_the_rest() {
+    let data = (0..4000).map(|i| format!("record {:05}\n", i)).collect::<String>().into_bytes();
+    let compressed = compress("-fpaq", "index", &data, &["--index", "--block-size", "4K"]);
+    assert_eq!(decompress("-fpaq", "index", &compressed).unwrap(), data);
+
+    let mut reader = IndexedReader::open(Cursor::new(&compressed), Algorithm::Fpaq, None).unwrap();
+    assert_eq!(reader.size(), data.len() as u64);
+    let len = data.len() as u64;
+    // Within a block, across blocks, past the end, and all of it.
+    for (offset, n) in [(0, 10), (4090, 20), (20000, 9000), (len - 5, 100), (len + 10, 5), (0, len)] {
+        let end = (offset + n).min(len).max(offset.min(len));
+        assert_eq!(reader.read_range(offset, n).unwrap(), &data[offset.min(len) as usize..end as usize], "{} {}", offset, n);
+    }
+
+    // A damaged block is only noticed when it's read.
+    let mut damaged = compressed.clone();
+    damaged[HEADER + 8] ^= 1;
+    let mut reader = IndexedReader::open(Cursor::new(&damaged), Algorithm::Fpaq, None).unwrap();
+    assert!(matches!(reader.read_range(0, 10), Err(Error::InvalidData(_))));
+    assert_eq!(reader.read_range(len - 10, 10).unwrap(), &data[data.len() - 10..]);
+
+    let unindexed = compress("-fpaq", "no-index", &data, &["--threads", "1", "--block-size", "4K"]);
+    assert!(matches!(IndexedReader::open(Cursor::new(&unindexed), Algorithm::Fpaq, None), Err(Error::InvalidData(_))));
+    assert!(matches!(IndexedReader::open(Cursor::new(&compressed), Algorithm::Lpaq1, None), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn hash_prints_checksums() {
+    let input = temp_path("hash");
+    fs::write(&input, b"123456789").unwrap();
+    let path = input.to_str().unwrap();
+    for (kind, hash) in [("--crc32", "cbf43926"), ("--xxh64", "8cb841db40e6ae83")] {
+        let output = Command::new(EXE).args(["hash", kind, path]).output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), format!("{}  {}\n", hash, path));
+    }
+
+    // The others are still hashed if one can't be read.
+    let missing = temp_path("hash-missing");
+    let output = Command::new(EXE).args(["hash", "--crc32"]).arg(&missing).arg(&input).output().unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8(output.stdout).unwrap().contains("cbf43926"));
+    fs::remove_file(input).unwrap();
+}
+
+#[test]
+fn delta_round_trips_against_reference() {
+    let reference = temp_path("delta-ref");
+    let input = temp_path("delta-in");
+    let delta = temp_path("delta-out");
+    let restored = temp_path("delta-restored");
+
+    let snapshot = (0..200000u32).map(|i| (i.wrapping_mul(2654435761) >> 13) as u8).collect::<Vec<u8>>();
+    let mut changed = snapshot.clone();
+    changed[1000..1010].copy_from_slice(b"0123456789");
+    changed.extend_from_slice(b"appended past the end of the reference");
+    fs::write(&reference, &snapshot).unwrap();
+    fs::write(&input, &changed).unwrap();
+
+    let status = Command::new(EXE)
+        .args(["delta", "-ref"])
+        .args([&reference, &input, &delta])
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    assert!(fs::metadata(&delta).unwrap().len() < 200);
+
+    let status = Command::new(EXE)
+        .args(["delta", "-d", "-ref"])
+        .args([&reference, &delta, &restored])
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    assert_eq!(fs::read(&restored).unwrap(), changed);
+
+    // Another reference, whether a different length or just different
+    // bytes, is rejected rather than XORed into wrong output.
+    let mut other = snapshot.clone();
+    other[5000] ^= 1;
+    for wrong in [&snapshot[..1000], &other[..]] {
+        fs::write(&reference, wrong).unwrap();
+        let output = Command::new(EXE)
+            .args(["delta", "-d", "-ref"])
+            .args([&reference, &delta, &restored])
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8(output.stderr).unwrap();
+        assert!(stderr.contains("reference doesn't match"), "{}", stderr);
+    }
+
+    for path in [reference, input, delta, restored] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn patch_reproduces_new_file() {
+    let old = temp_path("patch-old");
+    let new = temp_path("patch-new");
+    let patch = temp_path("patch-patch");
+    let restored = temp_path("patch-restored");
+
+    // Records that move and have their fields shifted, as code does when
+    // something is inserted before it.
+    let record = |i: u32, shift: u32| {
+        let mut r = format!("record {:06} ", i).into_bytes();
+        r.extend_from_slice(&(i * 64 + shift).to_le_bytes());
+        r
+    };
+    let old_data = (0..5000).flat_map(|i| record(i, 0)).collect::<Vec<u8>>();
+    let new_data = b"inserted header".iter().copied()
+        .chain((0..5000).filter(|i| i % 700 != 3).flat_map(|i| record(i, 16)))
+        .collect::<Vec<u8>>();
+    fs::write(&old, &old_data).unwrap();
+    fs::write(&new, &new_data).unwrap();
+
+    let status = Command::new(EXE).arg("diff").args([&old, &new, &patch]).output().unwrap().status;
+    assert!(status.success());
+    assert!(fs::metadata(&patch).unwrap().len() < new_data.len() as u64 / 50);
+
+    let status = Command::new(EXE).arg("patch").args([&old, &patch, &restored]).output().unwrap().status;
+    assert!(status.success());
+    assert_eq!(fs::read(&restored).unwrap(), new_data);
+
+    // A patch only applies to the file it was made from.
+    fs::write(&old, &old_data[..1000]).unwrap();
+    let result = Command::new(EXE).arg("patch").args([&old, &patch, &restored]).output().unwrap();
+    assert!(!result.status.success());
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("patch doesn't match old file"), "{}", stderr);
+
+    for path in [old, new, patch, restored] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+// Run the CLI with args, returning whether it succeeded and its stderr.
+fn run_with(args: &[&str], input: &PathBuf, output: &PathBuf) -> (bool, String) {
+    let result = Command::new(EXE).args(args).arg(input).arg(output).output().unwrap();
+    (result.status.success(), String::from_utf8(result.stderr).unwrap())
+}
+
+#[test]
+fn max_memory_scales_lpaq1_and_bwt() {
+    let input = temp_path("max-memory");
+    let compressed = temp_path("max-memory.c");
+    let restored = temp_path("max-memory.d");
+    let data = "memory, memory, all alone in the moonlight. ".repeat(2000);
+    fs::write(&input, &data).unwrap();
+
+    // The lpaq1 memory level follows the model mask in the header.
+    assert!(run_with(&["-lpaq1", "-c", "--no-mtime"], &input, &compressed).0);
+    assert_eq!(fs::read(&compressed).unwrap()[HEADER + 25], 0x20 | 0x10 | 3);
+    assert!(run_with(&["-lpaq1", "-c", "--no-mtime", "--max-memory", "12M"], &input, &compressed).0);
+    assert_eq!(fs::read(&compressed).unwrap()[HEADER + 25], 0x20 | 0x10 | 1);
+    assert!(run_with(&["-lpaq1", "-d", "--max-memory", "12M"], &compressed, &restored).0);
+    assert_eq!(fs::read(&restored).unwrap(), data.as_bytes());
+
+    let (ok, stderr) = run_with(&["-lpaq1", "-d", "--max-memory", "10M"], &compressed, &restored);
+    assert!(!ok);
+    assert!(stderr.contains("more than --max-memory"), "{}", stderr);
+    let (ok, stderr) = run_with(&["-lpaq1", "-c", "--max-memory", "1M"], &input, &compressed);
+    assert!(!ok);
+    assert!(stderr.contains("-lpaq1 needs about"), "{}", stderr);
+
+    // bwt records its smaller block size in the stream.
+    assert!(run_with(&["-bwt", "-c", "--no-mtime", "--max-memory", "10M"], &input, &compressed).0);
+    assert_eq!(fs::read(&compressed).unwrap()[HEADER..HEADER + 8], (1u64 << 18).to_le_bytes());
+    assert!(run_with(&["-bwt", "-d", "--max-memory", "10M"], &compressed, &restored).0);
+    assert_eq!(fs::read(&restored).unwrap(), data.as_bytes());
+
+    // flzp always needs the same.
+    let (ok, stderr) = run_with(&["-flzp", "-c", "--max-memory", "8M"], &input, &compressed);
+    assert!(!ok);
+    assert!(stderr.contains("-flzp needs about"), "{}", stderr);
+
+    for path in [input, compressed, restored] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn nice_and_cpu_limit_round_trip() {
+    let data = "sharing the machine, ".repeat(5000).into_bytes();
+    let compressed = compress("-lpaq1", "cpu-limit", &data, &["--nice", "1", "--cpu-limit", "50%"]);
+    assert_eq!(compressed, compress("-lpaq1", "cpu-limit", &data, &[]));
+}
+
+#[test]
+fn cpu_limit_with_threads_round_trip() {
+    let data = "sharing the machine between workers, ".repeat(2000).into_bytes();
+    let args = ["--threads", "4", "--block-size", "16K"];
+    let limited = compress("-fpaq", "cpu-limit-threads", &data, &[&args[..], &["--cpu-limit", "50%"]].concat());
+    assert_eq!(limited, compress("-fpaq", "cpu-limit-threads", &data, &args));
+    assert_eq!(decompress("-fpaq", "cpu-limit-threads", &limited).unwrap(), data);
+}
+
+#[test]
+fn model_stats_lists_each_model() {
+    let input = temp_path("model-stats");
+    let compressed = temp_path("model-stats.c");
+    let data = "the models report what they contributed. ".repeat(2000);
+    fs::write(&input, &data).unwrap();
+
+    let result = Command::new(EXE)
+        .args(["-lpaq1", "-c", "--no-mtime", "--model-stats", "--models", "o2,word,match"])
+        .arg(&input).arg(&compressed)
+        .output().unwrap();
+    assert!(result.status.success());
+    // The report goes to stderr, leaving stdout for the data.
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    let models = stderr.lines()
+        .skip_while(|line| !line.starts_with("model"))
+        .skip(1)
+        .map(|line| line.split_whitespace().next().unwrap())
+        .take(6)
+        .collect::<Vec<_>>();
+    assert_eq!(models, ["match", "match-len", "word", "o1", "o2", "mixed"]);
+
+    // The report doesn't change the stream.
+    assert_eq!(fs::read(&compressed).unwrap(), compress("-lpaq1", "model-stats-plain", data.as_bytes(), &["--models", "o2,word,match"]));
+
+    for path in [input, compressed] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn flzp_block_stats_show_incompressible_regions() {
+    // Text, then bytes that don't repeat.
+    let mut data = "blocks of text compress well. ".repeat(3000).into_bytes();
+    let mut x = 1u32;
+    data.extend((0..20000).map(|_| { x = x.wrapping_mul(1664525).wrapping_add(1013904223); (x >> 24) as u8 }));
+
+    let plain = compress("-flzp", "block-stats", &data, &[]);
+    let compressed = compress("-flzp", "block-stats", &data, &["--block-stats"]);
+    assert_eq!(compressed[..plain.len() - TRAILER], plain[..plain.len() - TRAILER]);
+    assert_eq!(decompress("-flzp", "block-stats", &compressed).unwrap(), data);
+
+    let input = temp_path("block-stats.c");
+    fs::write(&input, &compressed).unwrap();
+    let result = Command::new(EXE).args(["info", "--blocks"]).arg(&input).output().unwrap();
+    assert!(result.status.success());
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let blocks = stdout.lines().skip(1)
+        .map(|line| line.split_whitespace().map(|field| field.trim_end_matches('%').parse::<f64>().unwrap()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(blocks.iter().map(|block| block[2]).sum::<f64>(), data.len() as f64);
+    assert!(blocks[0][4] < 10.0, "{}", stdout);
+    assert!(blocks.last().unwrap()[4] > 100.0, "{}", stdout);
+
+    // In blocks on several threads, each block's stats are listed in
+    // turn, with offsets into the whole input.
+    let threaded = compress("-flzp", "block-stats-threads", &data, &["--block-stats", "--threads", "2", "--block-size", "32K"]);
+    assert_eq!(decompress("-flzp", "block-stats-threads", &threaded).unwrap(), data);
+    fs::write(&input, &threaded).unwrap();
+    let result = Command::new(EXE).args(["info", "--blocks"]).arg(&input).output().unwrap();
+    assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+    let stdout = String::from_utf8(result.stdout).unwrap();
+    let blocks = stdout.lines().skip(1)
+        .map(|line| line.split_whitespace().map(|field| field.trim_end_matches('%').parse::<f64>().unwrap()).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut offset = 0.0;
+    for block in blocks.iter() {
+        assert_eq!(block[1], offset, "{}", stdout);
+        offset += block[2];
+    }
+    assert_eq!(offset, data.len() as f64);
+    assert!(blocks[0][4] < 10.0, "{}", stdout);
+    assert!(blocks.last().unwrap()[4] > 100.0, "{}", stdout);
+
+    // Without block stats there's nothing to list.
+    fs::write(&input, &plain).unwrap();
+    let result = Command::new(EXE).args(["info", "--blocks"]).arg(&input).output().unwrap();
+    fs::remove_file(&input).unwrap();
+    assert!(!result.status.success());
+    assert!(String::from_utf8(result.stderr).unwrap().contains("no block stats"));
+}
+
+#[test]
+fn container_header_is_checked() {
+    let data = "every stream starts with a container header. ".repeat(200).into_bytes();
+    let compressed = compress("-lz77", "container", &data, &[]);
+    assert_eq!(compressed[..HEADER], [&b"CMPR"[..], &[2, 1, 0], &(data.len() as u64).to_le_bytes()].concat()[..]);
+    assert_eq!(compressed[compressed.len() - TRAILER..], [&(compressed.len() as u64).to_le_bytes()[..], b"CEND"].concat()[..]);
+
+    let err = decompress("-lzw", "container-algorithm", &compressed).unwrap_err();
+    assert!(err.contains("stream was compressed with -lz77, not -lzw"), "{}", err);
+
+    let err = decompress("-lz77", "container-magic", &data).unwrap_err();
+    assert!(err.contains("not a compressed stream"), "{}", err);
+
+    let mut bad = compressed.clone();
+    bad[4] = 3;
+    let err = decompress("-lz77", "container-version", &bad).unwrap_err();
+    assert!(err.contains("unsupported container version 3"), "{}", err);
+
+    let mut bad = compressed.clone();
+    bad[7] ^= 1;
+    let err = decompress("-lz77", "container-size", &bad).unwrap_err();
+    assert!(err.contains("but the header says"), "{}", err);
+}
+
+#[test]
+fn concatenated_streams_decompress_to_concatenated_originals() {
+    let first = "the first file, which repeats. ".repeat(500).into_bytes();
+    let second = b"and a second, shorter one".to_vec();
+    let mut x = 1u32;
+    let random = (0..3000).map(|_| { x = x.wrapping_mul(1664525).wrapping_add(1013904223); (x >> 24) as u8 }).collect::<Vec<u8>>();
+    let expected = [&first[..], &second, &random, &first].concat();
+    // lzw streams end where their input does, and lpaq1 seeks back to
+    // finish its header.
+    for algorithm in ["-lzw", "-fpaq", "-lpaq1", "-bwt"] {
+        let a = compress(algorithm, "concat-a", &first, &[]);
+        let b = compress(algorithm, "concat-b", &second, &["--threads", "1"]);
+        let stored = compress(algorithm, "concat-stored", &random, &["--max-expand", "0%"]);
+        assert_eq!(stored[6], 1);
+        let joined = [&a[..], &b, &stored, &a].concat();
+        assert_eq!(decompress(algorithm, "concat", &joined).unwrap(), expected, "{}", algorithm);
+
+        let err = decompress(algorithm, "concat-cut", &joined[..joined.len() - 1]).unwrap_err();
+        assert!(err.contains("truncated stream"), "{}", err);
+    }
+    let err = decompress("-fpaq", "concat-mixed", &[compress("-fpaq", "concat-a", &first, &[]), compress("-lzw", "concat-b", &second, &[])].concat()).unwrap_err();
+    assert!(err.contains("compressed with -lzw, not -fpaq"), "{}", err);
+}
+
+#[test]
+fn sortrans_fast_profile_round_trips() {
+    let data = "the fast profile codes ranks with fpaq. ".repeat(2000).into_bytes();
+    let fast = compress("-sortrans", "sortrans-fast", &data, &["--fast"]);
+    assert_eq!(fast[HEADER], 1);
+    assert!(fast.len() < data.len() / 20, "{} bytes", fast.len());
+    assert_eq!(decompress("-sortrans", "sortrans-fast", &fast).unwrap(), data);
+
+    let mut unknown = fast.clone();
+    unknown[HEADER] = 2;
+    let err = decompress("-sortrans", "sortrans-unknown", &unknown).unwrap_err();
+    assert!(err.contains("unknown sortrans profile 2"), "{}", err);
+}
+
+#[test]
+fn sortrans_codes_sorted_blocks_with_short_contexts() {
+    let data = "sorted blocks put alike contexts together. ".repeat(2000).into_bytes();
+    let compressed = compress("-sortrans", "sortrans", &data, &[]);
+    // The Max profile, then an lpaq1 stream with o2, o3 and o4, at the
+    // default level.
+    assert_eq!(compressed[HEADER], 0);
+    assert_eq!(compressed[HEADER + 25..HEADER + 27], [7, 0x20 | 0x10 | 3]);
+    assert_eq!(decompress("-sortrans", "sortrans", &compressed).unwrap(), data);
+
+    // Blocks coded at once each have their own temporary file.
+    let compressed = compress("-sortrans", "sortrans-threads", &data, &["--threads", "2", "--block-size", "16K"]);
+    assert_eq!(decompress("-sortrans", "sortrans-threads", &compressed).unwrap(), data);
+}
+
+#[test]
+fn volumes_split_output_and_decompress_from_the_first() {
+    let data = (0..5000).map(|i| format!("volume line {} of {}\n", i, i * i)).collect::<String>();
+    let input = temp_path("volumes");
+    let output = temp_path("volumes.c");
+    let volume = |n: usize| temp_path(&format!("volumes.c.{:03}", n));
+    let restored = temp_path("volumes.d");
+    fs::write(&input, &data).unwrap();
+    // lpaq1 seeks back to finish its header, in the first volume.
+    for algorithm in ["-lpaq1", "-flzp", "-huffman"] {
+        let whole = compress(algorithm, "volumes-whole", data.as_bytes(), &[]);
+        // Left from a longer stream, and removed.
+        for n in 1..=40 {
+            fs::write(volume(n), b"stale").unwrap();
+        }
+        let status = Command::new(EXE)
+            .args([algorithm, "-c", "--no-mtime", "--volume-size", "1K"])
+            .arg(&input)
+            .arg(&output)
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert!(!output.exists());
+        let volumes = (1..).map(volume).take_while(|path| path.exists()).collect::<Vec<_>>();
+        assert!(volumes.len() > 2);
+        assert_eq!(volumes.len(), whole.len().div_ceil(1024), "{}", algorithm);
+        let joined = volumes.iter().flat_map(|path| fs::read(path).unwrap()).collect::<Vec<u8>>();
+        assert_eq!(joined, whole, "{}", algorithm);
+        assert!(volumes[..volumes.len() - 1].iter().all(|path| fs::metadata(path).unwrap().len() == 1024));
+
+        let result = Command::new(EXE)
+            .args([algorithm, "-d"])
+            .arg(volume(1))
+            .arg(&restored)
+            .output()
+            .unwrap();
+        assert!(result.status.success(), "{}", String::from_utf8_lossy(&result.stderr));
+        assert_eq!(fs::read_to_string(&restored).unwrap(), data);
+
+        // Without the last volume, the stream is truncated.
+        fs::remove_file(volumes.last().unwrap()).unwrap();
+        let result = Command::new(EXE)
+            .args([algorithm, "-t"])
+            .arg(volume(1))
+            .output()
+            .unwrap();
+        assert!(!result.status.success());
+        assert!(String::from_utf8_lossy(&result.stderr).contains("truncated stream"), "{}", algorithm);
+        for path in &volumes[..volumes.len() - 1] {
+            fs::remove_file(path).unwrap();
+        }
+    }
+    for path in [input, restored] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn rans_round_trips_adaptive_and_static_blocks() {
+    // More than one 1M block, the second all one byte, which a static
+    // table gives every slot.
+    let mut x = 1u32;
+    let mut data = (0..1 << 20).map(|_| { x = x.wrapping_mul(1664525).wrapping_add(1013904223); b"aaaabbc\n"[(x >> 29) as usize] }).collect::<Vec<u8>>();
+    data.extend(std::iter::repeat_n(b'z', 5000));
+    for args in [&[][..], &["--static"]] {
+        let compressed = compress("-rans", "rans", &data, args);
+        assert_eq!(compressed[HEADER], args.len() as u8);
+        // The first block has an entropy of 1.75 bits a byte.
+        assert!(compressed.len() < (1 << 20) * 2 / 8, "{}", compressed.len());
+        assert_eq!(decompress("-rans", "rans", &compressed).unwrap(), data);
+    }
+
+    let mut compressed = compress("-rans", "rans-static", &data, &["--static"]);
+    compressed[HEADER + 5] ^= 1;
+    let err = decompress("-rans", "rans-static", &compressed).unwrap_err();
+    assert!(err.contains("bad frequency table"), "{}", err);
+}
+
+#[test]
+fn watchdog_stops_decoders_writing_past_the_original_size() {
+    let data = "more output than the header allows. ".repeat(8000).into_bytes();
+    for (algorithm, args) in [("-lz77", &[][..]), ("-lpaq1", &[]), ("-flzp", &["--threads", "2", "--block-size", "16K"])] {
+        let mut compressed = compress(algorithm, "watchdog", &data, args);
+        compressed[7..HEADER].copy_from_slice(&1000u64.to_le_bytes());
+        if !args.is_empty() {
+            // The first block's length, after the block size.
+            compressed[HEADER + 8..HEADER + 16].copy_from_slice(&1000u64.to_le_bytes());
+        }
+        let err = decompress(algorithm, "watchdog", &compressed).unwrap_err();
+        assert!(err.contains("stream decodes to more than the 1000 bytes expected"), "{}: {}", algorithm, err);
+        assert!(!err.contains("panicked"), "{}: {}", algorithm, err);
+    }
+}
+
+#[test]
+fn fse_stores_a_count_table_for_each_block() {
+    // A 128K block of every byte value, then one of a single byte, which
+    // takes every state and codes in 0 bits.
+    let mut data = (0..1 << 17).map(|i| (i * 7 % 251) as u8).collect::<Vec<u8>>();
+    data.extend(std::iter::repeat_n(b'z', 1000));
+    let compressed = compress("-fse", "fse", &data, &[]);
+    assert_eq!(compressed[HEADER..HEADER + 4], (1u32 << 17).to_le_bytes());
+    assert_eq!(compressed[HEADER + 4..HEADER + 36].iter().map(|byte| byte.count_ones()).sum::<u32>(), 251);
+    // The last block: its length, the one symbol, its count of every
+    // state, and 2 bytes of data, the 12 bit first state alone.
+    let last = &compressed[compressed.len() - TRAILER - 4 - 44..compressed.len() - TRAILER - 4];
+    assert_eq!(last[..4], 1000u32.to_le_bytes());
+    assert_eq!(last[4 + b'z' as usize / 8], 1 << (b'z' % 8));
+    assert_eq!(last[36..38], 4096u16.to_le_bytes());
+    assert_eq!(last[38..42], 2u32.to_le_bytes());
+    assert_eq!(decompress("-fse", "fse", &compressed).unwrap(), data);
+}
+
+#[test]
+fn cpu_features_change_speed_but_not_output() {
+    let data = "The match and the mixer, at each level. ".repeat(2000).into_bytes();
+    for algorithm in ["-lpaq1", "-lz77"] {
+        let best = compress(algorithm, "cpu", &data, &[]);
+        let scalar = compress(algorithm, "cpu", &data, &["--cpu-features", "scalar"]);
+        assert!(best == scalar, "{}", algorithm);
+    }
+
+    let level = if cfg!(target_arch = "aarch64") { "avx2" } else { "neon" };
+    let input = temp_path("cpu-unsupported");
+    fs::write(&input, &data).unwrap();
+    let result = Command::new(EXE)
+        .args(["-lz77", "-c", "--cpu-features", level])
+        .arg(&input)
+        .arg(temp_path("cpu-unsupported.c"))
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+    assert!(!result.status.success());
+    assert!(String::from_utf8(result.stderr).unwrap().contains(&format!("doesn't support {}", level)));
+    assert!(!temp_path("cpu-unsupported.c").exists());
+}
+
+#[test]
+fn ppm_records_its_order_and_model_size() {
+    let mut x = 1u32;
+    let words = ["context ", "model ", "escape ", "order ", "count ", "byte ", "tree\n"];
+    let data = (0..60000).map(|_| {
+        x = x.wrapping_mul(1664525).wrapping_add(1013904223);
+        format!("{}{} ", words[(x >> 29) as usize % 7], x % 1000)
+    })
+    .collect::<String>().into_bytes();
+
+    let default = compress("-ppm", "ppm", &data, &[]);
+    assert_eq!(default[HEADER], 6);
+    assert_eq!(default[HEADER + 1..HEADER + 3], 64u16.to_le_bytes());
+    let order0 = compress("-ppm", "ppm", &data, &["--order", "0"]);
+    assert!(default.len() < order0.len() / 2, "{} {}", default.len(), order0.len());
+
+    // A 1M model, left over from 5M after buffers, fills and starts over
+    // many times, and costs some ratio.
+    let small = compress("-ppm", "ppm", &data, &["--max-memory", "5M"]);
+    assert_eq!(small[HEADER + 1..HEADER + 3], 1u16.to_le_bytes());
+    assert!(small.len() > default.len(), "{} {}", small.len(), default.len());
+    for compressed in [&default, &order0, &small] {
+        assert_eq!(decompress("-ppm", "ppm", compressed).unwrap(), data);
+    }
+
+    let input = temp_path("ppm-memory");
+    fs::write(&input, &default).unwrap();
+    let result = Command::new(EXE)
+        .args(["-ppm", "-t", "--max-memory", "32M"])
+        .arg(&input)
+        .output()
+        .unwrap();
+    fs::remove_file(&input).unwrap();
+    assert!(!result.status.success());
+    let err = String::from_utf8(result.stderr).unwrap();
+    assert!(err.contains("ppm stream needs 71303168 bytes of memory, more than --max-memory"), "{}", err);
+}
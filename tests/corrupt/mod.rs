@@ -0,0 +1,120 @@
+// Corrupted variants of valid compressed streams, for checking that
+// decoders reject damaged input with an error rather than panicking,
+// aborting or running forever.
+//
+// The corruption follows the layout of each format: every header byte
+// gets a flipped bit, length fields are set to oversize values, and the
+// stream is truncated inside and just after the header, as well as at
+// a few points in the body.
+
+// Where a format's header ends and where its length and count fields
+// are, as (offset, width in bytes).
+pub struct Layout {
+    pub header: usize,
+    pub fields: Vec<(usize, usize)>,
+}
+
+// The container header in front of every stream, and its original size
+// field.
+const CONTAINER: usize = 15;
+const CONTAINER_FIELDS: [(usize, usize); 1] = [(7, 8)];
+
+// The layout of a stream in its container.
+pub fn layout(algorithm: &str) -> Layout {
+    let stream = stream_layout(algorithm);
+    Layout {
+        header: CONTAINER + stream.header,
+        fields: CONTAINER_FIELDS.iter().copied()
+            .chain(stream.fields.iter().map(|&(offset, width)| (CONTAINER + offset, width)))
+            .collect(),
+    }
+}
+
+fn stream_layout(algorithm: &str) -> Layout {
+    match algorithm {
+        "-lz77"    => Layout { header: 2,  fields: vec![] },
+        "-lzw"     => Layout { header: 0,  fields: vec![] },
+        "-flzp"    => Layout { header: 35, fields: vec![(34, 1)] },
+        "-sr"      => Layout { header: 0,  fields: vec![] },
+        "-fpaq0"   => Layout { header: 0,  fields: vec![] },
+        "-fpaq"    => Layout { header: 5,  fields: vec![(0, 1)] },
+        "-lpaq1"   => Layout { header: 26, fields: vec![(0, 8), (8, 8), (16, 8), (25, 1)] },
+        // The order, then the model size.
+        "-ppm"     => Layout { header: 3,  fields: vec![(1, 2)] },
+        // The flags, then the first block's length and coded size.
+        "-rans"    => Layout { header: 9,  fields: vec![(1, 4), (5, 4)] },
+        "-rans --static" => Layout { header: 521, fields: vec![(1, 4), (5, 2), (517, 4)] },
+        // The first block's length, its symbols, a count of each of the
+        // input's 29 different bytes, and its coded size.
+        "-fse"     => Layout { header: 98, fields: vec![(0, 4), (36, 2), (94, 4)] },
+        "-huffman" => Layout { header: 9,  fields: vec![(1, 8), (9, 4)] },
+        "-huffman --order1" => Layout { header: 41, fields: vec![(1, 8)] },
+        "-bwt"     => Layout { header: 16, fields: vec![(0, 8), (8, 8)] },
+        "-remap"   => Layout { header: 1,  fields: vec![(0, 1)] },
+        "-split"   => Layout { header: 5,  fields: vec![(0, 1), (1, 4)] },
+        // One text segment, then its stream's length.
+        "-segment" => Layout { header: 21, fields: vec![(0, 4), (5, 8), (13, 8)] },
+        // The profile, then an lpaq1 stream of the bwt stream, or an fpaq
+        // stream of the move-to-front coded bwt stream.
+        "-sortrans" => Layout { header: 27, fields: vec![(0, 1), (1, 8), (9, 8), (17, 8), (26, 1)] },
+        "-sortrans --fast" => Layout { header: 6, fields: vec![(0, 1), (1, 1)] },
+        // The block size, then the first block's lengths.
+        "-fpaq --threads 2 --block-size 4K" => Layout { header: 24, fields: vec![(0, 8), (8, 8), (16, 8)] },
+        "-fpaq --index --block-size 4K" => Layout { header: 24, fields: vec![(0, 8), (8, 8), (16, 8)] },
+        _ => panic!("no layout for {}", algorithm),
+    }
+}
+
+pub struct Case {
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// Corrupted copies of stream, a valid stream in the given layout.
+pub fn cases(stream: &[u8], layout: &Layout) -> Vec<Case> {
+    let mut rng = Rng(stream.len() as u64);
+    let header = layout.header.min(stream.len());
+    let mut cases = Vec::new();
+
+    let cut = |len: usize| {
+        let len = len.min(stream.len());
+        Case { name: format!("truncated to {}", len), data: stream[..len].to_vec() }
+    };
+    for len in [0, 1, header / 2, header, header + 1, stream.len() / 2, stream.len() - 1] {
+        cases.push(cut(len));
+    }
+
+    let flip = |offset: usize, bit: u64| {
+        let mut data = stream.to_vec();
+        data[offset] ^= 1 << bit;
+        Case { name: format!("bit {} of byte {} flipped", bit, offset), data }
+    };
+    for offset in 0..header {
+        cases.push(flip(offset, rng.next() % 8));
+    }
+    for _ in 0..16 {
+        cases.push(flip(header + rng.next() as usize % (stream.len() - header), rng.next() % 8));
+    }
+
+    for &(offset, width) in layout.fields.iter().filter(|(offset, width)| offset + width <= stream.len()) {
+        for value in [0, u64::MAX, 1 << (width * 8 - 1)] {
+            let mut data = stream.to_vec();
+            data[offset..offset + width].copy_from_slice(&value.to_le_bytes()[..width]);
+            cases.push(Case { name: format!("field at {} set to {:#x}", offset, value), data });
+        }
+    }
+    cases
+}
+
+// splitmix64, so every run makes the same cases.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
@@ -0,0 +1,129 @@
+// Every decoder must reject corrupted streams from corrupt::cases with
+// an error, or decode them to something, without panicking or hanging.
+
+mod corrupt;
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+const EXE: &str = env!("CARGO_BIN_EXE_compression");
+const TIMEOUT: Duration = Duration::from_secs(20);
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("fuzz-{}-{}", std::process::id(), name))
+}
+
+// Compress with algorithm and args, then decompress corrupted copies.
+// The layout is looked up by the whole command, since options like
+// --order1 change the header.
+fn check(algorithm: &str, args: &[&str]) {
+    let name = [&[algorithm][..], args].concat().join(" ");
+    let input = temp_path(&format!("{}.in", name));
+    let stream = temp_path(&format!("{}.c", name));
+    let output = temp_path(&format!("{}.out", name));
+
+    let data = "A valid stream to corrupt, 0123456789.\n".repeat(200);
+    fs::write(&input, data).unwrap();
+    let status = Command::new(EXE).args([algorithm, "-c", "--no-mtime"]).args(args).arg(&input).arg(&stream).output().unwrap().status;
+    assert!(status.success());
+    let compressed = fs::read(&stream).unwrap();
+
+    for case in corrupt::cases(&compressed, &corrupt::layout(&name)) {
+        fs::write(&stream, &case.data).unwrap();
+        let mut child = Command::new(EXE)
+            .args([algorithm, "-d"])
+            .arg(&stream)
+            .arg(&output)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let start = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if start.elapsed() > TIMEOUT {
+                child.kill().unwrap();
+                panic!("{} hung on a stream with {}", name, case.name);
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        // 0 if the damage went unnoticed, 1 for a reported error. Panics
+        // exit with 101, and aborts have no code.
+        assert!(matches!(status.code(), Some(0 | 1)), 
+            "{} exited with {:?} on a stream with {}", name, status, case.name);
+    }
+    for path in [input, stream, output] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+#[test]
+fn lz77() { check("-lz77", &[]); }
+
+#[test]
+fn lzw() { check("-lzw", &[]); }
+
+#[test]
+fn flzp() { check("-flzp", &[]); }
+
+#[test]
+fn sr() { check("-sr", &[]); }
+
+#[test]
+fn fpaq0() { check("-fpaq0", &[]); }
+
+#[test]
+fn fpaq() { check("-fpaq", &[]); }
+
+#[test]
+fn lpaq1() { check("-lpaq1", &[]); }
+
+#[test]
+fn ppm() { check("-ppm", &[]); }
+
+#[test]
+fn rans() { check("-rans", &[]); }
+
+#[test]
+fn rans_static() { check("-rans", &["--static"]); }
+
+#[test]
+fn fse() { check("-fse", &[]); }
+
+#[test]
+fn huffman() { check("-huffman", &[]); }
+
+#[test]
+fn huffman_order1() { check("-huffman", &["--order1"]); }
+
+#[test]
+fn bwt() { check("-bwt", &[]); }
+
+#[test]
+fn remap() { check("-remap", &[]); }
+
+#[test]
+fn split() { check("-split", &[]); }
+
+#[test]
+fn segment() { check("-segment", &[]); }
+
+#[test]
+fn sortrans() { check("-sortrans", &[]); }
+
+#[test]
+fn sortrans_fast() { check("-sortrans", &["--fast"]); }
+
+#[test]
+fn blocks() { check("-fpaq", &["--threads", "2", "--block-size", "4K"]); }
+
+#[test]
+fn index() { check("-fpaq", &["--index", "--block-size", "4K"]); }
@@ -0,0 +1,304 @@
+// Golden vectors for every compressed format.
+//
+// Each vector compresses a deterministic generated input with the CLI and
+// compares the length and FNV-1a hash of the output against a pinned value,
+// then decompresses it again and checks the round trip. A refactor that
+// changes any emitted bit shows up here as a mismatch; if a format change
+// is intended, update the constants in the same commit.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+const EXE: &str = env!("CARGO_BIN_EXE_compression");
+
+#[derive(Clone, Copy, Debug)]
+enum Kind {
+    Text,
+    Random,
+}
+
+// Simple linear congruential generator so inputs are identical on every
+// platform without depending on an external crate.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self) -> u32 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) as u32
+    }
+}
+
+fn generate(kind: Kind, size: usize) -> Vec<u8> {
+    const WORDS: [&str; 12] = [
+        "the ", "quick ", "brown ", "fox ", "jumps ", "over ",
+        "lazy ", "dog ", "and ", "compresses ", "data, ", "again.\n",
+    ];
+    let mut rng = Lcg(size as u64);
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        match kind {
+            Kind::Text => {
+                let word = WORDS[rng.next() as usize % WORDS.len()];
+                data.extend_from_slice(word.as_bytes());
+            }
+            Kind::Random => {
+                data.push(rng.next() as u8);
+            }
+        }
+    }
+    data.truncate(size);
+    data
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("golden-{}-{}", std::process::id(), name))
+}
+
+// Without file times, which would change the output on every run.
+fn run(algorithm: &str, mode: &str, input: &PathBuf, output: &PathBuf) {
+    let status = Command::new(EXE)
+        .args([algorithm, mode, "--no-mtime"])
+        .arg(input)
+        .arg(output)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success(), "{} {} failed", algorithm, mode);
+}
+
+// (kind, input size, compressed size, FNV-1a hash of compressed output)
+type Vector = (Kind, usize, usize, u64);
+
+fn check(algorithm: &str, vectors: &[Vector]) {
+    let mut mismatches = Vec::new();
+
+    for &(kind, size, len, hash) in vectors.iter() {
+        let name = format!("{}-{:?}-{}", &algorithm[1..], kind, size);
+        let input = temp_path(&name);
+        let compressed = temp_path(&format!("{}.c", name));
+        let decompressed = temp_path(&format!("{}.d", name));
+
+        let data = generate(kind, size);
+        fs::write(&input, &data).unwrap();
+
+        run(algorithm, "-c", &input, &compressed);
+        let output = fs::read(&compressed).unwrap();
+
+        run(algorithm, "-d", &compressed, &decompressed);
+        let restored = fs::read(&decompressed).unwrap();
+
+        for path in [&input, &compressed, &decompressed] {
+            fs::remove_file(path).unwrap();
+        }
+
+        assert!(restored == data, "{}: round trip failed", name);
+        if output.len() != len || fnv1a(&output) != hash {
+            mismatches.push(format!(
+                "(Kind::{:?}, {}, {}, 0x{:016x}),", kind, size, output.len(), fnv1a(&output)
+            ));
+        }
+    }
+    assert!(mismatches.is_empty(), "{} format changed:\n{}", algorithm, mismatches.join("\n"));
+}
+
+#[test]
+fn lz77() {
+    check("-lz77", &[
+        (Kind::Text, 1, 31, 0xfd939da9fb328944),
+        (Kind::Text, 1000, 381, 0xc994a5db57c34851),
+        (Kind::Text, 70000, 11899, 0x1872c1cae455d6e9),
+        (Kind::Random, 1, 31, 0x2fbd5e5e47f96af0),
+        (Kind::Random, 1000, 2001, 0xa80ae744b5ed9fad),
+        (Kind::Random, 70000, 135847, 0x0b6eceef17fa5fd4),
+    ]);
+}
+
+#[test]
+fn lzw() {
+    check("-lzw", &[
+        (Kind::Text, 1, 29, 0xa31fe6b380b4b335),
+        (Kind::Text, 1000, 673, 0x7529dd224ab496a1),
+        (Kind::Text, 70000, 15985, 0xff18a95875761524),
+        (Kind::Random, 1, 29, 0xcd4c9d5f29dfcde1),
+        (Kind::Random, 1000, 1999, 0x817fda44abdde2b9),
+        (Kind::Random, 70000, 106557, 0x1ce66d0a1a8558e9),
+        // Large enough to fill and reset the dictionary
+        (Kind::Random, 200000, 298937, 0x648caf2bddc3de96),
+    ]);
+}
+
+#[test]
+fn flzp() {
+    check("-flzp", &[
+        (Kind::Text, 1, 96, 0xa3d8879fd8f2b5f7),
+        (Kind::Text, 1000, 620, 0x429fb209ebcf9683),
+        (Kind::Text, 70000, 23888, 0xb6f44cb9eb1994db),
+        (Kind::Random, 1, 96, 0x1cf86d2fc64879cf),
+        (Kind::Random, 1000, 1129, 0xa63ff7fdc999272d),
+        (Kind::Random, 70000, 74648, 0xbfdec288e05f6bc4),
+    ]);
+}
+
+#[test]
+fn sr() {
+    check("-sr", &[
+        (Kind::Text, 1, 30, 0x93044fcaf6fe4f30),
+        (Kind::Text, 1000, 295, 0xdedd119a0bea1b5d),
+        (Kind::Text, 70000, 6542, 0x816264a04b2284f2),
+        (Kind::Random, 1, 30, 0x6dc76dbb28b46219),
+        (Kind::Random, 1000, 1241, 0x614c25a7d90deb98),
+        (Kind::Random, 70000, 75921, 0x6ced9d732e183b23),
+    ]);
+}
+
+#[test]
+fn fpaq0() {
+    check("-fpaq0", &[
+        (Kind::Text, 1, 29, 0xf75ac3b5b6f6fd0a),
+        (Kind::Text, 1000, 600, 0x1f2422bba591397c),
+        (Kind::Text, 70000, 39244, 0x1f6c9882a0f4850d),
+        (Kind::Random, 1, 29, 0x1e40e3c751c7c5a4),
+        (Kind::Random, 1000, 1062, 0x97007b3d9ea8a049),
+        (Kind::Random, 70000, 70162, 0xbb6045a4ad18c3b7),
+    ]);
+}
+
+#[test]
+fn fpaq() {
+    check("-fpaq", &[
+        (Kind::Text, 1, 46, 0x1443b8fbc74e7505),
+        (Kind::Text, 1000, 597, 0x4a840d1d4277e6c6),
+        (Kind::Text, 70000, 10152, 0x609cb74606cfb976),
+        (Kind::Random, 1, 46, 0xac3b724c4b2fc0ea),
+        (Kind::Random, 1000, 1068, 0x98ada164adea31e9),
+        (Kind::Random, 70000, 70282, 0x25c1c17b6abefaaa),
+    ]);
+}
+
+#[test]
+fn lpaq1() {
+    check("-lpaq1", &[
+        (Kind::Text, 1, 54, 0x41e50fb9a5a70c24),
+        (Kind::Text, 1000, 254, 0xf1f10ef1d41580f4),
+        (Kind::Text, 70000, 5786, 0xa24072309d939369),
+        (Kind::Random, 1, 54, 0x977b67628c2f49ed),
+        (Kind::Random, 1000, 1055, 0x6192f83861a14396),
+        (Kind::Random, 70000, 70204, 0x40293fe5b3a18956),
+    ]);
+}
+
+#[test]
+fn ppm() {
+    check("-ppm", &[
+        (Kind::Text, 1, 33, 0xeb277a2e7d522636),
+        (Kind::Text, 1000, 208, 0x523112da2d7e17d8),
+        (Kind::Text, 70000, 5906, 0x8a04475266ebb4fd),
+        (Kind::Random, 1, 33, 0xfbc40ec62096f0a1),
+        (Kind::Random, 1000, 1074, 0x61d3174bdd36c8c2),
+        (Kind::Random, 70000, 71073, 0xf72622f23988d739),
+    ]);
+}
+
+#[test]
+fn rans() {
+    check("-rans", &[
+        (Kind::Text, 1, 45, 0x2768845266daf13b),
+        (Kind::Text, 1000, 713, 0x64b66cbef1dea624),
+        (Kind::Text, 70000, 39602, 0x4f6ded0b45b7a466),
+        (Kind::Random, 1, 45, 0xe6af79453f8dac2a),
+        (Kind::Random, 1000, 1064, 0xa863f62d82fdb48b),
+        (Kind::Random, 70000, 70228, 0xe84390ec0ae8416a),
+    ]);
+}
+
+#[test]
+fn fse() {
+    check("-fse", &[
+        (Kind::Text, 1, 75, 0xc80f6ec8a8d04eaf),
+        (Kind::Text, 1000, 680, 0xfcb54fbf04fddae5),
+        (Kind::Text, 70000, 39290, 0x5da78c929a28b2c8),
+        (Kind::Random, 1, 75, 0xff081d9eb1b81853),
+        (Kind::Random, 1000, 1550, 0xc27faffe72c0f7a6),
+        (Kind::Random, 70000, 70890, 0xde74d714e8c5a2c2),
+    ]);
+}
+
+#[test]
+fn huffman() {
+    check("-huffman", &[
+        (Kind::Text, 1, 1061, 0xc5ebcd40d090d354),
+        (Kind::Text, 1000, 1655, 0x5942e339a077274a),
+        (Kind::Text, 70000, 40663, 0x4696e5f864ef1be3),
+        (Kind::Random, 1, 1061, 0x6fb02132dd0916d4),
+        (Kind::Random, 1000, 2042, 0x081daef510c4d834),
+        (Kind::Random, 70000, 71060, 0xd6b8f198e9c3910d),
+    ]);
+}
+
+#[test]
+fn bwt() {
+    check("-bwt", &[
+        (Kind::Text, 1, 44, 0x03013ad6c3808e4f),
+        (Kind::Text, 1000, 1043, 0xf69a184a61786b08),
+        (Kind::Text, 70000, 70043, 0xa5236083d01a0663),
+        (Kind::Random, 1, 44, 0xb022637683a9698b),
+        (Kind::Random, 1000, 1043, 0xd8dbd832a5eda623),
+        (Kind::Random, 70000, 70043, 0xb87a87f324740adc),
+    ]);
+}
+
+#[test]
+fn remap() {
+    check("-remap", &[
+        (Kind::Text, 1, 34, 0x8f701144d9321543),
+        (Kind::Text, 1000, 1062, 0x35d0ec04956dd331),
+        (Kind::Text, 70000, 70062, 0x72d8dcb6fa1f76fd),
+        (Kind::Random, 1, 34, 0x69313b6d75df6e8f),
+        (Kind::Random, 1000, 1282, 0x9233e6fc49ca876c),
+        (Kind::Random, 70000, 70288, 0x43db45ac90c8b089),
+    ]);
+}
+
+#[test]
+fn split() {
+    check("-split", &[
+        (Kind::Text, 1, 33, 0x01d119a4d454fc0d),
+        (Kind::Text, 1000, 1032, 0xa5cdaea24e6748aa),
+        (Kind::Text, 70000, 70032, 0x65d0b197dca82a90),
+        (Kind::Random, 1, 33, 0xe3f8df62affd8889),
+        (Kind::Random, 1000, 1032, 0x1803c23fd8f99bbc),
+        (Kind::Random, 70000, 70032, 0x89d4b776c9ab7d2c),
+    ]);
+}
+
+#[test]
+fn segment() {
+    check("-segment", &[
+        (Kind::Text, 1, 75, 0x73e6c1af2505cd70),
+        (Kind::Text, 1000, 275, 0x2ac66d5372713b33),
+        (Kind::Text, 70000, 5807, 0xea9ab5a94a4f3fc3),
+        (Kind::Random, 1, 76, 0x73a4c495545e80d4),
+        (Kind::Random, 1000, 1077, 0xad4e0f7f6cf13fff),
+        (Kind::Random, 70000, 70048, 0x6ca4185be1c5dc76),
+    ]);
+}
+
+#[test]
+fn sortrans() {
+    check("-sortrans", &[
+        (Kind::Text, 1, 67, 0x7e7edce3e2db22fc),
+        (Kind::Text, 1000, 370, 0xc79a4578c769054f),
+        (Kind::Text, 70000, 5996, 0x28c793a65bb9b298),
+        (Kind::Random, 1, 67, 0xa36104cafba36dde),
+        (Kind::Random, 1000, 1069, 0x68bc4572a028bfdf),
+        (Kind::Random, 70000, 70221, 0xca7602e2bbaf4741),
+    ]);
+}
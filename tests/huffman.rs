@@ -0,0 +1,166 @@
+// Encoder/decoder symmetry tests for huffman coding.
+//
+// Both sides build their code tables from the frequencies stored in the
+// header, so any difference in how they build the tree, such as breaking
+// ties between equal frequencies differently, breaks decoding. These
+// inputs are chosen to have many ties.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+use compression::huffman::huffman::{build_codes, HuffmanCodeMap};
+
+const EXE: &str = env!("CARGO_BIN_EXE_compression");
+
+// Size of the container trailer after the stream.
+const TRAILER: usize = 12;
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("huffman-{}-{}", std::process::id(), name))
+}
+
+fn run(mode: &str, args: &[&str], input: &PathBuf, output: &PathBuf) {
+    let status = Command::new(EXE)
+        .args(["-huffman", mode, "--no-mtime"])
+        .args(args)
+        .arg(input)
+        .arg(output)
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+// Compress and decompress data, returning the compressed stream.
+fn round_trip(name: &str, data: &[u8], args: &[&str]) -> Vec<u8> {
+    let input = temp_path(name);
+    let compressed = temp_path(&format!("{}.c", name));
+    let decompressed = temp_path(&format!("{}.d", name));
+    fs::write(&input, data).unwrap();
+
+    run("-c", args, &input, &compressed);
+    run("-d", &[], &compressed, &decompressed);
+    let output = fs::read(&compressed).unwrap();
+    let restored = fs::read(&decompressed).unwrap();
+
+    for path in [&input, &compressed, &decompressed] {
+        fs::remove_file(path).unwrap();
+    }
+    assert!(restored == data, "{}: round trip failed", name);
+    output
+}
+
+fn inputs() -> Vec<(&'static str, Vec<u8>)> {
+    vec![
+        // Every byte equally often
+        ("uniform", (0..=255u8).cycle().take(256 * 40).collect()),
+        // Pairs of bytes with equal counts
+        ("pairs", (0..64u8).flat_map(|i| vec![i; (i as usize / 2 + 1) * 3]).collect()),
+        // Powers of two, where merged nodes tie with leaves
+        ("powers", (0..12u8).flat_map(|i| vec![i; 1 << i]).collect()),
+        ("single", vec![42; 1000]),
+    ]
+}
+
+#[test]
+fn order0_ties_round_trip() {
+    for (name, data) in inputs() {
+        round_trip(name, &data, &[]);
+    }
+}
+
+#[test]
+fn order1_ties_round_trip() {
+    for (name, data) in inputs() {
+        round_trip(&format!("{}-order1", name), &data, &["--order1"]);
+    }
+}
+
+// Byte counts with many ties: all equal, in pairs, and powers of two,
+// where merged nodes tie with leaves.
+fn tied_frequencies() -> Vec<[u32; 256]> {
+    vec![
+        [40; 256],
+        std::array::from_fn(|i| if i < 64 { (i as u32 / 2 + 1) * 3 } else { 0 }),
+        std::array::from_fn(|i| if i < 24 { 1 << (i / 2) } else { 0 }),
+        std::array::from_fn(|i| if i % 3 == 0 { 7 } else { 0 }),
+    ]
+}
+
+// The codes of the bytes with each count, sorted, which permuting the
+// bytes with equal counts leaves as they are.
+fn codes_by_count(frequencies: &[u32; 256], codes: &HuffmanCodeMap) -> BTreeMap<u32, Vec<Vec<u8>>> {
+    let mut by_count = BTreeMap::<u32, Vec<Vec<u8>>>::new();
+    for (frequency, code) in frequencies.iter().zip(codes.iter()).filter(|(&f, _)| f > 0) {
+        by_count.entry(*frequency).or_default().push(code.clone());
+    }
+    by_count.values_mut().for_each(|codes| codes.sort());
+    by_count
+}
+
+#[test]
+fn codes_depend_only_on_frequencies() {
+    for frequencies in tied_frequencies() {
+        let codes = build_codes(&frequencies);
+        assert_eq!(codes, build_codes(&frequencies));
+
+        // Every byte with a count has a code, and no code is a prefix of
+        // another.
+        for (byte, code) in codes.iter().enumerate() {
+            assert_eq!(code.is_empty(), frequencies[byte] == 0, "byte {}", byte);
+        }
+        let used = codes.iter().filter(|code| !code.is_empty()).collect::<Vec<_>>();
+        for (i, a) in used.iter().enumerate() {
+            for b in used[i + 1..].iter() {
+                assert!(!a.starts_with(b) && !b.starts_with(a), "{:?} and {:?}", a, b);
+            }
+        }
+
+        // Moving the counts to other bytes gives the bytes with each count
+        // the same codes between them.
+        let mut rotated = frequencies;
+        rotated.rotate_left(7);
+        let mut reversed = frequencies;
+        reversed.reverse();
+        let mut shuffled = frequencies;
+        let mut seed = 12345u32;
+        for i in (1..256).rev() {
+            seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+            shuffled.swap(i, (seed >> 16) as usize % (i + 1));
+        }
+        for permuted in [rotated, reversed, shuffled] {
+            assert_eq!(codes_by_count(&permuted, &build_codes(&permuted)), codes_by_count(&frequencies, &codes));
+        }
+    }
+}
+
+#[test]
+fn truncated_stream_is_rejected() {
+    let data = b"a fairly ordinary sentence to compress. ".repeat(100);
+    for args in [&[][..], &["--order1"][..]] {
+        let compressed = round_trip("truncate", &data, args);
+        let input = temp_path("truncated.c");
+        let output = temp_path("truncated.d");
+        // Cut 10 bytes from the stream, with a trailer that fits, so the
+        // cut stream is what decompressing reads.
+        let mut truncated = compressed[..compressed.len() - TRAILER - 10].to_vec();
+        truncated.extend((truncated.len() as u64 + TRAILER as u64).to_le_bytes());
+        truncated.extend(b"CEND");
+        fs::write(&input, &truncated).unwrap();
+
+        let result = Command::new(EXE)
+            .args(["-huffman", "-d"])
+            .arg(&input)
+            .arg(&output)
+            .output()
+            .unwrap();
+        fs::remove_file(&input).unwrap();
+        let _ = fs::remove_file(&output);
+
+        assert!(!result.status.success());
+        let stderr = String::from_utf8(result.stderr).unwrap();
+        assert!(stderr.contains("truncated huffman stream"), "{}", stderr);
+    }
+}
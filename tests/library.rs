@@ -0,0 +1,444 @@
+// The algorithms used through the library crate rather than the binary.
+
+use std::env;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Write;
+use std::path::PathBuf;
+
+use compression::ari::lpaq1::{lpaq1_compress, lpaq1_compress_with_stats, lpaq1_decompress, ModelSet, DEFAULT_LEVEL};
+use compression::blocks::{compress_blocks, decompress_blocks, decompress_threads, MAX_DEFAULT_THREADS};
+use compression::bwt::bwt::{bwt_transform, bwt_inverse_transform, DEFAULT_BLOCK_SIZE};
+use compression::lz::flzp::{flzp_compress, flzp_decompress, Effort, DEFAULT_CXT_LEN};
+use compression::lz::lzw::{lzw_compress, lzw_decompress};
+use compression::ari::fpaq::{fpaq_compress, fpaq_decompress, SseProfile};
+use compression::ari::message::MessageCodec;
+use compression::framing::{read_member, write_member, Framing};
+use compression::crc32::Crc32;
+use compression::xxh64::Xxh64;
+use compression::job::Job;
+use compression::cpu;
+use compression::cpu::Level;
+use compression::huffman;
+use compression::bytes::{compress_bytes, decompress_bytes, Algorithm};
+use compression::container;
+use compression::error::{Error, Result};
+use compression::stats::Sizes;
+use compression::temp;
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("library-{}-{}", std::process::id(), name))
+}
+
+fn reader(path: &PathBuf) -> BufReader<File> {
+    BufReader::with_capacity(1 << 20, File::open(path).unwrap())
+}
+
+fn writer(path: &PathBuf) -> BufWriter<File> {
+    BufWriter::with_capacity(1 << 20, File::create(path).unwrap())
+}
+
+#[test]
+fn lpaq1_and_bwt_round_trip() {
+    let input = temp_path("in");
+    let compressed = temp_path("c");
+    let output = temp_path("out");
+    let data = "a library of compressors, usable from other crates. ".repeat(1000);
+    fs::write(&input, &data).unwrap();
+
+    lpaq1_compress(reader(&input), writer(&compressed), ModelSet::default(), false, DEFAULT_LEVEL, false).unwrap();
+    assert!(fs::metadata(&compressed).unwrap().len() < data.len() as u64 / 10);
+    lpaq1_decompress(reader(&compressed), writer(&output), None).unwrap();
+    assert_eq!(fs::read(&output).unwrap(), data.as_bytes());
+
+    bwt_transform(reader(&input), writer(&compressed), false, DEFAULT_BLOCK_SIZE).unwrap();
+    bwt_inverse_transform(reader(&compressed), writer(&output), None).unwrap();
+    assert_eq!(fs::read(&output).unwrap(), data.as_bytes());
+
+    for path in [input, compressed, output] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+// A reader like a pipe or socket, which returns at most a few bytes per
+// read, so values often straddle buffer refills.
+struct Trickle<'a>(&'a [u8]);
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(7);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+fn trickle(data: &[u8]) -> BufReader<Trickle<'_>> {
+    BufReader::with_capacity(1 << 20, Trickle(data))
+}
+
+// Run a codec from one in-memory buffer to another, checking the output
+// size it returns.
+fn in_memory<F: FnOnce(BufWriter<&mut Cursor<Vec<u8>>>) -> Result<Sizes>>(codec: F) -> Vec<u8> {
+    let mut out = Cursor::new(Vec::new());
+    let sizes = codec(BufWriter::new(&mut out)).unwrap();
+    assert_eq!(sizes.output, out.get_ref().len() as u64);
+    out.into_inner()
+}
+
+#[test]
+fn codecs_read_from_any_reader() {
+    let data = "pipes and sockets deliver a few bytes at a time. ".repeat(500).into_bytes();
+
+    let c = in_memory(|out| lzw_compress(trickle(&data), out));
+    assert_eq!(in_memory(|out| lzw_decompress(trickle(&c), out)), data);
+
+    let c = in_memory(|out| flzp_compress(trickle(&data), out, Effort::Normal, true, DEFAULT_CXT_LEN, true));
+    assert_eq!(in_memory(|out| flzp_decompress(trickle(&c), out)), data);
+
+    let c = in_memory(|out| fpaq_compress(trickle(&data), out, &SseProfile::default()));
+    assert_eq!(in_memory(|out| fpaq_decompress(trickle(&c), out)), data);
+
+    let c = in_memory(|out| lpaq1_compress(trickle(&data), out, ModelSet::default(), false, 0, false));
+    assert_eq!(in_memory(|out| lpaq1_decompress(trickle(&c), out, None)), data);
+
+    let c = in_memory(|out| bwt_transform(trickle(&data), out, false, DEFAULT_BLOCK_SIZE));
+    assert_eq!(in_memory(|out| bwt_inverse_transform(trickle(&c), out, None)), data);
+
+    // Huffman coding reads its input twice, so needs to seek.
+    let c = in_memory(|out| huffman::encoder::compress(BufReader::new(Cursor::new(&data)), out));
+    assert_eq!(in_memory(|out| huffman::decoder::decompress(trickle(&c), out)), data);
+}
+
+// A writer to a full disk.
+struct Full;
+
+impl Write for Full {
+    fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::StorageFull, "no space left on device"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A reader that fails once it has given the first n bytes of data, like
+// a file on a disk that goes away.
+struct Failing<'a>(&'a [u8], usize);
+
+impl Read for Failing<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.1 == 0 {
+            return Err(io::Error::other("device went away"));
+        }
+        let len = buf.len().min(self.1).min(self.0.len());
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        self.1 -= len;
+        Ok(len)
+    }
+}
+
+#[test]
+fn lpaq1_model_stats_are_returned() {
+    let data = "statistics come back to the caller. ".repeat(500).into_bytes();
+    let mut stats = Vec::new();
+    let c = in_memory(|out| {
+        let models = ModelSet::parse("o2,match").unwrap();
+        let (sizes, s) = lpaq1_compress_with_stats(trickle(&data), out, models, false, 0, false)?;
+        stats = s;
+        Ok(sizes)
+    });
+    // Statistics don't change the stream.
+    assert_eq!(c, in_memory(|out| lpaq1_compress(trickle(&data), out, ModelSet::parse("o2,match").unwrap(), false, 0, false)));
+
+    assert_eq!(stats.len(), 1);
+    // Every bit but the first, before the Mixer has inputs.
+    assert_eq!(stats[0].bits(), data.len() as u64 * 8 - 1);
+    let names = stats[0].inputs().iter().map(|input| input.name).collect::<Vec<_>>();
+    assert_eq!(names, ["match", "match-len", "o1", "o2"]);
+    assert!(stats[0].mixed() < 1.0, "{}", stats[0].mixed());
+}
+
+#[test]
+fn default_decompress_threads_are_bounded() {
+    // Without --threads or --max-memory, one per CPU up to the cap.
+    let threads = decompress_threads(None, None);
+    assert!((1..=MAX_DEFAULT_THREADS).contains(&threads), "{}", threads);
+    assert_eq!(decompress_threads(None, Some(1 << 30)), 1);
+    assert_eq!(decompress_threads(Some(16), None), 16);
+    assert_eq!(decompress_threads(Some(16), Some(1 << 30)), 16);
+
+    // A stream in blocks decompresses on the default threads.
+    let data = "blocks decoded on the default threads. ".repeat(4000).into_bytes();
+    let compressed = in_memory(|out| {
+        compress_blocks(trickle(&data), out, 1 << 14, 2, false, |file_in, file_out| {
+            lzw_compress(file_in, file_out)
+        })
+    });
+    let restored = in_memory(|out| {
+        decompress_blocks(BufReader::new(&compressed[..]), out, threads, |file_in, file_out| {
+            lzw_decompress(file_in, file_out)
+        })
+    });
+    assert_eq!(restored, data);
+}
+
+#[test]
+fn errors_are_returned() {
+    let data = "errors come back from the call instead of panicking. ".repeat(500).into_bytes();
+
+    let result = lzw_compress(trickle(&data), BufWriter::new(Full));
+    assert!(matches!(result, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::StorageFull));
+
+    let mut c = in_memory(|out| fpaq_compress(trickle(&data), out, &SseProfile::default()));
+    c.truncate(3);
+    let result = fpaq_decompress(trickle(&c), BufWriter::new(io::sink()));
+    assert!(matches!(result, Err(Error::InvalidData(_))));
+
+    let sizes = lzw_compress(trickle(&data), BufWriter::new(io::sink())).unwrap();
+    assert_eq!(sizes.input, data.len() as u64);
+
+    // A read error stops the encoder where it is, rather than ending the
+    // stream there as if the input had.
+    for failing_at in [0, 100, 10_000] {
+        let input = || BufReader::with_capacity(64, Failing(&data, failing_at));
+        let mut out = Vec::new();
+        let result = lpaq1_compress(input(), BufWriter::new(Cursor::new(&mut out)), ModelSet::default(), false, 0, false);
+        assert!(matches!(result, Err(Error::Io(ref e)) if e.kind() == io::ErrorKind::Other));
+        let result = flzp_compress(input(), BufWriter::new(io::sink()), Effort::Greedy, false, DEFAULT_CXT_LEN, false);
+        assert!(matches!(result, Err(Error::Io(_))));
+        let result = fpaq_compress(input(), BufWriter::new(io::sink()), &SseProfile::default());
+        assert!(matches!(result, Err(Error::Io(_))));
+    }
+}
+
+#[test]
+fn bytes_round_trip_every_algorithm() {
+    let data = "no files needed, 0123456789. ".repeat(400).into_bytes();
+    for algorithm in Algorithm::ALL {
+        let compressed = compress_bytes(&data, algorithm);
+        assert_eq!(decompress_bytes(&compressed, algorithm, None).unwrap(), data, "{:?}", algorithm);
+        assert_eq!(decompress_bytes(&compress_bytes(&[], algorithm), algorithm, None).unwrap(), [], "{:?}", algorithm);
+    }
+
+    let compressed = compress_bytes(&data, Algorithm::Lpaq1);
+    assert!(matches!(decompress_bytes(&compressed[..10], Algorithm::Lpaq1, None), Err(Error::InvalidData(_))));
+    assert!(matches!(decompress_bytes(&compressed, Algorithm::Lpaq1, Some(1 << 20)), Err(Error::OutOfMemory(_))));
+}
+
+#[test]
+fn messages_round_trip_with_a_warm_model() {
+    let dictionary = br#"{"level":"info","service":"api","msg":"request served","status":200,"path":"/v1/users","ms":12}"#.repeat(20);
+    let messages = (0..200).map(|i| {
+        format!(r#"{{"level":"{}","service":"api","msg":"request served","status":{},"path":"/v1/users/{}","ms":{}}}"#,
+            ["info", "warn", "error"][i % 3], 200 + i % 5, i * 37, i % 90).into_bytes()
+    }).chain([Vec::new(), vec![0xAB; 300]]).collect::<Vec<_>>();
+
+    let mut compressor = MessageCodec::new(&dictionary);
+    let mut decompressor = MessageCodec::new(&dictionary);
+    let mut size = 0;
+    for msg in messages.iter() {
+        let compressed = compressor.compress_msg(msg);
+        size += compressed.len();
+        assert_eq!(decompressor.decompress_msg(&compressed).unwrap(), *msg);
+    }
+    let total = messages.iter().map(|msg| msg.len()).sum::<usize>();
+    // Each message alone, with its header and a cold model, expands.
+    assert!(compress_bytes(&messages[0], Algorithm::Lpaq1).len() > messages[0].len());
+    assert!(size < total / 4, "{} bytes -> {}", total, size);
+
+    // A decompressor without the dictionary gets something else, and a
+    // truncated message is reported.
+    let compressed = compressor.compress_msg(&messages[7]);
+    assert_ne!(MessageCodec::new(b"").decompress_msg(&compressed).ok(), Some(messages[7].clone()));
+    let result = MessageCodec::new(&dictionary).decompress_msg(&compressed[..compressed.len() / 2]);
+    assert!(matches!(result, Err(Error::InvalidData(_))));
+    assert!(matches!(MessageCodec::new(b"").decompress_msg(&[0x80]), Err(Error::InvalidData(_))));
+}
+
+#[test]
+fn framed_members_can_be_embedded() {
+    let data = "embedded in someone else's container. ".repeat(200).into_bytes();
+    let mut container = Vec::new();
+    for algorithm in Algorithm::ALL {
+        write_member(&mut container, &data, algorithm, Framing::LengthPrefixed).unwrap();
+        write_member(&mut container, &data[..100], algorithm, Framing::SelfDelimiting).unwrap();
+    }
+    container.extend_from_slice(b"trailing data");
+
+    let mut file_in = &container[..];
+    for algorithm in Algorithm::ALL {
+        assert_eq!(read_member(&mut file_in, algorithm, Framing::LengthPrefixed, None).unwrap(), data, "{:?}", algorithm);
+        assert_eq!(read_member(&mut file_in, algorithm, Framing::SelfDelimiting, None).unwrap(), &data[..100], "{:?}", algorithm);
+    }
+    assert_eq!(file_in, b"trailing data");
+
+    // Raw members take all of what they're given.
+    let mut raw = Vec::new();
+    write_member(&mut raw, &data, Algorithm::Lzw, Framing::Raw).unwrap();
+    assert_eq!(read_member(&mut &raw[..], Algorithm::Lzw, Framing::Raw, None).unwrap(), data);
+
+    for framing in [Framing::LengthPrefixed, Framing::SelfDelimiting] {
+        let mut member = Vec::new();
+        write_member(&mut member, &data, Algorithm::Fpaq, framing).unwrap();
+        let result = read_member(&mut &member[..member.len() - 2], Algorithm::Fpaq, framing, None);
+        assert!(matches!(result, Err(Error::InvalidData(_))), "{:?}", framing);
+    }
+}
+
+#[test]
+fn jobs_run_in_the_background_and_can_be_cancelled() {
+    let input = temp_path("job-in");
+    let compressed = temp_path("job-c");
+    let output = temp_path("job-out");
+    let data = "compressed on another thread while the caller waits. ".repeat(1000);
+    fs::write(&input, &data).unwrap();
+
+    let job = Job::compress(&input, &compressed, Algorithm::Lpaq1).unwrap();
+    while !job.is_finished() {
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+    assert_eq!(job.progress(), (data.len() as u64, data.len() as u64));
+    job.wait().unwrap();
+    assert_eq!(fs::read(&compressed).unwrap(), compress_bytes(data.as_bytes(), Algorithm::Lpaq1));
+    Job::decompress(&compressed, &output, Algorithm::Lpaq1, None).unwrap().wait().unwrap();
+    assert_eq!(fs::read(&output).unwrap(), data.as_bytes());
+
+    // Cancelled before it reads all of its input, which it reads 1 MB
+    // at a time, so it stops and removes its output.
+    fs::write(&input, data.repeat(60)).unwrap();
+    let job = Job::compress(&input, &compressed, Algorithm::Lpaq1).unwrap();
+    job.cancel();
+    assert!(matches!(job.wait(), Err(Error::Cancelled)));
+    assert!(!compressed.exists());
+
+    for path in [input, output] {
+        fs::remove_file(path).unwrap();
+    }
+}
+
+#[test]
+fn checksums_match_reference_values() {
+    let mut crc = Crc32::new();
+    crc.update(b"123456789");
+    assert_eq!(crc.value(), 0xCBF43926);
+
+    for (data, hash) in [
+        (&b""[..], 0xEF46DB3751D8E999),
+        (b"a", 0xD24EC4F1A98C6E5B),
+        (b"abc", 0x44BC2CF5AD770999),
+        (b"Nobody inspects the spammish repetition", 0xFBCEA83C8A378BF1),
+    ] {
+        let mut xxh = Xxh64::new(0);
+        xxh.update(data);
+        assert_eq!(xxh.digest(), hash, "{:?}", data);
+    }
+
+    // Fed in pieces that don't line up with its 32 byte stripes.
+    let data = "Nobody inspects the spammish repetition".repeat(100).into_bytes();
+    let mut whole = Xxh64::new(0);
+    whole.update(&data);
+    let mut pieces = Xxh64::new(0);
+    for piece in data.chunks(7) {
+        pieces.update(piece);
+    }
+    assert_eq!(pieces.digest(), whole.digest());
+}
+
+#[test]
+fn cpu_kernels_agree_at_every_level() {
+    let mut seed = 1u64;
+    let mut next = || {
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (seed >> 32) as i32
+    };
+    // Large enough to overflow, as the kernels wrap.
+    let inputs = (0..37).map(|_| next()).collect::<Vec<i32>>();
+    let weights = (0..40).map(|_| next()).collect::<Vec<i32>>();
+    let error = next() >> 16;
+    let bytes = (0..100).map(|i| (i * 7) as u8).collect::<Vec<u8>>();
+
+    // Kernel results at a level, for every length of input, and a
+    // mismatch at every position.
+    let run = |level: Level| {
+        cpu::set(level).unwrap();
+        let mut results = Vec::new();
+        for n in 0..=inputs.len() {
+            results.push(cpu::dot_product(&inputs[..n], &weights) as i64);
+            let mut trained = weights.clone();
+            cpu::train(&inputs[..n], &mut trained, error);
+            results.extend(trained.iter().map(|&w| w as i64));
+        }
+        for i in 0..=bytes.len() {
+            let mut other = bytes.clone();
+            if i < other.len() {
+                other[i] ^= 0x40;
+            }
+            results.push(cpu::common_prefix(&bytes, &other) as i64);
+            results.push(cpu::common_prefix(&bytes[..i], &bytes) as i64);
+        }
+        results
+    };
+    let scalar = run(Level::Scalar);
+    assert_eq!(scalar[..3], [0, weights[0] as i64, weights[1] as i64]);
+    for level in [Level::Sse2, Level::Avx2, Level::Neon].into_iter().filter(|level| level.supported()) {
+        assert!(run(level) == scalar, "{}", level.name());
+    }
+    cpu::set(cpu::detect()).unwrap();
+
+    assert!(cpu::set(Level::Scalar).is_ok());
+    assert_eq!(cpu::level(), Level::Scalar);
+    let unsupported = if cfg!(target_arch = "aarch64") { Level::Avx2 } else { Level::Neon };
+    assert_eq!(cpu::set(unsupported).unwrap_err().kind(), io::ErrorKind::Unsupported);
+    cpu::set(cpu::detect()).unwrap();
+}
+
+#[test]
+fn concatenated_members_decompress_without_seeking() {
+    // Trickle can't seek, like a pipe, and hands over 7 bytes at a time,
+    // so trailers straddle reads.
+    let first = "a first member, which repeats itself. ".repeat(300).into_bytes();
+    let second = b"and a short second one".to_vec();
+    let joined = [compress_bytes(&first, Algorithm::Lzw), compress_bytes(&second, Algorithm::Lzw)].concat();
+    let restored = in_memory(|out| {
+        container::decompress(trickle(&joined), out, Algorithm::Lzw, |_, file_in, file_out| lzw_decompress(file_in, file_out))
+    });
+    assert_eq!(restored, [first, second].concat());
+
+    let result = container::decompress(trickle(&joined[..joined.len() - 1]), BufWriter::new(io::sink()), Algorithm::Lzw,
+        |_, file_in, file_out| lzw_decompress(file_in, file_out));
+    assert!(matches!(result, Err(Error::InvalidData(ref msg)) if msg == "truncated stream"), "{:?}", result);
+}
+
+#[test]
+fn temp_files_are_new_and_distinct() {
+    let (first, _) = temp::create("library", "temp").unwrap();
+    let (second, _) = temp::create("library", "temp").unwrap();
+    assert_ne!(first, second);
+
+    // Files left where the next names would be are skipped, not reused.
+    let name = second.file_name().unwrap().to_str().unwrap();
+    let n = name.split('-').nth(2).unwrap().parse::<usize>().unwrap();
+    let taken = (n + 1..n + 65)
+        .map(|n| env::temp_dir().join(format!("library-{}-{}-temp", std::process::id(), n)))
+        .collect::<Vec<_>>();
+    for path in taken.iter() {
+        fs::write(path, b"someone else's").unwrap();
+    }
+    let (third, mut file) = temp::create("library", "temp").unwrap();
+    file.write_all(b"ours").unwrap();
+    assert!(!taken.contains(&third));
+    for path in taken.iter() {
+        assert_eq!(fs::read(path).unwrap(), b"someone else's");
+    }
+    for path in [first, second, third].iter().chain(taken.iter()) {
+        fs::remove_file(path).unwrap();
+    }
+}
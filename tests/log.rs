@@ -0,0 +1,55 @@
+// The fixed point squash and stretch against their float definitions.
+
+use compression::ari::log::{squash, stretch, squash16, stretch16, STRETCH16_SCALE, STRETCH16_MAX};
+
+fn logistic(x: f64) -> f64 {
+    1.0 / (1.0 + (-x).exp())
+}
+
+fn logit(p: f64) -> f64 {
+    (p / (1.0 - p)).ln()
+}
+
+#[test]
+fn squash16_matches_float() {
+    for d in -40000..=40000 {
+        let expected = (65536.0 * logistic(d as f64 / STRETCH16_SCALE as f64)).round().clamp(1.0, 65535.0);
+        let p = squash16(d);
+        assert!((p - expected as i32).abs() <= 1, "squash16({}) = {}, not {}", d, p, expected);
+    }
+}
+
+#[test]
+fn stretch16_matches_float() {
+    for p in 1..65536 {
+        let expected = (STRETCH16_SCALE as f64 * logit(p as f64 / 65536.0)).round() as i32;
+        assert_eq!(stretch16(p), expected, "stretch16({})", p);
+    }
+    assert_eq!(stretch16(0), stretch16(1));
+    assert_eq!(stretch16(1), -STRETCH16_MAX);
+    assert_eq!(stretch16(65535), STRETCH16_MAX);
+}
+
+#[test]
+fn stretch16_inverts_squash16() {
+    for p in 1..65536 {
+        let q = squash16(stretch16(p));
+        assert!((q - p).abs() <= 1 + p.min(65536 - p) / 2048, "squash16(stretch16({})) = {}", p, q);
+    }
+}
+
+// The 12 bit versions interpolate a coarse table, so they're only close,
+// and they're fixed by the formats that use them.
+#[test]
+fn squash_is_close_to_float() {
+    for d in -2047..=2047 {
+        let expected = 4096.0 * logistic(d as f64 / 256.0);
+        let p = squash(d);
+        assert!((p as f64 - expected).abs() < 16.0, "squash({}) = {}, not {:.1}", d, p, expected);
+    }
+    for p in 1..4095 {
+        let expected = (256.0 * logit(p as f64 / 4096.0)).clamp(-2047.0, 2047.0);
+        let d = stretch(p);
+        assert!((d as f64 - expected).abs() < 40.0, "stretch({}) = {}, not {:.1}", p, d, expected);
+    }
+}
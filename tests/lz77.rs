@@ -0,0 +1,157 @@
+// Regression tests for lz77 match decoding.
+
+use std::env;
+use std::fs;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::Command;
+
+use compression::lz::lz77::{lz77_compress, lz77_decompress};
+
+const EXE: &str = env!("CARGO_BIN_EXE_compression");
+
+// Stream header: overlap flag and 11 window bits, 5 length bits.
+const HEADER: [u8; 2] = [0x80 | 11, 5];
+
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("lz77-{}-{}", std::process::id(), name))
+}
+
+fn literal(byte: u8) -> [u8; 2] {
+    [0, byte]
+}
+
+fn match_token(offset: u16, len: u16) -> [u8; 2] {
+    ((offset << 5) | len).to_be_bytes()
+}
+
+// Run the CLI on data, returning the output if it succeeded.
+fn run(mode: &str, name: &str, data: &[u8]) -> Option<Vec<u8>> {
+    let input = temp_path(name);
+    let output = temp_path(&format!("{}.out", name));
+    fs::write(&input, data).unwrap();
+
+    let status = Command::new(EXE)
+        .args(["-lz77", mode, "--no-mtime"])
+        .arg(&input)
+        .arg(&output)
+        .output()
+        .unwrap()
+        .status;
+    let result = fs::read(&output).ok();
+
+    fs::remove_file(&input).unwrap();
+    let _ = fs::remove_file(&output);
+    if status.success() { result } else { None }
+}
+
+// Offsets below 8 can't be coded, so fill window positions 0..8 first.
+// The container header in front gives size, the length of the output,
+// and the trailer after gives the length of the whole member.
+fn stream(tokens: &[[u8; 2]], size: u64) -> Vec<u8> {
+    stream_with_header(HEADER, tokens, size)
+}
+
+fn stream_with_header(header: [u8; 2], tokens: &[[u8; 2]], size: u64) -> Vec<u8> {
+    let mut data = [&b"CMPR"[..], &[2, 1, 0], &size.to_le_bytes()].concat();
+    data.extend(header);
+    for byte in b"abcdefgh" {
+        data.extend(literal(*byte));
+    }
+    for token in tokens {
+        data.extend(token);
+    }
+    data.extend((data.len() as u64 + 12).to_le_bytes());
+    data.extend(b"CEND");
+    data
+}
+
+#[test]
+fn overlapping_match_repeats_single_byte() {
+    let data = stream(&[literal(b'x'), match_token(8, 6)], 15);
+    assert_eq!(run("-d", "run", &data).unwrap(), b"abcdefghxxxxxxx");
+}
+
+#[test]
+fn overlapping_match_repeats_pattern() {
+    let data = stream(&[literal(b'x'), literal(b'y'), match_token(8, 7)], 17);
+    assert_eq!(run("-d", "pattern", &data).unwrap(), b"abcdefghxyxyxyxyx");
+}
+
+#[test]
+fn non_overlapping_match() {
+    let data = stream(&[
+        literal(b'p'), literal(b'q'), literal(b'r'), literal(b's'),
+        match_token(9, 2), literal(b'z'), match_token(10, 3),
+    ], 18);
+    assert_eq!(run("-d", "plain", &data).unwrap(), b"abcdefghpqrsqrzrsq");
+}
+
+#[test]
+fn streams_without_overlap_flag_reject_overlapping_matches() {
+    let plain = [
+        literal(b'p'), literal(b'q'), literal(b'r'), literal(b's'),
+        match_token(9, 2), literal(b'z'), match_token(10, 3),
+    ];
+    let data = stream_with_header([11, 5], &plain, 18);
+    assert_eq!(run("-d", "flagless", &data).unwrap(), b"abcdefghpqrsqrzrsq");
+
+    let data = stream_with_header([11, 5], &[literal(b'x'), match_token(8, 6)], 15);
+    assert!(run("-d", "flagless-run", &data).is_none());
+}
+
+#[test]
+fn unknown_header_flags_are_rejected() {
+    for flag in [0x10, 0x20, 0x40] {
+        let data = stream_with_header([0x80 | flag | 11, 5], &[literal(b'x')], 9);
+        assert!(run("-d", &format!("flag-{:x}", flag), &data).is_none());
+    }
+}
+
+#[test]
+fn encoder_uses_overlapping_matches_for_runs() {
+    let data = vec![b'a'; 1000];
+    let compressed = run("-c", "runs", &data).unwrap();
+    // One literal plus one 31 byte match per 31 bytes of input, and the
+    // container header and trailer.
+    assert!(compressed.len() < 112, "{} bytes", compressed.len());
+    assert_eq!(run("-d", "runs.c", &compressed).unwrap(), data);
+}
+
+#[test]
+fn truncated_token_is_rejected() {
+    let mut data = stream(&[literal(b'x')], 9);
+    assert_eq!(run("-d", "whole", &data).unwrap(), b"abcdefghx");
+    data.push(0x01);
+    assert!(run("-d", "truncated", &data).is_none());
+}
+
+// A reader that returns one byte per read, like a slow pipe.
+struct OneByte<'a>(&'a [u8]);
+
+impl Read for OneByte<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.0.len()).min(1);
+        buf[..len].copy_from_slice(&self.0[..len]);
+        self.0 = &self.0[len..];
+        Ok(len)
+    }
+}
+
+#[test]
+fn header_split_across_reads_is_decoded() {
+    let data = "a header that arrives a byte at a time. ".repeat(50).into_bytes();
+    let mut compressed = Vec::new();
+    lz77_compress(BufReader::new(&data[..]), BufWriter::new(&mut compressed)).unwrap();
+
+    let mut restored = Vec::new();
+    lz77_decompress(BufReader::new(OneByte(&compressed)), BufWriter::new(&mut restored)).unwrap();
+    assert_eq!(restored, data);
+
+    let mut restored = Vec::new();
+    let result = lz77_decompress(BufReader::new(OneByte(&compressed[..1])), BufWriter::new(&mut restored));
+    assert!(result.unwrap_err().to_string().contains("truncated lz77 header"));
+}